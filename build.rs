@@ -38,8 +38,8 @@ fn main() {
         version.truncate(l);
     }
 
-    writeln!(f, "pub const VERSION: &'static str = \
+    writeln!(f, "pub const VERSION: &str = \
                  \"{}\";", version).unwrap();
-    writeln!(f, "pub const VERSION_CSTR: &'static str = \
+    writeln!(f, "pub const VERSION_CSTR: &str = \
                  \"{}\\0\";", version).unwrap();
 }
\ No newline at end of file
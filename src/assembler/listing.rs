@@ -0,0 +1,310 @@
+//! Assembler listing and symbol map
+//!
+//! The listing shows the address and the generated code next to every
+//! line of the source. The symbol map uses the plain `address name`
+//! format of no$psx `.sym` files, one symbol per line, which is also
+//! understood by most tools loading `.map` files.
+
+use std::fmt::Write;
+
+use super::syntax::*;
+use super::{parser, Assembler};
+
+impl<'a> Assembler<'a> {
+    /// Assemble the GNU-style `source` (see `parser`) and return its
+    /// listing: the address, the generated code and the text of every
+    /// source line. Errors are prefixed by the line number. The parsed
+    /// instructions are allocated in `arena`.
+    pub fn assemble_listing(&mut self,
+                            arena: &'a parser::Arena,
+                            source: &str) -> Result<String, String> {
+        let lines = parser::parse_lines(arena, source).map_err(|e| e.to_string())?;
+        let instructions: Vec<_> = lines.iter().map(|&(_, i)| i).collect();
+
+        self.parse_labels(&instructions)?;
+
+        // Line number and address range of the code generated by each
+        // instruction
+        let mut spans = Vec::with_capacity(lines.len());
+
+        for &(line, i) in &lines {
+            let start = self.machine_code.len();
+
+            self.assemble_statement(i)
+                .map_err(|e| format!("{}: {}", line, e))?;
+
+            spans.push((line, i, start, self.machine_code.len()));
+        }
+
+        let mut listing = String::new();
+        let mut spans = spans.into_iter().peekable();
+
+        for (n, text) in source.lines().enumerate() {
+            // Address and hex dump of each row of the line
+            let mut rows: Vec<(u32, String)> = Vec::new();
+            // Data bytes not yet added to `rows`
+            let mut data: Vec<u8> = Vec::new();
+            let mut data_start = 0;
+
+            while let Some(&(line, i, s, e)) = spans.peek() {
+                if line != n + 1 {
+                    break;
+                }
+
+                spans.next();
+
+                let addr = self.base.wrapping_add(s as u32);
+                let code = &self.machine_code[s..e];
+
+                if rows.is_empty() && data.is_empty() {
+                    rows.push((addr, String::new()));
+                }
+
+                match i {
+                    // Padding is not dumped
+                    Align(_) | Org(_) => (),
+                    // Data is shown as bytes in memory order
+                    Half(_) | Byte(_) | Ascii(_) |
+                    Fixup(&Half(_), _, _) | Fixup(&Byte(_), _, _) => {
+                        if data.is_empty() {
+                            data_start = addr;
+                        }
+
+                        data.extend_from_slice(code);
+                    }
+                    // Anything else is made of words
+                    _ => {
+                        push_data(&mut rows, &mut data, data_start);
+
+                        for (w, word) in code.chunks(4).enumerate() {
+                            let word = u32::from_le_bytes(word.try_into().unwrap());
+
+                            rows.push((addr.wrapping_add(w as u32 * 4),
+                                       format!("{:08x}", word)));
+                        }
+                    }
+                }
+            }
+
+            push_data(&mut rows, &mut data, data_start);
+
+            // Rows created for an address only are replaced by the
+            // first actual dump
+            if rows.len() > 1 && rows[0].1.is_empty() {
+                rows.remove(0);
+            }
+
+            if rows.is_empty() {
+                push_line(&mut listing, "", "", text);
+            }
+
+            for (r, (addr, dump)) in rows.iter().enumerate() {
+                let text = if r == 0 { text } else { "" };
+
+                push_line(&mut listing, &format!("{:08x}", addr), dump, text);
+            }
+        }
+
+        Ok(listing)
+    }
+
+    /// Return all the global and local labels sorted by address. Local
+    /// labels are named after the closest global label defined before
+    /// them, `start.1` for the local `1` following `start`, or `.1`
+    /// if there's none.
+    pub fn symbols(&self) -> Vec<(u32, String)> {
+        let mut globals: Vec<_> = self.globals().map(|(n, a)| (a, n)).collect();
+        globals.sort();
+
+        let mut symbols: Vec<_> =
+            self.locals.iter()
+            .map(|&(addr, id)| {
+                let scope =
+                    match globals.partition_point(|&(a, _)| a <= addr) {
+                        0 => "",
+                        p => globals[p - 1].1,
+                    };
+
+                (addr, 1, format!("{}.{}", scope, id))
+            })
+            .collect();
+
+        symbols.extend(globals.iter().map(|&(a, n)| (a, 0, n.to_owned())));
+
+        // Globals come before the locals at the same address
+        symbols.sort();
+        symbols.dedup();
+
+        symbols.into_iter().map(|(a, _, n)| (a, n)).collect()
+    }
+
+    /// Generate a symbol map with one `address name` line per label, in
+    /// the order of `symbols`
+    pub fn symbol_map(&self) -> String {
+        let mut map = String::new();
+
+        for (addr, name) in self.symbols() {
+            let _ = writeln!(map, "{:08x} {}", addr, name);
+        }
+
+        map
+    }
+}
+
+/// Parse a symbol map in the format generated by
+/// `Assembler::symbol_map`. Empty lines and lines starting with `#` or
+/// `;` are ignored, the address may have a `0x` prefix.
+pub fn parse_symbol_map(map: &str) -> Result<Vec<(u32, String)>, String> {
+    let mut symbols = Vec::new();
+
+    for (n, line) in map.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        let (addr, name) =
+            match line.split_once(char::is_whitespace) {
+                Some((a, n)) => (a, n.trim()),
+                None => return Err(format!("{}: Missing symbol name", n + 1)),
+            };
+
+        let addr = addr.strip_prefix("0x").unwrap_or(addr);
+
+        let addr = u32::from_str_radix(addr, 16)
+            .map_err(|_| format!("{}: Invalid address '{}'", n + 1, addr))?;
+
+        symbols.push((addr, name.to_owned()));
+    }
+
+    Ok(symbols)
+}
+
+/// Add `data` to `rows`, up to 4 bytes per row
+fn push_data(rows: &mut Vec<(u32, String)>, data: &mut Vec<u8>, start: u32) {
+    for (c, chunk) in data.chunks(4).enumerate() {
+        let dump = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+
+        rows.push((start.wrapping_add(c as u32 * 4), dump));
+    }
+
+    data.clear();
+}
+
+fn push_line(listing: &mut String, addr: &str, data: &str, text: &str) {
+    let line = format!("{:<8} {:<8}  {}", addr, data, text);
+
+    listing.push_str(line.trim_end());
+    listing.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let source = [
+            "        .macro inc r",
+            "        addiu \\r, \\r, 1",
+            "        .endm",
+            "start:  li $t0, 0x12345678",
+            "        .byte 1, 2, 3",
+            "        .half 0x0504",
+            "        .ascii \"abcdefgh\"",
+            "        .align 2",
+            "1:      inc $t0",
+            "        .word start",
+            "        b 1b",
+            "        nop",
+        ].join("\n");
+
+        let arena = parser::Arena::new();
+        let mut asm = Assembler::from_base(0x8001_0000);
+
+        let listing = asm.assemble_listing(&arena, &source).unwrap();
+
+        // Data is dumped in memory order, 4 bytes per row. Macro
+        // expansions are shown on the invocation line.
+        assert_eq!(listing.lines().collect::<Vec<_>>(), [
+            "                           .macro inc r",
+            "                           addiu \\r, \\r, 1",
+            "                           .endm",
+            "80010000 3c081234  start:  li $t0, 0x12345678",
+            "80010004 35085678",
+            "80010008 010203            .byte 1, 2, 3",
+            "8001000b 0405              .half 0x0504",
+            "8001000d 61626364          .ascii \"abcdefgh\"",
+            "80010011 65666768",
+            "80010015                   .align 2",
+            "80010018 25080001  1:      inc $t0",
+            "8001001c 80010000          .word start",
+            "80010020 1000fffd          b 1b",
+            "80010024 00000000          nop",
+        ]);
+    }
+
+    #[test]
+    fn listing_errors() {
+        let arena = parser::Arena::new();
+        let mut asm = Assembler::from_base(0x8001_0000);
+
+        assert_eq!(asm.assemble_listing(&arena, "nop\n\nb missing"),
+                   Err("3: Unknown global label 'missing'".into()));
+        assert_eq!(asm.assemble_listing(&arena, "nop\n  lw $t0"),
+                   Err("2:9: Expected ',', found end of line".into()));
+    }
+
+    #[test]
+    fn symbols() {
+        let arena = parser::Arena::new();
+        let mut asm = Assembler::from_base(0x8001_0000);
+
+        let source = "\
+1:      nop
+start:
+1:      nop
+2:      nop
+1:      b 1b
+        nop
+end:
+        .equ SIZE, end - start";
+
+        asm.assemble_listing(&arena, source).unwrap();
+
+        let symbols = asm.symbols();
+
+        assert_eq!(symbols, [
+            (0x8001_0000, ".1".to_owned()),
+            (0x8001_0004, "start".to_owned()),
+            (0x8001_0004, "start.1".to_owned()),
+            (0x8001_0008, "start.2".to_owned()),
+            (0x8001_000c, "start.1".to_owned()),
+            (0x8001_0014, "end".to_owned()),
+        ]);
+
+        let map = asm.symbol_map();
+
+        assert_eq!(map.lines().next(), Some("80010000 .1"));
+        assert_eq!(parse_symbol_map(&map), Ok(symbols));
+    }
+
+    #[test]
+    fn symbol_map_syntax() {
+        let map = "\
+# Comment
+; Other comment
+
+0x80010000 start
+8001000c   main loop  ";
+
+        assert_eq!(parse_symbol_map(map), Ok(vec![
+            (0x8001_0000, "start".to_owned()),
+            (0x8001_000c, "main loop".to_owned()),
+        ]));
+
+        assert_eq!(parse_symbol_map("80010000"), Err("1: Missing symbol name".into()));
+        assert_eq!(parse_symbol_map("\nfoo bar"), Err("2: Invalid address 'foo'".into()));
+    }
+}
@@ -0,0 +1,1514 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+
+pub mod parser;
+pub mod output;
+pub mod text;
+pub mod listing;
+
+pub mod syntax {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Register(pub u8);
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Label<'a> {
+        Local(&'a str, char),
+        Global(&'a str),
+        Absolute(u32),
+        /// Address computed from an expression
+        Expr(&'a Expr<'a>),
+    }
+
+    /// Expression evaluated when the instruction is assembled, with
+    /// 64-bit signed arithmetic
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Expr<'a> {
+        Const(i64),
+        /// Address of a label, or value of a `.equ` symbol
+        Label(Label<'a>),
+        Neg(&'a Expr<'a>),
+        Not(&'a Expr<'a>),
+        Add(&'a Expr<'a>, &'a Expr<'a>),
+        Sub(&'a Expr<'a>, &'a Expr<'a>),
+        Mul(&'a Expr<'a>, &'a Expr<'a>),
+        Div(&'a Expr<'a>, &'a Expr<'a>),
+        Shl(&'a Expr<'a>, &'a Expr<'a>),
+        Shr(&'a Expr<'a>, &'a Expr<'a>),
+        And(&'a Expr<'a>, &'a Expr<'a>),
+        Or(&'a Expr<'a>, &'a Expr<'a>),
+        Xor(&'a Expr<'a>, &'a Expr<'a>),
+        /// High halfword, adjusted for a sign-extended `Lo`
+        Hi(&'a Expr<'a>),
+        /// Low halfword, sign-extended
+        Lo(&'a Expr<'a>),
+    }
+
+    /// Assembler option changed by `Set`
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum SetOption {
+        /// Pseudo-instructions may use $at (default)
+        At,
+        /// Pseudo-instructions needing $at are rejected
+        NoAt,
+        /// A nop is added after every branch and jump to fill the
+        /// delay slot
+        Reorder,
+        /// Delay slots are filled by hand (default)
+        NoReorder,
+    }
+
+    /// Field of an instruction patched by a `Fixup`
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Field {
+        /// Signed 16-bit immediate
+        Imm16Signed,
+        /// Unsigned 16-bit immediate
+        Imm16,
+        /// Shift amount
+        Shift,
+        Word,
+        Half,
+        Byte,
+    }
+
+    impl Field {
+        /// Range of the values that can be stored in the field
+        pub fn range(self) -> (i64, i64) {
+            match self {
+                Field::Imm16Signed => (i16::MIN as i64, i16::MAX as i64),
+                Field::Imm16 => (0, u16::MAX as i64),
+                Field::Shift => (0, 31),
+                Field::Word => (i32::MIN as i64, u32::MAX as i64),
+                Field::Half => (i16::MIN as i64, u16::MAX as i64),
+                Field::Byte => (i8::MIN as i64, u8::MAX as i64),
+            }
+        }
+
+        /// Encode `v` in the field. The value must be in range.
+        pub fn encode(self, v: i64) -> u32 {
+            match self {
+                Field::Imm16Signed | Field::Imm16 | Field::Half => v as u32 & 0xffff,
+                Field::Shift => (v as u32 & 0x1f) << 6,
+                Field::Word => v as u32,
+                Field::Byte => v as u32 & 0xff,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Instruction<'a> {
+        Sll(Register, Register, u8),
+        Srl(Register, Register, u8),
+        Sra(Register, Register, u8),
+        Sllv(Register, Register, Register),
+        Srlv(Register, Register, Register),
+        Srav(Register, Register, Register),
+        Jr(Register),
+        Jalr(Register, Register),
+        Syscall(u32),
+        Break(u32),
+        Mfhi(Register),
+        Mthi(Register),
+        Mflo(Register),
+        Mtlo(Register),
+        Mult(Register, Register),
+        Multu(Register, Register),
+        Div(Register, Register),
+        Divu(Register, Register),
+        Add(Register, Register, Register),
+        Addu(Register, Register, Register),
+        Sub(Register, Register, Register),
+        Subu(Register, Register, Register),
+        And(Register, Register, Register),
+        Or(Register, Register, Register),
+        Xor(Register, Register, Register),
+        Nor(Register, Register, Register),
+        Slt(Register, Register, Register),
+        Sltu(Register, Register, Register),
+        Bgez(Register, Label<'a>),
+        Bltz(Register, Label<'a>),
+        Bgezal(Register, Label<'a>),
+        Bltzal(Register, Label<'a>),
+        J(Label<'a>),
+        Jal(Label<'a>),
+        Beq(Register, Register, Label<'a>),
+        Bne(Register, Register, Label<'a>),
+        Blez(Register, Label<'a>),
+        Bgtz(Register, Label<'a>),
+        Addi(Register, Register, i16),
+        Addiu(Register, Register, i16),
+        Slti(Register, Register, i16),
+        Sltiu(Register, Register, i16),
+        Andi(Register, Register, u16),
+        Ori(Register, Register, u16),
+        Xori(Register, Register, u16),
+        Lui(Register, u16),
+        Lb(Register, Register, i16),
+        Lh(Register, Register, i16),
+        Lwl(Register, Register, i16),
+        Lw(Register, Register, i16),
+        Lbu(Register, Register, i16),
+        Lhu(Register, Register, i16),
+        Lwr(Register, Register, i16),
+        Sb(Register, Register, i16),
+        Sh(Register, Register, i16),
+        Swl(Register, Register, i16),
+        Sw(Register, Register, i16),
+        Swr(Register, Register, i16),
+
+        // Coprocessor opcodes
+        Mfc0(Register, u8),
+        Mtc0(Register, u8),
+        /// Return From Exception
+        Rfe,
+        Mfc2(Register, u8),
+        Cfc2(Register, u8),
+        Mtc2(Register, u8),
+        Ctc2(Register, u8),
+        /// Load word into GTE data register
+        Lwc2(u8, Register, i16),
+        /// Store word from GTE data register
+        Swc2(u8, Register, i16),
+
+        // GTE commands, encoded like the official SDK macros. The
+        // boolean `sf` parameter selects a 12-bit fraction shift.
+        Rtps,
+        Rtpt,
+        Nclip,
+        Op(bool),
+        Dpcs,
+        Intpl,
+        /// Multiply vector by matrix and add vector: sf, matrix (mx),
+        /// multiplied vector (v), translation vector (cv) and lm
+        /// (clamp negative results to 0)
+        Mvmva(bool, u8, u8, u8, bool),
+        Ncds,
+        Cdp,
+        Ncdt,
+        Nccs,
+        Cc,
+        Ncs,
+        Nct,
+        Sqr(bool),
+        Dcpl,
+        Dpct,
+        Avsz3,
+        Avsz4,
+        Gpf(bool),
+        Gpl(bool),
+        Ncct,
+        /// Raw GTE command, only the low 25 bits are used
+        Cop2(u32),
+
+        /// Global labels: can't be redefined
+        Global(&'a str),
+        /// Local labels: can be redefined
+        Local(&'a str),
+
+        /// Add padding (if necessary) to reach the desired byte
+        /// alignment expressed as a power of two. E.g. Align(2)
+        /// aligns on 4 bytes.
+        Align(u8),
+        /// Add padding to reach the given absolute address
+        Org(u32),
+
+        // Data
+        Word(u32),
+        Half(u16),
+        Byte(u8),
+        Ascii(&'a [u8]),
+
+        /// Define a symbol usable in expressions, like a global label
+        Equ(&'a str, &'a Expr<'a>),
+        /// Assemble the instruction then store the value of the
+        /// expression in the field
+        Fixup(&'a Instruction<'a>, Field, &'a Expr<'a>),
+        /// Change an assembler option for the following instructions
+        Set(SetOption),
+
+        // Pseudo-instructions
+        Nop,
+        Move(Register, Register),
+        /// Load immediate, can take two instructions if the immediate
+        /// has low and high halfword both non-zero.
+        Li(Register, u32),
+        /// Load address: always takes two instructions
+        La(Register, Label<'a>),
+        B(Label<'a>),
+        Beqz(Register, Label<'a>),
+        Bnez(Register, Label<'a>),
+        Not(Register, Register),
+        Neg(Register, Register),
+        // Compare with `slt` then branch: these use $at
+        Blt(Register, Register, Label<'a>),
+        Bge(Register, Register, Label<'a>),
+        Bgt(Register, Register, Label<'a>),
+        Ble(Register, Register, Label<'a>),
+        /// Multiply and keep the low 32 bits of the result: `mult`
+        /// then `mflo`
+        Mul(Register, Register, Register),
+        /// Store a register below $sp and decrement $sp
+        Push(Register),
+        /// Load a register from $sp and increment $sp
+        Pop(Register),
+    }
+
+    impl Instruction<'_> {
+        // Length of the instruction in bytes
+        pub fn bytes(&self, here: u32) -> u32 {
+            match *self {
+                Local(_) | Global(_) | Equ(..) | Set(_) => 0,
+                Fixup(i, _, _) => i.bytes(here),
+                Li(_, v) => {
+                    let mut b = 0;
+
+                    if v & 0xffff0000 != 0{
+                        b += 4;
+                    }
+
+                    if v & 0xffff != 0 || v == 0 {
+                        b += 4;
+                    }
+
+                    b
+                }
+                La(..) | Blt(..) | Bge(..) | Bgt(..) | Ble(..) | Mul(..)
+                    | Push(_) | Pop(_) => 8,
+                Align(o) => {
+                    super::pad_to_order(here, o)
+                }
+                Org(a) => a.saturating_sub(here),
+                Half(_) => 2,
+                Byte(_) => 1,
+                Ascii(s) => s.len() as u32,
+                _ => 4,
+            }
+        }
+
+        /// True if the instruction ends with a branch or jump, which
+        /// has a delay slot
+        pub fn has_delay_slot(&self) -> bool {
+            match *self {
+                Fixup(i, _, _) => i.has_delay_slot(),
+                Jr(_) | Jalr(..) | Bgez(..) | Bltz(..) | Bgezal(..) | Bltzal(..)
+                    | J(_) | Jal(_) | Beq(..) | Bne(..) | Blez(..) | Bgtz(..)
+                    | B(_) | Beqz(..) | Bnez(..)
+                    | Blt(..) | Bge(..) | Bgt(..) | Ble(..) => true,
+                _ => false,
+            }
+        }
+    }
+
+    pub use self::Instruction::*;
+
+    pub const R0: Register = Register(0);
+    pub const R1: Register = Register(1);
+    pub const R2: Register = Register(2);
+    pub const R3: Register = Register(3);
+    pub const R4: Register = Register(4);
+    pub const R5: Register = Register(5);
+    pub const R6: Register = Register(6);
+    pub const R7: Register = Register(7);
+    pub const R8: Register = Register(8);
+    pub const R9: Register = Register(9);
+    pub const R10: Register = Register(10);
+    pub const R11: Register = Register(11);
+    pub const R12: Register = Register(12);
+    pub const R13: Register = Register(13);
+    pub const R14: Register = Register(14);
+    pub const R15: Register = Register(15);
+    pub const R16: Register = Register(16);
+    pub const R17: Register = Register(17);
+    pub const R18: Register = Register(18);
+    pub const R19: Register = Register(19);
+    pub const R20: Register = Register(20);
+    pub const R21: Register = Register(21);
+    pub const R22: Register = Register(22);
+    pub const R23: Register = Register(23);
+    pub const R24: Register = Register(24);
+    pub const R25: Register = Register(25);
+    pub const R26: Register = Register(26);
+    pub const R27: Register = Register(27);
+    pub const R28: Register = Register(28);
+    pub const R29: Register = Register(29);
+    pub const R30: Register = Register(30);
+    pub const R31: Register = Register(31);
+
+    pub const AT: Register = R1;
+    pub const V0: Register = R2;
+    pub const V1: Register = R3;
+    pub const A0: Register = R4;
+    pub const A1: Register = R5;
+    pub const A2: Register = R6;
+    pub const A3: Register = R7;
+    pub const T0: Register = R8;
+    pub const T1: Register = R9;
+    pub const T2: Register = R10;
+    pub const T3: Register = R11;
+    pub const T4: Register = R12;
+    pub const T5: Register = R13;
+    pub const T6: Register = R14;
+    pub const T7: Register = R15;
+    pub const S0: Register = R16;
+    pub const S1: Register = R17;
+    pub const S2: Register = R18;
+    pub const S3: Register = R19;
+    pub const S4: Register = R20;
+    pub const S5: Register = R21;
+    pub const S6: Register = R22;
+    pub const S7: Register = R23;
+    pub const T8: Register = R24;
+    pub const T9: Register = R25;
+    pub const K0: Register = R26;
+    pub const K1: Register = R27;
+    pub const GP: Register = R28;
+    pub const SP: Register = R29;
+    pub const FP: Register = R30;
+    pub const RA: Register = R31;
+}
+
+use self::syntax::*;
+
+/// Assembler state
+pub struct Assembler<'a> {
+    /// Currently generated machine code
+    machine_code: Vec<u8>,
+    /// Address of the first instruction
+    base: u32,
+    /// Hash table containing the absolute address of all known global
+    /// labels. Global labels are unique and can't be redefined.
+    globals: HashMap<&'a str, u32>,
+    /// List of all the local labels with their absolute
+    /// address. Local labels can be redefined.
+    locals: Vec<(u32, &'a str)>,
+    /// Symbols defined with `Equ`, evaluated when used
+    equs: HashMap<&'a str, &'a Expr<'a>>,
+    /// Nesting level of `Equ` evaluation, used to detect circular
+    /// definitions
+    equ_depth: Cell<u32>,
+    /// Pseudo-instructions may use $at (`SetOption::At`)
+    at: bool,
+    /// Delay slots are filled automatically (`SetOption::Reorder`)
+    reorder: bool,
+}
+
+impl<'a> Assembler<'a> {
+    /// Create a new assembler instance which will generate code meant
+    /// to be loaded at the `base` address
+    pub fn from_base(base: u32) -> Assembler<'a> {
+        Assembler {
+            machine_code: Vec::new(),
+            base,
+            globals: HashMap::new(),
+            locals: Vec::new(),
+            equs: HashMap::new(),
+            equ_depth: Cell::new(0),
+            at: true,
+            reorder: false,
+        }
+    }
+
+    /// Attempt to assemble the provided instructions. Returns the
+    /// size of the generated machine code in bytes on success, a
+    /// String describing the assembler error on failure.
+    pub fn assemble(&mut self,
+                    instructions: &[Instruction<'a>]) -> Result<u32, String> {
+        let start_loc = self.location();
+
+        // First we map the labels. Local labels from previous calls are
+        // kept so that they can still be referenced backwards.
+        (self.parse_labels(instructions))?;
+
+        for (n, &i) in instructions.iter().enumerate() {
+            self.assemble_statement(i)
+                .map_err(|e| format!("Instruction {} ({:?}): {}", n, i, e))?;
+        }
+
+        Ok(self.location() - start_loc)
+    }
+
+    /// Consume the Assembler and return the generated machine code
+    /// alongside the base address
+    pub fn machine_code(self) -> (Vec<u8>, u32) {
+        (self.machine_code, self.base)
+    }
+
+    /// Address of the first instruction
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Machine code generated so far
+    pub fn code(&self) -> &[u8] {
+        &self.machine_code
+    }
+
+    /// Return the value of global label or `Equ` symbol `name`
+    pub fn symbol(&self, name: &str) -> Result<u32, String> {
+        match (self.globals.get(name), self.equs.get(name)) {
+            (Some(&v), _) => Ok(v),
+            (None, Some(e)) => self.eval(e).map(|v| v as u32),
+            (None, None) => Err(format!("Unknown symbol '{}'", name)),
+        }
+    }
+
+    /// Iterate over the global labels and their address
+    pub fn globals(&self) -> impl Iterator<Item = (&'a str, u32)> + '_ {
+        self.globals.iter().map(|(&n, &a)| (n, a))
+    }
+
+    fn location(&self) -> u32 {
+        self.base + self.machine_code.len() as u32
+    }
+
+    /// Look for global and local labels in `instructions` and collect
+    /// them
+    fn parse_labels(&mut self,
+                    instructions: &[Instruction<'a>]) -> Result<(), String> {
+        let mut loc = self.location();
+        let mut reorder = self.reorder;
+
+        for &i in instructions {
+            match i {
+                Global(name) =>
+                    if self.globals.insert(name, loc).is_some() {
+                        // Globals can't be redefined
+                        return Err(
+                            format!("Global label '{}' is redefined", name));
+                    },
+                // Locals can be redefined any number of times
+                Local(id) => self.locals.push((loc, id)),
+                Equ(name, e) =>
+                    if self.equs.insert(name, e).is_some() {
+                        return Err(
+                            format!("Symbol '{}' is redefined", name));
+                    },
+                Set(SetOption::Reorder) => reorder = true,
+                Set(SetOption::NoReorder) => reorder = false,
+                _ => {
+                    loc += i.bytes(loc);
+
+                    if reorder && i.has_delay_slot() {
+                        loc += 4;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn label_address(&self, label: Label) -> Result<u32, String> {
+        match label {
+            Label::Global(l) =>
+                match (self.globals.get(l), self.equs.get(l)) {
+                    (Some(&v), _) => Ok(v),
+                    (None, Some(e)) => {
+                        let depth = self.equ_depth.get();
+
+                        if depth >= 64 {
+                            return Err(format!("Circular definition of symbol '{}'", l));
+                        }
+
+                        self.equ_depth.set(depth + 1);
+                        let v = self.eval(e);
+                        self.equ_depth.set(depth);
+
+                        v.map(|v| v as u32)
+                    }
+                    (None, None) => Err(format!("Unknown global label '{}'", l)),
+                },
+            Label::Local(l, d) => {
+                let here = self.location();
+
+                // Search for the closest label with name `l` in the
+                // relative direction gived by `d`
+                let v =
+                    match d {
+                        // Search for the closest label *before* (and
+                        // including) `here`. We start by the end and
+                        // iterate backwards.
+                        'b' => self.locals.iter().rev()
+                            .find(|&&(addr, name)| {
+                                addr <= here && l == name
+                            }),
+                        // Search for the closest label *after* `here`
+                        'f' => self.locals.iter()
+                            .find(|&&(addr, name)| {
+                                addr > here && l == name
+                            }),
+                        _ => return
+                            Err(format!("Unknown local label direction '{}'",
+                                        d)),
+                    };
+
+                match v {
+                    Some(&(addr, _)) => Ok(addr),
+                    None => Err(format!("Unknown local label '{}'", l)),
+                }
+            },
+            Label::Absolute(a) => Ok(a),
+            Label::Expr(e) => {
+                let v = self.eval(e)?;
+
+                if v < i32::MIN as i64 || v > u32::MAX as i64 {
+                    return Err(format!("Address 0x{:x} out of range", v));
+                }
+
+                Ok(v as u32)
+            }
+        }
+    }
+
+    /// Evaluate `expr` at the current location
+    fn eval(&self, expr: &Expr) -> Result<i64, String> {
+        let bin = |a: &Expr, b: &Expr| -> Result<(i64, i64), String> {
+            Ok((self.eval(a)?, self.eval(b)?))
+        };
+
+        let v =
+            match *expr {
+                Expr::Const(v) => v,
+                // Addresses are unsigned
+                Expr::Label(Label::Expr(e)) => self.eval(e)?,
+                Expr::Label(l) => self.label_address(l)? as i64,
+                Expr::Neg(e) => self.eval(e)?.wrapping_neg(),
+                Expr::Not(e) => !self.eval(e)?,
+                Expr::Add(a, b) => { let (a, b) = bin(a, b)?; a.wrapping_add(b) }
+                Expr::Sub(a, b) => { let (a, b) = bin(a, b)?; a.wrapping_sub(b) }
+                Expr::Mul(a, b) => { let (a, b) = bin(a, b)?; a.wrapping_mul(b) }
+                Expr::Div(a, b) => {
+                    let (a, b) = bin(a, b)?;
+
+                    if b == 0 {
+                        return Err("Division by zero".into());
+                    }
+
+                    a.wrapping_div(b)
+                }
+                Expr::Shl(a, b) => { let (a, b) = bin(a, b)?; a.wrapping_shl(b as u32) }
+                Expr::Shr(a, b) => { let (a, b) = bin(a, b)?; a.wrapping_shr(b as u32) }
+                Expr::And(a, b) => { let (a, b) = bin(a, b)?; a & b }
+                Expr::Or(a, b) => { let (a, b) = bin(a, b)?; a | b }
+                Expr::Xor(a, b) => { let (a, b) = bin(a, b)?; a ^ b }
+                Expr::Hi(e) => {
+                    let v = self.eval(e)? as u32;
+
+                    (v.wrapping_add(0x8000) >> 16) as i64
+                }
+                Expr::Lo(e) => self.eval(e)? as i16 as i64,
+            };
+
+        Ok(v)
+    }
+
+    fn branch_target(&self, label: Label) -> Result<i16, String> {
+        // The offset is relative to the *next* instruction
+        let here = self.location().wrapping_add(4);
+
+        let there = self.label_address(label)?;
+
+        if there & 3 != 0 {
+            return Err(format!("Branch target '{}' (0x{:08x}) is not word aligned",
+                               label, there));
+        }
+
+        let delta = there.wrapping_sub(here) as i32;
+
+        // The offset is a signed 16 bit word count
+        if !(-0x2_0000..0x2_0000).contains(&delta) {
+            return Err(format!("Branch target '{}' (0x{:08x}) out of range: \
+                                {} bytes away from 0x{:08x}",
+                               label, there, delta, here));
+        }
+
+        // 2 LSBs are truncated since PC addresses are always word aligned
+        Ok((delta >> 2) as i16)
+    }
+
+    fn jump_target(&self, label: Label) -> Result<u32, String> {
+        let there = self.label_address(label)?;
+
+        if there & 3 != 0 {
+            return Err(format!("Jump target '{}' (0x{:08x}) is not word aligned",
+                               label, there));
+        }
+
+        // The 4 MSBs of the target come from the address of the delay
+        // slot, the jump can't leave the current 256MiB region
+        let here = self.location().wrapping_add(4);
+
+        if (here ^ there) & 0xf000_0000 != 0 {
+            return Err(format!("Jump target '{}' (0x{:08x}) is not in the \
+                                same 256MiB region as 0x{:08x}",
+                               label, there, here));
+        }
+
+        // 2 LSBs are truncated since PC addresses are always word aligned
+        Ok(there >> 2)
+    }
+
+    /// Check the operands which don't fit the full range of their
+    /// type
+    fn check_operands(instruction: Instruction) -> Result<(), String> {
+        let (what, v, max) =
+            match instruction {
+                Sll(_, _, s) | Srl(_, _, s) | Sra(_, _, s) =>
+                    ("Shift amount", s as u32, 31),
+                Syscall(c) | Break(c) =>
+                    ("Code", c, 0xf_ffff),
+                Mfc0(_, r) | Mtc0(_, r) | Mfc2(_, r) | Cfc2(_, r)
+                    | Mtc2(_, r) | Ctc2(_, r)
+                    | Lwc2(r, _, _) | Swc2(r, _, _) =>
+                    ("Coprocessor register", r as u32, 31),
+                Align(o) =>
+                    ("Alignment order", o as u32, 31),
+                Cop2(c) =>
+                    ("GTE command", c, 0x1ff_ffff),
+                _ => return Ok(()),
+            };
+
+        if v > max {
+            return Err(format!("{} {} out of range [0, {}]", what, v, max));
+        }
+
+        Ok(())
+    }
+
+    /// Assemble `instruction` and fill its delay slot with a nop when
+    /// reordering is enabled
+    fn assemble_statement(&mut self,
+                          instruction: Instruction<'a>) -> Result<(), String> {
+        self.assemble_instruction(instruction)?;
+
+        if self.reorder && instruction.has_delay_slot() {
+            self.assemble_instruction(Nop)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return an error if pseudo-instructions can't use $at
+    fn check_at(&self) -> Result<(), String> {
+        if !self.at {
+            return Err("Pseudo-instruction needs $at after .set noat".into());
+        }
+
+        Ok(())
+    }
+
+    fn assemble_instruction(&mut self,
+                            instruction: Instruction<'a>) -> Result<(), String> {
+        Assembler::check_operands(instruction)?;
+
+        match instruction {
+            Sll(r0, r1, shift) =>
+                self.emit_code(MachineCode::sub(0b000000)
+                               .d(r0)
+                               .t(r1)
+                               .shift(shift)),
+            Srl(r0, r1, shift) =>
+                self.emit_code(MachineCode::sub(0b000010)
+                               .d(r0)
+                               .t(r1)
+                               .shift(shift)),
+            Sra(r0, r1, shift) =>
+                self.emit_code(MachineCode::sub(0b000011)
+                               .d(r0)
+                               .t(r1)
+                               .shift(shift)),
+            Sllv(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b000100)
+                               .d(r0)
+                               .t(r1)
+                               .s(r2)),
+            Srlv(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b000110)
+                               .d(r0)
+                               .t(r1)
+                               .s(r2)),
+            Srav(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b000111)
+                               .d(r0)
+                               .t(r1)
+                               .s(r2)),
+            Jr(r0) =>
+                self.emit_code(MachineCode::sub(0b001000)
+                               .s(r0)),
+
+            Jalr(r0, r1) =>
+                self.emit_code(MachineCode::sub(0b001001)
+                               .d(r0)
+                               .s(r1)),
+            Syscall(v) =>
+                self.emit_code(MachineCode::sub(0b001100)
+                               .sys_brk(v)),
+            Break(v) =>
+                self.emit_code(MachineCode::sub(0b001101)
+                               .sys_brk(v)),
+            Mfhi(r0) =>
+                self.emit_code(MachineCode::sub(0b010000)
+                               .d(r0)),
+            Mthi(r0) =>
+                self.emit_code(MachineCode::sub(0b010001)
+                               .s(r0)),
+            Mflo(r0) =>
+                self.emit_code(MachineCode::sub(0b010010)
+                               .d(r0)),
+            Mtlo(r0) =>
+                self.emit_code(MachineCode::sub(0b010011)
+                               .s(r0)),
+            Mult(r0, r1) =>
+                self.emit_code(MachineCode::sub(0b011000)
+                               .s(r0)
+                               .t(r1)),
+            Multu(r0, r1) =>
+                self.emit_code(MachineCode::sub(0b011001)
+                               .s(r0)
+                               .t(r1)),
+            Div(r0, r1) =>
+                self.emit_code(MachineCode::sub(0b011010)
+                               .s(r0)
+                               .t(r1)),
+            Divu(r0, r1) =>
+                self.emit_code(MachineCode::sub(0b011011)
+                               .s(r0)
+                               .t(r1)),
+            Add(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100000)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Addu(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100001)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Sub(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100010)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Subu(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100011)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            And(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100100)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Or(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100101)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Xor(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100110)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Nor(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b100111)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Slt(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b101010)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Sltu(r0, r1, r2) =>
+                self.emit_code(MachineCode::sub(0b101011)
+                               .d(r0)
+                               .s(r1)
+                               .t(r2)),
+            Bgez(r0, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000001)
+                               .is_link(false)
+                               .is_bgez(true)
+                               .s(r0)
+                               .imm_se(i));
+            }
+            Bltz(r0, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000001)
+                               .is_link(false)
+                               .is_bgez(false)
+                               .s(r0)
+                               .imm_se(i));
+            }
+            Bgezal(r0, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000001)
+                               .is_link(true)
+                               .is_bgez(true)
+                               .s(r0)
+                               .imm_se(i));
+            }
+            Bltzal(r0, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000001)
+                               .is_link(true)
+                               .is_bgez(false)
+                               .s(r0)
+                               .imm_se(i));
+            }
+            J(l) => {
+                let i = self.jump_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000010)
+                               .imm_jump(i));
+            }
+            Jal(l) => {
+                let i = self.jump_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000011)
+                               .imm_jump(i));
+            }
+            Beq(r0, r1, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000100)
+                               .s(r0)
+                               .t(r1)
+                               .imm_se(i));
+            }
+            Bne(r0, r1, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000101)
+                               .s(r0)
+                               .t(r1)
+                               .imm_se(i));
+            }
+            Blez(r0, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000110)
+                               .s(r0)
+                               .imm_se(i));
+            }
+            Bgtz(r0, l) => {
+                let i = self.branch_target(l)?;
+
+                self.emit_code(MachineCode::op(0b000111)
+                               .s(r0)
+                               .imm_se(i));
+            }
+            Addi(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b001000)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Addiu(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b001001)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Slti(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b001010)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Sltiu(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b001011)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Andi(r0, r1, u) => {
+                self.emit_code(MachineCode::op(0b001100)
+                               .t(r0)
+                               .s(r1)
+                               .imm(u));
+            }
+            Ori(r0, r1, u) => {
+                self.emit_code(MachineCode::op(0b001101)
+                               .t(r0)
+                               .s(r1)
+                               .imm(u));
+            }
+            Xori(r0, r1, u) => {
+                self.emit_code(MachineCode::op(0b001110)
+                               .t(r0)
+                               .s(r1)
+                               .imm(u));
+            }
+            Lui(r0, u) => {
+                self.emit_code(MachineCode::op(0b001111)
+                               .t(r0)
+                               .imm(u));
+            }
+            Lb(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100000)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Lh(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100001)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Lwl(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100010)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Lw(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100011)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Lbu(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100100)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Lhu(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100101)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Lwr(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b100110)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Sb(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b101000)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Sh(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b101001)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Swl(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b101010)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Sw(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b101011)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Swr(r0, r1, i) => {
+                self.emit_code(MachineCode::op(0b101110)
+                               .t(r0)
+                               .s(r1)
+                               .imm_se(i));
+            }
+            Mfc0(r0, cop_r) => {
+                self.emit_code(MachineCode::op(0b010000)
+                               .cop_opcode(0b00000)
+                               .t(r0)
+                               .cop_r(cop_r))
+            }
+            Mtc0(r0, cop_r) => {
+                self.emit_code(MachineCode::op(0b010000)
+                               .cop_opcode(0b00100)
+                               .t(r0)
+                               .cop_r(cop_r))
+            }
+            Rfe => {
+                self.emit_code(MachineCode::op(0b010000)
+                               .cop_opcode(0b10000)
+                               .sub_op(0b010000))
+            }
+            Mfc2(r0, cop_r) => {
+                self.emit_code(MachineCode::op(0b010010)
+                               .cop_opcode(0b00000)
+                               .t(r0)
+                               .cop_r(cop_r))
+            }
+            Cfc2(r0, cop_r) => {
+                self.emit_code(MachineCode::op(0b010010)
+                               .cop_opcode(0b00010)
+                               .t(r0)
+                               .cop_r(cop_r))
+            }
+            Mtc2(r0, cop_r) => {
+                self.emit_code(MachineCode::op(0b010010)
+                               .cop_opcode(0b00100)
+                               .t(r0)
+                               .cop_r(cop_r))
+            }
+            Ctc2(r0, cop_r) => {
+                self.emit_code(MachineCode::op(0b010010)
+                               .cop_opcode(0b00110)
+                               .t(r0)
+                               .cop_r(cop_r))
+            }
+            Lwc2(cop_r, r0, i) => {
+                self.emit_code(MachineCode::op(0b110010)
+                               .t(Register(cop_r))
+                               .s(r0)
+                               .imm_se(i));
+            }
+            Swc2(cop_r, r0, i) => {
+                self.emit_code(MachineCode::op(0b111010)
+                               .t(Register(cop_r))
+                               .s(r0)
+                               .imm_se(i));
+            }
+
+            // GTE commands
+            Rtps => self.emit_gte(0x0180001),
+            Rtpt => self.emit_gte(0x0280030),
+            Nclip => self.emit_gte(0x1400006),
+            Op(sf) => self.emit_gte(0x170000c | gte_sf(sf)),
+            Dpcs => self.emit_gte(0x0780010),
+            Intpl => self.emit_gte(0x0980011),
+            Mvmva(sf, mx, v, cv, lm) => {
+                if mx > 3 || v > 3 || cv > 3 {
+                    return Err(format!("Invalid MVMVA operands {}, {}, {}",
+                                       mx, v, cv));
+                }
+
+                self.emit_gte(0x0400012
+                              | gte_sf(sf)
+                              | (mx as u32) << 17
+                              | (v as u32) << 15
+                              | (cv as u32) << 13
+                              | (lm as u32) << 10)
+            }
+            Ncds => self.emit_gte(0x0e80413),
+            Cdp => self.emit_gte(0x1280414),
+            Ncdt => self.emit_gte(0x0f80416),
+            Nccs => self.emit_gte(0x108041b),
+            Cc => self.emit_gte(0x138041c),
+            Ncs => self.emit_gte(0x0c8041e),
+            Nct => self.emit_gte(0x0d80420),
+            Sqr(sf) => self.emit_gte(0x0a00428 | gte_sf(sf)),
+            Dcpl => self.emit_gte(0x0680029),
+            Dpct => self.emit_gte(0x0f8002a),
+            Avsz3 => self.emit_gte(0x158002d),
+            Avsz4 => self.emit_gte(0x168002e),
+            Gpf(sf) => self.emit_gte(0x190003d | gte_sf(sf)),
+            Gpl(sf) => self.emit_gte(0x1a0003e | gte_sf(sf)),
+            Ncct => self.emit_gte(0x118043f),
+            Cop2(c) => self.emit_gte(c),
+
+            // Alignment padding
+            Align(o) =>
+                for _ in 0..pad_to_order(self.location(), o) {
+                    self.emit_byte(0);
+                },
+            Org(a) => {
+                let here = self.location();
+
+                if a < here {
+                    return Err(format!("Can't move location backwards \
+                                        from 0x{:08x} to 0x{:08x}", here, a));
+                }
+
+                for _ in here..a {
+                    self.emit_byte(0);
+                }
+            }
+
+            // Data
+            Word(w) =>
+                self.emit_code(MachineCode(w)),
+            Half(h) => {
+                self.emit_byte(h as u8);
+                self.emit_byte((h >> 8) as u8);
+            }
+            Byte(b) =>
+                self.emit_byte(b),
+            Ascii(s) =>
+                for &b in s {
+                    self.emit_byte(b);
+                },
+
+            Fixup(i, field, e) => {
+                let v = self.eval(e)?;
+                let (min, max) = field.range();
+
+                if v < min || v > max {
+                    return Err(format!("Value {} out of range [{}, {}] for {:?}",
+                                       v, min, max, i));
+                }
+
+                let start = self.machine_code.len();
+
+                (self.assemble_instruction(*i))?;
+
+                let code = &mut self.machine_code[start..];
+                let v = field.encode(v);
+
+                for (n, b) in code.iter_mut().take(4).enumerate() {
+                    *b |= (v >> (n * 8)) as u8;
+                }
+            }
+
+            // Pseudo instructions
+            Nop =>
+                (self.assemble_instruction(Sll(R0, R0, 0)))?,
+            Move(r0, r1) =>
+                (self.assemble_instruction(Addu(r0, r1, R0)))?,
+            Li(r0, v) => {
+                let hi = (v >> 16) as u16;
+                let lo = v as u16;
+
+                if hi != 0 {
+                    (self.assemble_instruction(Lui(r0, hi)))?;
+
+                    if lo != 0 {
+                        (self.assemble_instruction(Ori(r0, r0, lo)))?;
+                    }
+                } else {
+                    (self.assemble_instruction(Ori(r0, R0, lo)))?;
+                }
+            }
+            La(r0, l) => {
+                let loc = (self.label_address(l))?;
+
+                let hi = (loc >> 16) as u16;
+                let lo = loc as u16;
+
+                (self.assemble_instruction(Lui(r0, hi)))?;
+                (self.assemble_instruction(Ori(r0, r0, lo)))?;
+            }
+            B(l) =>
+                (self.assemble_instruction(Beq(R0, R0, l)))?,
+            Beqz(r0, l) =>
+                (self.assemble_instruction(Beq(r0, R0, l)))?,
+            Bnez(r0, l) =>
+                (self.assemble_instruction(Bne(r0, R0, l)))?,
+            Not(r0, r1) =>
+                (self.assemble_instruction(Nor(r0, r1, R0)))?,
+            Neg(r0, r1) =>
+                (self.assemble_instruction(Subu(r0, R0, r1)))?,
+            Blt(r0, r1, l) => {
+                (self.check_at())?;
+                (self.assemble_instruction(Slt(AT, r0, r1)))?;
+                (self.assemble_instruction(Bne(AT, R0, l)))?;
+            }
+            Bge(r0, r1, l) => {
+                (self.check_at())?;
+                (self.assemble_instruction(Slt(AT, r0, r1)))?;
+                (self.assemble_instruction(Beq(AT, R0, l)))?;
+            }
+            Bgt(r0, r1, l) => {
+                (self.check_at())?;
+                (self.assemble_instruction(Slt(AT, r1, r0)))?;
+                (self.assemble_instruction(Bne(AT, R0, l)))?;
+            }
+            Ble(r0, r1, l) => {
+                (self.check_at())?;
+                (self.assemble_instruction(Slt(AT, r1, r0)))?;
+                (self.assemble_instruction(Beq(AT, R0, l)))?;
+            }
+            Mul(r0, r1, r2) => {
+                (self.assemble_instruction(Mult(r1, r2)))?;
+                (self.assemble_instruction(Mflo(r0)))?;
+            }
+            Push(r0) => {
+                (self.assemble_instruction(Addiu(SP, SP, -4)))?;
+                (self.assemble_instruction(Sw(r0, SP, 0)))?;
+            }
+            // The increment is in the load delay slot
+            Pop(r0) => {
+                (self.assemble_instruction(Lw(r0, SP, 0)))?;
+                (self.assemble_instruction(Addiu(SP, SP, 4)))?;
+            }
+
+            Set(o) =>
+                match o {
+                    SetOption::At => self.at = true,
+                    SetOption::NoAt => self.at = false,
+                    SetOption::Reorder => self.reorder = true,
+                    SetOption::NoReorder => self.reorder = false,
+                },
+
+            // Labels should already have been handled
+            Local(..) | Global(..) | Equ(..) => (),
+        }
+
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, b: u8) {
+        self.machine_code.push(b);
+    }
+
+    /// Emit a GTE command
+    fn emit_gte(&mut self, command: u32) {
+        self.emit_code(MachineCode::op(0b010010)
+                       .cop_opcode(0b10000)
+                       .imm_cop(command))
+    }
+
+    fn emit_code(&mut self, code: MachineCode) {
+        let word = code.0;
+
+        self.emit_byte(word as u8);
+        self.emit_byte((word >> 8) as u8);
+        self.emit_byte((word >> 16) as u8);
+        self.emit_byte((word >> 24) as u8);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct MachineCode(u32);
+
+impl MachineCode {
+    fn op(op: u8) -> MachineCode {
+        MachineCode((op as u32) << 26)
+    }
+
+    fn sub(op: u8) -> MachineCode {
+        MachineCode(op as u32)
+    }
+
+    fn sub_op(self, op: u8) -> MachineCode {
+        MachineCode(self.0 | (op as u32))
+    }
+
+    fn cop_opcode(self, op: u32) -> MachineCode {
+        MachineCode(self.0 | (op << 21))
+    }
+
+    fn s(self, r: Register) -> MachineCode {
+        MachineCode(self.0 | ((r.0 as u32) << 21))
+    }
+
+    fn t(self, r: Register) -> MachineCode {
+        MachineCode(self.0 | ((r.0 as u32) << 16))
+    }
+
+    fn d(self, r: Register) -> MachineCode {
+        MachineCode(self.0 | ((r.0 as u32) << 11))
+    }
+
+    fn cop_r(self, cop_r: u8) -> MachineCode {
+        MachineCode(self.0 | ((cop_r as u32) << 11))
+    }
+
+    fn shift(self, s: u8) -> MachineCode {
+        MachineCode(self.0 | ((s as u32) << 6))
+    }
+
+    fn sys_brk(self, v: u32) -> MachineCode {
+        MachineCode(self.0 | ((v & 0xfffff) << 6))
+    }
+
+    fn is_link(self, is_link: bool) -> MachineCode {
+        MachineCode(self.0 | ((is_link as u32) << 20))
+    }
+
+    fn is_bgez(self, is_bgez: bool) -> MachineCode {
+        MachineCode(self.0| ((is_bgez as u32) << 16))
+    }
+
+    fn imm(self, v: u16) -> MachineCode {
+        MachineCode(self.0 | (v as u32))
+    }
+
+    fn imm_se(self, v: i16) -> MachineCode {
+        MachineCode(self.0 | (v as u16 as u32))
+    }
+
+    fn imm_jump(self, v: u32) -> MachineCode {
+        MachineCode(self.0 | (v & 0x3ffffff))
+    }
+
+    fn imm_cop(self, v: u32) -> MachineCode {
+        MachineCode(self.0 | (v & 0x1ffffff))
+    }
+}
+
+/// `sf` bit of a GTE command
+fn gte_sf(sf: bool) -> u32 {
+    (sf as u32) << 19
+}
+
+/// Return the number of bytes necessary to add after `loc` in order
+/// to reach an address aligned on `1 << order`
+fn pad_to_order(loc: u32, order: u8) -> u32 {
+    let mask = (1u32 << order) - 1;
+
+    ((!loc).wrapping_add(1)) & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assemble `instructions` at `base`, return the generated words
+    fn assemble(base: u32, instructions: &[Instruction]) -> Result<Vec<u32>, String> {
+        let mut asm = Assembler::from_base(base);
+
+        asm.assemble(instructions)?;
+
+        Ok(asm.code()
+           .chunks(4)
+           .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+           .collect())
+    }
+
+    /// Check that assembling `instructions` fails on instruction `n`
+    /// with a message containing `message`
+    fn check_error(base: u32, instructions: &[Instruction], n: usize, message: &str) {
+        let e = assemble(base, instructions).unwrap_err();
+
+        assert!(e.starts_with(&format!("Instruction {} ", n)), "{}", e);
+        assert!(e.contains(message), "{}", e);
+    }
+
+    #[test]
+    fn branch_range() {
+        let base = 0x8010_0000;
+        // Branches are relative to the delay slot
+        let slot = base + 8;
+
+        let forward = |delta: u32| [Nop, Beq(T0, T1, Label::Absolute(slot + delta))];
+        let backward = |delta: u32| [Nop, Bne(T0, T1, Label::Absolute(slot - delta))];
+
+        assert_eq!(assemble(base, &forward(0x1_fffc)).unwrap(), [0, 0x1109_7fff]);
+        assert_eq!(assemble(base, &backward(0x2_0000)).unwrap(), [0, 0x1509_8000]);
+
+        check_error(base, &forward(0x2_0000), 1, "out of range: 131072 bytes away");
+        check_error(base, &backward(0x2_0004), 1, "out of range: -131076 bytes away");
+    }
+
+    #[test]
+    fn misaligned_targets() {
+        let base = 0x8001_0000;
+
+        check_error(base, &[Nop, Nop, B(Label::Absolute(base + 0x102))],
+                    2, "(0x80010102) is not word aligned");
+        check_error(base, &[J(Label::Absolute(base + 0x101))],
+                    0, "(0x80010101) is not word aligned");
+    }
+
+    #[test]
+    fn jump_region() {
+        // The delay slot is the first word of the next region
+        let base = 0x8fff_fffc;
+
+        assert_eq!(assemble(base, &[J(Label::Absolute(0x9000_0010))]).unwrap(),
+                   [0x0800_0004]);
+
+        check_error(base, &[J(Label::Absolute(0x8fff_fff0))],
+                    0, "is not in the same 256MiB region as 0x90000000");
+        check_error(0x8001_0000, &[Nop, Jal(Label::Absolute(0xbfc0_0000))],
+                    1, "is not in the same 256MiB region as 0x80010008");
+    }
+
+    #[test]
+    fn immediate_range() {
+        let base = 0x8001_0000;
+
+        check_error(base, &[Nop, Sll(T0, T0, 32)], 1, "Shift amount 32 out of range [0, 31]");
+        check_error(base, &[Break(0x10_0000)], 0, "Code 1048576 out of range [0, 1048575]");
+        check_error(base, &[Nop, Nop, Mtc0(T0, 32)],
+                    2, "Coprocessor register 32 out of range [0, 31]");
+
+        let e = Expr::Const(0x8000);
+        check_error(base, &[Fixup(&Addiu(T0, T0, 0), Field::Imm16Signed, &e)],
+                    0, "Value 32768 out of range [-32768, 32767]");
+    }
+
+    #[test]
+    fn locals_across_calls() {
+        let mut asm = Assembler::from_base(0x8001_0000);
+
+        asm.assemble(&[Local("1"), Nop]).unwrap();
+
+        // Backward references see the labels of previous calls
+        asm.assemble(&[Local("2"), B(Label::Local("1", 'b')), Nop]).unwrap();
+        asm.assemble(&[Bnez(T0, Label::Local("2", 'b'))]).unwrap();
+
+        let e = asm.assemble(&[Nop, B(Label::Local("3", 'f'))]).unwrap_err();
+        assert!(e.starts_with("Instruction 1 "), "{}", e);
+        assert!(e.contains("Unknown local label '3'"), "{}", e);
+    }
+
+    #[test]
+    fn noat() {
+        let base = 0x8001_0000;
+        let target = Label::Absolute(base);
+
+        assert_eq!(assemble(base, &[Blt(T0, T1, target), Nop]).unwrap(),
+                   [0x0109_082a, 0x1420_fffe, 0]);
+
+        check_error(base, &[Set(SetOption::NoAt), Nop, Blt(T0, T1, target)],
+                    2, "Pseudo-instruction needs $at after .set noat");
+        check_error(base, &[Set(SetOption::NoAt), Ble(T0, T1, target)],
+                    1, "Pseudo-instruction needs $at after .set noat");
+
+        // Pseudo-instructions which don't use $at are still accepted
+        assert!(assemble(base, &[Set(SetOption::NoAt), Mul(T0, T1, T2), Push(T0)]).is_ok());
+        assert!(assemble(base, &[Set(SetOption::NoAt), Set(SetOption::At),
+                                 Bge(T0, T1, target)]).is_ok());
+    }
+
+    #[test]
+    fn reorder_labels() {
+        let base = 0x8001_0000;
+
+        let mut asm = Assembler::from_base(base);
+
+        // The labels must account for the nops inserted in the delay
+        // slots
+        asm.assemble(&[
+            Set(SetOption::Reorder),
+            Global("a"),
+            Blt(T0, T1, Label::Global("c")),
+            Global("b"),
+            Jr(RA),
+            Global("c"),
+            Set(SetOption::NoReorder),
+            B(Label::Global("a")),
+            Global("d"),
+        ]).unwrap();
+
+        assert_eq!(asm.symbol("b"), Ok(base + 12));
+        assert_eq!(asm.symbol("c"), Ok(base + 20));
+        assert_eq!(asm.symbol("d"), Ok(base + 24));
+
+        let words: Vec<_> = asm.code()
+            .chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(words, [
+            0x0109_082a,
+            0x1420_0003,
+            0,
+            0x03e0_0008,
+            0,
+            0x1000_fffa,
+        ]);
+
+        // The option is kept across calls
+        asm.assemble(&[Set(SetOption::Reorder)]).unwrap();
+        asm.assemble(&[J(Label::Global("f")), Global("f")]).unwrap();
+        assert_eq!(asm.symbol("f"), Ok(base + 32));
+    }
+}
@@ -0,0 +1,393 @@
+//! Executable file writers
+//!
+//! Wrap the code generated by the `Assembler` in a PS-X EXE, the format
+//! loaded by the BIOS shell, or in a minimal MIPS ELF32 executable that
+//! can be inspected with binutils and loaded by other tools.
+
+use crate::cdrom::disk::Region;
+
+use super::Assembler;
+
+/// Size of the PS-X EXE header. The text is also padded to a multiple
+/// of this size.
+const EXE_HEADER_SIZE: usize = 0x800;
+
+/// PS-X EXE header parameters
+#[derive(Debug, Clone, Copy)]
+pub struct ExeHeader {
+    /// Entry point
+    pub pc: u32,
+    /// Initial value of $gp
+    pub gp: u32,
+    /// Initial value of $sp and $fp. If 0 the BIOS default is used.
+    pub sp: u32,
+    /// Region in the license marker. Only Japan, North America and
+    /// Europe have one, it's left empty for the other regions.
+    pub region: Region,
+}
+
+impl Assembler<'_> {
+    /// Build a PS-X EXE loading the generated code at the base address
+    pub fn to_exe(&self, header: &ExeHeader) -> Vec<u8> {
+        let code = self.code();
+        let text_size = code.len().div_ceil(EXE_HEADER_SIZE) * EXE_HEADER_SIZE;
+
+        let mut exe = Vec::with_capacity(EXE_HEADER_SIZE + text_size);
+
+        exe.extend_from_slice(b"PS-X EXE");
+        exe.resize(0x10, 0);
+
+        for &v in &[
+            header.pc,
+            header.gp,
+            // Text address and size
+            self.base(),
+            text_size as u32,
+            // Data address and size, unused
+            0,
+            0,
+            // BSS address and size
+            0,
+            0,
+            // Stack base and size, the stack pointer is base + size
+            header.sp,
+            0,
+        ] {
+            push_u32(&mut exe, v);
+        }
+
+        exe.resize(0x4c, 0);
+
+        let marker: &[u8] =
+            match header.region {
+                Region::Japan =>
+                    b"Sony Computer Entertainment Inc. for Japan area",
+                Region::NorthAmerica =>
+                    b"Sony Computer Entertainment Inc. for North America area",
+                Region::Europe =>
+                    b"Sony Computer Entertainment Inc. for Europe area",
+                Region::Asia | Region::China => b"",
+            };
+
+        exe.extend_from_slice(marker);
+        exe.resize(EXE_HEADER_SIZE, 0);
+
+        exe.extend_from_slice(code);
+        exe.resize(EXE_HEADER_SIZE + text_size, 0);
+
+        exe
+    }
+
+    /// Build a little-endian MIPS ELF32 executable with entry point
+    /// `entry`. The code is put in a single `.text` section loaded at
+    /// the base address. The global labels and the `.equ` symbols are
+    /// exported in the symbol table.
+    pub fn to_elf(&self, entry: u32) -> Vec<u8> {
+        const EHDR_SIZE: usize = 52;
+        const PHDR_SIZE: usize = 32;
+        const SHDR_SIZE: usize = 40;
+        const SYM_SIZE: usize = 16;
+
+        // Section index of the absolute symbols
+        const SHN_ABS: u16 = 0xfff1;
+
+        let base = self.base();
+        let code = self.code();
+
+        // Labels are in .text, `.equ` constants are absolute whatever
+        // their value
+        let mut globals: Vec<_> = self.globals().map(|(name, addr)| (name, addr, 1)).collect();
+
+        // Symbols which can't be evaluated are left out
+        globals.extend(self.equs.keys()
+                       .filter_map(|&name| self.symbol(name).ok().map(|v| (name, v, SHN_ABS))));
+
+        globals.sort_by_key(|&(name, addr, _)| (addr, name));
+
+        // The file offset of the code must be congruent with its
+        // address modulo the alignment
+        let code_off = (EHDR_SIZE + PHDR_SIZE).next_multiple_of(16) + (base & 0xf) as usize;
+
+        // Symbol and string tables
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+
+        for (name, addr, shndx) in globals {
+            push_u32(&mut symtab, strtab.len() as u32);
+            push_u32(&mut symtab, addr);
+            // Size
+            push_u32(&mut symtab, 0);
+            // STB_GLOBAL, STT_NOTYPE
+            symtab.push(0x10);
+            // Visibility
+            symtab.push(0);
+
+            push_u16(&mut symtab, shndx);
+
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let mut shstrtab = vec![0];
+        let mut section_name = |name: &str| {
+            let off = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+            off
+        };
+
+        let text_name = section_name(".text");
+        let symtab_name = section_name(".symtab");
+        let strtab_name = section_name(".strtab");
+        let shstrtab_name = section_name(".shstrtab");
+
+        let symtab_off = (code_off + code.len()).next_multiple_of(4);
+        let strtab_off = symtab_off + symtab.len();
+        let shstrtab_off = strtab_off + strtab.len();
+        let shdr_off = (shstrtab_off + shstrtab.len()).next_multiple_of(4);
+
+        let mut elf = Vec::with_capacity(shdr_off + 5 * SHDR_SIZE);
+
+        // ELF header
+        elf.extend_from_slice(b"\x7fELF");
+        // 32 bit, little endian, version 1, System V ABI
+        elf.extend_from_slice(&[1, 1, 1, 0]);
+        elf.resize(16, 0);
+        // ET_EXEC
+        push_u16(&mut elf, 2);
+        // EM_MIPS
+        push_u16(&mut elf, 8);
+        push_u32(&mut elf, 1);
+        push_u32(&mut elf, entry);
+        push_u32(&mut elf, EHDR_SIZE as u32);
+        push_u32(&mut elf, shdr_off as u32);
+        // Flags: MIPS I
+        push_u32(&mut elf, 0);
+        push_u16(&mut elf, EHDR_SIZE as u16);
+        push_u16(&mut elf, PHDR_SIZE as u16);
+        push_u16(&mut elf, 1);
+        push_u16(&mut elf, SHDR_SIZE as u16);
+        // Section count and index of .shstrtab
+        push_u16(&mut elf, 5);
+        push_u16(&mut elf, 4);
+
+        // PT_LOAD program header, RWX
+        for &v in &[
+            1,
+            code_off as u32,
+            base,
+            base,
+            code.len() as u32,
+            code.len() as u32,
+            7,
+            16,
+        ] {
+            push_u32(&mut elf, v);
+        }
+
+        elf.resize(code_off, 0);
+        elf.extend_from_slice(code);
+
+        elf.resize(symtab_off, 0);
+        elf.extend_from_slice(&symtab);
+        elf.extend_from_slice(&strtab);
+        elf.extend_from_slice(&shstrtab);
+        elf.resize(shdr_off, 0);
+
+        // Section headers: name, type, flags, address, offset, size,
+        // link, info, alignment, entry size
+        let sections: [[u32; 10]; 5] = [
+            [0; 10],
+            // PROGBITS, WRITE | ALLOC | EXECINSTR
+            [text_name, 1, 7, base, code_off as u32, code.len() as u32, 0, 0, 16, 0],
+            // SYMTAB, linked to .strtab. All the symbols after the
+            // null one are global.
+            [symtab_name, 2, 0, 0, symtab_off as u32, symtab.len() as u32,
+             3, 1, 4, SYM_SIZE as u32],
+            // STRTAB
+            [strtab_name, 3, 0, 0, strtab_off as u32, strtab.len() as u32, 0, 0, 1, 0],
+            [shstrtab_name, 3, 0, 0, shstrtab_off as u32, shstrtab.len() as u32, 0, 0, 1, 0],
+        ];
+
+        for section in &sections {
+            for &v in section {
+                push_u32(&mut elf, v);
+            }
+        }
+
+        elf
+    }
+}
+
+fn push_u32(v: &mut Vec<u8>, w: u32) {
+    v.extend_from_slice(&w.to_le_bytes());
+}
+
+fn push_u16(v: &mut Vec<u8>, h: u16) {
+    v.extend_from_slice(&h.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::syntax::*;
+
+    fn u16_at(b: &[u8], off: usize) -> u16 {
+        u16::from_le_bytes([b[off], b[off + 1]])
+    }
+
+    fn u32_at(b: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+    }
+
+    /// NUL-terminated string at `off`
+    fn str_at(b: &[u8], off: usize) -> &str {
+        let len = b[off..].iter().position(|&c| c == 0).unwrap();
+
+        std::str::from_utf8(&b[off..off + len]).unwrap()
+    }
+
+    /// Check the section names of `elf` and return the name, value and
+    /// section index of its symbols
+    fn elf_symbols(elf: &[u8]) -> Vec<(&str, u32, u16)> {
+        let shoff = u32_at(elf, 0x20) as usize;
+        let shnum = u16_at(elf, 0x30) as usize;
+        let shstrndx = u16_at(elf, 0x32) as usize;
+
+        let section = |n: usize| shoff + n * 40;
+        let shstrtab = u32_at(elf, section(shstrndx) + 16) as usize;
+
+        let names: Vec<_> = (0..shnum)
+            .map(|n| str_at(elf, shstrtab + u32_at(elf, section(n)) as usize))
+            .collect();
+        assert_eq!(names, ["", ".text", ".symtab", ".strtab", ".shstrtab"]);
+
+        // SHT_SYMTAB, linked to the string table
+        let symtab = section(2);
+        assert_eq!(u32_at(elf, symtab + 4), 2);
+        let strtab = section(u32_at(elf, symtab + 24) as usize);
+
+        let sym_off = u32_at(elf, symtab + 16) as usize;
+        let sym_count = u32_at(elf, symtab + 20) as usize / 16;
+        let str_off = u32_at(elf, strtab + 16) as usize;
+
+        // Skip the null symbol
+        (1..sym_count)
+            .map(|n| {
+                let sym = sym_off + n * 16;
+
+                (str_at(elf, str_off + u32_at(elf, sym) as usize),
+                 u32_at(elf, sym + 4),
+                 u16_at(elf, sym + 14))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exe_header() {
+        let mut asm = Assembler::from_base(0x8001_0000);
+        asm.assemble(&[Nop, Li(T0, 0x1234_5678), Word(0xdead_beef)]).unwrap();
+
+        let header = ExeHeader {
+            pc: 0x8001_0004,
+            gp: 0x8002_0000,
+            sp: 0x801f_fff0,
+            region: Region::Europe,
+        };
+
+        let exe = asm.to_exe(&header);
+
+        // The text is padded to 0x800 bytes
+        assert_eq!(exe.len(), 0x1000);
+        assert_eq!(&exe[..0x10], b"PS-X EXE\0\0\0\0\0\0\0\0");
+        assert_eq!(u32_at(&exe, 0x10), 0x8001_0004);
+        assert_eq!(u32_at(&exe, 0x14), 0x8002_0000);
+        assert_eq!(u32_at(&exe, 0x18), 0x8001_0000);
+        assert_eq!(u32_at(&exe, 0x1c), 0x800);
+        // Data and BSS
+        assert!(exe[0x20..0x30].iter().all(|&b| b == 0));
+        assert_eq!(u32_at(&exe, 0x30), 0x801f_fff0);
+        assert_eq!(u32_at(&exe, 0x34), 0);
+
+        let marker = b"Sony Computer Entertainment Inc. for Europe area";
+        assert_eq!(&exe[0x4c..0x4c + marker.len()], marker);
+        assert!(exe[0x4c + marker.len()..0x800].iter().all(|&b| b == 0));
+
+        assert_eq!(&exe[0x800..0x810], asm.code());
+        assert!(exe[0x810..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn exe_padding() {
+        let mut asm = Assembler::from_base(0x8001_0000);
+        asm.assemble(&[Nop, Org(0x8001_0800)]).unwrap();
+
+        let header = ExeHeader {
+            pc: 0x8001_0000,
+            gp: 0,
+            sp: 0,
+            region: Region::Asia,
+        };
+
+        let exe = asm.to_exe(&header);
+
+        // Already a multiple of 0x800, no marker for Asia
+        assert_eq!(exe.len(), 0x1000);
+        assert_eq!(u32_at(&exe, 0x1c), 0x800);
+        assert!(exe[0x38..0x800].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn elf() {
+        let base = 0x8001_0004;
+
+        let io = Expr::Const(0x1f80_1810);
+        let next = Expr::Label(Label::Global("end"));
+
+        let mut asm = Assembler::from_base(base);
+        asm.assemble(&[
+            Global("start"),
+            Nop,
+            Global("loop"),
+            B(Label::Global("loop")),
+            Nop,
+            Global("end"),
+            Equ("IO", &io),
+            Equ("NEXT", &next),
+        ]).unwrap();
+
+        let elf = asm.to_elf(base + 4);
+
+        assert_eq!(&elf[..6], b"\x7fELF\x01\x01");
+        // ET_EXEC, EM_MIPS
+        assert_eq!(u16_at(&elf, 0x10), 2);
+        assert_eq!(u16_at(&elf, 0x12), 8);
+        // e_entry
+        assert_eq!(u32_at(&elf, 0x18), base + 4);
+
+        // PT_LOAD
+        let phoff = u32_at(&elf, 0x1c) as usize;
+        assert_eq!(u16_at(&elf, 0x2c), 1);
+        assert_eq!(u32_at(&elf, phoff), 1);
+
+        let offset = u32_at(&elf, phoff + 4) as usize;
+        let size = u32_at(&elf, phoff + 16) as usize;
+
+        assert_eq!(u32_at(&elf, phoff + 8), base);
+        assert_eq!(u32_at(&elf, phoff + 12), base);
+        assert_eq!(size, 12);
+        assert_eq!(u32_at(&elf, phoff + 20), 12);
+        assert_eq!(offset % 16, base as usize % 16);
+        assert_eq!(&elf[offset..offset + size], asm.code());
+
+        // Sorted by address, `.equ` symbols are absolute even when
+        // they point into the code
+        assert_eq!(elf_symbols(&elf), [
+            ("IO", 0x1f80_1810, 0xfff1),
+            ("start", base, 1),
+            ("loop", base + 4, 1),
+            ("NEXT", base + 12, 0xfff1),
+            ("end", base + 12, 1),
+        ]);
+    }
+}
@@ -0,0 +1,1560 @@
+//! Parser for GNU-style assembly source
+//!
+//! Turns the text of a `.s` file into a list of `syntax::Instruction`
+//! that can be fed to the `Assembler`. The supported syntax is a subset
+//! of what GNU as accepts:
+//!
+//! * `# comments` until the end of the line
+//! * `label:` definitions, numeric labels (`1:`) are local and can be
+//!   referenced with `1b` (closest one backwards) and `1f` (closest one
+//!   forward)
+//! * registers by number (`$8`) or ABI name (`$t0`)
+//! * all the mnemonics of `syntax::Instruction`, including the
+//!   pseudo-instructions and GTE commands. `op`, `sqr`, `gpf` and
+//!   `gpl` take an optional `sf` flag (1 by default), `mvmva` takes
+//!   `sf, mx, v, cv, lm`.
+//! * the `.word`, `.half`, `.byte`, `.ascii`, `.align`, `.org` and
+//!   `.equ` directives. Since the assembler only has a single section
+//!   `.org` takes an absolute address.
+//! * `.set at`, `.set noat`, `.set reorder` and `.set noreorder`
+//! * macros defined with `.macro name param, ...` and `.endm`. In the
+//!   body `\param` is replaced by the argument and `\@` by the number
+//!   of macro expansions so far, which can be used to make unique
+//!   label names.
+//! * expressions with the usual C operators (`+ - * / << >> & | ^ ~`),
+//!   parentheses and the `%hi()`/`%lo()` relocation operators.
+//!   Operands which depend on symbols are resolved by the assembler.
+//!
+//! Label names, strings and expressions are allocated in an `Arena`
+//! which must outlive the parsed instructions.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bumpalo::Bump;
+
+use super::syntax::*;
+use super::Assembler;
+
+/// Storage for the label names, strings and expressions referenced by
+/// parsed instructions
+#[derive(Default)]
+pub struct Arena(Bump);
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena(Bump::new())
+    }
+}
+
+/// Parse `source` into a list of instructions allocated in `arena`
+pub fn parse<'a>(arena: &'a Arena,
+                 source: &str) -> Result<Vec<Instruction<'a>>, ParseError> {
+    let lines = parse_lines(arena, source)?;
+
+    Ok(lines.into_iter().map(|(_, i)| i).collect())
+}
+
+/// Parse `source` into a list of instructions alongside the number of
+/// the line they come from, starting at 1
+pub fn parse_lines<'a>(arena: &'a Arena,
+                       source: &str) -> Result<Vec<(usize, Instruction<'a>)>, ParseError> {
+    let mut parser = Parser {
+        arena: &arena.0,
+        names: HashMap::new(),
+        instructions: Vec::new(),
+        macros: HashMap::new(),
+        definition: None,
+        expansions: 0,
+        depth: 0,
+    };
+
+    let mut lines = Vec::new();
+
+    for (n, line) in source.lines().enumerate() {
+        if parser.definition.is_some() {
+            parser.define_line(line);
+        } else {
+            parser.parse_line(n + 1, line)?;
+        }
+
+        lines.resize(parser.instructions.len(), n + 1);
+    }
+
+    if let Some((line, name, _)) = parser.definition {
+        return Err(ParseError {
+            line,
+            column: 1,
+            message: format!("Missing .endm for macro '{}'", name),
+        });
+    }
+
+    Ok(lines.into_iter().zip(parser.instructions).collect())
+}
+
+/// Error returned when the source can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column number in characters, starting at 1
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Identifier, mnemonic or directive
+    Ident(String),
+    Register(u8),
+    Int(i64),
+    /// Reference to a numeric local label: `1b` or `1f`
+    LocalRef(String, char),
+    Str(Vec<u8>),
+    Punct(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "'{}'", s),
+            Token::Register(r) => write!(f, "register ${}", r),
+            Token::Int(i) => write!(f, "{}", i),
+            Token::LocalRef(n, d) => write!(f, "'{}{}'", n, d),
+            Token::Str(_) => write!(f, "string"),
+            Token::Punct('<') => write!(f, "'<<'"),
+            Token::Punct('>') => write!(f, "'>>'"),
+            Token::Punct(c) => write!(f, "'{}'", c),
+        }
+    }
+}
+
+struct Parser<'a> {
+    arena: &'a Bump,
+    /// Interned label names
+    names: HashMap<String, &'a str>,
+    instructions: Vec<Instruction<'a>>,
+    /// User-defined macros
+    macros: HashMap<String, Macro>,
+    /// Line, name and lines so far of the macro being defined
+    definition: Option<(usize, String, Macro)>,
+    /// Number of macro expansions, substituted for `\@`
+    expansions: usize,
+    /// Nesting level of macro expansion
+    depth: u32,
+}
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn intern(&mut self, name: &str) -> &'a str {
+        if let Some(&n) = self.names.get(name) {
+            return n;
+        }
+
+        let n: &'a str = self.arena.alloc_str(name);
+
+        self.names.insert(name.to_owned(), n);
+
+        n
+    }
+
+    fn parse_line(&mut self, line_no: usize, text: &str) -> Result<(), ParseError> {
+        let tokens = tokenize(line_no, text)?;
+
+        let mut line = Line {
+            tokens,
+            pos: 0,
+            line: line_no,
+            end: text.chars().count() + 1,
+        };
+
+        // Label definitions
+        while line.peek_at(1) == Some(&Token::Punct(':')) {
+            let label =
+                match line.next() {
+                    Some(Token::Ident(name)) => Global(self.intern(&name)),
+                    Some(Token::Int(n)) if n >= 0 => Local(self.intern(&n.to_string())),
+                    _ => return Err(line.error_at(line.pos - 1, "Invalid label name")),
+                };
+
+            line.next();
+
+            self.instructions.push(label);
+        }
+
+        let mnemonic =
+            match line.next() {
+                None => return Ok(()),
+                Some(Token::Ident(m)) => m.to_lowercase(),
+                Some(t) => return Err(line.error_at(line.pos - 1,
+                                                    &format!("Expected mnemonic, found {}", t))),
+            };
+
+        if let Some(m) = self.macros.get(&mnemonic).cloned() {
+            return self.expand(&mut line, text, &mnemonic, &m);
+        }
+
+        if mnemonic.starts_with('.') {
+            self.parse_directive(&mut line, &mnemonic)?;
+        } else {
+            let i = self.parse_instruction(&mut line, &mnemonic)?;
+
+            self.instructions.push(i);
+        }
+
+        line.end_of_line()
+    }
+
+    /// Add a line to the macro being defined, or end the definition
+    /// on `.endm`
+    fn define_line(&mut self, text: &str) {
+        let code = text.split('#').next().unwrap_or("").trim();
+
+        if code.eq_ignore_ascii_case(".endm") {
+            if let Some((_, name, m)) = self.definition.take() {
+                self.macros.insert(name, m);
+            }
+        } else if let Some((_, _, m)) = &mut self.definition {
+            m.body.push(text.to_owned());
+        }
+    }
+
+    /// Expand the invocation of macro `name` whose arguments are the
+    /// rest of `line`
+    fn expand(&mut self,
+              line: &mut Line,
+              text: &str,
+              name: &str,
+              m: &Macro) -> Result<(), ParseError> {
+        let column = line.column();
+        let args = macro_args(text, column);
+
+        let error = |message: String| ParseError {
+            line: line.line,
+            column,
+            message,
+        };
+
+        if args.len() != m.params.len() {
+            return Err(error(format!("Macro '{}' takes {} arguments, got {}",
+                                     name, m.params.len(), args.len())));
+        }
+
+        if self.depth >= 64 {
+            return Err(error(format!("Macro '{}' nested too deeply", name)));
+        }
+
+        let counter = self.expansions.to_string();
+        self.expansions += 1;
+        self.depth += 1;
+
+        for body in &m.body {
+            let expanded = substitute(body, &m.params, &args, &counter);
+
+            let r = self.parse_line(line.line, &expanded);
+
+            if let Err(e) = r {
+                self.depth -= 1;
+
+                // Errors are reported at the outermost invocation
+                if self.depth > 0 {
+                    return Err(e);
+                }
+
+                return Err(error(format!("In macro '{}': {}", name, e.message)));
+            }
+        }
+
+        self.depth -= 1;
+
+        // The arguments have been consumed
+        line.pos = line.tokens.len();
+
+        Ok(())
+    }
+
+    fn parse_directive(&mut self, line: &mut Line, directive: &str) -> Result<(), ParseError> {
+        match directive {
+            ".word" => loop {
+                let i = self.imm(line, Field::Word, |v| Word(v as u32))?;
+                self.instructions.push(i);
+
+                if !line.eat(',') {
+                    break;
+                }
+            },
+            ".half" => loop {
+                let i = self.imm(line, Field::Half, |v| Half(v as u16))?;
+                self.instructions.push(i);
+
+                if !line.eat(',') {
+                    break;
+                }
+            },
+            ".byte" => loop {
+                let i = self.imm(line, Field::Byte, |v| Byte(v as u8))?;
+                self.instructions.push(i);
+
+                if !line.eat(',') {
+                    break;
+                }
+            },
+            ".ascii" => loop {
+                let column = line.column();
+
+                match line.next() {
+                    Some(Token::Str(s)) => {
+                        let s = self.arena.alloc_slice_copy(&s);
+                        self.instructions.push(Ascii(s));
+                    }
+                    _ => return Err(line.error(column, "Expected string")),
+                }
+
+                if !line.eat(',') {
+                    break;
+                }
+            },
+            ".align" => {
+                let o = line.int(0, 31)?;
+                self.instructions.push(Align(o as u8));
+            }
+            ".org" => {
+                let a = line.int(0, u32::MAX as i64)?;
+                self.instructions.push(Org(a as u32));
+            }
+            ".equ" => {
+                let column = line.column();
+
+                let name =
+                    match line.next() {
+                        Some(Token::Ident(name)) => self.intern(&name),
+                        _ => return Err(line.error(column, "Expected symbol name")),
+                    };
+
+                line.comma()?;
+
+                let e = self.expr(line)?;
+
+                self.instructions.push(Equ(name, self.arena.alloc(e)));
+            }
+            ".set" => {
+                let column = line.column();
+
+                let o =
+                    match line.next() {
+                        Some(Token::Ident(o)) =>
+                            match o.to_lowercase().as_str() {
+                                "at" => SetOption::At,
+                                "noat" => SetOption::NoAt,
+                                "reorder" => SetOption::Reorder,
+                                "noreorder" => SetOption::NoReorder,
+                                _ => return Err(line.error(column,
+                                                           &format!("Unknown option '{}'", o))),
+                            },
+                        _ => return Err(line.error(column, "Expected option")),
+                    };
+
+                self.instructions.push(Set(o));
+            }
+            ".macro" => {
+                if self.depth > 0 {
+                    return Err(line.error_at(line.pos - 1,
+                                             "Macros can't be defined in a macro"));
+                }
+
+                let column = line.column();
+
+                let name =
+                    match line.next() {
+                        Some(Token::Ident(n)) => n.to_lowercase(),
+                        _ => return Err(line.error(column, "Expected macro name")),
+                    };
+
+                let mut params = Vec::new();
+
+                while !line.at_end() {
+                    let column = line.column();
+
+                    match line.next() {
+                        Some(Token::Ident(p)) => params.push(p),
+                        _ => return Err(line.error(column, "Expected parameter name")),
+                    }
+
+                    line.eat(',');
+                }
+
+                self.definition = Some((line.line, name, Macro {
+                    params,
+                    body: Vec::new(),
+                }));
+            }
+            ".endm" => {
+                return Err(line.error_at(line.pos - 1, ".endm outside of a macro"));
+            }
+            _ => return Err(line.error_at(line.pos - 1,
+                                          &format!("Unknown directive '{}'", directive))),
+        }
+
+        Ok(())
+    }
+
+    /// Parse the operands of `mnemonic`
+    fn parse_instruction(&mut self,
+                         line: &mut Line,
+                         mnemonic: &str) -> Result<Instruction<'a>, ParseError> {
+        let i =
+            match mnemonic {
+                "sll" | "srl" | "sra" => {
+                    let (d, t) = line.r2()?;
+                    line.comma()?;
+
+                    self.imm(line, Field::Shift, |sa| match mnemonic {
+                        "sll" => Sll(d, t, sa as u8),
+                        "srl" => Srl(d, t, sa as u8),
+                        _ => Sra(d, t, sa as u8),
+                    })?
+                }
+                "sllv" | "srlv" | "srav" => {
+                    let (d, t, s) = line.r3()?;
+
+                    match mnemonic {
+                        "sllv" => Sllv(d, t, s),
+                        "srlv" => Srlv(d, t, s),
+                        _ => Srav(d, t, s),
+                    }
+                }
+                "jr" => Jr(line.reg()?),
+                "jalr" => {
+                    let r = line.reg()?;
+
+                    if line.eat(',') {
+                        Jalr(r, line.reg()?)
+                    } else {
+                        Jalr(RA, r)
+                    }
+                }
+                "syscall" | "break" => {
+                    let code =
+                        if line.at_end() {
+                            0
+                        } else {
+                            line.int(0, 0xfffff)? as u32
+                        };
+
+                    match mnemonic {
+                        "syscall" => Syscall(code),
+                        _ => Break(code),
+                    }
+                }
+                "mfhi" => Mfhi(line.reg()?),
+                "mthi" => Mthi(line.reg()?),
+                "mflo" => Mflo(line.reg()?),
+                "mtlo" => Mtlo(line.reg()?),
+                "mult" | "multu" | "div" | "divu" => {
+                    let (s, t) = line.r2()?;
+
+                    match mnemonic {
+                        "mult" => Mult(s, t),
+                        "multu" => Multu(s, t),
+                        "div" => Div(s, t),
+                        _ => Divu(s, t),
+                    }
+                }
+                "add" | "addu" | "sub" | "subu" | "and" | "or" | "xor" | "nor"
+                    | "slt" | "sltu" => {
+                    let (d, s, t) = line.r3()?;
+
+                    match mnemonic {
+                        "add" => Add(d, s, t),
+                        "addu" => Addu(d, s, t),
+                        "sub" => Sub(d, s, t),
+                        "subu" => Subu(d, s, t),
+                        "and" => And(d, s, t),
+                        "or" => Or(d, s, t),
+                        "xor" => Xor(d, s, t),
+                        "nor" => Nor(d, s, t),
+                        "slt" => Slt(d, s, t),
+                        _ => Sltu(d, s, t),
+                    }
+                }
+                "bgez" | "bltz" | "bgezal" | "bltzal" | "blez" | "bgtz"
+                    | "beqz" | "bnez" => {
+                    let s = line.reg()?;
+                    line.comma()?;
+                    let l = self.label(line)?;
+
+                    match mnemonic {
+                        "bgez" => Bgez(s, l),
+                        "bltz" => Bltz(s, l),
+                        "bgezal" => Bgezal(s, l),
+                        "bltzal" => Bltzal(s, l),
+                        "blez" => Blez(s, l),
+                        "bgtz" => Bgtz(s, l),
+                        "beqz" => Beqz(s, l),
+                        _ => Bnez(s, l),
+                    }
+                }
+                "j" => J(self.label(line)?),
+                "jal" => Jal(self.label(line)?),
+                "b" => B(self.label(line)?),
+                "beq" | "bne" => {
+                    let (s, t) = line.r2()?;
+                    line.comma()?;
+                    let l = self.label(line)?;
+
+                    match mnemonic {
+                        "beq" => Beq(s, t, l),
+                        _ => Bne(s, t, l),
+                    }
+                }
+                "addi" | "addiu" | "slti" | "sltiu" => {
+                    let (t, s) = line.r2()?;
+                    line.comma()?;
+
+                    self.imm(line, Field::Imm16Signed, |i| match mnemonic {
+                        "addi" => Addi(t, s, i as i16),
+                        "addiu" => Addiu(t, s, i as i16),
+                        "slti" => Slti(t, s, i as i16),
+                        _ => Sltiu(t, s, i as i16),
+                    })?
+                }
+                "andi" | "ori" | "xori" => {
+                    let (t, s) = line.r2()?;
+                    line.comma()?;
+
+                    self.imm(line, Field::Imm16, |u| match mnemonic {
+                        "andi" => Andi(t, s, u as u16),
+                        "ori" => Ori(t, s, u as u16),
+                        _ => Xori(t, s, u as u16),
+                    })?
+                }
+                "lui" => {
+                    let t = line.reg()?;
+                    line.comma()?;
+                    self.imm(line, Field::Imm16, |u| Lui(t, u as u16))?
+                }
+                "lb" | "lh" | "lwl" | "lw" | "lbu" | "lhu" | "lwr"
+                    | "sb" | "sh" | "swl" | "sw" | "swr" => {
+                    let t = line.reg()?;
+                    line.comma()?;
+
+                    self.address(line, |base, off| match mnemonic {
+                        "lb" => Lb(t, base, off),
+                        "lh" => Lh(t, base, off),
+                        "lwl" => Lwl(t, base, off),
+                        "lw" => Lw(t, base, off),
+                        "lbu" => Lbu(t, base, off),
+                        "lhu" => Lhu(t, base, off),
+                        "lwr" => Lwr(t, base, off),
+                        "sb" => Sb(t, base, off),
+                        "sh" => Sh(t, base, off),
+                        "swl" => Swl(t, base, off),
+                        "sw" => Sw(t, base, off),
+                        _ => Swr(t, base, off),
+                    })?
+                }
+                "mfc0" | "mtc0" | "mfc2" | "cfc2" | "mtc2" | "ctc2" => {
+                    let t = line.reg()?;
+                    line.comma()?;
+                    let r = line.cop_reg()?;
+
+                    match mnemonic {
+                        "mfc0" => Mfc0(t, r),
+                        "mtc0" => Mtc0(t, r),
+                        "mfc2" => Mfc2(t, r),
+                        "cfc2" => Cfc2(t, r),
+                        "mtc2" => Mtc2(t, r),
+                        _ => Ctc2(t, r),
+                    }
+                }
+                "rfe" => Rfe,
+                "lwc2" | "swc2" => {
+                    let r = line.cop_reg()?;
+                    line.comma()?;
+
+                    self.address(line, |base, off| match mnemonic {
+                        "lwc2" => Lwc2(r, base, off),
+                        _ => Swc2(r, base, off),
+                    })?
+                }
+                "rtps" => Rtps,
+                "rtpt" => Rtpt,
+                "nclip" => Nclip,
+                "op" => Op(line.sf()?),
+                "dpcs" => Dpcs,
+                "intpl" => Intpl,
+                "mvmva" => {
+                    let sf = line.flag()?;
+                    line.comma()?;
+                    let mx = line.int(0, 3)? as u8;
+                    line.comma()?;
+                    let v = line.int(0, 3)? as u8;
+                    line.comma()?;
+                    let cv = line.int(0, 3)? as u8;
+                    line.comma()?;
+                    let lm = line.flag()?;
+
+                    Mvmva(sf, mx, v, cv, lm)
+                }
+                "ncds" => Ncds,
+                "cdp" => Cdp,
+                "ncdt" => Ncdt,
+                "nccs" => Nccs,
+                "cc" => Cc,
+                "ncs" => Ncs,
+                "nct" => Nct,
+                "sqr" => Sqr(line.sf()?),
+                "dcpl" => Dcpl,
+                "dpct" => Dpct,
+                "avsz3" => Avsz3,
+                "avsz4" => Avsz4,
+                "gpf" => Gpf(line.sf()?),
+                "gpl" => Gpl(line.sf()?),
+                "ncct" => Ncct,
+                "cop2" => Cop2(line.int(0, 0x1ff_ffff)? as u32),
+                "nop" => Nop,
+                "move" => {
+                    let (d, s) = line.r2()?;
+                    Move(d, s)
+                }
+                "li" => {
+                    let t = line.reg()?;
+                    line.comma()?;
+
+                    // Values unknown at parse time always use the two
+                    // instruction form
+                    match self.imm(line, Field::Word, |v| Li(t, v as u32))? {
+                        Fixup(_, _, e) => La(t, Label::Expr(e)),
+                        i => i,
+                    }
+                }
+                "la" => {
+                    let t = line.reg()?;
+                    line.comma()?;
+                    La(t, self.label(line)?)
+                }
+                "not" | "neg" => {
+                    let (d, s) = line.r2()?;
+
+                    match mnemonic {
+                        "not" => Not(d, s),
+                        _ => Neg(d, s),
+                    }
+                }
+                "blt" | "bge" | "bgt" | "ble" => {
+                    let (s, t) = line.r2()?;
+                    line.comma()?;
+                    let l = self.label(line)?;
+
+                    match mnemonic {
+                        "blt" => Blt(s, t, l),
+                        "bge" => Bge(s, t, l),
+                        "bgt" => Bgt(s, t, l),
+                        _ => Ble(s, t, l),
+                    }
+                }
+                "mul" => {
+                    let (d, s, t) = line.r3()?;
+                    Mul(d, s, t)
+                }
+                "push" => Push(line.reg()?),
+                "pop" => Pop(line.reg()?),
+                _ => return Err(line.error_at(line.pos - 1,
+                                              &format!("Unknown mnemonic '{}'", mnemonic))),
+            };
+
+        Ok(i)
+    }
+
+    /// Parse a branch or jump target: a label name, a local label
+    /// reference or an expression evaluating to an address
+    fn label(&mut self, line: &mut Line) -> Result<Label<'a>, ParseError> {
+        let column = line.column();
+
+        let e = self.expr(line)?;
+
+        if let Expr::Label(l) = e {
+            return Ok(l);
+        }
+
+        match constant(&e) {
+            Some(a) if (0..=u32::MAX as i64).contains(&a) => Ok(Label::Absolute(a as u32)),
+            Some(a) => Err(line.error(column, &format!("Address {} out of range", a))),
+            None => Ok(Label::Expr(self.arena.alloc(e))),
+        }
+    }
+
+    /// Parse the memory operand of a load or store, `offset($base)`,
+    /// and build the instruction. The offset is optional.
+    fn address<F>(&mut self, line: &mut Line, build: F) -> Result<Instruction<'a>, ParseError>
+        where F: Fn(Register, i16) -> Instruction<'a>
+    {
+        let column = line.column();
+
+        let offset =
+            match (line.peek_at(0), line.peek_at(1)) {
+                (Some(Token::Punct('(')), Some(Token::Register(_))) => Expr::Const(0),
+                _ => self.expr(line)?,
+            };
+
+        line.expect('(')?;
+        let base = line.reg()?;
+        line.expect(')')?;
+
+        self.finish(line, column, Field::Imm16Signed, offset, |off| build(base, off as i16))
+    }
+
+    /// Parse an expression and build the instruction with its value
+    /// stored in `field`. If the value is not known yet a `Fixup` is
+    /// returned.
+    fn imm<F>(&mut self,
+              line: &mut Line,
+              field: Field,
+              build: F) -> Result<Instruction<'a>, ParseError>
+        where F: Fn(i64) -> Instruction<'a>
+    {
+        let column = line.column();
+
+        let e = self.expr(line)?;
+
+        self.finish(line, column, field, e, build)
+    }
+
+    fn expr(&mut self, line: &mut Line) -> Result<Expr<'a>, ParseError> {
+        self.binary(line, 0)
+    }
+
+    /// Parse binary operators with precedence level `level` and above
+    fn binary(&mut self, line: &mut Line, level: usize) -> Result<Expr<'a>, ParseError> {
+        // Operators by increasing precedence. '<' and '>' stand for the
+        // shift operators.
+        const LEVELS: [&[char]; 6] = [
+            &['|'],
+            &['^'],
+            &['&'],
+            &['<', '>'],
+            &['+', '-'],
+            &['*', '/'],
+        ];
+
+        if level == LEVELS.len() {
+            return self.unary(line);
+        }
+
+        let mut lhs = self.binary(line, level + 1)?;
+
+        while let Some(&Token::Punct(op)) = line.peek_at(0) {
+            if !LEVELS[level].contains(&op) {
+                break;
+            }
+
+            line.next();
+
+            let a = self.arena.alloc(lhs);
+            let b = self.arena.alloc(self.binary(line, level + 1)?);
+
+            lhs =
+                match op {
+                    '|' => Expr::Or(a, b),
+                    '^' => Expr::Xor(a, b),
+                    '&' => Expr::And(a, b),
+                    '<' => Expr::Shl(a, b),
+                    '>' => Expr::Shr(a, b),
+                    '+' => Expr::Add(a, b),
+                    '-' => Expr::Sub(a, b),
+                    '*' => Expr::Mul(a, b),
+                    _ => Expr::Div(a, b),
+                };
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self, line: &mut Line) -> Result<Expr<'a>, ParseError> {
+        let e =
+            match line.peek_at(0).cloned() {
+                Some(Token::Punct('-')) => {
+                    line.next();
+
+                    match self.unary(line)? {
+                        Expr::Const(v) => Expr::Const(-v),
+                        e => Expr::Neg(self.arena.alloc(e)),
+                    }
+                }
+                Some(Token::Punct('~')) => {
+                    line.next();
+                    Expr::Not(self.arena.alloc(self.unary(line)?))
+                }
+                Some(Token::Punct('+')) => {
+                    line.next();
+                    self.unary(line)?
+                }
+                Some(Token::Punct('(')) => {
+                    line.next();
+                    let e = self.expr(line)?;
+                    line.expect(')')?;
+                    e
+                }
+                Some(Token::Punct('%')) => {
+                    line.next();
+
+                    let column = line.column();
+
+                    let op =
+                        match line.next() {
+                            Some(Token::Ident(op)) if op == "hi" || op == "lo" => op,
+                            _ => return Err(line.error(column, "Expected %hi or %lo")),
+                        };
+
+                    line.expect('(')?;
+                    let e = self.arena.alloc(self.expr(line)?);
+                    line.expect(')')?;
+
+                    if op == "hi" {
+                        Expr::Hi(e)
+                    } else {
+                        Expr::Lo(e)
+                    }
+                }
+                Some(Token::Int(v)) => {
+                    line.next();
+                    Expr::Const(v)
+                }
+                Some(Token::Ident(name)) => {
+                    line.next();
+                    Expr::Label(Label::Global(self.intern(&name)))
+                }
+                Some(Token::LocalRef(name, d)) => {
+                    line.next();
+                    Expr::Label(Label::Local(self.intern(&name), d))
+                }
+                _ => return Err(line.unexpected("expression")),
+            };
+
+        Ok(e)
+    }
+
+    /// Build an instruction with the value of `e` stored in `field`,
+    /// checking the range if the value is already known
+    fn finish<F>(&self,
+                 line: &Line,
+                 column: usize,
+                 field: Field,
+                 e: Expr<'a>,
+                 build: F) -> Result<Instruction<'a>, ParseError>
+        where F: Fn(i64) -> Instruction<'a>
+    {
+        match constant(&e) {
+            Some(v) => {
+                let (min, max) = field.range();
+
+                if v < min || v > max {
+                    return Err(line.error(column,
+                                          &format!("Value {} out of range [{}, {}]",
+                                                   v, min, max)));
+                }
+
+                Ok(build(v))
+            }
+            None => Ok(Fixup(self.arena.alloc(build(0)), field, self.arena.alloc(e))),
+        }
+    }
+}
+
+/// Return the value of `e` if it doesn't depend on any symbol
+fn constant(e: &Expr) -> Option<i64> {
+    Assembler::from_base(0).eval(e).ok()
+}
+
+/// Tokens of a single line with a cursor
+struct Line {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    line: usize,
+    /// Column past the end of the line, used for errors on missing
+    /// operands
+    end: usize,
+}
+
+impl Line {
+    fn peek_at(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).map(|(t, _)| t.clone());
+
+        if t.is_some() {
+            self.pos += 1;
+        }
+
+        t
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    /// Column of the next token
+    fn column(&self) -> usize {
+        self.tokens.get(self.pos).map(|&(_, c)| c).unwrap_or(self.end)
+    }
+
+    fn error(&self, column: usize, message: &str) -> ParseError {
+        ParseError {
+            line: self.line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn error_at(&self, pos: usize, message: &str) -> ParseError {
+        let column = self.tokens.get(pos).map(|&(_, c)| c).unwrap_or(self.end);
+
+        self.error(column, message)
+    }
+
+    /// Consume the next token if it's the punctuation `c`
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek_at(0) == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", c)))
+        }
+    }
+
+    fn comma(&mut self) -> Result<(), ParseError> {
+        self.expect(',')
+    }
+
+    fn end_of_line(&self) -> Result<(), ParseError> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.unexpected("end of line"))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let message =
+            match self.peek_at(0) {
+                Some(t) => format!("Expected {}, found {}", expected, t),
+                None => format!("Expected {}, found end of line", expected),
+            };
+
+        self.error(self.column(), &message)
+    }
+
+    fn reg(&mut self) -> Result<Register, ParseError> {
+        match self.peek_at(0) {
+            Some(&Token::Register(r)) => {
+                self.pos += 1;
+                Ok(Register(r))
+            }
+            _ => Err(self.unexpected("register")),
+        }
+    }
+
+    fn r2(&mut self) -> Result<(Register, Register), ParseError> {
+        let a = self.reg()?;
+        self.comma()?;
+        let b = self.reg()?;
+
+        Ok((a, b))
+    }
+
+    fn r3(&mut self) -> Result<(Register, Register, Register), ParseError> {
+        let (a, b) = self.r2()?;
+        self.comma()?;
+        let c = self.reg()?;
+
+        Ok((a, b, c))
+    }
+
+    /// Coprocessor register, either `$12` or `12`
+    fn cop_reg(&mut self) -> Result<u8, ParseError> {
+        match self.peek_at(0) {
+            Some(&Token::Register(r)) => {
+                self.pos += 1;
+                Ok(r)
+            }
+            _ => Ok(self.int(0, 31)? as u8),
+        }
+    }
+
+    /// Integer in the range `min..=max`
+    fn int(&mut self, min: i64, max: i64) -> Result<i64, ParseError> {
+        let column = self.column();
+
+        let negative = self.eat('-');
+
+        if !negative {
+            self.eat('+');
+        }
+
+        let v =
+            match self.peek_at(0) {
+                Some(&Token::Int(v)) => {
+                    self.pos += 1;
+                    if negative { -v } else { v }
+                }
+                _ => return Err(self.unexpected("integer")),
+            };
+
+        if v < min || v > max {
+            return Err(self.error(column,
+                                  &format!("Value {} out of range [{}, {}]",
+                                           v, min, max)));
+        }
+
+        Ok(v)
+    }
+
+    /// Optional GTE `sf` flag, 1 if missing
+    fn sf(&mut self) -> Result<bool, ParseError> {
+        if self.at_end() {
+            Ok(true)
+        } else {
+            self.flag()
+        }
+    }
+
+    fn flag(&mut self) -> Result<bool, ParseError> {
+        Ok(self.int(0, 1)? != 0)
+    }
+}
+
+/// Split the macro arguments in `text`, starting at `column`, on the
+/// commas outside of parentheses and strings
+fn macro_args(text: &str, column: usize) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in text.chars().skip(column - 1) {
+        if quoted {
+            arg.push(c);
+
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => (),
+            }
+
+            continue;
+        }
+
+        match c {
+            '#' => break,
+            '"' => quoted = true,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(arg.trim().to_owned());
+                arg.clear();
+                continue;
+            }
+            _ => (),
+        }
+
+        arg.push(c);
+    }
+
+    let arg = arg.trim();
+
+    if !arg.is_empty() || !args.is_empty() {
+        args.push(arg.to_owned());
+    }
+
+    args
+}
+
+/// Replace `\param` by the corresponding argument and `\@` by
+/// `counter` in the macro line `text`
+fn substitute(text: &str, params: &[String], args: &[String], counter: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(p) = rest.find('\\') {
+        out.push_str(&rest[..p]);
+        rest = &rest[p + 1..];
+
+        if let Some(r) = rest.strip_prefix('@') {
+            out.push_str(counter);
+            rest = r;
+            continue;
+        }
+
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+
+        match params.iter().position(|param| *param == rest[..len]) {
+            Some(n) => {
+                out.push_str(&args[n]);
+                rest = &rest[len..];
+            }
+            // Not a parameter, probably a string escape
+            None => out.push('\\'),
+        }
+    }
+
+    out.push_str(rest);
+
+    out
+}
+
+/// Split `text` into tokens, along with their column
+fn tokenize(line: usize, text: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    let error = |column: usize, message: String| ParseError {
+        line,
+        column: column + 1,
+        message,
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c == '#' {
+            break;
+        }
+
+        let token =
+            if c == '$' {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+
+                let name: String = chars[start + 1..i].iter().collect();
+
+                match register(&name) {
+                    Some(r) => Token::Register(r),
+                    None => return Err(error(start,
+                                             format!("Unknown register '${}'", name))),
+                }
+            } else if c.is_ascii_digit() {
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+
+                let word: String = chars[start..i].iter().collect();
+
+                number(&word).ok_or_else(|| error(start,
+                                                  format!("Invalid number '{}'", word)))?
+            } else if c.is_alphabetic() || c == '_' || c == '.' {
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                    i += 1;
+                }
+
+                Token::Ident(chars[start..i].iter().collect())
+            } else if c == '"' {
+                i += 1;
+
+                let mut s = Vec::new();
+
+                loop {
+                    let c =
+                        match chars.get(i) {
+                            Some(&c) => c,
+                            None => return Err(error(start,
+                                                     "Unterminated string".into())),
+                        };
+
+                    i += 1;
+
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            let e = chars.get(i).cloned();
+                            i += 1;
+
+                            let b =
+                                match e {
+                                    Some('n') => b'\n',
+                                    Some('r') => b'\r',
+                                    Some('t') => b'\t',
+                                    Some('0') => 0,
+                                    Some('\\') => b'\\',
+                                    Some('"') => b'"',
+                                    Some('x') => {
+                                        let hex: String = chars.iter().skip(i).take(2).collect();
+
+                                        i += 2;
+
+                                        match u8::from_str_radix(&hex, 16) {
+                                            Ok(b) if hex.len() == 2 => b,
+                                            _ => return Err(error(i - 4,
+                                                                  "Invalid escape sequence".into())),
+                                        }
+                                    }
+                                    _ => return Err(error(i - 2,
+                                                          "Invalid escape sequence".into())),
+                                };
+
+                            s.push(b);
+                        }
+                        c => {
+                            let mut buf = [0; 4];
+                            s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                    }
+                }
+
+                Token::Str(s)
+            } else if ",:()+-*/%|&^~".contains(c) {
+                i += 1;
+                Token::Punct(c)
+            } else if (c == '<' || c == '>') && chars.get(i + 1) == Some(&c) {
+                // Shift operators
+                i += 2;
+                Token::Punct(c)
+            } else {
+                return Err(error(start, format!("Unexpected character '{}'", c)));
+            };
+
+        tokens.push((token, start + 1));
+    }
+
+    Ok(tokens)
+}
+
+/// Parse a number or a local label reference
+fn number(word: &str) -> Option<Token> {
+    if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok().map(Token::Int);
+    }
+
+    if let Ok(v) = word.parse() {
+        return Some(Token::Int(v));
+    }
+
+    let (name, d) = word.split_at(word.len() - 1);
+
+    match d {
+        "b" | "f" if name.bytes().all(|b| b.is_ascii_digit()) =>
+            Some(Token::LocalRef(name.into(), d.chars().next().unwrap())),
+        _ => None,
+    }
+}
+
+/// Return the index of register `name`, without the leading `$`
+fn register(name: &str) -> Option<u8> {
+    if let Ok(r) = name.parse::<u8>() {
+        return if r < 32 { Some(r) } else { None };
+    }
+
+    let r =
+        match name {
+            "zero" => 0,
+            "at" => 1,
+            "v0" => 2,
+            "v1" => 3,
+            "a0" => 4,
+            "a1" => 5,
+            "a2" => 6,
+            "a3" => 7,
+            "t0" => 8,
+            "t1" => 9,
+            "t2" => 10,
+            "t3" => 11,
+            "t4" => 12,
+            "t5" => 13,
+            "t6" => 14,
+            "t7" => 15,
+            "s0" => 16,
+            "s1" => 17,
+            "s2" => 18,
+            "s3" => 19,
+            "s4" => 20,
+            "s5" => 21,
+            "s6" => 22,
+            "s7" => 23,
+            "t8" => 24,
+            "t9" => 25,
+            "k0" => 26,
+            "k1" => 27,
+            "gp" => 28,
+            "sp" => 29,
+            "fp" | "s8" => 30,
+            "ra" => 31,
+            _ => return None,
+        };
+
+    Some(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        let arena = Arena::new();
+
+        let source = "
+start:  addiu $sp, $sp, -8      # Comment
+        sw $ra, 4($sp)
+        lw $t0, ($a0)
+        SLL $8, $9, 3
+1:      bne $t0, $zero, 1b
+        nop
+        beqz $v0, 1f
+        jalr $t9
+1:      j start
+        li $a0, 0x12345678
+        mvmva 1, 2, 3, 0, 0
+        op";
+
+        assert_eq!(parse(&arena, source).unwrap(), [
+            Global("start"),
+            Addiu(SP, SP, -8),
+            Sw(RA, SP, 4),
+            Lw(T0, A0, 0),
+            Sll(T0, T1, 3),
+            Local("1"),
+            Bne(T0, R0, Label::Local("1", 'b')),
+            Nop,
+            Beqz(V0, Label::Local("1", 'f')),
+            Jalr(RA, T9),
+            Local("1"),
+            J(Label::Global("start")),
+            Li(A0, 0x12345678),
+            Mvmva(true, 2, 3, 0, false),
+            Op(true),
+        ]);
+    }
+
+    #[test]
+    fn directives() {
+        let arena = Arena::new();
+
+        let source = "
+        .word 0x12345678, -1
+        .half 0xffff
+        .byte 1, 2
+        .ascii \"hi\\n\", \"\\x00\"
+        .align 2
+        .org 0x80010100";
+
+        assert_eq!(parse(&arena, source).unwrap(), [
+            Word(0x12345678),
+            Word(0xffff_ffff),
+            Half(0xffff),
+            Byte(1),
+            Byte(2),
+            Ascii(b"hi\n"),
+            Ascii(b"\0"),
+            Align(2),
+            Org(0x8001_0100),
+        ]);
+    }
+
+    #[test]
+    fn line_numbers() {
+        let arena = Arena::new();
+
+        let lines = parse_lines(&arena, "nop\n\n# Comment\nfoo: li $t0, 1\n").unwrap();
+
+        assert_eq!(lines, [
+            (1, Nop),
+            (4, Global("foo")),
+            (4, Li(T0, 1)),
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| {
+            let arena = Arena::new();
+
+            let e = parse(&arena, source).unwrap_err();
+
+            (e.line, e.column, e.message)
+        };
+
+        assert_eq!(error("nop\n  addiu $t0, $t9x, 1"),
+                   (2, 14, "Unknown register '$t9x'".into()));
+        assert_eq!(error("foo $t0"),
+                   (1, 1, "Unknown mnemonic 'foo'".into()));
+        assert_eq!(error("lw $t0, 4($t1"),
+                   (1, 14, "Expected ')', found end of line".into()));
+        assert_eq!(error("addiu $t0, $t0, 0x8000"),
+                   (1, 17, "Value 32768 out of range [-32768, 32767]".into()));
+        assert_eq!(error("\n\n.ascii \"abc"),
+                   (3, 8, "Unterminated string".into()));
+        assert_eq!(error("jr $ra, $t0"),
+                   (1, 7, "Expected end of line, found ','".into()));
+        assert_eq!(error("1b: nop"),
+                   (1, 1, "Invalid label name".into()));
+    }
+
+    /// Parse and assemble `source` at `base`, return the generated
+    /// words
+    fn assemble(base: u32, source: &str) -> Result<Vec<u32>, String> {
+        let arena = Arena::new();
+
+        let instructions = parse(&arena, source).map_err(|e| e.to_string())?;
+
+        let mut asm = Assembler::from_base(base);
+        asm.assemble(&instructions)?;
+
+        Ok(asm.code()
+           .chunks(4)
+           .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+           .collect())
+    }
+
+    #[test]
+    fn expressions() {
+        let source = "
+        .equ COUNT, (end - start) / 4
+        .equ BIG, 1 + 2 * 3 << 1
+start:  lui $t0, %hi(0x12348000)
+        addiu $t0, $t0, %lo(0x12348000)
+        lui $t1, %hi(start)
+        addiu $t1, $t1, %lo(start + 4)
+        li $t2, COUNT
+        .word BIG, ~0 & 0xff, -(3), 7 / 2, 1 ^ 3 | 8
+end:";
+
+        // The low halves are negative: %hi rounds up to compensate
+        assert_eq!(assemble(0x8000_8000, source).unwrap(), [
+            0x3c08_1235,
+            0x2508_8000,
+            0x3c09_8001,
+            0x2529_8004,
+            0x3c0a_0000,
+            0x354a_000b,
+            14,
+            0xff,
+            0xffff_fffd,
+            3,
+            10,
+        ]);
+    }
+
+    #[test]
+    fn constant_expressions() {
+        let arena = Arena::new();
+
+        assert_eq!(parse(&arena, "addiu $t0, $t0, -0x10 * 2
+li $t1, 1 << 31").unwrap(), [
+            Addiu(T0, T0, -0x20),
+            Li(T1, 0x8000_0000),
+        ]);
+
+        let error = parse(&arena, "andi $t0, $t0, 0x8000 << 1").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 16));
+        assert_eq!(error.message, "Value 65536 out of range [0, 65535]");
+    }
+
+    #[test]
+    fn expression_errors() {
+        let error = |source| assemble(0x8001_0000, source).unwrap_err();
+
+        assert!(error(".word missing + 4").contains("Unknown global label 'missing'"));
+        assert!(error(".equ BIG, 0x10000\naddiu $t0, $t0, BIG")
+                .contains("Value 65536 out of range [-32768, 32767]"));
+        assert!(error(".equ A, B + 1\n.equ B, A\n.word A")
+                .contains("Circular definition of symbol"));
+        assert!(error("end: .word 1 / (end - end)").contains("Division by zero"));
+    }
+
+    #[test]
+    fn macro_arguments() {
+        assert_eq!(macro_args("m (a, b), \"c, \\\"d\", e  # f, g", 3),
+                   ["(a, b)", "\"c, \\\"d\"", "e"]);
+        assert_eq!(macro_args("m a,, b", 3), ["a", "", "b"]);
+        assert_eq!(macro_args("m a,", 3), ["a", ""]);
+        assert!(macro_args("m   # comment", 3).is_empty());
+
+        let params = ["r".to_owned(), "reg".to_owned()];
+        let args = ["$t0".to_owned(), "$t1".to_owned()];
+
+        // Longest name wins, unknown escapes are kept
+        assert_eq!(substitute("add \\reg, \\r, \\r\\@ \"\\n\"", &params, &args, "7"),
+                   "add $t1, $t0, $t07 \"\\n\"");
+    }
+
+    #[test]
+    fn macros() {
+        let arena = Arena::new();
+
+        let source = r#"
+        .macro store reg, addr
+loop\@: sw \reg, \addr
+        .endm
+        .macro text s
+        .ascii \s
+        .endm
+        store $t0, 4($sp)
+        STORE $t1, %lo(0x10 + 4)($a0)
+        text "a, b""#;
+
+        assert_eq!(parse(&arena, source).unwrap(), [
+            Global("loop0"),
+            Sw(T0, SP, 4),
+            Global("loop1"),
+            Sw(T1, A0, 0x14),
+            Ascii(b"a, b"),
+        ]);
+    }
+
+    #[test]
+    fn macro_errors() {
+        let error = |source| {
+            let arena = Arena::new();
+
+            let e = parse(&arena, source).unwrap_err();
+
+            (e.line, e.column, e.message)
+        };
+
+        assert_eq!(error("nop\n.macro m a\nnop\n"),
+                   (2, 1, "Missing .endm for macro 'm'".into()));
+        assert_eq!(error(".macro m a, b\n.endm\nm $t0"),
+                   (3, 3, "Macro 'm' takes 2 arguments, got 1".into()));
+        assert_eq!(error(".macro r\nr\n.endm\nnop\n  r"),
+                   (5, 4, "In macro 'r': Macro 'r' nested too deeply".into()));
+        // Errors in the body are reported on the invocation line
+        assert_eq!(error(".macro m r\naddiu \\r, \\r, 0x10000\n.endm\nm $t0"),
+                   (4, 3, "In macro 'm': Value 65536 out of range [-32768, 32767]".into()));
+    }
+}
@@ -0,0 +1,262 @@
+//! Textual representation of instructions
+//!
+//! Instructions are printed in the GNU syntax accepted by the `parser`
+//! module, using the ABI register names.
+
+use std::fmt::{self, Write};
+
+use super::syntax::*;
+
+/// ABI names of the general purpose registers
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match REGISTER_NAMES.get(self.0 as usize) {
+            Some(name) => write!(f, "${}", name),
+            None => write!(f, "${}", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Label::Local(name, d) => write!(f, "{}{}", name, d),
+            Label::Global(name) => write!(f, "{}", name),
+            Label::Absolute(a) => write!(f, "0x{:08x}", a),
+            Label::Expr(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for Expr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (op, a, b) =
+            match *self {
+                Expr::Const(v) if (-9..=9).contains(&v) => return write!(f, "{}", v),
+                Expr::Const(v) if v < 0 => return write!(f, "-0x{:x}", v.unsigned_abs()),
+                Expr::Const(v) => return write!(f, "0x{:x}", v),
+                Expr::Label(l) => return write!(f, "{}", l),
+                Expr::Neg(e) => return write!(f, "-({})", e),
+                Expr::Not(e) => return write!(f, "~({})", e),
+                Expr::Hi(e) => return write!(f, "%hi({})", e),
+                Expr::Lo(e) => return write!(f, "%lo({})", e),
+                Expr::Add(a, b) => ("+", a, b),
+                Expr::Sub(a, b) => ("-", a, b),
+                Expr::Mul(a, b) => ("*", a, b),
+                Expr::Div(a, b) => ("/", a, b),
+                Expr::Shl(a, b) => ("<<", a, b),
+                Expr::Shr(a, b) => (">>", a, b),
+                Expr::And(a, b) => ("&", a, b),
+                Expr::Or(a, b) => ("|", a, b),
+                Expr::Xor(a, b) => ("^", a, b),
+            };
+
+        write!(f, "({} {} {})", a, op, b)
+    }
+}
+
+impl fmt::Display for Instruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_text(&|_| None))
+    }
+}
+
+impl Instruction<'_> {
+    /// Format the instruction, using `symbol` to name absolute
+    /// addresses
+    pub fn to_text(&self, symbol: &dyn Fn(u32) -> Option<String>) -> String {
+        let mut s = String::new();
+
+        // Writing to a String can't fail
+        let _ = write_instruction(&mut s, *self, symbol, None);
+
+        s
+    }
+}
+
+/// Write `i` to `w`. If `imm` is not `None` it's used instead of the
+/// immediate value of the instruction.
+fn write_instruction(w: &mut String,
+                     i: Instruction,
+                     symbol: &dyn Fn(u32) -> Option<String>,
+                     imm: Option<&str>) -> fmt::Result {
+    let label = |l: Label| match l {
+        Label::Absolute(a) => symbol(a).unwrap_or_else(|| l.to_string()),
+        _ => l.to_string(),
+    };
+
+    let signed = |v: i16| imm.map(str::to_owned).unwrap_or_else(|| v.to_string());
+    let unsigned = |v: u32| imm.map(str::to_owned).unwrap_or_else(|| format!("0x{:x}", v));
+
+    // Label definitions and directives are not indented like
+    // instructions
+    match i {
+        Global(name) => return write!(w, "{}:", name),
+        Local(name) => return write!(w, "{}:", name),
+        Equ(name, e) => return write!(w, ".equ {}, {}", name, e),
+        Fixup(i, _, e) => return write_instruction(w, *i, symbol, Some(&e.to_string())),
+        _ => (),
+    }
+
+    let (mnemonic, operands) =
+        match i {
+            Sll(d, t, sa) => ("sll", format!("{}, {}, {}", d, t, imm.unwrap_or(&sa.to_string()))),
+            Srl(d, t, sa) => ("srl", format!("{}, {}, {}", d, t, imm.unwrap_or(&sa.to_string()))),
+            Sra(d, t, sa) => ("sra", format!("{}, {}, {}", d, t, imm.unwrap_or(&sa.to_string()))),
+            Sllv(d, t, s) => ("sllv", format!("{}, {}, {}", d, t, s)),
+            Srlv(d, t, s) => ("srlv", format!("{}, {}, {}", d, t, s)),
+            Srav(d, t, s) => ("srav", format!("{}, {}, {}", d, t, s)),
+            Jr(s) => ("jr", s.to_string()),
+            Jalr(d, s) => ("jalr", format!("{}, {}", d, s)),
+            Syscall(0) => ("syscall", String::new()),
+            Syscall(c) => ("syscall", format!("0x{:x}", c)),
+            Break(0) => ("break", String::new()),
+            Break(c) => ("break", format!("0x{:x}", c)),
+            Mfhi(d) => ("mfhi", d.to_string()),
+            Mthi(s) => ("mthi", s.to_string()),
+            Mflo(d) => ("mflo", d.to_string()),
+            Mtlo(s) => ("mtlo", s.to_string()),
+            Mult(s, t) => ("mult", format!("{}, {}", s, t)),
+            Multu(s, t) => ("multu", format!("{}, {}", s, t)),
+            Div(s, t) => ("div", format!("{}, {}", s, t)),
+            Divu(s, t) => ("divu", format!("{}, {}", s, t)),
+            Add(d, s, t) => ("add", format!("{}, {}, {}", d, s, t)),
+            Addu(d, s, t) => ("addu", format!("{}, {}, {}", d, s, t)),
+            Sub(d, s, t) => ("sub", format!("{}, {}, {}", d, s, t)),
+            Subu(d, s, t) => ("subu", format!("{}, {}, {}", d, s, t)),
+            And(d, s, t) => ("and", format!("{}, {}, {}", d, s, t)),
+            Or(d, s, t) => ("or", format!("{}, {}, {}", d, s, t)),
+            Xor(d, s, t) => ("xor", format!("{}, {}, {}", d, s, t)),
+            Nor(d, s, t) => ("nor", format!("{}, {}, {}", d, s, t)),
+            Slt(d, s, t) => ("slt", format!("{}, {}, {}", d, s, t)),
+            Sltu(d, s, t) => ("sltu", format!("{}, {}, {}", d, s, t)),
+            Bgez(s, l) => ("bgez", format!("{}, {}", s, label(l))),
+            Bltz(s, l) => ("bltz", format!("{}, {}", s, label(l))),
+            Bgezal(s, l) => ("bgezal", format!("{}, {}", s, label(l))),
+            Bltzal(s, l) => ("bltzal", format!("{}, {}", s, label(l))),
+            J(l) => ("j", label(l)),
+            Jal(l) => ("jal", label(l)),
+            Beq(s, t, l) => ("beq", format!("{}, {}, {}", s, t, label(l))),
+            Bne(s, t, l) => ("bne", format!("{}, {}, {}", s, t, label(l))),
+            Blez(s, l) => ("blez", format!("{}, {}", s, label(l))),
+            Bgtz(s, l) => ("bgtz", format!("{}, {}", s, label(l))),
+            Addi(t, s, v) => ("addi", format!("{}, {}, {}", t, s, signed(v))),
+            Addiu(t, s, v) => ("addiu", format!("{}, {}, {}", t, s, signed(v))),
+            Slti(t, s, v) => ("slti", format!("{}, {}, {}", t, s, signed(v))),
+            Sltiu(t, s, v) => ("sltiu", format!("{}, {}, {}", t, s, signed(v))),
+            Andi(t, s, v) => ("andi", format!("{}, {}, {}", t, s, unsigned(v as u32))),
+            Ori(t, s, v) => ("ori", format!("{}, {}, {}", t, s, unsigned(v as u32))),
+            Xori(t, s, v) => ("xori", format!("{}, {}, {}", t, s, unsigned(v as u32))),
+            Lui(t, v) => ("lui", format!("{}, {}", t, unsigned(v as u32))),
+            Lb(t, s, o) => ("lb", format!("{}, {}({})", t, signed(o), s)),
+            Lh(t, s, o) => ("lh", format!("{}, {}({})", t, signed(o), s)),
+            Lwl(t, s, o) => ("lwl", format!("{}, {}({})", t, signed(o), s)),
+            Lw(t, s, o) => ("lw", format!("{}, {}({})", t, signed(o), s)),
+            Lbu(t, s, o) => ("lbu", format!("{}, {}({})", t, signed(o), s)),
+            Lhu(t, s, o) => ("lhu", format!("{}, {}({})", t, signed(o), s)),
+            Lwr(t, s, o) => ("lwr", format!("{}, {}({})", t, signed(o), s)),
+            Sb(t, s, o) => ("sb", format!("{}, {}({})", t, signed(o), s)),
+            Sh(t, s, o) => ("sh", format!("{}, {}({})", t, signed(o), s)),
+            Swl(t, s, o) => ("swl", format!("{}, {}({})", t, signed(o), s)),
+            Sw(t, s, o) => ("sw", format!("{}, {}({})", t, signed(o), s)),
+            Swr(t, s, o) => ("swr", format!("{}, {}({})", t, signed(o), s)),
+            Mfc0(t, r) => ("mfc0", format!("{}, ${}", t, r)),
+            Mtc0(t, r) => ("mtc0", format!("{}, ${}", t, r)),
+            Rfe => ("rfe", String::new()),
+            Mfc2(t, r) => ("mfc2", format!("{}, ${}", t, r)),
+            Cfc2(t, r) => ("cfc2", format!("{}, ${}", t, r)),
+            Mtc2(t, r) => ("mtc2", format!("{}, ${}", t, r)),
+            Ctc2(t, r) => ("ctc2", format!("{}, ${}", t, r)),
+            Lwc2(r, s, o) => ("lwc2", format!("${}, {}({})", r, signed(o), s)),
+            Swc2(r, s, o) => ("swc2", format!("${}, {}({})", r, signed(o), s)),
+            Rtps => ("rtps", String::new()),
+            Rtpt => ("rtpt", String::new()),
+            Nclip => ("nclip", String::new()),
+            Op(sf) => ("op", (sf as u8).to_string()),
+            Dpcs => ("dpcs", String::new()),
+            Intpl => ("intpl", String::new()),
+            Mvmva(sf, mx, v, cv, lm) =>
+                ("mvmva", format!("{}, {}, {}, {}, {}", sf as u8, mx, v, cv, lm as u8)),
+            Ncds => ("ncds", String::new()),
+            Cdp => ("cdp", String::new()),
+            Ncdt => ("ncdt", String::new()),
+            Nccs => ("nccs", String::new()),
+            Cc => ("cc", String::new()),
+            Ncs => ("ncs", String::new()),
+            Nct => ("nct", String::new()),
+            Sqr(sf) => ("sqr", (sf as u8).to_string()),
+            Dcpl => ("dcpl", String::new()),
+            Dpct => ("dpct", String::new()),
+            Avsz3 => ("avsz3", String::new()),
+            Avsz4 => ("avsz4", String::new()),
+            Gpf(sf) => ("gpf", (sf as u8).to_string()),
+            Gpl(sf) => ("gpl", (sf as u8).to_string()),
+            Ncct => ("ncct", String::new()),
+            Cop2(c) => ("cop2", format!("0x{:x}", c)),
+            Align(o) => (".align", o.to_string()),
+            Org(a) => (".org", format!("0x{:08x}", a)),
+            Word(v) => (".word", unsigned(v)),
+            Half(v) => (".half", unsigned(v as u32)),
+            Byte(v) => (".byte", unsigned(v as u32)),
+            Ascii(s) => (".ascii", escape(s)),
+            Nop => ("nop", String::new()),
+            Move(d, s) => ("move", format!("{}, {}", d, s)),
+            Li(t, v) => ("li", format!("{}, {}", t, unsigned(v))),
+            La(t, l) => ("la", format!("{}, {}", t, label(l))),
+            B(l) => ("b", label(l)),
+            Beqz(s, l) => ("beqz", format!("{}, {}", s, label(l))),
+            Bnez(s, l) => ("bnez", format!("{}, {}", s, label(l))),
+            Not(d, s) => ("not", format!("{}, {}", d, s)),
+            Neg(d, s) => ("neg", format!("{}, {}", d, s)),
+            Blt(s, t, l) => ("blt", format!("{}, {}, {}", s, t, label(l))),
+            Bge(s, t, l) => ("bge", format!("{}, {}, {}", s, t, label(l))),
+            Bgt(s, t, l) => ("bgt", format!("{}, {}, {}", s, t, label(l))),
+            Ble(s, t, l) => ("ble", format!("{}, {}, {}", s, t, label(l))),
+            Mul(d, s, t) => ("mul", format!("{}, {}, {}", d, s, t)),
+            Push(t) => ("push", t.to_string()),
+            Pop(t) => ("pop", t.to_string()),
+            Set(o) => (".set",
+                       match o {
+                           SetOption::At => "at",
+                           SetOption::NoAt => "noat",
+                           SetOption::Reorder => "reorder",
+                           SetOption::NoReorder => "noreorder",
+                       }.to_owned()),
+            Global(_) | Local(_) | Equ(..) | Fixup(..) => unreachable!(),
+        };
+
+    if operands.is_empty() {
+        write!(w, "    {}", mnemonic)
+    } else {
+        write!(w, "    {:<7} {}", mnemonic, operands)
+    }
+}
+
+/// Quote and escape `s` for the `.ascii` directive
+fn escape(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+
+    for &b in s {
+        match b {
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0 => out.push_str("\\0"),
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b if b.is_ascii_graphic() || b == b' ' => out.push(b as char),
+            b => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+
+    out.push('"');
+
+    out
+}
@@ -2,7 +2,10 @@ use std::fmt;
 
 use shaman::{digest::Digest, sha2::Sha256};
 
-use crate::{assembler::{syntax::Label, Assembler}, bios::BIOS_SIZE};
+use crate::{assembler::syntax::*, cdrom::disk::Region};
+
+use super::patch::{Patch, DEBUG_UART, FAST_BOOT};
+use super::romdir::RomDir;
 
 
 
@@ -12,9 +15,12 @@ pub struct Metadata {
 	pub version_minor: u8,
 	pub region: Region,
 	pub known_bad: bool,
+	/// False if the metadata was guessed from the contents of the ROM
+	/// instead of coming from the database
+	pub verified: bool,
 
-	pub animation_jump_hook: Option<u32>,
-	pub patch_debug_uart: Option<fn (&mut Bios)>
+	/// Patches supported by this dump
+	pub patches: &'static [Patch],
 }
 
 impl fmt::Debug for Metadata {
@@ -27,42 +33,162 @@ impl fmt::Debug for Metadata {
 		if self.known_bad {
 			write!(f, "[BAD]")?;
 		}
+		if !self.verified {
+			write!(f, "[UNVERIFIED]")?;
+		}
 		Ok(())
 	}
 }
 
-pub fn lookup_blob(binary: &[u8; BIOS_SIZE]) -> Option<&'static Metadata> {
+pub fn lookup_blob(binary: &[u8]) -> Option<&'static Metadata> {
+	lookup_sha256(&sha256(binary))
+}
+
+/// Compute the SHA-256 of `binary`
+pub fn sha256(binary: &[u8]) -> [u8; 32] {
 	let mut hasher = Sha256::new();
 	hasher.input(binary);
 	let mut sha256 = [0;32];
 	hasher.result(&mut sha256);
-	lookup_sha256(&sha256)
+	sha256
 }
 
+/// Format `sha256` as a lowercase hex string
+pub fn format_sha256(sha256: &[u8; 32]) -> String {
+	sha256.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Look up a PS1 dump. There's no database of PS2 dumps: they're
+/// identified by their ROMVER module instead, see `guess_metadata`.
 pub fn lookup_sha256(sha256: &[u8; 32]) -> Option<&'static Metadata> {
-	for md in &DATABASE {
-		if md.sha256 == *sha256 {
-			return Some(md);
-		}
-	}
+	DATABASE.iter().find(|md| md.sha256 == *sha256)
+}
+
+/// Build unverified metadata for a dump missing from the database by
+/// parsing the version string embedded in the ROM, for instance
+/// "System ROM Version 4.1 12/16/97 E". Very early BIOS versions don't
+/// contain this string and can't be identified this way.
+///
+/// PS2 images are identified using the ROMVER module instead.
+pub fn guess_metadata(binary: &[u8]) -> Option<Metadata> {
+	let (major, minor, region) =
+		match RomVersion::find(binary) {
+			Some(v) => (v.major, v.minor, v.region),
+			None => ps2_version(binary)?,
+		};
+
+	Some(Metadata {
+		sha256: sha256(binary),
+		version_major: major,
+		version_minor: minor,
+		region,
+		known_bad: false,
+		verified: false,
+		patches: &[],
+	})
+}
+
+/// Read the version and region from the ROMVER module of a PS2 BIOS
+fn ps2_version(binary: &[u8]) -> Option<(u8, u8, Region)> {
+	let romver = RomDir::parse(binary).ok()?.romver(binary).ok()?;
+
+	Some((romver.major, romver.minor, romver.region))
 }
 
-fn patch_debug_uart_na_30(bios: &mut Bios) {
-	let mut asm = Assembler::from_base(0);
-	asm.assemble(&[
-		Li(AT, 1),
-		Jal(Label::Absolute(0xBFC06784)),
-		Sw(AT, GP, -18000 - 0xFF0),
-	]).unwrap();
+/// Version information parsed from the ROM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomVersion {
+	pub major: u8,
+	pub minor: u8,
+	pub region: Region,
+	/// Build date: (year, month, day)
+	pub date: (u16, u8, u8),
+}
+
+impl RomVersion {
+	/// Look for the version string in `binary` and parse it
+	pub fn find(binary: &[u8]) -> Option<RomVersion> {
+		const MARKER: &[u8] = b"System ROM Version ";
+
+		let start = binary.windows(MARKER.len())
+			.position(|w| w == MARKER)?;
+
+		let s: Vec<u8> = binary[start..].iter()
+			.take_while(|&&b| b != 0 && b != b'\n')
+			.take(64)
+			.cloned()
+			.collect();
+
+		RomVersion::parse(&String::from_utf8_lossy(&s))
+	}
+
+	/// Parse a version string of the form
+	/// "System ROM Version 4.1 12/16/97 E"
+	pub fn parse(s: &str) -> Option<RomVersion> {
+		let s = s.strip_prefix("System ROM Version ")?;
+
+		let mut fields = s.split_whitespace();
+
+		let (major, minor) = fields.next()?.split_once('.')?;
+		let major = major.parse().ok()?;
+		let minor = minor.parse().ok()?;
+
+		let mut date = fields.next()?.split('/');
+		let month = date.next()?.parse().ok()?;
+		let day = date.next()?.parse().ok()?;
+		let year: u16 = date.next()?.parse().ok()?;
+
+		// Two digit years, the PlayStation was released in 1994
+		let year =
+			match year {
+				0..=89 => 2000 + year,
+				90..=99 => 1900 + year,
+				y => y,
+			};
+
+		let region =
+			match fields.next()? {
+				"J" => Region::Japan,
+				"A" => Region::NorthAmerica,
+				"E" => Region::Europe,
+				_ => return None,
+			};
 
-	let (mc, _) = asm.machine_code();
-	assert!(mc.len() == 12);
-	for (i, &b) in mc.iter().enumerate() {
-		bios.data[0x6F0C + i] = b;
+		Some(RomVersion {
+			major,
+			minor,
+			region,
+			date: (year, month, day),
+		})
 	}
 }
 
+/// Patches for NA/3.0, JP/4.0 uses the same code layout
+static PATCHES_NA_30: [Patch; 2] = [
+	Patch {
+		name: FAST_BOOT,
+		description: "Skip the boot animation",
+		offset: 0x6990,
+		original: None,
+		code: &[Nop],
+	},
+	Patch {
+		name: DEBUG_UART,
+		description: "Enable the kernel debug output on the expansion UART",
+		offset: 0x6F0C,
+		original: None,
+		code: &[
+			Li(AT, 1),
+			Jal(Label::Absolute(0xBFC06784)),
+			Sw(AT, GP, -18000 - 0xFF0),
+		],
+	},
+];
 
+/// Known BIOS dumps. The patch offsets have only been located and
+/// checked against real dumps for NA/3.0 and JP/4.0, other versions
+/// need their own disassembly before patches can be added (the code
+/// layout differs between them).
 pub static DATABASE: [Metadata; 24] = [
     Metadata {
         sha256: [0xcf, 0xc1, 0xfc, 0x38, 0xeb, 0x44, 0x2f, 0x6f,
@@ -73,8 +199,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x5e, 0xb3, 0xae, 0xe4, 0x95, 0x93, 0x75, 0x58,
@@ -85,8 +211,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x42, 0xe4, 0x12, 0x4b, 0xe7, 0x62, 0x3e, 0x2e,
@@ -97,8 +223,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::NorthAmerica,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x0a, 0xf2, 0xbe, 0x34, 0x68, 0xd3, 0x0b, 0x60,
@@ -109,8 +235,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x6f, 0x71, 0xca, 0x1e, 0x71, 0x6d, 0xa7, 0x61,
@@ -121,8 +247,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x6a, 0xd5, 0x52, 0x1d, 0x10, 0x5a, 0x6b, 0x86,
@@ -133,8 +259,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::NorthAmerica,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x1e, 0xfb, 0x0c, 0xfc, 0x5d, 0xb8, 0xa8, 0x75,
@@ -145,8 +271,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x0c, 0x83, 0x59, 0x87, 0x0c, 0xba, 0xc0, 0xea,
@@ -157,8 +283,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 2,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x8e, 0x03, 0x83, 0x17, 0x1e, 0x67, 0xb3, 0x3e,
@@ -169,8 +295,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 2,
         region: Region::Japan,
         known_bad: true,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x71, 0xaf, 0x94, 0xd1, 0xe4, 0x7a, 0x68, 0xc1,
@@ -181,8 +307,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 2,
         region: Region::NorthAmerica,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x3d, 0x06, 0xd2, 0xc4, 0x69, 0x31, 0x3c, 0x2a,
@@ -193,8 +319,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 2,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x40, 0x18, 0x74, 0x9b, 0x36, 0x98, 0xb8, 0x69,
@@ -205,8 +331,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 2,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x9c, 0x04, 0x21, 0x85, 0x8e, 0x21, 0x78, 0x05,
@@ -217,8 +343,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x11, 0x05, 0x2b, 0x64, 0x99, 0xe4, 0x66, 0xbb,
@@ -229,8 +355,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::NorthAmerica,
         known_bad: false,
-        animation_jump_hook: Some(0x6990),
-        patch_debug_uart: Some(patch_debug_uart_na_30),
+        verified: true,
+        patches: &PATCHES_NA_30,
     },
     Metadata {
         sha256: [0x1f, 0xaa, 0xa1, 0x8f, 0xa8, 0x20, 0xa0, 0x22,
@@ -241,8 +367,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x9e, 0x1f, 0x8f, 0xb4, 0xfa, 0x35, 0x6a, 0x5a,
@@ -253,8 +379,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::Europe,
         known_bad: true,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0xe9, 0x00, 0x50, 0x4d, 0x17, 0x55, 0xf0, 0x21,
@@ -265,9 +391,9 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 0,
         region: Region::Japan,
         known_bad: false,
-        // Same patch as NA/3.0
-        animation_jump_hook: Some(0x6990),
-        patch_debug_uart: Some(patch_debug_uart_na_30),
+        verified: true,
+        // Same patches as NA/3.0
+        patches: &PATCHES_NA_30,
     },
     Metadata {
         sha256: [0xb3, 0xaa, 0x63, 0xcf, 0x30, 0xc8, 0x1e, 0x0a,
@@ -278,8 +404,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x39, 0xdc, 0xc1, 0xa0, 0x71, 0x70, 0x36, 0xc9,
@@ -290,8 +416,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::NorthAmerica,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x5e, 0x84, 0xa9, 0x48, 0x18, 0xcf, 0x52, 0x82,
@@ -302,8 +428,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 1,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0xb2, 0x9b, 0x4b, 0x5f, 0xcd, 0xde, 0xf3, 0x69,
@@ -314,8 +440,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 3,
         region: Region::Japan,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x5c, 0x01, 0x66, 0xda, 0x24, 0xe2, 0x7d, 0xea,
@@ -326,8 +452,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 4,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0xac, 0xa9, 0xcb, 0xfa, 0x97, 0x4b, 0x93, 0x36,
@@ -338,8 +464,8 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 5,
         region: Region::NorthAmerica,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
     Metadata {
         sha256: [0x42, 0x24, 0x4b, 0x0c, 0x65, 0x08, 0x21, 0x51,
@@ -350,7 +476,7 @@ pub static DATABASE: [Metadata; 24] = [
         version_minor: 5,
         region: Region::Europe,
         known_bad: false,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
+        verified: true,
+        patches: &[],
     },
-];
\ No newline at end of file
+];
@@ -0,0 +1,1621 @@
+//! High-level emulation of the BIOS kernel
+//!
+//! Instead of running the code of a real BIOS dump the CPU intercepts
+//! jumps to the A0, B0 and C0 kernel vectors as well as the exception
+//! vector and runs the corresponding function on the host. This is
+//! enough to run executables that only rely on the kernel for
+//! memory/string functions, heap management, events, threads and
+//! interrupt dispatch.
+//!
+//! Out of scope until the matching hardware is emulated:
+//!
+//! - There's no CD-ROM drive emulation yet so executables can't be
+//!   booted from a disc's SYSTEM.CNF, they have to be loaded directly
+//!   with `boot_exe`. File I/O calls fail, except writes to stdout.
+//! - Pad and memory card initialization succeed but no device is ever
+//!   connected: the pad buffers and card events are left untouched.
+
+use serde::{Deserialize, Serialize};
+
+use crate::assembler::syntax::{Instruction::{Global, Nop, B}, Label};
+use crate::assembler::Assembler;
+use crate::cpu::Cpu;
+use crate::interrupt::Interrupt;
+use crate::memory::{Byte, Word, RAM_SIZE};
+use crate::savestate::Component;
+
+use super::BIOS_SIZE;
+
+/// Kernel function tables. The function number is passed in $t1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vector {
+	A0,
+	B0,
+	C0,
+}
+
+/// Return the kernel vector targeted by a jump to `pc`, if any
+pub fn vector(pc: u32) -> Option<Vector> {
+	// The tables are called through KUSEG or KSEG0
+	match pc & 0x7fff_ffff {
+		0xa0 => Some(Vector::A0),
+		0xb0 => Some(Vector::B0),
+		0xc0 => Some(Vector::C0),
+		_ => None,
+	}
+}
+
+/// Return true if `function` of table `vector` is putchar
+pub fn is_putchar(vector: Vector, function: u32) -> bool {
+	matches!((vector, function & 0xff), (Vector::A0, 0x3c) | (Vector::B0, 0x3d))
+}
+
+/// Address of the general exception handler when SR.BEV is clear
+const EXCEPTION_VECTOR: u32 = 0x80;
+
+/// Return true if the HLE kernel handles the code at `pc`
+pub fn is_hooked(pc: u32) -> bool {
+	vector(pc).is_some() || pc & 0x7fff_ffff == EXCEPTION_VECTOR
+}
+
+/// Build the ROM image used in HLE mode. It only contains an idle
+/// loop at the reset vector, the actual program is loaded by
+/// `boot_exe`.
+pub fn rom() -> Box<[u8]> {
+	let mut asm = Assembler::from_base(0xbfc0_0000);
+
+	asm.assemble(&[
+		Global("reset"),
+		B(Label::Global("reset")),
+		Nop,
+	]).unwrap();
+
+	let (mc, _) = asm.machine_code();
+
+	let mut rom = vec![0; BIOS_SIZE];
+
+	rom[..mc.len()].copy_from_slice(&mc);
+
+	rom.into_boxed_slice()
+}
+
+/// Load the PS-X EXE `exe` in RAM and jump to its entry point
+pub fn boot_exe(cpu: &mut Cpu, exe: &[u8]) -> Result<(), String> {
+	if exe.len() < 0x800 || &exe[..8] != b"PS-X EXE" {
+		return Err("Not a PS-X EXE".into());
+	}
+
+	let header = |off: usize| {
+		let mut b = [0; 4];
+		b.copy_from_slice(&exe[off..off + 4]);
+		u32::from_le_bytes(b)
+	};
+
+	let pc = header(0x10);
+	let gp = header(0x14);
+	let text_addr = header(0x18);
+	let text_size = header(0x1c) as usize;
+	let bss_addr = header(0x28);
+	let bss_size = header(0x2c);
+	let stack_base = header(0x30);
+	let stack_size = header(0x34);
+
+	let text = match exe.get(0x800..0x800 + text_size) {
+		Some(t) => t,
+		None => return Err(format!("Truncated EXE: text is {} bytes long but \
+									file only contains {}",
+								   text_size, exe.len() - 0x800)),
+	};
+
+	if write_bytes(cpu, text_addr, text).is_none() {
+		return Err(format!("Text at 0x{:08x} doesn't fit in RAM", text_addr));
+	}
+
+	if memset(cpu, bss_addr, 0, bss_size).is_none() {
+		return Err(format!("BSS at 0x{:08x} doesn't fit in RAM", bss_addr));
+	}
+
+	let sp =
+		match stack_base {
+			0 => 0x801f_fff0,
+			b => b.wrapping_add(stack_size),
+		};
+
+	*cpu.hle_kernel_mut() = Kernel::new();
+	cpu.flush_icache();
+
+	cpu.set_reg(GP, gp);
+	cpu.set_reg(SP, sp);
+	cpu.set_reg(FP, sp);
+	// Returning from main lands in the idle loop
+	cpu.set_reg(RA, 0xbfc0_0000);
+	cpu.set_pc(pc);
+
+	Ok(())
+}
+
+/// Run the HLE code for the hooked address `cpu.pc()`
+pub fn run(cpu: &mut Cpu) {
+	let mut kernel = std::mem::take(cpu.hle_kernel_mut());
+
+	match vector(cpu.pc()) {
+		Some(v) => {
+			let ra = cpu.reg(RA);
+			cpu.set_pc(ra);
+
+			kernel.call(cpu, v, cpu.reg(T1) & 0xff);
+		}
+		None => kernel.exception(cpu),
+	}
+
+	*cpu.hle_kernel_mut() = kernel;
+}
+
+/// Return argument `n` of the current kernel call. The first four
+/// are passed in $a0-$a3, the others on the stack. Returns `None` if
+/// the stack pointer is invalid.
+pub fn arg(cpu: &Cpu, n: u32) -> Option<u32> {
+	if n < 4 {
+		Some(cpu.reg(A0 + n))
+	} else {
+		let sp = cpu.reg(SP);
+		read32(cpu, sp.wrapping_add(n * 4))
+	}
+}
+
+/// Read the NUL-terminated string at `addr`, truncated to a sane
+/// length. The string also ends at the first byte outside of RAM and
+/// ROM.
+pub fn read_string(cpu: &mut Cpu, addr: u32) -> Vec<u8> {
+	let mut s = Vec::new();
+
+	if addr == 0 {
+		return s;
+	}
+
+	for i in 0..0x1000 {
+		match cpu.interconnect().peek::<Byte>(addr.wrapping_add(i)) {
+			Some(0) | None => break,
+			Some(b) => s.push(b as u8),
+		}
+	}
+
+	s
+}
+
+/// Format `fmt` using the C printf conventions, reading the arguments
+/// through `next_arg`
+pub fn format(cpu: &mut Cpu, fmt: &[u8], next_arg: &mut dyn FnMut(&mut Cpu) -> u32) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut i = 0;
+
+	while i < fmt.len() {
+		let c = fmt[i];
+		i += 1;
+
+		if c != b'%' {
+			out.push(c);
+			continue;
+		}
+
+		let mut left = false;
+		let mut zero = false;
+		let mut sign = None;
+		let mut alt = false;
+
+		while i < fmt.len() {
+			match fmt[i] {
+				b'-' => left = true,
+				b'0' => zero = true,
+				b'+' => sign = Some(b'+'),
+				b' ' => sign = sign.or(Some(b' ')),
+				b'#' => alt = true,
+				_ => break,
+			}
+			i += 1;
+		}
+
+		let mut width = 0;
+		if fmt.get(i) == Some(&b'*') {
+			width = next_arg(cpu) as usize;
+			i += 1;
+		}
+		while let Some(d @ b'0'..=b'9') = fmt.get(i) {
+			width = width * 10 + (d - b'0') as usize;
+			i += 1;
+		}
+
+		let mut precision = None;
+		if fmt.get(i) == Some(&b'.') {
+			i += 1;
+			let mut p = 0;
+			if fmt.get(i) == Some(&b'*') {
+				p = next_arg(cpu) as usize;
+				i += 1;
+			}
+			while let Some(d @ b'0'..=b'9') = fmt.get(i) {
+				p = p * 10 + (d - b'0') as usize;
+				i += 1;
+			}
+			precision = Some(p);
+		}
+
+		// Length modifiers are meaningless on a 32bit machine
+		while let Some(b'l' | b'h') = fmt.get(i) {
+			i += 1;
+		}
+
+		let conv = match fmt.get(i) {
+			Some(&c) => c,
+			None => break,
+		};
+		i += 1;
+
+		let (prefix, body): (&[u8], Vec<u8>) =
+			match conv {
+				b'%' => {
+					out.push(b'%');
+					continue;
+				}
+				b'd' | b'i' => {
+					let v = next_arg(cpu) as i32;
+					let digits = v.unsigned_abs().to_string().into_bytes();
+					let prefix: &[u8] =
+						match (v < 0, sign) {
+							(true, _) => b"-",
+							(false, Some(b'+')) => b"+",
+							(false, Some(_)) => b" ",
+							(false, None) => b"",
+						};
+					(prefix, pad_precision(digits, precision))
+				}
+				b'u' => {
+					let v = next_arg(cpu);
+					(b"", pad_precision(v.to_string().into_bytes(), precision))
+				}
+				b'x' | b'X' | b'p' => {
+					let v = next_arg(cpu);
+					let digits =
+						match conv {
+							b'X' => format!("{:X}", v),
+							_ => format!("{:x}", v),
+						};
+					let prefix: &[u8] =
+						match (alt || conv == b'p', conv) {
+							(true, b'X') => b"0X",
+							(true, _) => b"0x",
+							_ => b"",
+						};
+					(prefix, pad_precision(digits.into_bytes(), precision))
+				}
+				b'o' => {
+					let v = next_arg(cpu);
+					let prefix: &[u8] = if alt { b"0" } else { b"" };
+					(prefix, pad_precision(format!("{:o}", v).into_bytes(), precision))
+				}
+				b'c' => (b"", vec![next_arg(cpu) as u8]),
+				b's' => {
+					let addr = next_arg(cpu);
+					let mut s =
+						match addr {
+							0 => b"(null)".to_vec(),
+							_ => read_string(cpu, addr),
+						};
+					if let Some(p) = precision {
+						s.truncate(p);
+					}
+					(b"", s)
+				}
+				c => {
+					// Unknown conversion, output it verbatim
+					out.push(b'%');
+					out.push(c);
+					continue;
+				}
+			};
+
+		let len = prefix.len() + body.len();
+		let padding = width.saturating_sub(len);
+		let numeric = !matches!(conv, b'c' | b's');
+
+		if left {
+			out.extend_from_slice(prefix);
+			out.extend_from_slice(&body);
+			out.resize(out.len() + padding, b' ');
+		} else if zero && numeric && precision.is_none() {
+			out.extend_from_slice(prefix);
+			out.resize(out.len() + padding, b'0');
+			out.extend_from_slice(&body);
+		} else {
+			out.resize(out.len() + padding, b' ');
+			out.extend_from_slice(prefix);
+			out.extend_from_slice(&body);
+		}
+	}
+
+	out
+}
+
+fn pad_precision(digits: Vec<u8>, precision: Option<usize>) -> Vec<u8> {
+	match precision {
+		Some(p) if p > digits.len() => {
+			let mut v = vec![b'0'; p - digits.len()];
+			v.extend_from_slice(&digits);
+			v
+		}
+		_ => digits,
+	}
+}
+
+/// State of the HLE kernel
+#[derive(Serialize, Deserialize)]
+pub struct Kernel {
+	/// Heap set by InitHeap: (start, end)
+	heap: Option<(u32, u32)>,
+	/// Allocated heap blocks (address, size), sorted by address
+	allocations: Vec<(u32, u32)>,
+	events: Vec<Event>,
+	threads: Vec<Option<Thread>>,
+	/// Index of the running thread
+	current_thread: usize,
+	/// Seed of the rand() generator
+	rand_seed: u32,
+	/// Jump buffer set by HookEntryInt
+	entry_int_hook: u32,
+}
+
+impl Kernel {
+	pub fn new() -> Kernel {
+		Kernel {
+			heap: None,
+			allocations: Vec::new(),
+			events: Vec::new(),
+			// Thread 0 is the one running the executable
+			threads: vec![Some(Thread::default())],
+			current_thread: 0,
+			rand_seed: 0x24040001,
+			entry_int_hook: 0,
+		}
+	}
+
+	fn call(&mut self, cpu: &mut Cpu, vector: Vector, function: u32) {
+		let ret =
+			match vector {
+				Vector::A0 => self.call_a0(cpu, function),
+				Vector::B0 => self.call_b0(cpu, function),
+				Vector::C0 => self.call_c0(cpu, function),
+			};
+
+		match ret {
+			Some(v) => cpu.set_reg(V0, v),
+			None => warn!("Unimplemented HLE kernel call {:?}({:02x})",
+						  vector, function),
+		}
+	}
+
+	/// Run A0 function `function`. Returns the value of $v0 or `None`
+	/// if the function isn't implemented.
+	fn call_a0(&mut self, cpu: &mut Cpu, function: u32) -> Option<u32> {
+		let a0 = cpu.reg(A0);
+		let a1 = cpu.reg(A1);
+		let a2 = cpu.reg(A2);
+
+		let v =
+			match function {
+				0x00..=0x08 => self.file_io(cpu, a0, a1, a2, function),
+				0x09 => self.file_putc(cpu, a0, a1),
+				0x0e | 0x0f => (a0 as i32).unsigned_abs(),
+				0x10 | 0x11 => atoi(&read_string(cpu, a0)) as u32,
+				0x13 => setjmp(cpu, a0).map_or(FAULT, |_| 0),
+				// Returns to the setjmp call with $v0 = a1
+				0x14 => longjmp(cpu, a0).map_or(FAULT, |_| a1),
+				0x15 => {
+					let len = strlen(cpu, a0);
+					strcpy(cpu, a0.wrapping_add(len), a1, None).map_or(0, |_| a0)
+				}
+				0x16 => strncat(cpu, a0, a1, a2).map_or(0, |_| a0),
+				0x17 => strcmp(cpu, a0, a1, u32::MAX).unwrap_or(FAULT),
+				0x18 => strcmp(cpu, a0, a1, a2).unwrap_or(FAULT),
+				0x19 => strcpy(cpu, a0, a1, None).map_or(0, |_| a0),
+				0x1a => strcpy(cpu, a0, a1, Some(a2)).map_or(0, |_| a0),
+				0x1b => strlen(cpu, a0),
+				0x1c | 0x1e => strchr(cpu, a0, a1 as u8, false),
+				0x1d | 0x1f => strchr(cpu, a0, a1 as u8, true),
+				0x25 => (a0 as u8).to_ascii_uppercase() as u32,
+				0x26 => (a0 as u8).to_ascii_lowercase() as u32,
+				0x27 => memmove(cpu, a1, a0, a2).map_or(0, |_| a1),
+				0x28 => memset(cpu, a0, 0, a1).map_or(0, |_| a0),
+				0x29 | 0x2d => memcmp(cpu, a0, a1, a2).unwrap_or(FAULT),
+				0x2a | 0x2c => memmove(cpu, a0, a1, a2).map_or(0, |_| a0),
+				0x2b => memset(cpu, a0, a1 as u8, a2).map_or(0, |_| a0),
+				0x2e => memchr(cpu, a0, a1 as u8, a2).unwrap_or(0),
+				0x2f => self.rand(),
+				0x30 => {
+					self.rand_seed = a0;
+					0
+				}
+				0x33 => self.malloc(a0),
+				0x34 => {
+					self.free(a0);
+					0
+				}
+				0x37 => self.calloc(cpu, a0, a1),
+				0x38 => self.realloc(cpu, a0, a1),
+				0x39 => {
+					self.heap = Some((a0, a0.wrapping_add(a1)));
+					self.allocations.clear();
+					0
+				}
+				0x3c => {
+					putchar(cpu, a0 as u8);
+					a0
+				}
+				0x3e => {
+					self.puts(cpu, a0);
+					1
+				}
+				0x3f => self.printf(cpu),
+				0x44 => {
+					cpu.flush_icache();
+					0
+				}
+				// CD-ROM, memory card and TTY device setup
+				0x72 | 0x96 | 0x97 | 0x99 => 0,
+				_ => return None,
+			};
+
+		Some(v)
+	}
+
+	fn call_b0(&mut self, cpu: &mut Cpu, function: u32) -> Option<u32> {
+		let a0 = cpu.reg(A0);
+		let a1 = cpu.reg(A1);
+		let a2 = cpu.reg(A2);
+		let a3 = cpu.reg(A3);
+
+		let v =
+			match function {
+				// alloc_kernel_memory/free_kernel_memory
+				0x00 => self.malloc(a0),
+				0x01 => {
+					self.free(a0);
+					0
+				}
+				// Root counter setup, we don't manage the hardware
+				0x02..=0x06 => 1,
+				0x07 => {
+					self.deliver_event(a0, a1);
+					1
+				}
+				0x08 => self.open_event(a0, a1, a2, a3),
+				0x09 => self.set_event_status(a0, EVENT_FREE),
+				// We can't block, WaitEvent behaves like TestEvent
+				0x0a | 0x0b => self.test_event(a0),
+				0x0c => self.set_event_status(a0, EVENT_ENABLED),
+				0x0d => self.set_event_status(a0, EVENT_DISABLED),
+				0x0e => self.open_thread(a0, a1, a2),
+				0x0f => self.close_thread(a0),
+				0x10 => return Some(self.change_thread(cpu, a0)),
+				// InitPad, StartPad, StopPad
+				0x12 => 2,
+				0x13 | 0x14 => 1,
+				0x17 => {
+					return_from_exception(cpu, false);
+					return Some(cpu.reg(V0));
+				}
+				0x18 => {
+					self.entry_int_hook = 0;
+					0
+				}
+				0x19 => {
+					self.entry_int_hook = a0;
+					0
+				}
+				0x20 => {
+					self.undeliver_event(a0, a1);
+					0
+				}
+				0x32..=0x3a => self.file_io(cpu, a0, a1, a2, function - 0x32),
+				0x3b => self.file_putc(cpu, a0, a1),
+				0x3d => {
+					putchar(cpu, a0 as u8);
+					a0
+				}
+				0x3f => {
+					self.puts(cpu, a0);
+					1
+				}
+				// InitCard, StartCard, StopCard
+				0x4a..=0x4c => 1,
+				// Tables normally located in kernel RAM. Games patching
+				// them won't see any effect.
+				0x56 => 0x674,
+				0x57 => 0x874,
+				// ChangeClearPad
+				0x5b => 0,
+				_ => return None,
+			};
+
+		Some(v)
+	}
+
+	fn call_c0(&mut self, _cpu: &mut Cpu, function: u32) -> Option<u32> {
+		let v =
+			match function {
+				// Kernel initialization, interrupt queue management and
+				// device installation are handled internally
+				0x00..=0x03 | 0x07 | 0x08 | 0x0a | 0x12 | 0x1c => 0,
+				_ => return None,
+			};
+
+		Some(v)
+	}
+
+	/// Kernel exception handler
+	fn exception(&mut self, cpu: &mut Cpu) {
+		let irq_state = cpu.interconnect().irq_state();
+		let cause = cpu.cop0().cause(irq_state);
+		let code = (cause >> 2) & 0x1f;
+
+		match code {
+			// Interrupt
+			0 => {
+				self.dispatch_interrupts(cpu);
+
+				// If the interrupted instruction is a GTE command it
+				// has already been executed
+				let epc = cpu.cop0().epc();
+				let gte_op = read32(cpu, epc).is_some_and(|i| i >> 25 == 0b0100101);
+
+				return_from_exception(cpu, gte_op);
+			}
+			// Syscall
+			8 => {
+				let sr = cpu.cop0().sr();
+
+				match cpu.reg(A0) {
+					// EnterCriticalSection
+					1 => {
+						cpu.set_reg(V0, (sr & 0x404 == 0x404) as u32);
+						cpu.cop0_mut().set_sr(sr & !0x404);
+					}
+					// ExitCriticalSection
+					2 => cpu.cop0_mut().set_sr(sr | 0x404),
+					n => warn!("Unhandled syscall {}", n),
+				}
+
+				return_from_exception(cpu, true);
+			}
+			_ => {
+				warn!("Unhandled exception {} at 0x{:08x}", code, cpu.cop0().epc());
+				return_from_exception(cpu, true);
+			}
+		}
+	}
+
+	fn dispatch_interrupts(&mut self, cpu: &mut Cpu) {
+		let irq_state = cpu.interconnect().irq_state();
+		let pending = irq_state.status() & irq_state.mask();
+
+		let sources = [
+			(Interrupt::VBlank, 0xf200_0003),
+			(Interrupt::Timer0, 0xf200_0000),
+			(Interrupt::Timer1, 0xf200_0001),
+			(Interrupt::Timer2, 0xf200_0002),
+			(Interrupt::CdRom, 0xf000_0003),
+		];
+
+		for &(irq, class) in &sources {
+			if pending & (1 << irq as u16) != 0 {
+				self.deliver_event(class, 0x0002);
+			}
+		}
+
+		cpu.interconnect_mut().irq_state_mut().ack(!pending);
+	}
+
+	fn rand(&mut self) -> u32 {
+		self.rand_seed = self.rand_seed.wrapping_mul(1103515245).wrapping_add(12345);
+
+		(self.rand_seed >> 16) & 0x7fff
+	}
+
+	fn malloc(&mut self, size: u32) -> u32 {
+		let (start, end) =
+			match self.heap {
+				Some(h) => h,
+				None => {
+					warn!("malloc called before InitHeap");
+					return 0;
+				}
+			};
+
+		let size =
+			match size.max(1).checked_add(3) {
+				Some(s) => s & !3,
+				None => {
+					warn!("malloc({}): out of memory", size);
+					return 0;
+				}
+			};
+
+		// First fit
+		let mut addr = start;
+		let mut index = 0;
+
+		for &(a, s) in &self.allocations {
+			if a.wrapping_sub(addr) >= size {
+				break;
+			}
+
+			addr =
+				match a.checked_add(s) {
+					Some(a) => a,
+					None => {
+						warn!("malloc({}): out of memory", size);
+						return 0;
+					}
+				};
+			index += 1;
+		}
+
+		if addr > end || end - addr < size {
+			warn!("malloc({}): out of memory", size);
+			return 0;
+		}
+
+		self.allocations.insert(index, (addr, size));
+
+		addr
+	}
+
+	/// Allocate `n * size` bytes set to 0
+	fn calloc(&mut self, cpu: &mut Cpu, n: u32, size: u32) -> u32 {
+		let size =
+			match n.checked_mul(size) {
+				Some(s) => s,
+				None => {
+					warn!("calloc({}, {}): size overflow", n, size);
+					return 0;
+				}
+			};
+
+		let p = self.malloc(size);
+
+		if p != 0 && memset(cpu, p, 0, size).is_none() {
+			self.free(p);
+			return 0;
+		}
+
+		p
+	}
+
+	fn free(&mut self, addr: u32) {
+		self.allocations.retain(|&(a, _)| a != addr);
+	}
+
+	fn realloc(&mut self, cpu: &mut Cpu, addr: u32, size: u32) -> u32 {
+		if addr == 0 {
+			return self.malloc(size);
+		}
+
+		if size == 0 {
+			self.free(addr);
+			return 0;
+		}
+
+		let old_size =
+			match self.allocations.iter().find(|&&(a, _)| a == addr) {
+				Some(&(_, s)) => s,
+				None => return 0,
+			};
+
+		let new = self.malloc(size);
+
+		if new == 0 {
+			return 0;
+		}
+
+		if memmove(cpu, new, addr, old_size.min(size)).is_none() {
+			self.free(new);
+			return 0;
+		}
+
+		self.free(addr);
+
+		new
+	}
+
+	fn puts(&mut self, cpu: &mut Cpu, addr: u32) {
+		for c in read_string(cpu, addr) {
+			putchar(cpu, c);
+		}
+		putchar(cpu, b'\n');
+	}
+
+	fn printf(&mut self, cpu: &mut Cpu) -> u32 {
+		let fmt_addr = cpu.reg(A0);
+		let fmt = read_string(cpu, fmt_addr);
+
+		let mut n = 1;
+		let mut fault = false;
+		let out = format(cpu, &fmt, &mut |cpu| {
+			let v = arg(cpu, n);
+			n += 1;
+			v.unwrap_or_else(|| {
+				fault = true;
+				0
+			})
+		});
+
+		if fault {
+			return FAULT;
+		}
+
+		for &c in &out {
+			putchar(cpu, c);
+		}
+
+		out.len() as u32
+	}
+
+	/// open/lseek/read/write/close/ioctl/getc. Only writes to stdout
+	/// are supported.
+	fn file_io(&mut self, cpu: &mut Cpu, a0: u32, a1: u32, a2: u32, function: u32) -> u32 {
+		match function {
+			// write(fd, buf, len)
+			3 if a0 == 1 => {
+				let data = check_len(a2).and_then(|len| {
+					(0..len).map(|i| read8(cpu, a1.wrapping_add(i))).collect::<Option<Vec<u8>>>()
+				});
+
+				match data {
+					Some(data) => {
+						for c in data {
+							putchar(cpu, c);
+						}
+						a2
+					}
+					None => FAULT,
+				}
+			}
+			_ => {
+				warn!("Unsupported file I/O call {} ({:x}, {:x}, {:x})",
+					  function, a0, a1, a2);
+				!0
+			}
+		}
+	}
+
+	/// putc(char, fd)
+	fn file_putc(&mut self, cpu: &mut Cpu, c: u32, fd: u32) -> u32 {
+		if fd == 1 {
+			putchar(cpu, c as u8);
+			c
+		} else {
+			!0
+		}
+	}
+
+	fn open_event(&mut self, class: u32, spec: u32, mode: u32, func: u32) -> u32 {
+		let event = Event {
+			class,
+			spec,
+			mode,
+			func,
+			status: EVENT_DISABLED,
+		};
+
+		let index =
+			match self.events.iter().position(|e| e.status == EVENT_FREE) {
+				Some(i) => {
+					self.events[i] = event;
+					i
+				}
+				None => {
+					self.events.push(event);
+					self.events.len() - 1
+				}
+			};
+
+		EVENT_HANDLE | index as u32
+	}
+
+	fn event(&mut self, handle: u32) -> Option<&mut Event> {
+		if handle & 0xffff_0000 != EVENT_HANDLE {
+			return None;
+		}
+
+		self.events.get_mut((handle & 0xffff) as usize)
+	}
+
+	fn set_event_status(&mut self, handle: u32, status: u32) -> u32 {
+		match self.event(handle) {
+			Some(e) => {
+				e.status = status;
+				1
+			}
+			None => 0,
+		}
+	}
+
+	fn test_event(&mut self, handle: u32) -> u32 {
+		match self.event(handle) {
+			Some(e) if e.status == EVENT_READY => {
+				e.status = EVENT_ENABLED;
+				1
+			}
+			_ => 0,
+		}
+	}
+
+	fn deliver_event(&mut self, class: u32, spec: u32) {
+		for e in &mut self.events {
+			if e.status != EVENT_ENABLED || e.class != class || e.spec != spec {
+				continue;
+			}
+
+			match e.mode {
+				EVENT_MODE_READY => e.status = EVENT_READY,
+				_ => warn!("Event callback 0x{:08x} not supported in HLE mode",
+						   e.func),
+			}
+		}
+	}
+
+	fn undeliver_event(&mut self, class: u32, spec: u32) {
+		for e in &mut self.events {
+			if e.status == EVENT_READY && e.class == class && e.spec == spec {
+				e.status = EVENT_ENABLED;
+			}
+		}
+	}
+
+	fn open_thread(&mut self, pc: u32, sp: u32, gp: u32) -> u32 {
+		let mut thread = Thread {
+			pc,
+			..Thread::default()
+		};
+
+		thread.regs[SP as usize] = sp;
+		thread.regs[FP as usize] = sp;
+		thread.regs[GP as usize] = gp;
+
+		let index =
+			match self.threads.iter().position(|t| t.is_none()) {
+				Some(i) => {
+					self.threads[i] = Some(thread);
+					i
+				}
+				None => {
+					self.threads.push(Some(thread));
+					self.threads.len() - 1
+				}
+			};
+
+		THREAD_HANDLE | index as u32
+	}
+
+	fn close_thread(&mut self, handle: u32) -> u32 {
+		let index = (handle & 0xffff) as usize;
+
+		if handle & 0xffff_0000 != THREAD_HANDLE || index == self.current_thread {
+			return 0;
+		}
+
+		match self.threads.get_mut(index) {
+			Some(t) => {
+				*t = None;
+				1
+			}
+			None => 0,
+		}
+	}
+
+	/// Save the context of the running thread and switch to the one
+	/// designated by `handle`
+	fn change_thread(&mut self, cpu: &mut Cpu, handle: u32) -> u32 {
+		let index = (handle & 0xffff) as usize;
+
+		let target =
+			match self.threads.get(index) {
+				Some(Some(t)) if handle & 0xffff_0000 == THREAD_HANDLE => t.clone(),
+				_ => return 0,
+			};
+
+		// The current thread resumes as if ChangeThread returned 1
+		let mut current = Thread::default();
+		for (i, r) in current.regs.iter_mut().enumerate() {
+			*r = cpu.reg(i as u32);
+		}
+		current.regs[V0 as usize] = 1;
+		current.pc = cpu.pc();
+		current.hi = cpu.hi();
+		current.lo = cpu.lo();
+
+		self.threads[self.current_thread] = Some(current);
+		self.current_thread = index;
+
+		for (i, &r) in target.regs.iter().enumerate() {
+			cpu.set_reg(i as u32, r);
+		}
+		cpu.set_hi(target.hi);
+		cpu.set_lo(target.lo);
+		cpu.set_pc(target.pc);
+
+		target.regs[V0 as usize]
+	}
+}
+
+impl Default for Kernel {
+	fn default() -> Kernel {
+		Kernel::new()
+	}
+}
+
+impl Component for Kernel {
+	const SECTION: &'static str = "hle";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
+
+/// Returned by the calls given an invalid pointer or length, except
+/// for the ones returning a pointer which return NULL
+const FAULT: u32 = !0;
+
+const EVENT_HANDLE: u32 = 0xf100_0000;
+const THREAD_HANDLE: u32 = 0xff00_0000;
+
+const EVENT_FREE: u32 = 0x0000;
+const EVENT_DISABLED: u32 = 0x1000;
+const EVENT_ENABLED: u32 = 0x2000;
+const EVENT_READY: u32 = 0x4000;
+
+/// Event mode: mark the event ready instead of calling `func`
+const EVENT_MODE_READY: u32 = 0x2000;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Event {
+	class: u32,
+	spec: u32,
+	mode: u32,
+	func: u32,
+	status: u32,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Thread {
+	regs: [u32; 32],
+	pc: u32,
+	hi: u32,
+	lo: u32,
+}
+
+const V0: u32 = 2;
+const A0: u32 = 4;
+const A1: u32 = 5;
+const A2: u32 = 6;
+const A3: u32 = 7;
+const T1: u32 = 9;
+const S0: u32 = 16;
+const GP: u32 = 28;
+const SP: u32 = 29;
+const FP: u32 = 30;
+const RA: u32 = 31;
+
+/// Leave the exception handler, resuming at EPC (or the following
+/// instruction if `skip` is true)
+fn return_from_exception(cpu: &mut Cpu, skip: bool) {
+	let epc = cpu.cop0().epc();
+
+	cpu.cop0_mut().return_from_exception();
+
+	cpu.set_pc(if skip { epc.wrapping_add(4) } else { epc });
+}
+
+fn putchar(cpu: &mut Cpu, c: u8) {
+	cpu.interconnect_mut().tty_mut().putchar(c)
+}
+
+/// Guest memory accessors. They fail on addresses outside of RAM and
+/// ROM (RAM only for stores) and on misaligned words, the calls then
+/// return an error to the guest instead of touching the hardware.
+fn read8(cpu: &Cpu, addr: u32) -> Option<u8> {
+	let v = cpu.interconnect().peek::<Byte>(addr);
+
+	if v.is_none() {
+		warn!("HLE kernel read from invalid address 0x{:08x}", addr);
+	}
+
+	v.map(|b| b as u8)
+}
+
+fn write8(cpu: &mut Cpu, addr: u32, v: u8) -> Option<()> {
+	let r = cpu.interconnect_mut().poke::<Byte>(addr, v as u32);
+
+	if r.is_none() {
+		warn!("HLE kernel write to invalid address 0x{:08x}", addr);
+	}
+
+	r
+}
+
+fn read32(cpu: &Cpu, addr: u32) -> Option<u32> {
+	let v =
+		match addr & 3 {
+			0 => cpu.interconnect().peek::<Word>(addr),
+			_ => None,
+		};
+
+	if v.is_none() {
+		warn!("HLE kernel read from invalid address 0x{:08x}", addr);
+	}
+
+	v
+}
+
+fn write32(cpu: &mut Cpu, addr: u32, v: u32) -> Option<()> {
+	let r =
+		match addr & 3 {
+			0 => cpu.interconnect_mut().poke::<Word>(addr, v),
+			_ => None,
+		};
+
+	if r.is_none() {
+		warn!("HLE kernel write to invalid address 0x{:08x}", addr);
+	}
+
+	r
+}
+
+/// Reject buffer lengths larger than RAM, they can't be valid
+fn check_len(len: u32) -> Option<u32> {
+	if len as usize > RAM_SIZE {
+		warn!("HLE kernel call with invalid length 0x{:08x}", len);
+		return None;
+	}
+
+	Some(len)
+}
+
+fn write_bytes(cpu: &mut Cpu, addr: u32, bytes: &[u8]) -> Option<()> {
+	for (i, &b) in bytes.iter().enumerate() {
+		write8(cpu, addr.wrapping_add(i as u32), b)?;
+	}
+
+	Some(())
+}
+
+fn strlen(cpu: &mut Cpu, addr: u32) -> u32 {
+	read_string(cpu, addr).len() as u32
+}
+
+fn strcpy(cpu: &mut Cpu, dst: u32, src: u32, max: Option<u32>) -> Option<()> {
+	let s = read_string(cpu, src);
+
+	match max {
+		Some(n) => {
+			// strncpy pads with zeroes
+			for i in 0..check_len(n)? {
+				let b = s.get(i as usize).cloned().unwrap_or(0);
+				write8(cpu, dst.wrapping_add(i), b)?;
+			}
+		}
+		None => {
+			write_bytes(cpu, dst, &s)?;
+			write8(cpu, dst.wrapping_add(s.len() as u32), 0)?;
+		}
+	}
+
+	Some(())
+}
+
+/// Append at most `max` characters of `src` to `dst`
+fn strncat(cpu: &mut Cpu, dst: u32, src: u32, max: u32) -> Option<()> {
+	let len = strlen(cpu, dst);
+	let src = read_string(cpu, src);
+	let n = src.len().min(max as usize) as u32;
+
+	write_bytes(cpu, dst.wrapping_add(len), &src[..n as usize])?;
+	write8(cpu, dst.wrapping_add(len + n), 0)
+}
+
+fn strcmp(cpu: &mut Cpu, a: u32, b: u32, max: u32) -> Option<u32> {
+	// Strings can't be longer than RAM, strncmp is often called with
+	// a huge limit
+	for i in 0..max.min(RAM_SIZE as u32) {
+		let ca = read8(cpu, a.wrapping_add(i))?;
+		let cb = read8(cpu, b.wrapping_add(i))?;
+
+		if ca != cb {
+			return Some((ca as i32 - cb as i32) as u32);
+		}
+
+		if ca == 0 {
+			break;
+		}
+	}
+
+	Some(0)
+}
+
+fn strchr(cpu: &mut Cpu, addr: u32, c: u8, last: bool) -> u32 {
+	let s = read_string(cpu, addr);
+
+	let pos =
+		match (c, last) {
+			// The terminating NUL is part of the string
+			(0, _) => Some(s.len()),
+			(c, false) => s.iter().position(|&b| b == c),
+			(c, true) => s.iter().rposition(|&b| b == c),
+		};
+
+	match pos {
+		Some(p) => addr.wrapping_add(p as u32),
+		None => 0,
+	}
+}
+
+fn memset(cpu: &mut Cpu, addr: u32, v: u8, len: u32) -> Option<()> {
+	for i in 0..check_len(len)? {
+		write8(cpu, addr.wrapping_add(i), v)?;
+	}
+
+	Some(())
+}
+
+fn memmove(cpu: &mut Cpu, dst: u32, src: u32, len: u32) -> Option<()> {
+	let data = (0..check_len(len)?)
+		.map(|i| read8(cpu, src.wrapping_add(i)))
+		.collect::<Option<Vec<u8>>>()?;
+
+	write_bytes(cpu, dst, &data)
+}
+
+fn memcmp(cpu: &mut Cpu, a: u32, b: u32, len: u32) -> Option<u32> {
+	for i in 0..check_len(len)? {
+		let ca = read8(cpu, a.wrapping_add(i))?;
+		let cb = read8(cpu, b.wrapping_add(i))?;
+
+		if ca != cb {
+			return Some((ca as i32 - cb as i32) as u32);
+		}
+	}
+
+	Some(0)
+}
+
+fn memchr(cpu: &mut Cpu, addr: u32, c: u8, len: u32) -> Option<u32> {
+	for i in 0..check_len(len)? {
+		if read8(cpu, addr.wrapping_add(i))? == c {
+			return Some(addr.wrapping_add(i));
+		}
+	}
+
+	Some(0)
+}
+
+fn atoi(s: &[u8]) -> i32 {
+	let s: Vec<u8> = s.iter().cloned().skip_while(|c| c.is_ascii_whitespace()).collect();
+
+	let (neg, digits) =
+		match s.first() {
+			Some(b'-') => (true, &s[1..]),
+			Some(b'+') => (false, &s[1..]),
+			_ => (false, &s[..]),
+		};
+
+	let v = digits.iter()
+		.take_while(|c| c.is_ascii_digit())
+		.fold(0i32, |v, &c| v.wrapping_mul(10).wrapping_add((c - b'0') as i32));
+
+	if neg { v.wrapping_neg() } else { v }
+}
+
+/// Registers saved in a jmp_buf: $ra, $sp, $fp, $s0-$s7 and $gp
+fn jmp_buf_regs() -> impl Iterator<Item = u32> {
+	[RA, SP, FP].into_iter().chain(S0..S0 + 8).chain([GP])
+}
+
+fn setjmp(cpu: &mut Cpu, buf: u32) -> Option<()> {
+	for (i, r) in jmp_buf_regs().enumerate() {
+		let v = cpu.reg(r);
+		write32(cpu, buf.wrapping_add(i as u32 * 4), v)?;
+	}
+
+	Some(())
+}
+
+/// Restore the registers saved in `buf` and return to the matching
+/// setjmp. The registers are left untouched if `buf` is invalid.
+fn longjmp(cpu: &mut Cpu, buf: u32) -> Option<()> {
+	let saved = jmp_buf_regs()
+		.enumerate()
+		.map(|(i, r)| read32(cpu, buf.wrapping_add(i as u32 * 4)).map(|v| (r, v)))
+		.collect::<Option<Vec<_>>>()?;
+
+	for (r, v) in saved {
+		cpu.set_reg(r, v);
+	}
+
+	let ra = cpu.reg(RA);
+	cpu.set_pc(ra);
+
+	Some(())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::assembler::output::ExeHeader;
+	use crate::assembler::syntax::*;
+	// Shadow the register numbers of the kernel
+	use crate::assembler::syntax::{A0, A1, A2, A3, GP, RA, S0, SP, T1, V0};
+	use crate::bios::Bios;
+	use crate::cdrom::disk::Region;
+	use crate::cpu::test_rom::{TestRom, BASE};
+	use crate::memory::Interconnect;
+
+	use super::*;
+
+	fn kernel_with_heap(start: u32, size: u32) -> Kernel {
+		let mut kernel = Kernel::new();
+
+		kernel.heap = Some((start, start + size));
+
+		kernel
+	}
+
+	#[test]
+	fn malloc_free() {
+		let mut kernel = Kernel::new();
+
+		// No heap yet
+		assert_eq!(kernel.malloc(4), 0);
+
+		let mut kernel = kernel_with_heap(0x1000, 0x40);
+
+		let a = kernel.malloc(5);
+		let b = kernel.malloc(0);
+		let c = kernel.malloc(0x20);
+
+		// Sizes are rounded up to a multiple of 4, 0 bytes still
+		// takes a slot
+		assert_eq!((a, b, c), (0x1000, 0x1008, 0x100c));
+
+		// Doesn't fit in the 0x14 bytes left
+		assert_eq!(kernel.malloc(0x18), 0);
+
+		// The first hole large enough is reused
+		kernel.free(a);
+		assert_eq!(kernel.malloc(0xc), 0x102c);
+		assert_eq!(kernel.malloc(8), 0x1000);
+
+		// Freeing an unknown address does nothing
+		kernel.free(0x1004);
+		assert_eq!(kernel.allocations.len(), 4);
+	}
+
+	#[test]
+	fn malloc_overflow() {
+		let mut kernel = kernel_with_heap(0x1000, 0x40);
+
+		assert_eq!(kernel.malloc(u32::MAX), 0);
+		assert_eq!(kernel.malloc(u32::MAX - 3), 0);
+
+		// Heap at the very top of the address space
+		let mut kernel = Kernel::new();
+
+		kernel.heap = Some((0xffff_fff0, u32::MAX));
+		kernel.allocations.push((0xffff_fff8, 8));
+
+		assert_eq!(kernel.malloc(16), 0);
+		assert_eq!(kernel.malloc(8), 0xffff_fff0);
+		assert_eq!(kernel.malloc(4), 0);
+	}
+
+	#[test]
+	fn calloc() {
+		let mut cpu = Cpu::new(Interconnect::new(Bios::hle()));
+
+		for addr in 0..0x20 {
+			write8(&mut cpu, addr, 0xaa).unwrap();
+			write8(&mut cpu, 0x1000 + addr, 0xaa).unwrap();
+		}
+
+		// No heap: nothing gets cleared at address 0
+		let mut kernel = Kernel::new();
+
+		assert_eq!(kernel.calloc(&mut cpu, 4, 4), 0);
+		assert_eq!(read8(&cpu, 0), Some(0xaa));
+
+		let mut kernel = kernel_with_heap(0x1000, 0x40);
+
+		// n * size doesn't fit in 32 bits
+		assert_eq!(kernel.calloc(&mut cpu, 0x1_0000, 0x1_0000), 0);
+		assert!(kernel.allocations.is_empty());
+
+		assert_eq!(kernel.calloc(&mut cpu, 3, 4), 0x1000);
+
+		for addr in 0x1000..0x100c {
+			assert_eq!(read8(&cpu, addr), Some(0));
+		}
+
+		assert_eq!(read8(&cpu, 0x100c), Some(0xaa));
+	}
+
+	/// Call function `function` of the kernel table at `vector`, the
+	/// arguments must already be in $a0-$a3
+	fn call(vector: u32, function: u32) -> [Instruction<'static>; 4] {
+		[Li(T2, vector), Li(T1, function), Jalr(RA, T2), Nop]
+	}
+
+	fn take_output(rom: &mut TestRom) -> Vec<u8> {
+		rom.cpu_mut().interconnect_mut().tty_mut().take_output()
+	}
+
+	#[test]
+	fn dispatch() {
+		let mut code = vec![La(A0, Label::Global("hello"))];
+		// strlen
+		code.extend(call(0xa0, 0x1b));
+		code.extend([Move(S0, V0), Li(A0, b'!' as u32)]);
+		// putchar
+		code.extend(call(0xb0, 0x3d));
+		code.extend([Move(S1, V0), Li(V0, 0x55)]);
+		// Unimplemented, $v0 is left untouched
+		code.extend(call(0xc0, 0x7f));
+		code.push(Move(S2, V0));
+		// EnqueueTimerAndVblankIrqs
+		code.extend(call(0xc0, 0x00));
+		code.extend([Break(0), Global("hello"), Ascii(b"hello\0")]);
+
+		let mut rom = TestRom::run_hle(&code);
+
+		assert_eq!(rom.reg(S0), 5);
+		assert_eq!(rom.reg(S1), b'!' as u32);
+		assert_eq!(rom.reg(S2), 0x55);
+		assert_eq!(rom.reg(V0), 0);
+		assert_eq!(rom.traps().len(), 1);
+		assert_eq!(take_output(&mut rom), b"!");
+	}
+
+	#[test]
+	fn printf() {
+		let mut code = vec![
+			Li(SP, 0x801f_ff00),
+			Li(T0, 0xcafe),
+			Sw(T0, SP, 16),
+			Li(T0, b'z' as u32),
+			Sw(T0, SP, 20),
+			Li(T0, 7),
+			Sw(T0, SP, 24),
+			Li(T0, 5),
+			Sw(T0, SP, 28),
+			La(A0, Label::Global("fmt")),
+			Li(A1, -42i32 as u32),
+			La(A2, Label::Global("str")),
+			Li(A3, 0xbeef),
+		];
+		code.extend(call(0xa0, 0x3f));
+		code.extend([
+			Break(0),
+			Global("fmt"),
+			Ascii(b"%d|%5s|%-6x|%08X|%c|%+i|%%|%.3u|%q\n\0"),
+			Global("str"),
+			Ascii(b"ab\0"),
+		]);
+
+		let mut rom = TestRom::run_hle(&code);
+
+		let expected = b"-42|   ab|beef  |0000CAFE|z|+7|%|005|%q\n";
+
+		assert_eq!(take_output(&mut rom), expected);
+		assert_eq!(rom.reg(V0), expected.len() as u32);
+	}
+
+	#[test]
+	fn events_and_threads() {
+		let mut code = vec![
+			Li(A0, 0xf000_0001),
+			Li(A1, 0x20),
+			Li(A2, EVENT_MODE_READY),
+			Li(A3, 0),
+		];
+		// OpenEvent
+		code.extend(call(0xb0, 0x08));
+		code.extend([Move(S0, V0), Move(A0, S0)]);
+		// EnableEvent
+		code.extend(call(0xb0, 0x0c));
+		code.push(Move(A0, S0));
+		// TestEvent, nothing delivered yet
+		code.extend(call(0xb0, 0x0b));
+		code.extend([Move(S1, V0), Li(A0, 0xf000_0001), Li(A1, 0x20)]);
+		// DeliverEvent
+		code.extend(call(0xb0, 0x07));
+		code.push(Move(A0, S0));
+		code.extend(call(0xb0, 0x0b));
+		code.extend([Move(S2, V0), Move(A0, S0)]);
+		// The first TestEvent acknowledged it
+		code.extend(call(0xb0, 0x0b));
+		code.extend([
+			Move(S3, V0),
+			La(A0, Label::Global("thread")),
+			Li(A1, 0x801f_0000),
+			Li(A2, 0x1234),
+		]);
+		// OpenThread
+		code.extend(call(0xb0, 0x0e));
+		code.extend([Move(S4, V0), Move(A0, S4)]);
+		// ChangeThread, returns 1 once the main thread is resumed
+		code.extend(call(0xb0, 0x10));
+		code.extend([Move(S5, V0), Break(0)]);
+		// The registers of the main thread are restored when switching
+		// back, the results go to RAM
+		code.extend([
+			Global("thread"),
+			Li(T0, 0x8000_1000),
+			Sw(SP, T0, 0),
+			Sw(GP, T0, 4),
+			Sw(V0, T0, 8),
+			Li(A0, THREAD_HANDLE),
+		]);
+		code.extend(call(0xb0, 0x10));
+		code.push(Break(1));
+
+		let mut rom = TestRom::run_hle(&code);
+
+		assert_eq!(rom.reg(S0), EVENT_HANDLE);
+		assert_eq!((rom.reg(S1), rom.reg(S2), rom.reg(S3)), (0, 1, 0));
+
+		assert_eq!(rom.reg(S4), THREAD_HANDLE | 1);
+		assert_eq!(rom.reg(S5), 1);
+		assert_eq!(rom.load(0x8000_1000), 0x801f_0000);
+		assert_eq!(rom.load(0x8000_1004), 0x1234);
+		assert_eq!(rom.load(0x8000_1008), 0);
+		assert_eq!(rom.traps().len(), 1);
+	}
+
+	#[test]
+	fn syscall() {
+		let rom = TestRom::run_hle(&[
+			// IM2 | IEc
+			Li(T0, 0x401),
+			Mtc0(T0, 12),
+			// EnterCriticalSection
+			Li(A0, 1),
+			Syscall(0),
+			Move(S0, V0),
+			Mfc0(S1, 12),
+			// ExitCriticalSection
+			Li(A0, 2),
+			Syscall(0),
+			Mfc0(S2, 12),
+			Li(A0, 0x1234),
+			Syscall(0),
+			Nop,
+			Break(0),
+		]);
+
+		assert_eq!(rom.reg(S0), 1);
+		assert_eq!(rom.reg(S1), 0);
+		assert_eq!(rom.reg(S2), 0x401);
+
+		let traps: Vec<_> = rom.traps().iter().map(|t| (t.code(), t.epc)).collect();
+
+		// Execution resumes after the syscall, even unhandled ones
+		assert_eq!(traps, [(8, BASE + 12), (8, BASE + 28), (8, BASE + 40), (9, BASE + 48)]);
+	}
+
+	#[test]
+	fn boot() {
+		let mut asm = crate::assembler::Assembler::from_base(0x8001_0000);
+
+		let mut code = vec![
+			Move(S0, SP),
+			Move(S1, GP),
+			Move(S2, RA),
+			La(A0, Label::Global("msg")),
+		];
+		// puts
+		code.extend(call(0xa0, 0x3e));
+		code.extend([Break(0), Global("msg"), Ascii(b"booted\0")]);
+
+		asm.assemble(&code).unwrap();
+
+		let exe = asm.to_exe(&ExeHeader {
+			pc: 0x8001_0000,
+			gp: 0x1234,
+			sp: 0x801f_0000,
+			region: Region::Europe,
+		});
+
+		let mut cpu = Cpu::new(Interconnect::new(Bios::hle()));
+
+		boot_exe(&mut cpu, &exe).unwrap();
+
+		let mut rom = TestRom::from_cpu(cpu);
+
+		rom.resume();
+
+		assert_eq!(rom.reg(S0), 0x801f_0000);
+		assert_eq!(rom.reg(S1), 0x1234);
+		assert_eq!(rom.reg(S2), 0xbfc0_0000);
+		assert_eq!(take_output(&mut rom), b"booted\n");
+
+		let header = |exe: &mut Vec<u8>, off: usize, v: u32| {
+			exe[off..off + 4].copy_from_slice(&v.to_le_bytes());
+		};
+
+		assert!(boot_exe(&mut cpu_hle(), b"PS-X EXE").is_err());
+		assert!(boot_exe(&mut cpu_hle(), &exe[..0x800]).is_err());
+
+		// Text in ROM
+		let mut bad = exe.clone();
+		header(&mut bad, 0x18, 0xbfc0_0000);
+		assert!(boot_exe(&mut cpu_hle(), &bad).is_err());
+
+		// BSS larger than RAM
+		let mut bad = exe.clone();
+		header(&mut bad, 0x28, 0x8010_0000);
+		header(&mut bad, 0x2c, u32::MAX);
+		assert!(boot_exe(&mut cpu_hle(), &bad).is_err());
+	}
+
+	fn cpu_hle() -> Cpu {
+		Cpu::new(Interconnect::new(Bios::hle()))
+	}
+
+	/// Run A0 function `function` with `args` in $a0-$a2 and return
+	/// $v0
+	fn call_a0(cpu: &mut Cpu, function: u32, args: [u32; 3]) -> u32 {
+		for (i, &a) in args.iter().enumerate() {
+			cpu.set_reg(A0.0 as u32 + i as u32, a);
+		}
+
+		Kernel::new().call(cpu, Vector::A0, function);
+
+		cpu.reg(V0.0 as u32)
+	}
+
+	#[test]
+	fn invalid_pointers() {
+		let mut cpu = cpu_hle();
+
+		let rom = 0xbfc0_0000;
+		let io = 0x1f80_1000;
+
+		// memset, memmove, memcmp and memchr
+		assert_eq!(call_a0(&mut cpu, 0x2b, [rom, 0, 4]), 0);
+		assert_eq!(call_a0(&mut cpu, 0x2b, [0x100, 0xaa, u32::MAX]), 0);
+		assert_eq!(read8(&cpu, 0x100), Some(0));
+		assert_eq!(call_a0(&mut cpu, 0x2a, [0x100, 0x200, 0x8000_0000]), 0);
+		assert_eq!(call_a0(&mut cpu, 0x2a, [0x100, io, 4]), 0);
+		assert_eq!(call_a0(&mut cpu, 0x29, [0x100, io, 4]), FAULT);
+		assert_eq!(call_a0(&mut cpu, 0x2e, [0x100, 1, u32::MAX]), 0);
+
+		// strncmp is allowed a huge limit
+		assert_eq!(call_a0(&mut cpu, 0x18, [0x100, 0x200, u32::MAX]), 0);
+
+		// setjmp/longjmp need an aligned buffer
+		assert_eq!(call_a0(&mut cpu, 0x13, [0x102, 0, 0]), FAULT);
+		assert_eq!(read32(&cpu, 0x100), Some(0));
+
+		cpu.set_reg(RA.0 as u32, 0x8001_0000);
+		assert_eq!(call_a0(&mut cpu, 0x13, [0x1000, 0, 0]), 0);
+
+		cpu.set_reg(RA.0 as u32, 0x8002_0000);
+		assert_eq!(call_a0(&mut cpu, 0x14, [0x1002, 7, 0]), FAULT);
+		assert_eq!(cpu.reg(RA.0 as u32), 0x8002_0000);
+		assert_eq!(call_a0(&mut cpu, 0x14, [0x1000, 7, 0]), 7);
+		assert_eq!(cpu.pc(), 0x8001_0000);
+
+		// Neither is anything output with a bad buffer or stack
+		assert_eq!(call_a0(&mut cpu, 0x03, [1, io, 4]), FAULT);
+
+		write_bytes(&mut cpu, 0x100, b"%d %d %d %d\0").unwrap();
+		cpu.set_reg(SP.0 as u32, io);
+		assert_eq!(call_a0(&mut cpu, 0x3f, [0x100, 1, 2]), FAULT);
+
+		assert!(cpu.interconnect().tty().output().is_empty());
+	}
+}
@@ -1,41 +1,125 @@
 
 pub mod db;
+pub mod scan;
+pub mod patch;
+pub mod hle;
+pub mod trace;
+pub mod romdir;
 
-use std::{fmt, marker::PhantomData};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Mutex;
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use serde::{de::{SeqAccess, Visitor}, ser::SerializeSeq, Deserialize, Deserializer, Serialize};
-
-use crate::{box_array, cdrom::disk::Region, memory::Addressable};
+use crate::{cdrom::disk::Region, memory::Addressable};
 
 
+/// Size of a PS1 BIOS image
 pub const BIOS_SIZE: usize = 512 * 1024;
+/// Size of a PS2 BIOS image
+pub const PS2_BIOS_SIZE: usize = 4 * 1024 * 1024;
 
 use self::db::Metadata;
+use self::patch::{Applied, PatchError};
+use self::romdir::{RomDir, RomDirError, RomVer};
+
+/// Console a BIOS image belongs to, deduced from the image size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+	Ps1,
+	Ps2,
+}
 
 
 
 pub struct Bios {
-	data: Box<[u8; BIOS_SIZE]>,
-	metadata:	&'static Metadata
+	/// ROM contents, either `BIOS_SIZE` or `PS2_BIOS_SIZE` bytes long
+	data: Box<[u8]>,
+	metadata:	&'static Metadata,
+	/// Patches applied to `data`
+	applied: Vec<Applied>,
 }
 
 impl Bios {
 	pub fn new(binary: Box<[u8; BIOS_SIZE]>) -> Option<Bios> {
-		match db::lookup_blob(&*binary) {
-			Some(metadata) => Some(Bios {
-				data: binary,
-				metadata: metadata
-			}),
-			None => None
+		db::lookup_blob(&binary[..]).map(|metadata| Bios {
+			data: binary,
+			metadata,
+			applied: Vec::new(),
+		})
+	}
+
+	/// Load a BIOS image from the file at `path`. See `from_reader`.
+	pub fn from_path<P: AsRef<Path>>(path: P,
+									 allow_unknown: bool) -> Result<Bios, BiosError> {
+		let file = File::open(path)?;
+
+		Bios::from_reader(file, allow_unknown)
+	}
+
+	/// Read a BIOS image from `reader` and look it up in the
+	/// database. If `allow_unknown` is true images missing from the
+	/// database are accepted, their metadata is guessed from the ROM
+	/// version string if possible and left blank otherwise. Dumps
+	/// known to be bad are always rejected.
+	///
+	/// The database only lists PS1 dumps, PS2 images can only be
+	/// loaded with `allow_unknown` and are identified by their ROMVER.
+	pub fn from_reader<R: Read>(mut reader: R,
+								allow_unknown: bool) -> Result<Bios, BiosError> {
+		let mut binary = Vec::with_capacity(BIOS_SIZE);
+
+		reader.read_to_end(&mut binary)?;
+
+		if binary.len() != BIOS_SIZE && binary.len() != PS2_BIOS_SIZE {
+			return Err(BiosError::BadSize(binary.len()));
 		}
+
+		let sha256 = db::sha256(&binary);
+
+		let metadata =
+			match db::lookup_sha256(&sha256) {
+				Some(m) if m.known_bad => return Err(BiosError::KnownBad(m)),
+				Some(m) => m,
+				None if allow_unknown => {
+					warn!("Using unknown BIOS {}", db::format_sha256(&sha256));
+
+					guessed_metadata(&sha256, &binary)
+				}
+				None => return Err(BiosError::UnknownHash(sha256)),
+			};
+
+		Ok(Bios {
+			data: binary.into_boxed_slice(),
+			metadata,
+			applied: Vec::new(),
+		})
+	}
+
+	/// BIOS used for high-level emulation: the kernel functions are
+	/// implemented on the host, see the `hle` module
+	pub fn hle() -> Bios {
+		Bios {
+			data: hle::rom(),
+			metadata: &HLE_METADATA,
+			applied: Vec::new(),
+		}
+	}
+
+	/// Return true if this is the HLE BIOS
+	pub fn is_hle(&self) -> bool {
+		std::ptr::eq(self.metadata, &HLE_METADATA)
 	}
 
 	pub fn dummy() -> Bios {
 		let mut bios =
 			Bios {
-				data: box_array![0; BIOS_SIZE],
+				data: vec![0; BIOS_SIZE].into_boxed_slice(),
 				metadata: &DUMMY_METADATA,
+				applied: Vec::new(),
 			};
 
 		for (i, b) in bios.data.iter_mut().enumerate() {
@@ -45,41 +129,51 @@ impl Bios {
 		bios
 	}
 
-	pub fn patch_boot_animation(&mut self) -> Result<(), ()> {
-		self.patch_animation_jump_hook(0)
+	/// Skip the boot animation
+	pub fn patch_boot_animation(&mut self) -> Result<(), PatchError> {
+		self.apply_patch(patch::FAST_BOOT)
 	}
 
-	pub fn patch_animation_jump_hook(&mut self, instruction: u32) -> Result<(), ()> {
-		match self.metadata.animation_jump_hook {
-			Some(h) => {
-				let h = h as usize;
-				self.data[h] = instruction as u8;
-				self.data[h + 1] = (instruction >> 8) as u8;
-				self.data[h + 2] = (instruction >> 16) as u8;
-				self.data[h + 3] = (instruction >> 24) as u8;
-				Ok(())
-			}
-			None => Err(())
+	/// Make the kernel output its debug messages on the expansion UART
+	pub fn enable_debug_uart(&mut self) -> Result<(), PatchError> {
+		self.apply_patch(patch::DEBUG_UART)
+	}
+
+	/// Return the console this BIOS is for
+	pub fn console(&self) -> Console {
+		match self.data.len() {
+			PS2_BIOS_SIZE => Console::Ps2,
+			_ => Console::Ps1,
 		}
 	}
 
-	pub fn enable_debug_uart(&mut self) -> Result<(), ()> {
-        match self.metadata.patch_debug_uart {
-            Some(patch) => {
-                patch(self);
-                Ok(())
-            },
-            None => Err(()),
-        }
-    }
+	/// Raw contents of the ROM
+	pub fn data(&self) -> &[u8] {
+		&self.data
+	}
+
+	/// Parse the module directory of a PS2 BIOS
+	pub fn romdir(&self) -> Result<RomDir, RomDirError> {
+		RomDir::parse(&self.data)
+	}
+
+	/// Decode the ROMVER module of a PS2 BIOS
+	pub fn romver(&self) -> Result<RomVer, RomDirError> {
+		self.romdir()?.romver(&self.data)
+	}
 
+	/// Read from the ROM. The BIOS region is 4MiB wide, smaller PS1
+	/// images are mirrored.
 	pub fn load<T: Addressable>(&self, offset: u32) -> u32 {
+        let mask = self.data.len() - 1;
         let offset = offset as usize;
 
         let mut r = 0;
 
+        // Wrap every byte, a word straddling the end of the image
+        // continues at the start of the next mirror
         for i in 0..T::size() as usize {
-            r |= (self.data[offset + i] as u32) << (8 * i)
+            r |= (self.data[(offset + i) & mask] as u32) << (8 * i)
         }
 
         r
@@ -90,55 +184,112 @@ impl Bios {
     }
 }
 
+/// Only the SHA-256 of the BIOS is saved, the ROM contents have to be
+/// re-attached with `Interconnect::reattach_bios` after loading. It's
+/// preceded by a flag set for images missing from the database.
 impl Serialize for Bios {
 	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
 		where
-			S: serde::Serializer 
+			S: Serializer
 	{
 		let sha256 = &self.metadata.sha256;
-		let mut seq = serializer.serialize_seq(Some(sha256.len()))?;
-		for (i, b) in sha256.iter().enumerate() {
-			seq.serialize_element(b)?;
-		}
-		seq.end()
+
+		let guessed =
+			!std::ptr::eq(self.metadata, &DUMMY_METADATA) &&
+			!self.is_hle() &&
+			db::lookup_sha256(sha256).is_none();
+
+		(guessed, sha256).serialize(serializer)
 	}
 }
 
 impl<'de> Deserialize<'de> for Bios {
-	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	fn deserialize<D>(deserializer: D) -> Result<Bios, D::Error>
 		where
-			D: serde::Deserializer<'de>
+			D: Deserializer<'de>
 	{
-		
+		let (guessed, sha256) = <(bool, [u8; 32])>::deserialize(deserializer)?;
 
-		deserializer.deserialize_seq()	
+		if sha256 == DUMMY_METADATA.sha256 {
+			return Ok(Bios::dummy());
+		}
+
+		if sha256 == HLE_METADATA.sha256 {
+			return Ok(Bios::hle());
+		}
+
+		let metadata =
+			match db::lookup_sha256(&sha256) {
+				Some(metadata) => metadata,
+				None => {
+					if !guessed {
+						warn!("BIOS {} is missing from the database",
+							  db::format_sha256(&sha256));
+					}
+
+					// The actual metadata comes with the image when
+					// it's re-attached, which also checks the hash
+					intern(&PLACEHOLDER_METADATA, &sha256, || unknown_metadata(sha256))
+				}
+			};
+
+		// The actual ROM contents will be replaced when the BIOS is
+		// re-attached
+		let mut bios = Bios::dummy();
+		bios.metadata = metadata;
+		Ok(bios)
 	}
 }
 
+/// Metadata of images missing from the database, indexed by hash.
+/// Metadata is normally static so these are leaked, at most once per
+/// image.
+type MetadataCache = Mutex<Vec<&'static Metadata>>;
 
-fn decode_bios<'de, D>(deserialize: D) -> Result<Bios, D::Error>
-where 
-	D: Deserializer<'de>,
+/// Metadata of the unknown images loaded with `from_reader`
+static GUESSED_METADATA: MetadataCache = Mutex::new(Vec::new());
+
+/// Metadata of the unknown images found in save states, until the
+/// actual image is re-attached
+static PLACEHOLDER_METADATA: MetadataCache = Mutex::new(Vec::new());
+
+/// Return the metadata of `sha256` from `cache`, building it with
+/// `build` if it's not there yet
+fn intern<F>(cache: &MetadataCache, sha256: &[u8; 32], build: F) -> &'static Metadata
+	where F: FnOnce() -> Metadata
 {
-	struct BiosParser;
-	impl<'de> Visitor<'de> for BiosParser
-	{
-		type Value = Self;
+	let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
 
-		fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-			formatter.write_str("[u64; 32]")
-		}
+	if let Some(m) = cache.iter().find(|m| m.sha256 == *sha256) {
+		return m;
+	}
 
-		fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
-			
-			
-			
+	let m: &'static Metadata = Box::leak(Box::new(build()));
 
-			let mut bios = Bios::dummy();
+	cache.push(m);
 
+	m
+}
 
+/// Metadata of `binary`, an image missing from the database, guessed
+/// from its contents if possible
+pub(crate) fn guessed_metadata(sha256: &[u8; 32], binary: &[u8]) -> &'static Metadata {
+	intern(&GUESSED_METADATA, sha256, || {
+		db::guess_metadata(binary)
+			.unwrap_or_else(|| unknown_metadata(*sha256))
+	})
+}
 
-		}
+/// Metadata of an unknown image we know nothing about
+fn unknown_metadata(sha256: [u8; 32]) -> Metadata {
+	Metadata {
+		sha256,
+		version_major: 0,
+		version_minor: 0,
+		region: Region::NorthAmerica,
+		known_bad: false,
+		verified: false,
+		patches: &[],
 	}
 }
 
@@ -149,6 +300,283 @@ static DUMMY_METADATA: Metadata =
         version_minor: 0,
         region: Region::NorthAmerica,
         known_bad: true,
-        animation_jump_hook: None,
-        patch_debug_uart: None,
-    };
\ No newline at end of file
+        verified: true,
+        patches: &[],
+    };
+
+/// Error returned when a BIOS image can't be loaded
+#[derive(Debug)]
+pub enum BiosError {
+	/// I/O error while reading the image
+	Io(io::Error),
+	/// The image doesn't have the size of a BIOS ROM
+	BadSize(usize),
+	/// The image is not in the database, contains its SHA-256
+	UnknownHash([u8; 32]),
+	/// The image is in the database but known to be a bad dump
+	KnownBad(&'static Metadata),
+}
+
+impl From<io::Error> for BiosError {
+	fn from(e: io::Error) -> BiosError {
+		BiosError::Io(e)
+	}
+}
+
+impl fmt::Display for BiosError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BiosError::Io(e) => write!(f, "Can't read BIOS: {}", e),
+			BiosError::BadSize(s) =>
+				write!(f, "Bad BIOS size: expected {} or {} bytes, got {}",
+					   BIOS_SIZE, PS2_BIOS_SIZE, s),
+			BiosError::UnknownHash(sha256) =>
+				write!(f, "Unknown BIOS (SHA-256 {})", db::format_sha256(sha256)),
+			BiosError::KnownBad(m) =>
+				write!(f, "BIOS {:?} is known to be a bad dump", m),
+		}
+	}
+}
+
+impl std::error::Error for BiosError {}
+
+/// Metadata of the HLE BIOS. The checksum is not the one of the
+/// image, it only identifies HLE mode in save states.
+static HLE_METADATA: Metadata =
+	Metadata {
+		sha256: [0xfe; 32],
+		version_major: 0,
+		version_minor: 0,
+		region: Region::NorthAmerica,
+		known_bad: false,
+		verified: true,
+		patches: &[],
+	};
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::patch::{Patch, DEBUG_UART, FAST_BOOT};
+
+	/// Build an image with the contents of the dummy BIOS and the
+	/// metadata of a database entry
+	fn synthetic(metadata: &'static Metadata) -> Bios {
+		let mut bios = Bios::dummy();
+		bios.metadata = metadata;
+		bios
+	}
+
+	/// Expected encoding of the known patches
+	fn expected(patch: &Patch) -> &'static [u32] {
+		match patch.name {
+			// nop
+			FAST_BOOT => &[0],
+			DEBUG_UART => &[
+				// ori $at, $zero, 1
+				0x3401_0001,
+				// jal 0xbfc06784
+				0x0ff0_19e1,
+				// sw $at, -22080($gp)
+				0xaf81_a9c0,
+			],
+			n => panic!("No reference encoding for patch {}", n),
+		}
+	}
+
+	#[test]
+	fn apply_every_patch() {
+		let reference = Bios::dummy();
+
+		for metadata in db::DATABASE.iter() {
+			let mut bios = synthetic(metadata);
+
+			for patch in metadata.patches {
+				bios.apply_patch(patch.name).unwrap();
+
+				let start = patch.offset as usize;
+				let expected = expected(patch);
+
+				for (i, &e) in expected.iter().enumerate() {
+					let w = bios.load::<crate::memory::Word>((start + i * 4) as u32);
+					assert_eq!(w, e, "{:?} {:?}", metadata, patch);
+				}
+
+				assert!(matches!(bios.apply_patch(patch.name),
+								 Err(PatchError::AlreadyApplied(_))));
+			}
+
+			assert_eq!(bios.applied_patches().len(), metadata.patches.len());
+
+			bios.revert_all_patches();
+
+			assert!(bios.data[..] == reference.data[..], "{:?}", metadata);
+		}
+	}
+
+	#[test]
+	fn unsupported_patches() {
+		for metadata in db::DATABASE.iter().filter(|m| m.patches.is_empty()) {
+			let mut bios = synthetic(metadata);
+
+			assert!(matches!(bios.patch_boot_animation(),
+							 Err(PatchError::Unsupported { .. })));
+			assert!(matches!(bios.enable_debug_uart(),
+							 Err(PatchError::Unsupported { .. })));
+			assert!(matches!(bios.revert_patch(FAST_BOOT),
+							 Err(PatchError::NotApplied(_))));
+		}
+	}
+
+	#[test]
+	fn verify_original_bytes() {
+		use crate::assembler::syntax::Instruction::Nop;
+
+		static PATCHES: [Patch; 1] = [
+			Patch {
+				name: "test",
+				description: "",
+				offset: 0x100,
+				original: Some(&[0x12, 0x34, 0x56, 0x78]),
+				code: &[Nop],
+			},
+		];
+
+		static METADATA: Metadata = Metadata {
+			sha256: [0; 32],
+			version_major: 0,
+			version_minor: 0,
+			region: Region::Japan,
+			known_bad: false,
+			verified: true,
+			patches: &PATCHES,
+		};
+
+		let mut bios = synthetic(&METADATA);
+
+		assert!(matches!(bios.apply_patch("test"),
+						 Err(PatchError::Mismatch { offset: 0x100, .. })));
+
+		bios.data[0x100..0x104].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+
+		bios.apply_patch("test").unwrap();
+		assert_eq!(&bios.data[0x100..0x104], &[0; 4]);
+
+		bios.revert_patch("test").unwrap();
+		assert_eq!(&bios.data[0x100..0x104], &[0x12, 0x34, 0x56, 0x78]);
+	}
+
+	#[test]
+	fn original_length_mismatch() {
+		use crate::assembler::syntax::Instruction::Nop;
+
+		// Original longer than the replacement, at the very end of
+		// the ROM
+		static PATCHES: [Patch; 1] = [
+			Patch {
+				name: "test",
+				description: "",
+				offset: BIOS_SIZE as u32 - 4,
+				original: Some(&[0; 8]),
+				code: &[Nop],
+			},
+		];
+
+		static METADATA: Metadata = Metadata {
+			sha256: [0; 32],
+			version_major: 0,
+			version_minor: 0,
+			region: Region::Japan,
+			known_bad: false,
+			verified: true,
+			patches: &PATCHES,
+		};
+
+		let mut bios = synthetic(&METADATA);
+
+		bios.data[BIOS_SIZE - 4..].copy_from_slice(&[0; 4]);
+
+		assert!(matches!(bios.apply_patch("test"),
+						 Err(PatchError::Mismatch { ref found, .. }) if found.len() == 4));
+		assert!(bios.applied_patches().is_empty());
+	}
+
+	/// Unknown PS1 image containing the version string of `version`
+	fn unknown_image(version: &str) -> Vec<u8> {
+		let mut image = vec![0; BIOS_SIZE];
+
+		image[0x7000..0x7000 + version.len()].copy_from_slice(version.as_bytes());
+
+		image
+	}
+
+	#[test]
+	fn guessed_metadata_is_cached() {
+		let image = unknown_image("System ROM Version 4.1 12/16/97 E");
+
+		let a = Bios::from_reader(&image[..], true).unwrap();
+		let b = Bios::from_reader(&image[..], true).unwrap();
+
+		assert!(std::ptr::eq(a.metadata(), b.metadata()));
+		assert!(!a.metadata().verified);
+		assert_eq!(a.metadata().region, Region::Europe);
+		assert_eq!((a.metadata().version_major, a.metadata().version_minor), (4, 1));
+
+		// Without a version string
+		let image = vec![0x33; BIOS_SIZE];
+
+		let c = Bios::from_reader(&image[..], true).unwrap();
+
+		assert_eq!(c.metadata().sha256, db::sha256(&image));
+		assert!(!c.metadata().verified);
+		assert!(matches!(Bios::from_reader(&image[..], false),
+						 Err(BiosError::UnknownHash(_))));
+	}
+
+	#[test]
+	fn save_state_with_unknown_bios() {
+		use crate::cpu::Cpu;
+		use crate::memory::Interconnect;
+		use crate::savestate;
+
+		for image in [unknown_image("System ROM Version 2.2 12/04/95 A"),
+					  vec![0x44; BIOS_SIZE]] {
+			let bios = Bios::from_reader(&image[..], true).unwrap();
+			let metadata = bios.metadata();
+
+			let cpu = Cpu::new(Interconnect::new(bios));
+
+			let mut state = Vec::new();
+			savestate::save(&mut state, &cpu).unwrap();
+
+			let bios = Bios::from_reader(&image[..], true).unwrap();
+			let cpu = savestate::load(&state[..], bios).unwrap();
+
+			assert!(std::ptr::eq(cpu.interconnect().bios().metadata(), metadata));
+			assert_eq!(cpu.interconnect().bios().data(), &image[..]);
+
+			// The hash is still checked
+			let other = Bios::from_reader(&vec![0x45; BIOS_SIZE][..], true).unwrap();
+
+			assert!(matches!(savestate::load(&state[..], other),
+							 Err(savestate::Error::BiosMismatch)));
+			assert!(matches!(savestate::load(&state[..], Bios::dummy()),
+							 Err(savestate::Error::BiosMismatch)));
+		}
+	}
+
+	#[test]
+	fn load_across_mirror() {
+		use crate::memory::{Interconnect, Word};
+
+		let mut image = vec![0; BIOS_SIZE];
+
+		image[..2].copy_from_slice(&[0x33, 0x44]);
+		image[BIOS_SIZE - 2..].copy_from_slice(&[0x11, 0x22]);
+
+		let inter = Interconnect::new(Bios::from_reader(&image[..], true).unwrap());
+
+		assert_eq!(inter.peek::<Word>(0x1fc7fffe), Some(0x4433_2211));
+		assert_eq!(inter.peek::<Word>(0xbfc7fffe), Some(0x4433_2211));
+		assert_eq!(inter.peek::<Word>(0x1fc80000), Some(0x0000_4433));
+	}
+}
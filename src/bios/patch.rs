@@ -0,0 +1,241 @@
+//! Declarative BIOS patches
+//!
+//! Each database entry lists the patches known to work with that
+//! particular dump. The replacement code is generated by the assembler
+//! when the patch is applied and the bytes it overwrites are kept
+//! around so that the patch can be reverted later.
+
+use std::fmt;
+
+use crate::assembler::{syntax::Instruction, Assembler};
+
+use super::db::Metadata;
+use super::Bios;
+
+/// Address of the first byte of the BIOS in KSEG1
+const BIOS_BASE: u32 = 0xbfc0_0000;
+
+/// Name of the patch skipping the boot animation
+pub const FAST_BOOT: &str = "fast-boot";
+/// Name of the patch enabling the debug UART output
+pub const DEBUG_UART: &str = "debug-uart";
+
+pub struct Patch {
+	/// Name used to refer to the patch
+	pub name: &'static str,
+	/// Short human-readable description
+	pub description: &'static str,
+	/// Offset of the patch in the ROM
+	pub offset: u32,
+	/// Bytes expected at `offset` in the unpatched image, if known.
+	/// The patch is refused if the image doesn't match.
+	pub original: Option<&'static [u8]>,
+	/// Replacement code, assembled at the patch address
+	pub code: &'static [Instruction<'static>],
+}
+
+impl Patch {
+	/// Assemble the replacement code
+	pub fn assemble(&self) -> Result<Vec<u8>, PatchError> {
+		let mut asm = Assembler::from_base(BIOS_BASE + self.offset);
+
+		if let Err(e) = asm.assemble(self.code) {
+			return Err(PatchError::Assembler {
+				patch: self.name,
+				error: e,
+			});
+		}
+
+		let (mc, _) = asm.machine_code();
+
+		Ok(mc)
+	}
+}
+
+impl fmt::Debug for Patch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}@0x{:05x}", self.name, self.offset)
+	}
+}
+
+/// A patch currently applied to a BIOS, with the bytes it replaced
+pub struct Applied {
+	pub patch: &'static Patch,
+	saved: Vec<u8>,
+}
+
+impl Bios {
+	/// Patches available for this BIOS
+	pub fn patches(&self) -> &'static [Patch] {
+		self.metadata.patches
+	}
+
+	/// Patches currently applied, in the order they were applied
+	pub fn applied_patches(&self) -> &[Applied] {
+		&self.applied
+	}
+
+	/// Check that patch `name` can be applied to this image without
+	/// actually modifying it. Returns the patch and the assembled
+	/// replacement.
+	pub fn verify_patch(&self, name: &str) -> Result<(&'static Patch, Vec<u8>), PatchError> {
+		let patch = self.find_patch(name)?;
+
+		if self.applied.iter().any(|a| a.patch.name == patch.name) {
+			return Err(PatchError::AlreadyApplied(patch.name));
+		}
+
+		let code = patch.assemble()?;
+
+		let start = patch.offset as usize;
+		let end = start + code.len();
+
+		if end > self.data.len() {
+			return Err(PatchError::OutOfRange {
+				patch: patch.name,
+				end,
+			});
+		}
+
+		if let Some(original) = patch.original {
+			// A length mismatch is a bug in the database but must not
+			// read past the replacement (or the end of the ROM)
+			let found = &self.data[start..end];
+
+			if found != original {
+				return Err(PatchError::Mismatch {
+					patch: patch.name,
+					offset: patch.offset,
+					expected: original,
+					found: found.to_vec(),
+				});
+			}
+		}
+
+		for a in &self.applied {
+			let a_start = a.patch.offset as usize;
+			let a_end = a_start + a.saved.len();
+
+			if start < a_end && a_start < end {
+				return Err(PatchError::Overlap {
+					patch: patch.name,
+					other: a.patch.name,
+				});
+			}
+		}
+
+		Ok((patch, code))
+	}
+
+	/// Apply patch `name`. The image is left untouched on error.
+	pub fn apply_patch(&mut self, name: &str) -> Result<(), PatchError> {
+		let (patch, code) = self.verify_patch(name)?;
+
+		let start = patch.offset as usize;
+		let range = start..start + code.len();
+
+		let saved = self.data[range.clone()].to_vec();
+
+		self.data[range].copy_from_slice(&code);
+
+		self.applied.push(Applied { patch, saved });
+
+		Ok(())
+	}
+
+	/// Restore the bytes overwritten by patch `name`
+	pub fn revert_patch(&mut self, name: &str) -> Result<(), PatchError> {
+		let pos = match self.applied.iter().position(|a| a.patch.name == name) {
+			Some(p) => p,
+			None => return Err(PatchError::NotApplied(name.into())),
+		};
+
+		let Applied { patch, saved } = self.applied.remove(pos);
+
+		let start = patch.offset as usize;
+
+		self.data[start..start + saved.len()].copy_from_slice(&saved);
+
+		Ok(())
+	}
+
+	/// Revert all the applied patches
+	pub fn revert_all_patches(&mut self) {
+		while let Some(Applied { patch, saved }) = self.applied.pop() {
+			let start = patch.offset as usize;
+
+			self.data[start..start + saved.len()].copy_from_slice(&saved);
+		}
+	}
+
+	fn find_patch(&self, name: &str) -> Result<&'static Patch, PatchError> {
+		self.metadata.patches.iter()
+			.find(|p| p.name == name)
+			.ok_or_else(|| PatchError::Unsupported {
+				patch: name.into(),
+				bios: self.metadata,
+			})
+	}
+}
+
+/// Error returned when a patch can't be applied or reverted
+#[derive(Debug)]
+pub enum PatchError {
+	/// The patch doesn't exist for this BIOS
+	Unsupported {
+		patch: String,
+		bios: &'static Metadata,
+	},
+	/// The image doesn't contain the expected bytes at the patch
+	/// location
+	Mismatch {
+		patch: &'static str,
+		offset: u32,
+		expected: &'static [u8],
+		found: Vec<u8>,
+	},
+	/// The replacement code doesn't fit in the ROM
+	OutOfRange {
+		patch: &'static str,
+		end: usize,
+	},
+	/// The patch would overwrite bytes modified by another patch
+	Overlap {
+		patch: &'static str,
+		other: &'static str,
+	},
+	/// The patch has already been applied
+	AlreadyApplied(&'static str),
+	/// Attempted to revert a patch that isn't applied
+	NotApplied(String),
+	/// The replacement code failed to assemble
+	Assembler {
+		patch: &'static str,
+		error: String,
+	},
+}
+
+impl fmt::Display for PatchError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PatchError::Unsupported { patch, bios } =>
+				write!(f, "Patch '{}' is not available for BIOS {:?}", patch, bios),
+			PatchError::Mismatch { patch, offset, expected, found } =>
+				write!(f, "Can't apply patch '{}': expected {:02x?} at 0x{:05x}, \
+						   found {:02x?}", patch, expected, offset, found),
+			PatchError::OutOfRange { patch, end } =>
+				write!(f, "Patch '{}' ends past the end of the ROM (0x{:x})",
+					   patch, end),
+			PatchError::Overlap { patch, other } =>
+				write!(f, "Patch '{}' overlaps with patch '{}'", patch, other),
+			PatchError::AlreadyApplied(p) =>
+				write!(f, "Patch '{}' is already applied", p),
+			PatchError::NotApplied(p) =>
+				write!(f, "Patch '{}' is not applied", p),
+			PatchError::Assembler { patch, error } =>
+				write!(f, "Can't assemble patch '{}': {}", patch, error),
+		}
+	}
+}
+
+impl std::error::Error for PatchError {}
@@ -0,0 +1,459 @@
+//! PS2 ROM directory parser
+//!
+//! PS2 BIOS images are a collection of modules (RESET, ROMVER,
+//! IOPBTCONF, EELOAD...) described by a directory table, ROMDIR,
+//! located near the start of the image. Each 16-byte ROMDIR entry
+//! contains the name of the module, the size of its EXTINFO record
+//! and the size of the module. Modules are stored contiguously in
+//! directory order, each one aligned on 16 bytes. The EXTINFO module
+//! contains the optional build date, version and comment of every
+//! module.
+
+use std::fmt;
+
+use crate::cdrom::disk::Region;
+
+/// Size of a ROMDIR entry
+const ENTRY_SIZE: usize = 16;
+
+/// A module in the ROM
+#[derive(Debug, Clone)]
+pub struct Module {
+	pub name: String,
+	/// Offset of the module in the ROM
+	pub offset: usize,
+	pub size: usize,
+	pub ext_info: ExtInfo,
+}
+
+/// Optional information attached to a module
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtInfo {
+	/// Build date: (year, month, day)
+	pub date: Option<(u16, u8, u8)>,
+	/// Version, usually in BCD (0x0110 for 1.10)
+	pub version: Option<u16>,
+	pub comment: Option<String>,
+	/// Set if the module must be loaded at a fixed address
+	pub fixed: bool,
+}
+
+/// Parsed ROM directory
+#[derive(Debug, Clone)]
+pub struct RomDir {
+	pub modules: Vec<Module>,
+}
+
+impl RomDir {
+	/// Locate and parse the ROMDIR in `rom`
+	pub fn parse(rom: &[u8]) -> Result<RomDir, RomDirError> {
+		// The directory starts with the RESET entry, which describes
+		// the boot code at the start of the ROM
+		let start = (0..rom.len().saturating_sub(ENTRY_SIZE))
+			.step_by(ENTRY_SIZE)
+			.find(|&o| &rom[o..o + 10] == b"RESET\0\0\0\0\0")
+			.ok_or(RomDirError::NotFound)?;
+
+		let mut entries = Vec::new();
+		let mut offset = 0;
+
+		for e in rom[start..].chunks_exact(ENTRY_SIZE) {
+			if e[0] == 0 {
+				break;
+			}
+
+			let name_len = e[..10].iter().position(|&b| b == 0).unwrap_or(10);
+			let name = String::from_utf8_lossy(&e[..name_len]).into_owned();
+			let ext_size = u16::from_le_bytes([e[10], e[11]]) as usize;
+			let size = u32::from_le_bytes([e[12], e[13], e[14], e[15]]) as usize;
+
+			if offset + size > rom.len() {
+				return Err(RomDirError::Truncated(name));
+			}
+
+			entries.push((name, offset, size, ext_size));
+
+			// Modules are aligned on 16 bytes
+			offset += (size + 15) & !15;
+		}
+
+		let extinfo = entries.iter()
+			.find(|e| e.0 == "EXTINFO")
+			.map(|&(_, o, s, _)| &rom[o..o + s]);
+
+		let mut ext_offset = 0;
+		let mut modules = Vec::with_capacity(entries.len());
+
+		for (name, offset, size, ext_size) in entries {
+			let ext_info =
+				match extinfo {
+					Some(e) if ext_size > 0 => {
+						let data = e.get(ext_offset..ext_offset + ext_size)
+							.ok_or_else(|| RomDirError::BadExtInfo(name.clone()))?;
+
+						ext_offset += ext_size;
+
+						ExtInfo::parse(data)
+							.ok_or_else(|| RomDirError::BadExtInfo(name.clone()))?
+					}
+					_ => ExtInfo::default(),
+				};
+
+			modules.push(Module {
+				name,
+				offset,
+				size,
+				ext_info,
+			});
+		}
+
+		Ok(RomDir { modules })
+	}
+
+	/// Return the module named `name`
+	pub fn find(&self, name: &str) -> Option<&Module> {
+		self.modules.iter().find(|m| m.name == name)
+	}
+
+	/// Return the contents of module `name` in `rom`
+	pub fn data<'a>(&self, rom: &'a [u8], name: &str) -> Option<&'a [u8]> {
+		let m = self.find(name)?;
+
+		rom.get(m.offset..m.offset + m.size)
+	}
+
+	/// Parse the ROMVER module of `rom`
+	pub fn romver(&self, rom: &[u8]) -> Result<RomVer, RomDirError> {
+		let data = self.data(rom, "ROMVER")
+			.ok_or_else(|| RomDirError::MissingModule("ROMVER".into()))?;
+
+		let end = data.iter()
+			.position(|&b| b == 0 || b == b'\n')
+			.unwrap_or(data.len());
+
+		let s = String::from_utf8_lossy(&data[..end]);
+
+		RomVer::parse(&s).ok_or_else(|| RomDirError::BadRomVer(s.into_owned()))
+	}
+}
+
+/// Kind of hardware a PS2 BIOS was built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+	/// Retail console (CEX)
+	Retail,
+	/// Debugging station (DEX)
+	Debug,
+	/// TOOL development kit (DTL-T10000)
+	Tool,
+}
+
+/// Contents of the ROMVER module, for instance "0160EC20010704": version
+/// 1.60, region letter, model letter and build date (YYYYMMDD)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomVer {
+	pub major: u8,
+	pub minor: u8,
+	/// TOOL images use the region letter 'T'. They're Japanese units
+	/// and are reported as such.
+	pub region: Region,
+	pub model: Model,
+	/// Build date: (year, month, day)
+	pub date: (u16, u8, u8),
+}
+
+impl RomVer {
+	/// Parse a ROMVER string. Trailing data after the date is ignored.
+	pub fn parse(s: &str) -> Option<RomVer> {
+		// Only ASCII can be sliced by byte index below
+		let s = s.get(..14).filter(|s| s.is_ascii())?;
+
+		let major = s[0..2].parse().ok()?;
+		let minor = s[2..4].parse().ok()?;
+
+		let (region, tool) =
+			match &s[4..5] {
+				"J" => (Region::Japan, false),
+				"A" => (Region::NorthAmerica, false),
+				"E" => (Region::Europe, false),
+				"H" => (Region::Asia, false),
+				"C" => (Region::China, false),
+				"T" => (Region::Japan, true),
+				_ => return None,
+			};
+
+		let model =
+			match (&s[5..6], tool) {
+				(_, true) => Model::Tool,
+				("C", _) => Model::Retail,
+				("D", _) => Model::Debug,
+				_ => return None,
+			};
+
+		let year = s[6..10].parse().ok()?;
+		let month = s[10..12].parse().ok()?;
+		let day = s[12..14].parse().ok()?;
+
+		Some(RomVer {
+			major,
+			minor,
+			region,
+			model,
+			date: (year, month, day),
+		})
+	}
+}
+
+impl fmt::Display for RomVer {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (year, month, day) = self.date;
+
+		write!(f, "{:?} {:?} v{}.{:02} ({:04}-{:02}-{:02})",
+			   self.model, self.region, self.major, self.minor,
+			   year, month, day)
+	}
+}
+
+impl ExtInfo {
+	/// Parse the EXTINFO record of a module. Each field starts with a
+	/// 4-byte header: a 16-bit value, the length of the data following
+	/// the header and the field type.
+	fn parse(mut data: &[u8]) -> Option<ExtInfo> {
+		let mut info = ExtInfo::default();
+
+		while data.len() >= 4 {
+			let value = u16::from_le_bytes([data[0], data[1]]);
+			let len = data[2] as usize;
+			let kind = data[3];
+
+			let payload = data.get(4..4 + len)?;
+
+			match kind {
+				// Date, BCD encoded
+				0x01 => {
+					if payload.len() < 4 {
+						return None;
+					}
+
+					let day = bcd(payload[0] as u16) as u8;
+					let month = bcd(payload[1] as u16) as u8;
+					let year = bcd(u16::from_le_bytes([payload[2], payload[3]]));
+
+					info.date = Some((year, month, day));
+				}
+				0x02 => info.version = Some(value),
+				0x03 => {
+					let end = payload.iter().position(|&b| b == 0).unwrap_or(payload.len());
+					info.comment = Some(String::from_utf8_lossy(&payload[..end]).into_owned());
+				}
+				0x7f => info.fixed = true,
+				_ => warn!("Unknown EXTINFO field type 0x{:02x}", kind),
+			}
+
+			data = &data[4 + len..];
+		}
+
+		Some(info)
+	}
+}
+
+fn bcd(v: u16) -> u16 {
+	(v >> 12) * 1000 + ((v >> 8) & 0xf) * 100 + ((v >> 4) & 0xf) * 10 + (v & 0xf)
+}
+
+/// Error returned when the ROM directory can't be parsed
+#[derive(Debug)]
+pub enum RomDirError {
+	/// No ROMDIR in the image
+	NotFound,
+	/// The named module extends past the end of the ROM
+	Truncated(String),
+	/// The EXTINFO record of the named module is invalid
+	BadExtInfo(String),
+	/// The named module is not in the directory
+	MissingModule(String),
+	/// The ROMVER string can't be parsed
+	BadRomVer(String),
+}
+
+impl fmt::Display for RomDirError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			RomDirError::NotFound => write!(f, "ROMDIR not found"),
+			RomDirError::Truncated(m) =>
+				write!(f, "Module '{}' extends past the end of the ROM", m),
+			RomDirError::BadExtInfo(m) =>
+				write!(f, "Invalid EXTINFO for module '{}'", m),
+			RomDirError::MissingModule(m) =>
+				write!(f, "Module '{}' not found", m),
+			RomDirError::BadRomVer(s) =>
+				write!(f, "Invalid ROMVER '{}'", s),
+		}
+	}
+}
+
+impl std::error::Error for RomDirError {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::super::PS2_BIOS_SIZE;
+	use super::*;
+
+	/// EXTINFO record of ROMVER: date 2001-07-04 and version 1.60
+	const ROMVER_EXTINFO: [u8; 12] = [
+		0x00, 0x00, 0x04, 0x01, 0x04, 0x07, 0x01, 0x20,
+		0x60, 0x01, 0x00, 0x02,
+	];
+
+	/// Build a ROMDIR entry
+	fn entry(name: &str, ext_size: u16, size: u32) -> [u8; ENTRY_SIZE] {
+		let mut e = [0; ENTRY_SIZE];
+
+		e[..name.len()].copy_from_slice(name.as_bytes());
+		e[10..12].copy_from_slice(&ext_size.to_le_bytes());
+		e[12..16].copy_from_slice(&size.to_le_bytes());
+
+		e
+	}
+
+	/// Build a PS2 image with a RESET module, the ROMDIR, EXTINFO and
+	/// a ROMVER module containing `romver`
+	pub(crate) fn image(romver: &str) -> Vec<u8> {
+		let mut rom = vec![0; PS2_BIOS_SIZE];
+
+		let dir = [
+			entry("RESET", 0, 0x100),
+			entry("ROMDIR", 0, 5 * ENTRY_SIZE as u32),
+			entry("EXTINFO", 0, ROMVER_EXTINFO.len() as u32),
+			entry("ROMVER", ROMVER_EXTINFO.len() as u16, 16),
+			[0; ENTRY_SIZE],
+		];
+
+		// RESET is 0x100 bytes long, the directory follows
+		for (i, e) in dir.iter().enumerate() {
+			let o = 0x100 + i * ENTRY_SIZE;
+
+			rom[o..o + ENTRY_SIZE].copy_from_slice(e);
+		}
+
+		rom[0x150..0x15c].copy_from_slice(&ROMVER_EXTINFO);
+		rom[0x160..0x160 + romver.len()].copy_from_slice(romver.as_bytes());
+
+		rom
+	}
+
+	#[test]
+	fn parse_directory() {
+		let rom = image("0160EC20010704");
+
+		let dir = RomDir::parse(&rom).unwrap();
+
+		let names: Vec<_> = dir.modules.iter()
+			.map(|m| (m.name.as_str(), m.offset, m.size))
+			.collect();
+
+		assert_eq!(names, [
+			("RESET", 0, 0x100),
+			("ROMDIR", 0x100, 0x50),
+			("EXTINFO", 0x150, 12),
+			("ROMVER", 0x160, 16),
+		]);
+
+		let romver = dir.find("ROMVER").unwrap();
+
+		assert_eq!(romver.ext_info, ExtInfo {
+			date: Some((2001, 7, 4)),
+			version: Some(0x0160),
+			comment: None,
+			fixed: false,
+		});
+		assert_eq!(dir.find("RESET").unwrap().ext_info, ExtInfo::default());
+		assert_eq!(dir.data(&rom, "ROMVER").unwrap(), b"0160EC20010704\0\0");
+
+		assert_eq!(dir.romver(&rom).unwrap(), RomVer {
+			major: 1,
+			minor: 60,
+			region: Region::Europe,
+			model: Model::Retail,
+			date: (2001, 7, 4),
+		});
+	}
+
+	#[test]
+	fn missing_reset() {
+		let mut rom = image("0160EC20010704");
+
+		rom[0x100..0x105].copy_from_slice(b"RESEX");
+
+		assert!(matches!(RomDir::parse(&rom), Err(RomDirError::NotFound)));
+		assert!(matches!(RomDir::parse(&[]), Err(RomDirError::NotFound)));
+	}
+
+	#[test]
+	fn truncated() {
+		let rom = image("0160EC20010704");
+
+		// The ROMVER module ends past the end of the image
+		assert!(matches!(RomDir::parse(&rom[..0x168]),
+						 Err(RomDirError::Truncated(ref m)) if m == "ROMVER"));
+
+		// EXTINFO record larger than the EXTINFO module
+		let mut rom = image("0160EC20010704");
+
+		rom[0x13a] = 13;
+
+		assert!(matches!(RomDir::parse(&rom),
+						 Err(RomDirError::BadExtInfo(ref m)) if m == "ROMVER"));
+	}
+
+	#[test]
+	fn missing_romver() {
+		let mut rom = image("0160EC20010704");
+
+		rom[0x130..0x136].copy_from_slice(b"ROMVEX");
+
+		let dir = RomDir::parse(&rom).unwrap();
+
+		assert!(matches!(dir.romver(&rom), Err(RomDirError::MissingModule(_))));
+	}
+
+	#[test]
+	fn parse_romver() {
+		let parse = |s| RomVer::parse(s).map(|r| (r.major, r.minor, r.region, r.model, r.date));
+
+		assert_eq!(parse("0160EC20010704"),
+				   Some((1, 60, Region::Europe, Model::Retail, (2001, 7, 4))));
+		assert_eq!(parse("0220JD20060905xx"),
+				   Some((2, 20, Region::Japan, Model::Debug, (2006, 9, 5))));
+		assert_eq!(parse("0100AC20000117"),
+				   Some((1, 0, Region::NorthAmerica, Model::Retail, (2000, 1, 17))));
+		assert_eq!(parse("0170HC20030325"),
+				   Some((1, 70, Region::Asia, Model::Retail, (2003, 3, 25))));
+		assert_eq!(parse("0190CC20040614"),
+				   Some((1, 90, Region::China, Model::Retail, (2004, 6, 14))));
+		// TOOL images are Japanese, whatever the model letter
+		assert_eq!(parse("0150TZ20011210"),
+				   Some((1, 50, Region::Japan, Model::Tool, (2001, 12, 10))));
+	}
+
+	#[test]
+	fn parse_bad_romver() {
+		for s in [
+			"",
+			"0160EC2001070",
+			"0160XC20010704",
+			"0160EX20010704",
+			"01a0EC20010704",
+			"0160EC2001O704",
+			// Multi-byte characters in the first 14 bytes
+			"0\u{e9}60EC20010704xx",
+			"0160EC2001070\u{e9}",
+			"\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}",
+		] {
+			assert!(RomVer::parse(s).is_none(), "{:?}", s);
+		}
+
+		// Non-ASCII trailing data is ignored
+		assert!(RomVer::parse("0160EC20010704\u{e9}").is_some());
+	}
+}
@@ -0,0 +1,248 @@
+//! Scan directories for BIOS dumps and select the best one for a given
+//! region
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cdrom::disk::Region;
+
+use super::db::{self, Metadata};
+use super::romdir::{Model, RomDir, RomVer};
+use super::{Bios, BiosError, BIOS_SIZE, PS2_BIOS_SIZE};
+
+/// A recognised BIOS image
+pub struct Entry {
+	pub path: PathBuf,
+	pub metadata: &'static Metadata,
+	/// Decoded ROMVER of PS2 images
+	pub romver: Option<RomVer>,
+}
+
+/// Result of a directory scan
+#[derive(Default)]
+pub struct Inventory {
+	/// Recognised images, one per distinct dump. PS2 images are
+	/// identified by their ROMVER and have unverified metadata.
+	pub entries: Vec<Entry>,
+	/// Files that are copies of an image already present in `entries`,
+	/// alongside the path of the original
+	pub duplicates: Vec<(PathBuf, PathBuf)>,
+	/// Files with the size of a BIOS image but a checksum missing from
+	/// the database and no ROMVER to identify them
+	pub unknown: Vec<(PathBuf, [u8; 32])>,
+}
+
+impl Inventory {
+	/// Hash every file in `dir` (not recursively) with the size of a
+	/// PS1 or PS2 BIOS and build the inventory. Files of any other size
+	/// are ignored.
+	pub fn scan<P: AsRef<Path>>(dir: P) -> io::Result<Inventory> {
+		let mut paths = Vec::new();
+
+		for entry in fs::read_dir(dir)? {
+			paths.push(entry?.path());
+		}
+
+		// Make the result independent of the directory iteration order
+		paths.sort();
+
+		let mut inventory = Inventory::default();
+
+		for path in paths {
+			// Follows symlinks, a broken one only skips that file
+			let metadata =
+				match fs::metadata(&path) {
+					Ok(m) => m,
+					Err(e) => {
+						warn!("Can't stat {}: {}", path.display(), e);
+						continue;
+					}
+				};
+
+			let len = metadata.len() as usize;
+
+			if !metadata.is_file() || (len != BIOS_SIZE && len != PS2_BIOS_SIZE) {
+				continue;
+			}
+
+			match fs::read(&path) {
+				Ok(data) => inventory.add(path, &data),
+				Err(e) => warn!("Can't read {}: {}", path.display(), e),
+			}
+		}
+
+		Ok(inventory)
+	}
+
+	fn add(&mut self, path: PathBuf, binary: &[u8]) {
+		let sha256 = db::sha256(binary);
+
+		let romver = RomDir::parse(binary)
+			.and_then(|d| d.romver(binary))
+			.ok();
+
+		let metadata =
+			match (db::lookup_sha256(&sha256), romver) {
+				(Some(m), _) => m,
+				// There's no database of PS2 dumps
+				(None, Some(_)) => super::guessed_metadata(&sha256, binary),
+				(None, None) => {
+					self.unknown.push((path, sha256));
+					return;
+				}
+			};
+
+		let original = self.entries.iter()
+			.find(|e| std::ptr::eq(e.metadata, metadata));
+
+		match original {
+			Some(e) => self.duplicates.push((path, e.path.clone())),
+			None => self.entries.push(Entry { path, metadata, romver }),
+		}
+	}
+
+	/// Return the preferred image for `region`: the highest verified
+	/// version that isn't a known bad dump
+	pub fn best_for_region(&self, region: Region) -> Option<&Entry> {
+		self.entries.iter()
+			.filter(|e| e.metadata.verified && !e.metadata.known_bad)
+			.filter(|e| e.metadata.region == region)
+			.max_by_key(|e| (e.metadata.version_major, e.metadata.version_minor))
+	}
+
+	/// Return the preferred PS2 image for `region` and `model`: the
+	/// highest version, then the most recent build
+	pub fn best_for_model(&self, region: Region, model: Model) -> Option<&Entry> {
+		self.entries.iter()
+			.filter(|e| !e.metadata.known_bad)
+			.filter_map(|e| e.romver.map(|r| (e, r)))
+			.filter(|(_, r)| r.region == region && r.model == model)
+			.max_by_key(|(_, r)| (r.major, r.minor, r.date))
+			.map(|(e, _)| e)
+	}
+
+	/// Load the preferred image for `region`. Returns `None` if the
+	/// inventory doesn't contain any suitable image.
+	pub fn load_best(&self, region: Region) -> Option<Result<Bios, BiosError>> {
+		self.best_for_region(region)
+			.map(|e| Bios::from_path(&e.path, false))
+	}
+
+	/// Load the preferred PS2 image for `region` and `model`, see
+	/// `best_for_model`
+	pub fn load_best_for_model(&self,
+							   region: Region,
+							   model: Model) -> Option<Result<Bios, BiosError>> {
+		self.best_for_model(region, model)
+			.map(|e| Bios::from_path(&e.path, !e.metadata.verified))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::bios::romdir;
+
+	/// Empty directory for the test `name`, removed when dropped
+	struct TempDir(PathBuf);
+
+	impl TempDir {
+		fn new(name: &str) -> TempDir {
+			let path = std::env::temp_dir()
+				.join(format!("hvps2rs-{}-{}", name, std::process::id()));
+
+			let _ = fs::remove_dir_all(&path);
+			fs::create_dir_all(&path).unwrap();
+
+			TempDir(path)
+		}
+
+		fn write(&self, name: &str, data: &[u8]) -> PathBuf {
+			let path = self.0.join(name);
+
+			fs::write(&path, data).unwrap();
+
+			path
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn scan_directory() {
+		let dir = TempDir::new("scan");
+
+		let ps1 = dir.write("unknown.bin", &vec![0x55; BIOS_SIZE]);
+		let ps2 = dir.write("unknown-ps2.bin", &vec![0xaa; PS2_BIOS_SIZE]);
+		dir.write("short.bin", &vec![0x55; BIOS_SIZE - 1]);
+		dir.write("readme.txt", b"not a BIOS");
+		fs::create_dir(dir.0.join("subdir")).unwrap();
+
+		#[cfg(unix)]
+		std::os::unix::fs::symlink(dir.0.join("missing.bin"), dir.0.join("broken.bin"))
+			.unwrap();
+
+		let inventory = Inventory::scan(&dir.0).unwrap();
+
+		assert!(inventory.entries.is_empty());
+		assert!(inventory.duplicates.is_empty());
+
+		let unknown: Vec<_> = inventory.unknown.iter()
+			.map(|(p, sha256)| (p.clone(), *sha256))
+			.collect();
+
+		// Sorted by path
+		assert_eq!(unknown, [
+			(ps2, db::sha256(&vec![0xaa; PS2_BIOS_SIZE])),
+			(ps1, db::sha256(&vec![0x55; BIOS_SIZE])),
+		]);
+	}
+
+	#[test]
+	fn scan_missing_directory() {
+		let dir = TempDir::new("scan-missing");
+
+		assert!(Inventory::scan(dir.0.join("missing")).is_err());
+	}
+
+	#[test]
+	fn scan_ps2_images() {
+		let dir = TempDir::new("scan-ps2");
+
+		let old = dir.write("a.bin", &romdir::tests::image("0160EC20010704"));
+		let new = dir.write("b.bin", &romdir::tests::image("0170EC20030227"));
+		let copy = dir.write("c.bin", &romdir::tests::image("0170EC20030227"));
+		let debug = dir.write("d.bin", &romdir::tests::image("0190ED20040614"));
+
+		let inventory = Inventory::scan(&dir.0).unwrap();
+
+		assert!(inventory.unknown.is_empty());
+		assert_eq!(inventory.duplicates, [(copy, new.clone())]);
+
+		let paths: Vec<_> = inventory.entries.iter().map(|e| &e.path).collect();
+		assert_eq!(paths, [&old, &new, &debug]);
+
+		let best = inventory.best_for_model(Region::Europe, Model::Retail).unwrap();
+		assert_eq!(best.path, new);
+		assert!(!best.metadata.verified);
+		assert_eq!((best.metadata.version_major, best.metadata.version_minor), (1, 70));
+
+		let best_debug = inventory.best_for_model(Region::Europe, Model::Debug).unwrap();
+		assert_eq!(best_debug.path, debug);
+
+		assert!(inventory.best_for_model(Region::Japan, Model::Retail).is_none());
+
+		// Guessed metadata is never picked by region alone
+		assert!(inventory.best_for_region(Region::Europe).is_none());
+
+		let bios = inventory.load_best_for_model(Region::Europe, Model::Retail)
+			.unwrap()
+			.unwrap();
+		assert!(std::ptr::eq(bios.metadata(), best.metadata));
+	}
+}
@@ -0,0 +1,663 @@
+//! Tracing of the calls to the BIOS kernel functions
+//!
+//! Kernel functions are called by jumping to 0xA0, 0xB0 or 0xC0 with
+//! the function number in $t1. When a `Tracer` is installed in the CPU
+//! every call is decoded using the signature tables below and either
+//! logged or passed to a callback.
+
+use std::fmt;
+
+use crate::cpu::Cpu;
+use crate::memory::{Byte, Word};
+
+use super::hle::{self, Vector};
+
+/// How a kernel function argument should be displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+	/// Signed integer
+	Int,
+	/// Hexadecimal value (flags, handles...)
+	Hex,
+	/// Pointer
+	Ptr,
+	/// Pointer to a NUL-terminated string
+	Str,
+	/// Character
+	Char,
+	/// printf format string, the remaining arguments are consumed
+	/// according to its contents
+	Format,
+}
+
+/// Name and arguments of a kernel function
+pub struct Signature {
+	pub name: &'static str,
+	pub args: &'static [Arg],
+}
+
+/// Return the signature of `function` in table `vector`, if known
+pub fn signature(vector: Vector, function: u32) -> Option<&'static Signature> {
+	let table: &[(u8, Signature)] =
+		match vector {
+			Vector::A0 => &A0_TABLE,
+			Vector::B0 => &B0_TABLE,
+			Vector::C0 => &C0_TABLE,
+		};
+
+	table.iter()
+		.find(|&&(f, _)| f as u32 == function)
+		.map(|(_, s)| s)
+}
+
+/// A decoded kernel call
+pub struct KernelCall {
+	pub vector: Vector,
+	pub function: u32,
+	/// Address the function will return to
+	pub caller: u32,
+	pub signature: Option<&'static Signature>,
+	/// Formatted arguments
+	pub args: Vec<String>,
+}
+
+impl KernelCall {
+	/// Decode the call about to be made by `cpu`
+	fn decode(cpu: &mut Cpu, vector: Vector) -> KernelCall {
+		// Only the low byte is used by the kernel dispatchers
+		let function = cpu.reg(9) & 0xff;
+		let signature = signature(vector, function);
+
+		let mut args = Vec::new();
+
+		if let Some(sig) = signature {
+			for (i, &arg) in sig.args.iter().enumerate() {
+				let v =
+					match peek_arg(cpu, i as u32) {
+						Ok(v) => v,
+						Err(addr) => {
+							args.push(invalid(addr));
+							continue;
+						}
+					};
+
+				let s =
+					match arg {
+						Arg::Int => format!("{}", v as i32),
+						Arg::Hex => format!("0x{:x}", v),
+						Arg::Ptr => format!("0x{:08x}", v),
+						Arg::Str =>
+							match peek_string(cpu, v) {
+								Some(s) => quote(&s),
+								None => invalid(v),
+							},
+						Arg::Char => quote_char(v as u8),
+						Arg::Format => {
+							let fmt =
+								match peek_string(cpu, v) {
+									Some(s) => s,
+									None => {
+										args.push(invalid(v));
+										continue;
+									}
+								};
+
+							let mut n = i as u32 + 1;
+							let out = hle::format(cpu, &fmt, &mut |cpu| {
+								let v = peek_arg(cpu, n).unwrap_or(0);
+								n += 1;
+								v
+							});
+
+							format!("{} -> {}", quote(&fmt), quote(&out))
+						}
+					};
+
+				args.push(s);
+			}
+		}
+
+		KernelCall {
+			vector,
+			function,
+			caller: cpu.reg(31),
+			signature,
+			args,
+		}
+	}
+}
+
+impl fmt::Display for KernelCall {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}:{:02X} ", self.vector, self.function)?;
+
+		match self.signature {
+			Some(s) => write!(f, "{}({})", s.name, self.args.join(", "))?,
+			None => write!(f, "<unknown>")?,
+		}
+
+		write!(f, " from 0x{:08x}", self.caller)
+	}
+}
+
+type Callback = Box<dyn FnMut(&KernelCall)>;
+
+/// Kernel call tracer
+pub struct Tracer {
+	/// Calls for which tracing is enabled, indexed by vector and
+	/// function number
+	enabled: [[bool; 0x100]; 3],
+	/// Receives the decoded calls. If `None` they're logged instead.
+	callback: Option<Callback>,
+}
+
+impl Tracer {
+	/// Create a tracer logging every call through the `log` facade
+	pub fn new() -> Tracer {
+		Tracer {
+			enabled: [[true; 0x100]; 3],
+			callback: None,
+		}
+	}
+
+	/// Create a tracer passing every call to `callback`
+	pub fn with_callback<F>(callback: F) -> Tracer
+		where F: FnMut(&KernelCall) + 'static
+	{
+		Tracer {
+			enabled: [[true; 0x100]; 3],
+			callback: Some(Box::new(callback)),
+		}
+	}
+
+	/// Enable or disable tracing for a single function
+	pub fn set_enabled(&mut self, vector: Vector, function: u8, enabled: bool) {
+		self.enabled[vector as usize][function as usize] = enabled;
+	}
+
+	/// Only trace the listed functions
+	pub fn trace_only(&mut self, functions: &[(Vector, u8)]) {
+		self.enabled = [[false; 0x100]; 3];
+
+		for &(v, f) in functions {
+			self.set_enabled(v, f, true);
+		}
+	}
+
+	/// Enable or disable tracing for every function whose name is
+	/// `name`. Returns false if no function has this name.
+	pub fn set_enabled_by_name(&mut self, name: &str, enabled: bool) -> bool {
+		let mut found = false;
+
+		let tables = [
+			(Vector::A0, &A0_TABLE[..]),
+			(Vector::B0, &B0_TABLE[..]),
+			(Vector::C0, &C0_TABLE[..]),
+		];
+
+		for (v, table) in tables {
+			for (f, s) in table {
+				if s.name == name {
+					self.set_enabled(v, *f, enabled);
+					found = true;
+				}
+			}
+		}
+
+		found
+	}
+
+	/// Called by the CPU when it's about to execute a kernel call
+	pub(crate) fn trace(&mut self, cpu: &mut Cpu, vector: Vector) {
+		let function = cpu.reg(9) & 0xff;
+
+		if !self.enabled[vector as usize][function as usize] {
+			return;
+		}
+
+		let call = KernelCall::decode(cpu, vector);
+
+		match self.callback {
+			Some(ref mut cb) => cb(&call),
+			None => debug!("{}", call),
+		}
+	}
+}
+
+impl Default for Tracer {
+	fn default() -> Tracer {
+		Tracer::new()
+	}
+}
+
+/// Read argument `n` of the current call like `hle::arg` but without
+/// side effects. Returns the address of the stack slot if it's not
+/// mapped.
+fn peek_arg(cpu: &Cpu, n: u32) -> Result<u32, u32> {
+	if n < 4 {
+		Ok(cpu.reg(4 + n))
+	} else {
+		let addr = cpu.reg(29).wrapping_add(n * 4);
+
+		cpu.interconnect().peek::<Word>(addr).ok_or(addr)
+	}
+}
+
+/// Read the NUL-terminated string at `addr` like `hle::read_string`.
+/// Returns `None` if it's not entirely in RAM or ROM.
+fn peek_string(cpu: &Cpu, addr: u32) -> Option<Vec<u8>> {
+	let mut s = Vec::new();
+
+	if addr == 0 {
+		return Some(s);
+	}
+
+	for i in 0..0x1000 {
+		match cpu.interconnect().peek::<Byte>(addr.wrapping_add(i))? {
+			0 => break,
+			b => s.push(b as u8),
+		}
+	}
+
+	Some(s)
+}
+
+fn invalid(addr: u32) -> String {
+	format!("<invalid 0x{:08x}>", addr)
+}
+
+fn quote(s: &[u8]) -> String {
+	let mut q = String::with_capacity(s.len() + 2);
+
+	q.push('"');
+	for &c in s {
+		q.extend(std::ascii::escape_default(c).map(char::from));
+	}
+	q.push('"');
+
+	q
+}
+
+fn quote_char(c: u8) -> String {
+	let escaped: String = std::ascii::escape_default(c).map(char::from).collect();
+
+	format!("'{}'", escaped)
+}
+
+macro_rules! sig {
+	($f:expr, $name:ident($($arg:ident),*)) => {
+		($f, Signature { name: stringify!($name), args: &[$(Arg::$arg),*] })
+	};
+}
+
+static A0_TABLE: [(u8, Signature); 145] = [
+	sig!(0x00, open(Str, Hex)),
+	sig!(0x01, lseek(Int, Int, Int)),
+	sig!(0x02, read(Int, Ptr, Int)),
+	sig!(0x03, write(Int, Ptr, Int)),
+	sig!(0x04, close(Int)),
+	sig!(0x05, ioctl(Int, Hex, Hex)),
+	sig!(0x06, exit(Int)),
+	sig!(0x07, isatty(Int)),
+	sig!(0x08, getc(Int)),
+	sig!(0x09, putc(Char, Int)),
+	sig!(0x0a, todigit(Char)),
+	sig!(0x0b, atof(Str)),
+	sig!(0x0c, strtoul(Str, Ptr, Int)),
+	sig!(0x0d, strtol(Str, Ptr, Int)),
+	sig!(0x0e, abs(Int)),
+	sig!(0x0f, labs(Int)),
+	sig!(0x10, atoi(Str)),
+	sig!(0x11, atol(Str)),
+	sig!(0x12, atob(Str, Ptr)),
+	sig!(0x13, setjmp(Ptr)),
+	sig!(0x14, longjmp(Ptr, Int)),
+	sig!(0x15, strcat(Ptr, Str)),
+	sig!(0x16, strncat(Ptr, Str, Int)),
+	sig!(0x17, strcmp(Str, Str)),
+	sig!(0x18, strncmp(Str, Str, Int)),
+	sig!(0x19, strcpy(Ptr, Str)),
+	sig!(0x1a, strncpy(Ptr, Str, Int)),
+	sig!(0x1b, strlen(Str)),
+	sig!(0x1c, index(Str, Char)),
+	sig!(0x1d, rindex(Str, Char)),
+	sig!(0x1e, strchr(Str, Char)),
+	sig!(0x1f, strrchr(Str, Char)),
+	sig!(0x20, strpbrk(Str, Str)),
+	sig!(0x21, strspn(Str, Str)),
+	sig!(0x22, strcspn(Str, Str)),
+	sig!(0x23, strtok(Str, Str)),
+	sig!(0x24, strstr(Str, Str)),
+	sig!(0x25, toupper(Char)),
+	sig!(0x26, tolower(Char)),
+	sig!(0x27, bcopy(Ptr, Ptr, Int)),
+	sig!(0x28, bzero(Ptr, Int)),
+	sig!(0x29, bcmp(Ptr, Ptr, Int)),
+	sig!(0x2a, memcpy(Ptr, Ptr, Int)),
+	sig!(0x2b, memset(Ptr, Hex, Int)),
+	sig!(0x2c, memmove(Ptr, Ptr, Int)),
+	sig!(0x2d, memcmp(Ptr, Ptr, Int)),
+	sig!(0x2e, memchr(Ptr, Char, Int)),
+	sig!(0x2f, rand()),
+	sig!(0x30, srand(Hex)),
+	sig!(0x31, qsort(Ptr, Int, Int, Ptr)),
+	sig!(0x32, strtod(Str, Ptr)),
+	sig!(0x33, malloc(Int)),
+	sig!(0x34, free(Ptr)),
+	sig!(0x35, lsearch(Ptr, Ptr, Ptr, Int, Ptr)),
+	sig!(0x36, bsearch(Ptr, Ptr, Int, Int, Ptr)),
+	sig!(0x37, calloc(Int, Int)),
+	sig!(0x38, realloc(Ptr, Int)),
+	sig!(0x39, InitHeap(Ptr, Int)),
+	sig!(0x3a, _exit(Int)),
+	sig!(0x3b, getchar()),
+	sig!(0x3c, putchar(Char)),
+	sig!(0x3d, gets(Ptr)),
+	sig!(0x3e, puts(Str)),
+	sig!(0x3f, printf(Format)),
+	sig!(0x40, SystemErrorUnresolvedException()),
+	sig!(0x41, LoadTest(Str, Ptr)),
+	sig!(0x42, Load(Str, Ptr)),
+	sig!(0x43, Exec(Ptr, Ptr, Int)),
+	sig!(0x44, FlushCache()),
+	sig!(0x45, init_a0_b0_c0_vectors()),
+	sig!(0x46, GPU_dw(Int, Int, Int, Int, Ptr)),
+	sig!(0x47, gpu_send_dma(Int, Int, Int, Int, Ptr)),
+	sig!(0x48, SendGP1Command(Hex)),
+	sig!(0x49, GPU_cw(Hex)),
+	sig!(0x4a, GPU_cwp(Ptr, Int)),
+	sig!(0x4b, send_gpu_linked_list(Ptr)),
+	sig!(0x4c, gpu_abort_dma()),
+	sig!(0x4d, GetGPUStatus()),
+	sig!(0x4e, gpu_sync()),
+	sig!(0x4f, SystemError()),
+	sig!(0x50, SystemError()),
+	sig!(0x51, LoadExec(Str, Ptr, Ptr)),
+	sig!(0x52, GetSysSp()),
+	sig!(0x53, SystemError()),
+	sig!(0x54, _96_init()),
+	sig!(0x55, _bu_init()),
+	sig!(0x56, _96_remove()),
+	sig!(0x57, return_0()),
+	sig!(0x58, return_0()),
+	sig!(0x59, return_0()),
+	sig!(0x5a, return_0()),
+	sig!(0x5b, dev_tty_init()),
+	sig!(0x5c, dev_tty_open(Ptr, Str, Hex)),
+	sig!(0x5d, dev_tty_in_out(Ptr, Hex)),
+	sig!(0x5e, dev_tty_ioctl(Ptr, Hex, Hex)),
+	sig!(0x5f, dev_cd_open(Ptr, Str, Hex)),
+	sig!(0x60, dev_cd_read(Ptr, Ptr, Int)),
+	sig!(0x61, dev_cd_close(Ptr)),
+	sig!(0x62, dev_cd_firstfile(Ptr, Str, Ptr)),
+	sig!(0x63, dev_cd_nextfile(Ptr, Ptr)),
+	sig!(0x64, dev_cd_chdir(Ptr, Str)),
+	sig!(0x65, dev_card_open(Ptr, Str, Hex)),
+	sig!(0x66, dev_card_read(Ptr, Ptr, Int)),
+	sig!(0x67, dev_card_write(Ptr, Ptr, Int)),
+	sig!(0x68, dev_card_close(Ptr)),
+	sig!(0x69, dev_card_firstfile(Ptr, Str, Ptr)),
+	sig!(0x6a, dev_card_nextfile(Ptr, Ptr)),
+	sig!(0x6b, dev_card_erase(Ptr, Str)),
+	sig!(0x6c, dev_card_undelete(Ptr, Str)),
+	sig!(0x6d, dev_card_format(Ptr)),
+	sig!(0x6e, dev_card_rename(Ptr, Str, Ptr, Str)),
+	sig!(0x70, _bu_init()),
+	sig!(0x71, _96_init()),
+	sig!(0x72, _96_remove()),
+	sig!(0x78, CdAsyncSeekL(Ptr)),
+	sig!(0x7c, CdAsyncGetStatus(Ptr)),
+	sig!(0x7e, CdAsyncReadSector(Int, Ptr, Hex)),
+	sig!(0x81, CdAsyncSetMode(Hex)),
+	sig!(0x90, CdromIoIrqFunc1()),
+	sig!(0x91, CdromDmaIrqFunc1()),
+	sig!(0x92, CdromIoIrqFunc2()),
+	sig!(0x93, CdromDmaIrqFunc2()),
+	sig!(0x94, CdromGetInt5errCode(Ptr, Ptr)),
+	sig!(0x95, CdInitSubFunc()),
+	sig!(0x96, AddCDROMDevice()),
+	sig!(0x97, AddMemCardDevice()),
+	sig!(0x98, AddDuartTtyDevice()),
+	sig!(0x99, AddDummyTtyDevice()),
+	sig!(0x9c, SetConf(Int, Int, Ptr)),
+	sig!(0x9d, GetConf(Ptr, Ptr, Ptr)),
+	sig!(0x9e, SetCdromIrqAutoAbort(Int, Int)),
+	sig!(0x9f, SetMemSize(Int)),
+	sig!(0xa0, WarmBoot()),
+	sig!(0xa1, SystemErrorBootOrDiskFailure(Char, Int)),
+	sig!(0xa2, EnqueueCdIntr()),
+	sig!(0xa3, DequeueCdIntr()),
+	sig!(0xa4, CdGetLbn(Str)),
+	sig!(0xa5, CdReadSector(Int, Int, Ptr)),
+	sig!(0xa6, CdGetStatus()),
+	sig!(0xab, _card_info(Int)),
+	sig!(0xac, _card_async_load_directory(Int)),
+	sig!(0xad, set_card_auto_format(Int)),
+	sig!(0xaf, card_write_test(Int)),
+	sig!(0xb2, ioabort_raw(Int)),
+	sig!(0xb4, GetSystemInfo(Int)),
+];
+
+static B0_TABLE: [(u8, Signature); 70] = [
+	sig!(0x00, alloc_kernel_memory(Int)),
+	sig!(0x01, free_kernel_memory(Ptr)),
+	sig!(0x02, init_timer(Int, Int, Hex)),
+	sig!(0x03, get_timer(Int)),
+	sig!(0x04, enable_timer_irq(Int)),
+	sig!(0x05, disable_timer_irq(Int)),
+	sig!(0x06, restart_timer(Int)),
+	sig!(0x07, DeliverEvent(Hex, Hex)),
+	sig!(0x08, OpenEvent(Hex, Hex, Hex, Ptr)),
+	sig!(0x09, CloseEvent(Hex)),
+	sig!(0x0a, WaitEvent(Hex)),
+	sig!(0x0b, TestEvent(Hex)),
+	sig!(0x0c, EnableEvent(Hex)),
+	sig!(0x0d, DisableEvent(Hex)),
+	sig!(0x0e, OpenThread(Ptr, Ptr, Ptr)),
+	sig!(0x0f, CloseThread(Hex)),
+	sig!(0x10, ChangeThread(Hex)),
+	sig!(0x11, jump_to_00000000h()),
+	sig!(0x12, InitPad(Ptr, Int, Ptr, Int)),
+	sig!(0x13, StartPad()),
+	sig!(0x14, StopPad()),
+	sig!(0x15, OutdatedPadInitAndStart(Hex, Ptr, Hex, Hex)),
+	sig!(0x16, OutdatedPadGetButtons()),
+	sig!(0x17, ReturnFromException()),
+	sig!(0x18, ResetEntryInt()),
+	sig!(0x19, HookEntryInt(Ptr)),
+	sig!(0x1a, SystemError()),
+	sig!(0x1b, SystemError()),
+	sig!(0x1c, SystemError()),
+	sig!(0x1d, SystemError()),
+	sig!(0x1e, SystemError()),
+	sig!(0x1f, SystemError()),
+	sig!(0x20, UnDeliverEvent(Hex, Hex)),
+	sig!(0x21, SystemError()),
+	sig!(0x22, SystemError()),
+	sig!(0x23, SystemError()),
+	sig!(0x2a, SystemError()),
+	sig!(0x2b, SystemError()),
+	sig!(0x32, open(Str, Hex)),
+	sig!(0x33, lseek(Int, Int, Int)),
+	sig!(0x34, read(Int, Ptr, Int)),
+	sig!(0x35, write(Int, Ptr, Int)),
+	sig!(0x36, close(Int)),
+	sig!(0x37, ioctl(Int, Hex, Hex)),
+	sig!(0x38, exit(Int)),
+	sig!(0x39, isatty(Int)),
+	sig!(0x3a, getc(Int)),
+	sig!(0x3b, putc(Char, Int)),
+	sig!(0x3c, getchar()),
+	sig!(0x3d, putchar(Char)),
+	sig!(0x3e, gets(Ptr)),
+	sig!(0x3f, puts(Str)),
+	sig!(0x40, cd(Str)),
+	sig!(0x41, format(Str)),
+	sig!(0x42, firstfile(Str, Ptr)),
+	sig!(0x43, nextfile(Ptr)),
+	sig!(0x44, rename(Str, Str)),
+	sig!(0x45, erase(Str)),
+	sig!(0x46, undelete(Str)),
+	sig!(0x47, AddDrv(Ptr)),
+	sig!(0x48, DelDrv(Str)),
+	sig!(0x49, PrintInstalledDevices()),
+	sig!(0x4a, InitCard(Int)),
+	sig!(0x4b, StartCard()),
+	sig!(0x4c, StopCard()),
+	sig!(0x4e, write_card_sector(Int, Int, Ptr)),
+	sig!(0x4f, read_card_sector(Int, Int, Ptr)),
+	sig!(0x56, GetC0Table()),
+	sig!(0x57, GetB0Table()),
+	sig!(0x5b, ChangeClearPad(Int)),
+];
+
+static C0_TABLE: [(u8, Signature); 26] = [
+	sig!(0x00, EnqueueTimerAndVblankIrqs(Int)),
+	sig!(0x01, EnqueueSyscallHandler(Int)),
+	sig!(0x02, SysEnqIntRP(Int, Ptr)),
+	sig!(0x03, SysDeqIntRP(Int, Ptr)),
+	sig!(0x04, get_free_EvCB_slot()),
+	sig!(0x05, get_free_TCB_slot()),
+	sig!(0x06, ExceptionHandler()),
+	sig!(0x07, InstallExceptionHandlers()),
+	sig!(0x08, SysInitMemory(Ptr, Int)),
+	sig!(0x09, SysInitKernelVariables()),
+	sig!(0x0a, ChangeClearRCnt(Int, Int)),
+	sig!(0x0b, SystemError()),
+	sig!(0x0c, InitDefInt(Int)),
+	sig!(0x0d, SetIrqAutoAck(Int, Int)),
+	sig!(0x0e, return_0()),
+	sig!(0x0f, return_0()),
+	sig!(0x10, return_0()),
+	sig!(0x11, return_0()),
+	sig!(0x12, InstallDevices(Int)),
+	sig!(0x13, FlushStdInOutPut()),
+	sig!(0x15, tty_cdevinput(Ptr, Char)),
+	sig!(0x16, tty_cdevscan()),
+	sig!(0x17, tty_circgetc(Ptr)),
+	sig!(0x18, tty_circputc(Char, Ptr)),
+	sig!(0x19, ioabort(Str, Str)),
+	sig!(0x1c, AdjustA0Table()),
+];
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::bios::Bios;
+	use crate::memory::Interconnect;
+	use crate::savestate;
+
+	use super::*;
+
+	/// Not mapped to anything
+	const INVALID: u32 = 0x1f00_0000;
+
+	/// CPU with the dummy BIOS and a tracer recording every call
+	fn traced_cpu() -> (Cpu, Rc<RefCell<Vec<String>>>) {
+		let mut cpu = Cpu::new(Interconnect::new(Bios::dummy()));
+		let calls = Rc::new(RefCell::new(Vec::new()));
+
+		let c = calls.clone();
+		cpu.set_kernel_tracer(Some(Tracer::with_callback(move |call| {
+			c.borrow_mut().push(call.to_string());
+		})));
+
+		(cpu, calls)
+	}
+
+	fn write_string(cpu: &mut Cpu, addr: u32, s: &[u8]) {
+		for (i, &b) in s.iter().chain(&[0]).enumerate() {
+			cpu.interconnect_mut().store::<Byte>(addr + i as u32, b as u32);
+		}
+	}
+
+	/// Jump to `vector` with function `function` and arguments `args`
+	fn call(cpu: &mut Cpu, vector: u32, function: u32, args: &[u32]) {
+		cpu.set_reg(9, function);
+		cpu.set_reg(31, 0x8001_0000);
+
+		for (i, &a) in args.iter().enumerate() {
+			cpu.set_reg(4 + i as u32, a);
+		}
+
+		cpu.set_pc(vector);
+		cpu.run_next_instruction();
+	}
+
+	#[test]
+	fn signatures() {
+		let open = signature(Vector::A0, 0x00).unwrap();
+
+		assert_eq!(open.name, "open");
+		assert_eq!(open.args, [Arg::Str, Arg::Hex]);
+
+		assert_eq!(signature(Vector::A0, 0x3f).unwrap().args, [Arg::Format]);
+		assert_eq!(signature(Vector::B0, 0x3f).unwrap().name, "puts");
+		assert!(signature(Vector::C0, 0xff).is_none());
+
+		let mut tracer = Tracer::new();
+
+		assert!(tracer.set_enabled_by_name("puts", false));
+		assert!(!tracer.enabled[Vector::A0 as usize][0x3e]);
+		assert!(!tracer.enabled[Vector::B0 as usize][0x3f]);
+		assert!(!tracer.set_enabled_by_name("no_such_function", false));
+	}
+
+	#[test]
+	fn decode_arguments() {
+		let (mut cpu, calls) = traced_cpu();
+
+		write_string(&mut cpu, 0x1000, b"cdrom:\\SYSTEM.CNF;1");
+		write_string(&mut cpu, 0x1100, b"%d %c %s|");
+
+		call(&mut cpu, 0xa0, 0x00, &[0x1000, 1]);
+		call(&mut cpu, 0x800000a0, 0x3f, &[0x1100, -3i32 as u32, b'x' as u32, 0x1000]);
+		call(&mut cpu, 0xb0, 0x3d, &[b'\n' as u32]);
+		call(&mut cpu, 0xc0, 0xfe, &[]);
+
+		assert_eq!(*calls.borrow(), [
+			"A0:00 open(\"cdrom:\\\\SYSTEM.CNF;1\", 0x1) from 0x80010000",
+			"A0:3F printf(\"%d %c %s|\" -> \"-3 x cdrom:\\\\SYSTEM.CNF;1|\") from 0x80010000",
+			"B0:3D putchar('\\n') from 0x80010000",
+			"C0:FE <unknown> from 0x80010000",
+		]);
+	}
+
+	#[test]
+	fn invalid_pointers() {
+		let (mut cpu, calls) = traced_cpu();
+
+		write_string(&mut cpu, 0x1100, b"%s|");
+
+		call(&mut cpu, 0xa0, 0x00, &[INVALID, 0]);
+		call(&mut cpu, 0xa0, 0x3f, &[INVALID]);
+		// Invalid %s argument
+		call(&mut cpu, 0xa0, 0x3f, &[0x1100, INVALID]);
+		// String running into unmapped memory
+		cpu.interconnect_mut().store::<Word>(0x01ff_fffc, 0x6261_6261);
+		call(&mut cpu, 0xa0, 0x3e, &[0x01ff_fffc]);
+
+		assert_eq!(*calls.borrow(), [
+			"A0:00 open(<invalid 0x1f000000>, 0x0) from 0x80010000",
+			"A0:3F printf(<invalid 0x1f000000>) from 0x80010000",
+			"A0:3F printf(\"%s|\" -> \"|\") from 0x80010000",
+			"A0:3E puts(<invalid 0x01fffffc>) from 0x80010000",
+		]);
+	}
+
+	#[test]
+	fn tracer_survives_restore() {
+		let (mut cpu, calls) = traced_cpu();
+
+		let mut state = Vec::new();
+		savestate::save(&mut state, &cpu).unwrap();
+
+		savestate::restore(&state[..], &mut cpu).unwrap();
+
+		call(&mut cpu, 0xb0, 0x3d, &[b'a' as u32]);
+
+		assert_eq!(calls.borrow().len(), 1);
+	}
+}
@@ -1,4 +1,3 @@
-
 use serde::Serialize;
 use serde::Deserialize;
 
@@ -6,5 +5,10 @@ use serde::Deserialize;
 pub enum Region {
 	Japan,
 	NorthAmerica,
-	Europ,
-}
\ No newline at end of file
+	Europe,
+	/// Asian market outside of Japan (Hong Kong, Korea, Taiwan...).
+	/// Only used by PS2 BIOS images.
+	Asia,
+	/// Mainland China. Only used by PS2 BIOS images.
+	China,
+}
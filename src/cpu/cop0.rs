@@ -1,9 +1,15 @@
+use serde::{Deserialize, Serialize};
+
 use crate::interrupt::InterruptState;
+use crate::savestate::Component;
 
+#[derive(Serialize, Deserialize)]
 pub struct Cop0 {
 	sr: u32,
 	cause: u32,
 	epc: u32,
+	/// Address which caused the last address error
+	bad_vaddr: u32,
 }
 
 impl Cop0 {
@@ -12,6 +18,7 @@ impl Cop0 {
 			sr: 0,
 			cause: 0,
 			epc: 0,
+			bad_vaddr: 0,
 		}
 	}
 
@@ -36,21 +43,37 @@ impl Cop0 {
 		self.epc
 	}
 
+	pub fn bad_vaddr(&self) -> u32 {
+		self.bad_vaddr
+	}
+
+	pub fn set_bad_vaddr(&mut self, addr: u32) {
+		self.bad_vaddr = addr;
+	}
+
 	pub fn cache_isolated(&self) -> bool {
 		self.sr & 0x10000 != 0
 	}
 
+	/// Return true if coprocessor 2 (the GTE) is enabled in SR
+	pub fn cop2_enabled(&self) -> bool {
+		self.sr & (1 << 30) != 0
+	}
+
+	/// Enter the exception handler and return its address. `pc` is
+	/// the address of the faulting instruction, or of the branch if
+	/// it's in a delay slot.
 	pub fn enter_exeception(&mut self, cause: Exception, pc: u32, in_delay_slot: bool) -> u32 {
 		let mode = self.sr & 0x3F;
 		self.sr &= !0x3F;
 		self.sr |= (mode << 2) & 0x3F;
 		self.cause &= !0x7C;
 		self.cause |= (cause as u32) << 2;
+		self.epc = pc;
+
 		if in_delay_slot {
-			self.epc = pc.wrapping_sub(4);
 			self.cause |= 1 << 31;
 		} else {
-			self.epc = pc;
 			self.cause &= !(1 << 31);
 		}
 
@@ -60,6 +83,13 @@ impl Cop0 {
 		}
 	}
 
+	/// Set CAUSE.CE, the number of the coprocessor which raised a
+	/// coprocessor unusable exception
+	pub fn set_coprocessor_error(&mut self, cop: u32) {
+		self.cause &= !(3 << 28);
+		self.cause |= (cop & 3) << 28;
+	}
+
 	pub fn return_from_exception(&mut self) {
 		let mode = self.sr & 0x3F;
 		self.sr &= !0xF;
@@ -77,6 +107,18 @@ impl Cop0 {
 	}
 }
 
+impl Default for Cop0 {
+	fn default() -> Cop0 {
+		Cop0::new()
+	}
+}
+
+impl Component for Cop0 {
+	const SECTION: &'static str = "cop0";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Exception {
 	Interrupt = 0x0,
@@ -0,0 +1,475 @@
+//! Disassembler
+//!
+//! Decode instruction words into the assembler's `syntax::Instruction`
+//! so that they can be printed or re-assembled. Words which don't
+//! decode to an instruction the assembler would generate identically
+//! (reserved bits set, unsupported opcodes...) are returned as a `Word`
+//! directive.
+
+use crate::assembler::syntax::{self, Label, Register};
+use crate::assembler::syntax::Instruction::*;
+
+use super::{Instruction, RegisterIndex};
+
+/// Masks of the fields of an instruction word
+const S: u32 = 0x1f << 21;
+const T: u32 = 0x1f << 16;
+const D: u32 = 0x1f << 11;
+const SHIFT: u32 = 0x1f << 6;
+
+/// Variable fields of the MVMVA command: sf, mx, v, cv and lm
+const MVMVA_FIELDS: u32 = 1 << 19 | 3 << 17 | 3 << 15 | 3 << 13 | 1 << 10;
+
+/// GTE commands without operands, with the encoding used by the
+/// assembler
+static GTE_COMMANDS: [(u32, syntax::Instruction<'static>); 17] = [
+	(0x0180001, Rtps),
+	(0x0280030, Rtpt),
+	(0x1400006, Nclip),
+	(0x0780010, Dpcs),
+	(0x0980011, Intpl),
+	(0x0e80413, Ncds),
+	(0x1280414, Cdp),
+	(0x0f80416, Ncdt),
+	(0x108041b, Nccs),
+	(0x138041c, Cc),
+	(0x0c8041e, Ncs),
+	(0x0d80420, Nct),
+	(0x0680029, Dcpl),
+	(0x0f8002a, Dpct),
+	(0x158002d, Avsz3),
+	(0x168002e, Avsz4),
+	(0x118043f, Ncct),
+];
+
+/// Decode `instruction` located at address `pc`. Branch and jump
+/// targets are returned as absolute addresses.
+pub fn disassemble(instruction: Instruction, pc: u32) -> syntax::Instruction<'static> {
+	decode(instruction, pc).unwrap_or(Word(instruction.0))
+}
+
+/// Decode all the words in `code`, loaded at `base`
+pub fn disassemble_block(code: &[u8], base: u32) -> Vec<(u32, syntax::Instruction<'static>)> {
+	code.chunks_exact(4)
+		.enumerate()
+		.map(|(n, w)| {
+			let pc = base.wrapping_add(n as u32 * 4);
+			let word = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+
+			(pc, disassemble(Instruction(word), pc))
+		})
+		.collect()
+}
+
+fn decode(instruction: Instruction, pc: u32) -> Option<syntax::Instruction<'static>> {
+	let op = instruction.0;
+
+	let s = reg(instruction.s());
+	let t = reg(instruction.t());
+	let d = reg(instruction.d());
+	let imm = instruction.imm() as u16;
+	let imm_se = instruction.imm() as i16;
+	let shift = instruction.shift() as u8;
+
+	// Fields which must be zero for the decoding to be exact
+	let zero = |mask: u32| op & mask == 0;
+
+	// Branch target, relative to the delay slot
+	let branch = Label::Absolute(pc.wrapping_add(4)
+								 .wrapping_add((instruction.imm_se()) << 2));
+
+	let i =
+		match instruction.function() {
+			0b000000 =>
+				match instruction.subfunction() {
+					0b000000 if op == 0 => Nop,
+					0b000000 if zero(S) => Sll(d, t, shift),
+					0b000010 if zero(S) => Srl(d, t, shift),
+					0b000011 if zero(S) => Sra(d, t, shift),
+					0b000100 if zero(SHIFT) => Sllv(d, t, s),
+					0b000110 if zero(SHIFT) => Srlv(d, t, s),
+					0b000111 if zero(SHIFT) => Srav(d, t, s),
+					0b001000 if zero(T | D | SHIFT) => Jr(s),
+					0b001001 if zero(T | SHIFT) => Jalr(d, s),
+					0b001100 => Syscall((op >> 6) & 0xf_ffff),
+					0b001101 => Break((op >> 6) & 0xf_ffff),
+					0b010000 if zero(S | T | SHIFT) => Mfhi(d),
+					0b010001 if zero(T | D | SHIFT) => Mthi(s),
+					0b010010 if zero(S | T | SHIFT) => Mflo(d),
+					0b010011 if zero(T | D | SHIFT) => Mtlo(s),
+					0b011000 if zero(D | SHIFT) => Mult(s, t),
+					0b011001 if zero(D | SHIFT) => Multu(s, t),
+					0b011010 if zero(D | SHIFT) => Div(s, t),
+					0b011011 if zero(D | SHIFT) => Divu(s, t),
+					0b100001 if zero(SHIFT) && t.0 == 0 => Move(d, s),
+					0b100000 if zero(SHIFT) => Add(d, s, t),
+					0b100001 if zero(SHIFT) => Addu(d, s, t),
+					0b100010 if zero(SHIFT) => Sub(d, s, t),
+					0b100011 if zero(SHIFT) => Subu(d, s, t),
+					0b100100 if zero(SHIFT) => And(d, s, t),
+					0b100101 if zero(SHIFT) => Or(d, s, t),
+					0b100110 if zero(SHIFT) => Xor(d, s, t),
+					0b100111 if zero(SHIFT) => Nor(d, s, t),
+					0b101010 if zero(SHIFT) => Slt(d, s, t),
+					0b101011 if zero(SHIFT) => Sltu(d, s, t),
+					_ => return None,
+				},
+			0b000001 =>
+				match (op >> 16) & 0x1f {
+					0b00000 => Bltz(s, branch),
+					0b00001 => Bgez(s, branch),
+					0b10000 => Bltzal(s, branch),
+					0b10001 => Bgezal(s, branch),
+					// The CPU decodes the other values as BLTZ/BGEZ but
+					// the assembler never generates them
+					_ => return None,
+				},
+			0b000010 | 0b000011 => {
+				let target = (pc.wrapping_add(4) & 0xf000_0000) | (instruction.imm_jump() << 2);
+				let target = Label::Absolute(target);
+
+				if instruction.function() == 0b000010 {
+					J(target)
+				} else {
+					Jal(target)
+				}
+			}
+			0b000100 if s.0 == 0 && t.0 == 0 => B(branch),
+			0b000100 if t.0 == 0 => Beqz(s, branch),
+			0b000100 => Beq(s, t, branch),
+			0b000101 if t.0 == 0 => Bnez(s, branch),
+			0b000101 => Bne(s, t, branch),
+			0b000110 if zero(T) => Blez(s, branch),
+			0b000111 if zero(T) => Bgtz(s, branch),
+			0b001000 => Addi(t, s, imm_se),
+			0b001001 => Addiu(t, s, imm_se),
+			0b001010 => Slti(t, s, imm_se),
+			0b001011 => Sltiu(t, s, imm_se),
+			0b001100 => Andi(t, s, imm),
+			0b001101 => Ori(t, s, imm),
+			0b001110 => Xori(t, s, imm),
+			0b001111 if zero(S) => Lui(t, imm),
+			0b010000 => {
+				let cop_r = instruction.d().0 as u8;
+
+				match instruction.cop_opcode() {
+					0b00000 if zero(0x7ff) => Mfc0(t, cop_r),
+					0b00100 if zero(0x7ff) => Mtc0(t, cop_r),
+					0b10000 if op == 0x4200_0010 => Rfe,
+					_ => return None,
+				}
+			}
+			0b010010 => {
+				let cop_r = instruction.d().0 as u8;
+
+				match instruction.cop_opcode() {
+					0b00000 if zero(0x7ff) => Mfc2(t, cop_r),
+					0b00010 if zero(0x7ff) => Cfc2(t, cop_r),
+					0b00100 if zero(0x7ff) => Mtc2(t, cop_r),
+					0b00110 if zero(0x7ff) => Ctc2(t, cop_r),
+					o if o & 0x10 != 0 => gte_command(op & 0x1ff_ffff),
+					_ => return None,
+				}
+			}
+			0b100000 => Lb(t, s, imm_se),
+			0b100001 => Lh(t, s, imm_se),
+			0b100010 => Lwl(t, s, imm_se),
+			0b100011 => Lw(t, s, imm_se),
+			0b100100 => Lbu(t, s, imm_se),
+			0b100101 => Lhu(t, s, imm_se),
+			0b100110 => Lwr(t, s, imm_se),
+			0b101000 => Sb(t, s, imm_se),
+			0b101001 => Sh(t, s, imm_se),
+			0b101010 => Swl(t, s, imm_se),
+			0b101011 => Sw(t, s, imm_se),
+			0b101110 => Swr(t, s, imm_se),
+			0b110010 => Lwc2(t.0, s, imm_se),
+			0b111010 => Swc2(t.0, s, imm_se),
+			_ => return None,
+		};
+
+	Some(i)
+}
+
+/// Decode the GTE command in the low 25 bits of a COP2 instruction
+fn gte_command(command: u32) -> syntax::Instruction<'static> {
+	if let Some(&(_, i)) = GTE_COMMANDS.iter().find(|&&(c, _)| c == command) {
+		return i;
+	}
+
+	let sf = command & (1 << 19) != 0;
+
+	match command & !(1 << 19) {
+		0x170000c => return Op(sf),
+		0x0a00428 => return Sqr(sf),
+		0x190003d => return Gpf(sf),
+		0x1a0003e => return Gpl(sf),
+		_ => (),
+	}
+
+	if command & !MVMVA_FIELDS == 0x0400012 {
+		return Mvmva(sf,
+					 ((command >> 17) & 3) as u8,
+					 ((command >> 15) & 3) as u8,
+					 ((command >> 13) & 3) as u8,
+					 command & (1 << 10) != 0);
+	}
+
+	Cop2(command)
+}
+
+fn reg(r: RegisterIndex) -> Register {
+	Register(r.0 as u8)
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+
+	use crate::assembler::{parser, Assembler};
+	use crate::assembler::syntax::*;
+	use crate::assembler::syntax::Instruction;
+
+	use super::*;
+
+	/// Number of instructions generated by `build`
+	const VARIANTS: u32 = 103;
+
+	/// Every byte value, sliced to build `Ascii` directives
+	static BYTES: [u8; 256] = {
+		let mut b = [0; 256];
+		let mut i = 0;
+
+		while i < 256 {
+			b[i] = i as u8;
+			i += 1;
+		}
+
+		b
+	};
+
+	/// Operands used to build a random instruction
+	#[derive(Debug, Clone)]
+	struct Operands {
+		r: [u8; 3],
+		imm: u16,
+		code: u32,
+		small: [u8; 3],
+		flags: [bool; 2],
+		offset: i16,
+		target: u32,
+	}
+
+	fn operands() -> impl Strategy<Value = Operands> {
+		(any::<[u8; 3]>(),
+		 any::<u16>(),
+		 0..=0xf_ffffu32,
+		 any::<[u8; 3]>(),
+		 any::<[bool; 2]>(),
+		 any::<i16>(),
+		 0..(1u32 << 26))
+			.prop_map(|(r, imm, code, small, flags, offset, target)| Operands {
+				r,
+				imm,
+				code,
+				small,
+				flags,
+				offset,
+				target,
+			})
+	}
+
+	/// Build instruction number `n` of every instruction supported by
+	/// the assembler. The data directives are padded to a whole number
+	/// of words.
+	fn build(n: u32, o: &Operands, pc: u32) -> Vec<Instruction<'static>> {
+		let [a, b, c] = o.r.map(|r| Register(r & 0x1f));
+		let [cop_r, shift, mx] = o.small;
+		let (cop_r, shift, mx) = (cop_r & 0x1f, shift & 0x1f, mx & 3);
+		let [f0, f1] = o.flags;
+		let i = o.imm as i16;
+		let u = o.imm;
+
+		let branch = Label::Absolute(pc.wrapping_add(4).wrapping_add((o.offset as i32 as u32) << 2));
+		let jump = Label::Absolute((pc.wrapping_add(4) & 0xf000_0000) | (o.target << 2));
+		// Target of the branches expanded to two instructions
+		let branch2 = Label::Absolute(pc.wrapping_add(8).wrapping_add((o.offset as i32 as u32) << 2));
+
+		let i = match n {
+			0 => Sll(a, b, shift),
+			1 => Srl(a, b, shift),
+			2 => Sra(a, b, shift),
+			3 => Sllv(a, b, c),
+			4 => Srlv(a, b, c),
+			5 => Srav(a, b, c),
+			6 => Jr(a),
+			7 => Jalr(a, b),
+			8 => Syscall(o.code),
+			9 => Break(o.code),
+			10 => Mfhi(a),
+			11 => Mthi(a),
+			12 => Mflo(a),
+			13 => Mtlo(a),
+			14 => Mult(a, b),
+			15 => Multu(a, b),
+			16 => Div(a, b),
+			17 => Divu(a, b),
+			18 => Add(a, b, c),
+			19 => Addu(a, b, c),
+			20 => Sub(a, b, c),
+			21 => Subu(a, b, c),
+			22 => And(a, b, c),
+			23 => Or(a, b, c),
+			24 => Xor(a, b, c),
+			25 => Nor(a, b, c),
+			26 => Slt(a, b, c),
+			27 => Sltu(a, b, c),
+			28 => Bgez(a, branch),
+			29 => Bltz(a, branch),
+			30 => Bgezal(a, branch),
+			31 => Bltzal(a, branch),
+			32 => J(jump),
+			33 => Jal(jump),
+			34 => Beq(a, b, branch),
+			35 => Bne(a, b, branch),
+			36 => Blez(a, branch),
+			37 => Bgtz(a, branch),
+			38 => Addi(a, b, i),
+			39 => Addiu(a, b, i),
+			40 => Slti(a, b, i),
+			41 => Sltiu(a, b, i),
+			42 => Andi(a, b, u),
+			43 => Ori(a, b, u),
+			44 => Xori(a, b, u),
+			45 => Lui(a, u),
+			46 => Lb(a, b, i),
+			47 => Lh(a, b, i),
+			48 => Lwl(a, b, i),
+			49 => Lw(a, b, i),
+			50 => Lbu(a, b, i),
+			51 => Lhu(a, b, i),
+			52 => Lwr(a, b, i),
+			53 => Sb(a, b, i),
+			54 => Sh(a, b, i),
+			55 => Swl(a, b, i),
+			56 => Sw(a, b, i),
+			57 => Swr(a, b, i),
+			58 => Mfc0(a, cop_r),
+			59 => Mtc0(a, cop_r),
+			60 => Rfe,
+			61 => Mfc2(a, cop_r),
+			62 => Cfc2(a, cop_r),
+			63 => Mtc2(a, cop_r),
+			64 => Ctc2(a, cop_r),
+			65 => Lwc2(cop_r, b, i),
+			66 => Swc2(cop_r, b, i),
+			67 => Rtps,
+			68 => Rtpt,
+			69 => Nclip,
+			70 => Op(f0),
+			71 => Dpcs,
+			72 => Intpl,
+			73 => Mvmva(f0, mx, shift & 3, cop_r & 3, f1),
+			74 => Ncds,
+			75 => Cdp,
+			76 => Ncdt,
+			77 => Nccs,
+			78 => Cc,
+			79 => Ncs,
+			80 => Nct,
+			81 => Sqr(f0),
+			82 => Dcpl,
+			83 => Dpct,
+			84 => Avsz3,
+			85 => Avsz4,
+			86 => Gpf(f0),
+			87 => Gpl(f1),
+			88 => Ncct,
+			89 => Cop2(o.target & 0x1ff_ffff),
+			90 =>
+				match o.code % 8 {
+					0 => Nop,
+					1 => Move(a, b),
+					2 => Li(a, o.target.wrapping_mul(0x9e37_79b9)),
+					3 => La(a, jump),
+					4 => B(branch),
+					5 => Beqz(a, branch),
+					6 => Bnez(a, branch),
+					_ => Word(o.target.wrapping_mul(0x9e37_79b9)),
+				},
+			91 => Not(a, b),
+			92 => Neg(a, b),
+			93 => Mul(a, b, c),
+			94 => Push(a),
+			95 => Pop(a),
+			96 => Blt(a, b, branch2),
+			97 => Bge(a, b, branch2),
+			98 => Bgt(a, b, branch2),
+			99 => Ble(a, b, branch2),
+			100 => return vec![Half(u), Half(o.code as u16)],
+			101 => return o.target.to_le_bytes().map(Byte).to_vec(),
+			102 => {
+				let start = o.imm as usize % BYTES.len();
+				let len = (o.code as usize % 16).min(BYTES.len() - start);
+
+				let mut data = vec![Ascii(&BYTES[start..start + len])];
+
+				data.extend((len..len.next_multiple_of(4)).map(|_| Byte(0)));
+
+				return data;
+			}
+			_ => unreachable!(),
+		};
+
+		vec![i]
+	}
+
+	fn assemble(instructions: &[Instruction], pc: u32) -> Vec<u8> {
+		let mut asm = Assembler::from_base(pc);
+
+		asm.assemble(instructions).unwrap();
+
+		asm.machine_code().0
+	}
+
+	/// Disassemble `code` then assemble the result back, both directly
+	/// and through the textual representation
+	fn check_round_trip(code: &[u8], pc: u32) -> Result<(), TestCaseError> {
+		let decoded: Vec<_> = disassemble_block(code, pc)
+			.into_iter()
+			.map(|(_, i)| i)
+			.collect();
+
+		prop_assert_eq!(assemble(&decoded, pc), code, "{:?}", decoded);
+
+		let text: Vec<_> = decoded.iter().map(|i| i.to_string()).collect();
+		let text = text.join("\n");
+
+		let arena = parser::Arena::new();
+
+		let parsed = parser::parse(&arena, &text).map_err(|e| {
+			TestCaseError::fail(format!("{}\n{}", e, text))
+		})?;
+
+		prop_assert_eq!(assemble(&parsed, pc), code, "{}", text);
+
+		Ok(())
+	}
+
+	proptest! {
+		#[test]
+		fn assemble_disassemble_assemble(n in 0..VARIANTS,
+										 o in operands(),
+										 pc in (0x8000_0000u32..0x8020_0000).prop_map(|pc| pc & !3)) {
+			let code = assemble(&build(n, &o, pc), pc);
+
+			check_round_trip(&code, pc)?;
+		}
+
+		#[test]
+		fn any_word_round_trips(word in any::<u32>(),
+								pc in (0x8000_0000u32..0x8020_0000).prop_map(|pc| pc & !3)) {
+			check_round_trip(&word.to_le_bytes(), pc)?;
+		}
+	}
+}
@@ -1,6 +1,10 @@
 mod divider;
 
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+use crate::savestate::Component;
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Gte {
 	ofx: i32,
 	ofy: i32,
@@ -78,7 +82,7 @@ impl Gte {
 			0x3d => self.cmd_gpf(config),
 			0x3e => self.cmd_gpl(config),
 			0x3f => self.cmd_ncct(config),
-			_ => panic!("Unhandled GTE opcode {:02x}", opcode),
+			_ => warn!("Unhandled GTE opcode {:02x}", opcode),
 		}
 		let msb = self.flags & 0x7F87_E000 != 0;
 		self.flags |= (msb as u32) << 31;
@@ -1255,6 +1259,56 @@ impl Gte {
 	}
 }
 
+impl Default for Gte {
+	fn default() -> Gte {
+		Gte::new()
+	}
+}
+
+impl Component for Gte {
+	const SECTION: &'static str = "gte";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
+
+/// Latency assumed for commands not in the `command_cycles` table
+const DEFAULT_COMMAND_CYCLES: u32 = 8;
+
+/// Return the number of CPU cycles the GTE takes to execute `command`
+/// on real hardware. Results of the command can't be read back before
+/// this delay has elapsed.
+pub fn command_cycles(command: u32) -> u32 {
+	let opcode = command & 0x3F;
+
+	match opcode {
+		0x01 => 15, // RTPS
+		0x06 => 8,  // NCLIP
+		0x0c => 6,  // OP
+		0x10 => 8,  // DPCS
+		0x11 => 8,  // INTPL
+		0x12 => 8,  // MVMVA
+		0x13 => 19, // NCDS
+		0x14 => 13, // CDP
+		0x16 => 44, // NCDT
+		0x1b => 17, // NCCS
+		0x1c => 11, // CC
+		0x1e => 14, // NCS
+		0x20 => 30, // NCT
+		0x28 => 5,  // SQR
+		0x29 => 8,  // DCPL
+		0x2a => 17, // DPCT
+		0x2d => 5,  // AVSZ3
+		0x2e => 6,  // AVSZ4
+		0x30 => 23, // RTPT
+		0x3d => 5,  // GPF
+		0x3e => 5,  // GPL
+		0x3f => 39, // NCCT
+		_ => {
+			warn!("Unknown latency for GTE opcode {:02x}", opcode);
+			DEFAULT_COMMAND_CYCLES
+		}
+	}
+}
 
 #[derive(Clone, Copy)]
 struct CommandConfig {
@@ -1329,4 +1383,29 @@ impl ControlVector {
 	fn index(self) -> usize {
 		self as usize
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn command_latency() {
+		// RTPS, RTPT and NCCT
+		assert_eq!(command_cycles(0x0018_0001), 15);
+		assert_eq!(command_cycles(0x0028_0030), 23);
+		assert_eq!(command_cycles(0x00f8_043f), 39);
+		// Only the opcode matters, not the other fields
+		assert_eq!(command_cycles(0x0000_0030), 23);
+	}
+
+	#[test]
+	fn unknown_command() {
+		let mut gte = Gte::new();
+
+		// Ignored instead of bringing down the emulator
+		gte.command(0x00);
+
+		assert_eq!(command_cycles(0x00), DEFAULT_COMMAND_CYCLES);
+	}
+}
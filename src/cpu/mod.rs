@@ -1,8 +1,26 @@
 mod cop0;
 mod gte;
+pub mod disasm;
+#[cfg(test)]
+pub(crate) mod test_rom;
 use std::fmt::Display;
-use crate::{cpu::cop0::Cop0, cpu::gte::Gte, memory::Interconnect};
 
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
+use crate::{
+	bios::hle::{self, Kernel},
+	bios::trace::Tracer,
+	cpu::cop0::{Cop0, Exception},
+	cpu::gte::Gte,
+	memory::{Addressable, Byte, HalfWord, Interconnect, Word},
+	savestate::{self, Component, StateReader, StateWriter},
+};
+
+/// Address of the reset vector in the BIOS
+const RESET_VECTOR: u32 = 0xBFC0_0000;
+
+#[derive(Serialize, Deserialize)]
 pub struct Cpu {
 	///Program counter
 	pc: u32,
@@ -15,21 +33,1739 @@ pub struct Cpu {
 	///Low register
 	lo: u32,
 	///Instruction Cache (256 4-word cachelines)
-	icache: InstrCacheLines,
+	#[serde(with = "BigArray")]
+	icache: [InstrCacheLines; 0x100],
 	///Memory Interface
+	#[serde(skip)]
 	inter: Interconnect,
 	/// Coprocessor 0: System control
+	#[serde(skip)]
 	cop0: Cop0,
 	/// Coprocessor 2: Geometry Transform Engine
+	#[serde(skip)]
 	gte: Gte,
+	/// State of the high-level emulated kernel, only used when
+	/// running with `Bios::hle`
+	#[serde(skip)]
+	hle: Kernel,
+	/// Kernel call tracer, if enabled
+	#[serde(skip)]
+	tracer: Option<Tracer>,
+	/// Number of CPU cycles elapsed since reset
+	cycle_counter: u64,
+	/// Value of `cycle_counter` at which the GTE will be done
+	/// executing its current command
+	gte_ready: u64,
+	/// Pending load: target register and value. Loads are only
+	/// visible to the instruction following the delay slot.
 	load: (RegisterIndex, u32),
+	/// Pending GTE register write by MTC2, CTC2 or LWC2. Delayed like
+	/// the CPU loads.
+	gte_load: Option<(GteRegister, u32)>,
+	/// Set by the current instruction if it's a branch or jump, taken
+	/// or not: the next instruction will be in the delay slot
 	branch: bool,
+	/// Set if the current instruction executes in the delay slot
 	delay_slot: bool,
+	/// If true BREAK instructions are logged before the exception is
+	/// raised
 	debug_on_break: bool,
+	/// Address of the last branch or jump instruction. EPC points
+	/// there when an exception occurs in its delay slot.
+	branch_pc: u32,
+}
+
+impl Cpu {
+	pub fn new(inter: Interconnect) -> Cpu {
+		Cpu {
+			pc: RESET_VECTOR,
+			next_pc: RESET_VECTOR.wrapping_add(4),
+			current_pc: RESET_VECTOR,
+			regs: [0; 32],
+			hi: 0,
+			lo: 0,
+			icache: [InstrCacheLines::new(); 0x100],
+			inter,
+			cop0: Cop0::new(),
+			gte: Gte::new(),
+			hle: Kernel::new(),
+			tracer: None,
+			cycle_counter: 0,
+			gte_ready: 0,
+			load: (RegisterIndex(0), 0),
+			gte_load: None,
+			branch: false,
+			delay_slot: false,
+			debug_on_break: false,
+			branch_pc: 0,
+		}
+	}
+
+	pub fn pc(&self) -> u32 {
+		self.pc
+	}
+
+	/// Force the PC address. Any pending branch is cancelled.
+	pub fn set_pc(&mut self, pc: u32) {
+		self.pc = pc;
+		self.next_pc = pc.wrapping_add(4);
+		self.branch = false;
+		self.delay_slot = false;
+	}
+
+	/// Return the value of general purpose register `index`
+	pub fn reg(&self, index: u32) -> u32 {
+		self.regs[index as usize]
+	}
+
+	/// Set the value of general purpose register `index`. Writes to
+	/// R0 are ignored.
+	pub fn set_reg(&mut self, index: u32, val: u32) {
+		self.regs[index as usize] = val;
+		self.regs[0] = 0;
+	}
+
+	pub fn hi(&self) -> u32 {
+		self.hi
+	}
+
+	pub fn lo(&self) -> u32 {
+		self.lo
+	}
+
+	pub fn set_hi(&mut self, val: u32) {
+		self.hi = val;
+	}
+
+	pub fn set_lo(&mut self, val: u32) {
+		self.lo = val;
+	}
+
+	/// Install a tracer for the BIOS kernel calls, or remove it if
+	/// `tracer` is `None`. Returns the previous tracer.
+	pub fn set_kernel_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+		std::mem::replace(&mut self.tracer, tracer)
+	}
+
+	pub(crate) fn cop0(&self) -> &Cop0 {
+		&self.cop0
+	}
+
+	pub(crate) fn cop0_mut(&mut self) -> &mut Cop0 {
+		&mut self.cop0
+	}
+
+	pub(crate) fn hle_kernel_mut(&mut self) -> &mut Kernel {
+		&mut self.hle
+	}
+
+	/// Invalidate the whole instruction cache
+	pub fn flush_icache(&mut self) {
+		for line in self.icache.iter_mut() {
+			line.invalidate();
+		}
+	}
+
+	pub fn interconnect(&self) -> &Interconnect {
+		&self.inter
+	}
+
+	pub fn interconnect_mut(&mut self) -> &mut Interconnect {
+		&mut self.inter
+	}
+
+	pub fn set_debug_on_break(&mut self, enabled: bool) {
+		self.debug_on_break = enabled;
+	}
+
+	/// Store the CPU and every component it owns in `state`
+	pub fn save_state(&self, state: &mut StateWriter) -> Result<(), savestate::Error> {
+		state.add(self)?;
+		state.add(&self.cop0)?;
+		state.add(&self.gte)?;
+		state.add(&self.hle)?;
+		self.inter.save_state(state)
+	}
+
+	/// Rebuild a CPU from the sections in `state`
+	pub fn load_state(state: &StateReader) -> Result<Cpu, savestate::Error> {
+		let mut cpu: Cpu = state.get()?;
+
+		cpu.cop0 = state.get()?;
+		cpu.gte = state.get()?;
+		cpu.hle = state.get()?;
+		cpu.inter = Interconnect::load_state(state)?;
+
+		Ok(cpu)
+	}
+
+	/// Return the number of CPU cycles elapsed since reset
+	pub fn cycle_counter(&self) -> u64 {
+		self.cycle_counter
+	}
+
+	/// Run a single CPU instruction and return
+	pub fn run_next_instruction(&mut self) {
+		if let Some(vector) = hle::vector(self.pc) {
+			if let Some(mut tracer) = self.tracer.take() {
+				tracer.trace(self, vector);
+				self.tracer = Some(tracer);
+			}
+
+			if self.inter.tty().intercepts_putchar() &&
+				!self.inter.bios().is_hle() &&
+				hle::is_putchar(vector, self.regs[9]) {
+				let c = self.regs[4] as u8;
+				self.inter.tty_mut().putchar(c);
+			}
+		}
+
+		if self.inter.bios().is_hle() && hle::is_hooked(self.pc) {
+			// The kernel function runs instead of the code at PC
+			self.delayed_load();
+			self.delay_slot = false;
+			self.tick(1);
+
+			hle::run(self);
+			return;
+		}
+
+		self.current_pc = self.pc;
+
+		// The fetch fails at the branch target: we're not in a delay
+		// slot anymore, even if the previous instruction was one
+		self.delay_slot = self.branch;
+		self.branch = false;
+
+		if !self.current_pc.is_multiple_of(4) {
+			// PC is not correctly aligned!
+			self.address_error(Exception::LoadAddressError, self.current_pc);
+			return;
+		}
+
+		let instruction = self.fetch_instruction();
+
+		// Increment PC to point to the next instruction. All
+		// instructions are 32bit long.
+		self.pc = self.next_pc;
+		self.next_pc = self.pc.wrapping_add(4);
+
+		self.tick(1);
+
+		if self.cop0.irq_active(self.inter.irq_state()) {
+			// GTE commands still execute when an interrupt is
+			// triggered. The BIOS handler knows about this and skips
+			// the instruction at EPC in this situation.
+			if instruction.is_gte_op() {
+				self.decode_and_execute(instruction);
+			} else {
+				self.delayed_load();
+			}
+
+			self.exception(Exception::Interrupt);
+		} else {
+			self.decode_and_execute(instruction);
+		}
+	}
+
+	/// Fetch the instruction at `current_pc` through the instruction
+	/// cache
+	fn fetch_instruction(&mut self) -> Instruction {
+		let pc = self.current_pc;
+		let cc = self.inter.cache_control();
+
+		// KUSEG and KSEG0 regions are cached. KSEG1 is uncached and
+		// KSEG2 doesn't contain any code
+		let cached = pc < 0xa000_0000;
+
+		if cached && cc.icache_enabled() {
+			// The MSB is ignored: running from KUSEG or KSEG0 hits
+			// the same cachelines. So for instance addresses
+			// 0x00000000 and 0x80000000 have the same tag.
+			let tag = pc & 0x7fff_f000;
+
+			// Cache line "bucket"
+			let line_index = ((pc >> 4) & 0xff) as usize;
+			// Index in the cache line
+			let index = (pc >> 2) & 3;
+
+			let line = self.icache[line_index];
+
+			// Check the tag and validity
+			if line.tag() != tag || line.valid_index() > index {
+				// Cache miss. Fetch the cacheline starting at the
+				// current index. If the index is not 0 then some
+				// words are going to remain invalid in the cacheline.
+				let mut cpc = pc;
+
+				// Fetching takes 3 cycles + 1 per instruction on
+				// average.
+				self.tick(3);
+
+				for i in index..4 {
+					self.tick(1);
+
+					let instruction =
+						Instruction(self.inter.load::<Word>(cpc));
+
+					self.icache[line_index].set_instruction(i, instruction);
+					cpc = cpc.wrapping_add(4);
+				}
+
+				// Set the tag and valid bits
+				self.icache[line_index].set_tag_valid(pc);
+			}
+
+			self.icache[line_index].instruction(index)
+		} else {
+			// Cache is disabled, fetch directly from memory. Takes 4
+			// cycles on average.
+			self.tick(4);
+
+			Instruction(self.inter.load::<Word>(pc))
+		}
+	}
+
+	/// Memory read
+	fn load<T: Addressable>(&mut self, addr: u32) -> u32 {
+		self.inter.load::<T>(addr)
+	}
+
+	/// Memory write
+	fn store<T: Addressable>(&mut self, addr: u32, val: u32) {
+		if self.cop0.cache_isolated() {
+			self.cache_maintenance::<T>(addr, val);
+		} else {
+			self.inter.store::<T>(addr, val);
+		}
+	}
+
+	/// Handle writes when the cache is isolated
+	fn cache_maintenance<T: Addressable>(&mut self, addr: u32, val: u32) {
+		// Implementing full cache emulation requires handling many
+		// corner cases. For now I'm just going to add support for
+		// cache invalidation which is the only use case for cache
+		// isolation as far as I know.
+		let cc = self.inter.cache_control();
+
+		if !cc.icache_enabled() {
+			panic!("Cache maintenance while instruction cache is disabled");
+		}
+
+		if T::size() != 4 || val != 0 {
+			panic!("Unsupported write while cache is isolated: {:08x}",
+				   val);
+		}
+
+		let line = &mut self.icache[((addr >> 4) & 0xff) as usize];
+
+		if cc.tag_test_mode() {
+			// In tag test mode the write invalidates the entire
+			// targeted cacheline
+			line.invalidate();
+		} else {
+			// Otherwise the write ends up directly in the cache.
+			let index = (addr >> 2) & 3;
+
+			let instruction = Instruction(val);
+
+			line.set_instruction(index, instruction);
+		}
+	}
+
+	/// Put the next instruction in the delay slot of the current one.
+	/// Called by all the branches and jumps, even when not taken.
+	fn delay_slot_next(&mut self) {
+		self.branch = true;
+		self.branch_pc = self.current_pc;
+	}
+
+	/// Address of the delay slot of the current instruction. Branch
+	/// targets and return addresses are relative to it. It's not
+	/// always `pc` since the current instruction can itself be in the
+	/// delay slot of a taken branch.
+	fn delay_slot_pc(&self) -> u32 {
+		self.current_pc.wrapping_add(4)
+	}
+
+	/// Branch to immediate value `offset`
+	fn branch(&mut self, offset: u32) {
+		// Offset immediates are always shifted two places to the
+		// right since `PC` addresses have to be aligned on 32bits at
+		// all times.
+		let offset = offset << 2;
+
+		self.next_pc = self.delay_slot_pc().wrapping_add(offset);
+	}
+
+	/// Trigger an exception
+	fn exception(&mut self, cause: Exception) {
+		// In a delay slot EPC points to the branch so that it's
+		// executed again when returning from the handler
+		let pc =
+			if self.delay_slot {
+				self.branch_pc
+			} else {
+				self.current_pc
+			};
+
+		let handler = self.cop0.enter_exeception(cause, pc, self.delay_slot);
+
+		// Exceptions don't have a branch delay, we jump directly into
+		// the handler
+		self.pc = handler;
+		self.next_pc = handler.wrapping_add(4);
+	}
+
+	/// Trigger an address error exception for `addr`, which is stored
+	/// in BadVaddr
+	fn address_error(&mut self, cause: Exception, addr: u32) {
+		self.cop0.set_bad_vaddr(addr);
+		self.exception(cause);
+	}
+
+	/// Trigger a coprocessor unusable exception for coprocessor
+	/// `cop`, which is stored in CAUSE.CE
+	fn coprocessor_error(&mut self, cop: u32) {
+		self.cop0.set_coprocessor_error(cop);
+		self.exception(Exception::CoprocessorError);
+	}
+
+	/// Return the value of register `index` as seen by the current
+	/// instruction
+	fn r(&self, index: RegisterIndex) -> u32 {
+		self.regs[index.0 as usize]
+	}
+
+	/// Write `val` to register `index`
+	fn set_r(&mut self, index: RegisterIndex, val: u32) {
+		self.regs[index.0 as usize] = val;
+
+		// Make sure R0 is always 0
+		self.regs[0] = 0;
+	}
+
+	/// Execute the pending load (if any) and clear it
+	fn delayed_load(&mut self) {
+		let (reg, val) = self.load;
+
+		self.set_r(reg, val);
+
+		self.load = (RegisterIndex(0), 0);
+
+		self.gte_delayed_load();
+	}
+
+	/// Execute the pending load and schedule a new one. If the new
+	/// load targets the same register as the pending one the older
+	/// value never makes it to the register file.
+	fn delayed_load_chain(&mut self, reg: RegisterIndex, val: u32) {
+		let (pending_reg, pending_val) = self.load;
+
+		if pending_reg != reg {
+			self.set_r(pending_reg, pending_val);
+		}
+
+		self.load = (reg, val);
+
+		self.gte_delayed_load();
+	}
+
+	/// Execute the pending GTE register write (if any) and clear it
+	fn gte_delayed_load(&mut self) {
+		match self.gte_load.take() {
+			Some((GteRegister::Data(reg), val)) => self.gte.set_data(reg, val),
+			Some((GteRegister::Control(reg), val)) => self.gte.set_control(reg, val),
+			None => (),
+		}
+	}
+
+	/// Decode `instruction`'s opcode and run the function
+	fn decode_and_execute(&mut self, instruction: Instruction) {
+		match instruction.function() {
+			0b000000 => match instruction.subfunction() {
+				0b000000 => self.op_sll(instruction),
+				0b000010 => self.op_srl(instruction),
+				0b000011 => self.op_sra(instruction),
+				0b000100 => self.op_sllv(instruction),
+				0b000110 => self.op_srlv(instruction),
+				0b000111 => self.op_srav(instruction),
+				0b001000 => self.op_jr(instruction),
+				0b001001 => self.op_jalr(instruction),
+				0b001100 => self.op_syscall(instruction),
+				0b001101 => self.op_break(instruction),
+				0b010000 => self.op_mfhi(instruction),
+				0b010001 => self.op_mthi(instruction),
+				0b010010 => self.op_mflo(instruction),
+				0b010011 => self.op_mtlo(instruction),
+				0b011000 => self.op_mult(instruction),
+				0b011001 => self.op_multu(instruction),
+				0b011010 => self.op_div(instruction),
+				0b011011 => self.op_divu(instruction),
+				0b100000 => self.op_add(instruction),
+				0b100001 => self.op_addu(instruction),
+				0b100010 => self.op_sub(instruction),
+				0b100011 => self.op_subu(instruction),
+				0b100100 => self.op_and(instruction),
+				0b100101 => self.op_or(instruction),
+				0b100110 => self.op_xor(instruction),
+				0b100111 => self.op_nor(instruction),
+				0b101010 => self.op_slt(instruction),
+				0b101011 => self.op_sltu(instruction),
+				_        => self.op_illegal(instruction),
+			},
+			0b000001 => self.op_bxx(instruction),
+			0b000010 => self.op_j(instruction),
+			0b000011 => self.op_jal(instruction),
+			0b000100 => self.op_beq(instruction),
+			0b000101 => self.op_bne(instruction),
+			0b000110 => self.op_blez(instruction),
+			0b000111 => self.op_bgtz(instruction),
+			0b001000 => self.op_addi(instruction),
+			0b001001 => self.op_addiu(instruction),
+			0b001010 => self.op_slti(instruction),
+			0b001011 => self.op_sltiu(instruction),
+			0b001100 => self.op_andi(instruction),
+			0b001101 => self.op_ori(instruction),
+			0b001110 => self.op_xori(instruction),
+			0b001111 => self.op_lui(instruction),
+			0b010000 => self.op_cop0(instruction),
+			0b010001 => self.op_cop1(instruction),
+			0b010010 => self.op_cop2(instruction),
+			0b010011 => self.op_cop3(instruction),
+			0b100000 => self.op_lb(instruction),
+			0b100001 => self.op_lh(instruction),
+			0b100010 => self.op_lwl(instruction),
+			0b100011 => self.op_lw(instruction),
+			0b100100 => self.op_lbu(instruction),
+			0b100101 => self.op_lhu(instruction),
+			0b100110 => self.op_lwr(instruction),
+			0b101000 => self.op_sb(instruction),
+			0b101001 => self.op_sh(instruction),
+			0b101010 => self.op_swl(instruction),
+			0b101011 => self.op_sw(instruction),
+			0b101110 => self.op_swr(instruction),
+			0b110000 => self.op_lwc0(instruction),
+			0b110001 => self.op_lwc1(instruction),
+			0b110010 => self.op_lwc2(instruction),
+			0b110011 => self.op_lwc3(instruction),
+			0b111000 => self.op_swc0(instruction),
+			0b111001 => self.op_swc1(instruction),
+			0b111010 => self.op_swc2(instruction),
+			0b111011 => self.op_swc3(instruction),
+			_        => self.op_illegal(instruction),
+		}
+	}
+
+	/// Illegal instruction
+	fn op_illegal(&mut self, instruction: Instruction) {
+		self.delayed_load();
+
+		warn!("Illegal instruction {} at PC 0x{:08x}!",
+			  instruction, self.current_pc);
+
+		self.exception(Exception::IllegalInstruction);
+	}
+
+	/// Shift Left Logical
+	fn op_sll(&mut self, instruction: Instruction) {
+		let i = instruction.shift();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let v = self.r(t) << i;
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Shift Right Logical
+	fn op_srl(&mut self, instruction: Instruction) {
+		let i = instruction.shift();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let v = self.r(t) >> i;
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Shift Right Arithmetic
+	fn op_sra(&mut self, instruction: Instruction) {
+		let i = instruction.shift();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let v = (self.r(t) as i32) >> i;
+
+		self.delayed_load();
+
+		self.set_r(d, v as u32);
+	}
+
+	/// Shift Left Logical Variable
+	fn op_sllv(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		// Shift amount is truncated to 5 bits
+		let v = self.r(t) << (self.r(s) & 0x1f);
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Shift Right Logical Variable
+	fn op_srlv(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		// Shift amount is truncated to 5 bits
+		let v = self.r(t) >> (self.r(s) & 0x1f);
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Shift Right Arithmetic Variable
+	fn op_srav(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		// Shift amount is truncated to 5 bits
+		let v = (self.r(t) as i32) >> (self.r(s) & 0x1f);
+
+		self.delayed_load();
+
+		self.set_r(d, v as u32);
+	}
+
+	/// Jump Register
+	fn op_jr(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+
+		self.next_pc = self.r(s);
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+	}
+
+	/// Jump And Link Register
+	fn op_jalr(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+
+		let ra = self.delay_slot_pc().wrapping_add(4);
+
+		self.next_pc = self.r(s);
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+
+		// Store return address in `d`
+		self.set_r(d, ra);
+	}
+
+	/// System Call
+	fn op_syscall(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		self.exception(Exception::SysCall);
+	}
+
+	/// Break
+	fn op_break(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		if self.debug_on_break {
+			info!("BREAK instruction at PC 0x{:08x}", self.current_pc);
+		}
+
+		self.exception(Exception::Break);
+	}
+
+	/// Move From HI
+	fn op_mfhi(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+
+		let hi = self.hi;
+
+		self.delayed_load();
+
+		self.set_r(d, hi);
+	}
+
+	/// Move To HI
+	fn op_mthi(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+
+		self.hi = self.r(s);
+
+		self.delayed_load();
+	}
+
+	/// Move From LO
+	fn op_mflo(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+
+		let lo = self.lo;
+
+		self.delayed_load();
+
+		self.set_r(d, lo);
+	}
+
+	/// Move To LO
+	fn op_mtlo(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+
+		self.lo = self.r(s);
+
+		self.delayed_load();
+	}
+
+	/// Multiply (signed)
+	fn op_mult(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let a = (self.r(s) as i32) as i64;
+		let b = (self.r(t) as i32) as i64;
+
+		self.delayed_load();
+
+		let v = (a * b) as u64;
+
+		self.hi = (v >> 32) as u32;
+		self.lo = v as u32;
+	}
+
+	/// Multiply Unsigned
+	fn op_multu(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let a = self.r(s) as u64;
+		let b = self.r(t) as u64;
+
+		self.delayed_load();
+
+		let v = a * b;
+
+		self.hi = (v >> 32) as u32;
+		self.lo = v as u32;
+	}
+
+	/// Divide (signed)
+	fn op_div(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let n = self.r(s) as i32;
+		let d = self.r(t) as i32;
+
+		self.delayed_load();
+
+		if d == 0 {
+			// Division by zero, results are bogus
+			self.hi = n as u32;
+
+			if n >= 0 {
+				self.lo = 0xffffffff;
+			} else {
+				self.lo = 1;
+			}
+		} else if n as u32 == 0x80000000 && d == -1 {
+			// Result is not representable in a 32bit signed integer
+			self.hi = 0;
+			self.lo = 0x80000000;
+		} else {
+			self.hi = (n % d) as u32;
+			self.lo = (n / d) as u32;
+		}
+	}
+
+	/// Divide Unsigned
+	fn op_divu(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let n = self.r(s);
+		let d = self.r(t);
+
+		self.delayed_load();
+
+		if d == 0 {
+			// Division by zero, results are bogus
+			self.hi = n;
+			self.lo = 0xffffffff;
+		} else {
+			self.hi = n % d;
+			self.lo = n / d;
+		}
+	}
+
+	/// Add and check for signed overflow
+	fn op_add(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let s = self.r(s) as i32;
+		let t = self.r(t) as i32;
+
+		self.delayed_load();
+
+		match s.checked_add(t) {
+			Some(v) => self.set_r(d, v as u32),
+			None    => self.exception(Exception::Overflow),
+		}
+	}
+
+	/// Add Unsigned
+	fn op_addu(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let v = self.r(s).wrapping_add(self.r(t));
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Subtract and check for signed overflow
+	fn op_sub(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let s = self.r(s) as i32;
+		let t = self.r(t) as i32;
+
+		self.delayed_load();
+
+		match s.checked_sub(t) {
+			Some(v) => self.set_r(d, v as u32),
+			None    => self.exception(Exception::Overflow),
+		}
+	}
+
+	/// Subtract Unsigned
+	fn op_subu(&mut self, instruction: Instruction) {
+		let s = instruction.s();
+		let t = instruction.t();
+		let d = instruction.d();
+
+		let v = self.r(s).wrapping_sub(self.r(t));
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Bitwise And
+	fn op_and(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = self.r(s) & self.r(t);
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Bitwise Or
+	fn op_or(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = self.r(s) | self.r(t);
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Bitwise Exclusive Or
+	fn op_xor(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = self.r(s) ^ self.r(t);
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Bitwise Not Or
+	fn op_nor(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = !(self.r(s) | self.r(t));
+
+		self.delayed_load();
+
+		self.set_r(d, v);
+	}
+
+	/// Set on Less Than (signed)
+	fn op_slt(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let s = self.r(s) as i32;
+		let t = self.r(t) as i32;
+
+		let v = s < t;
+
+		self.delayed_load();
+
+		self.set_r(d, v as u32);
+	}
+
+	/// Set on Less Than Unsigned
+	fn op_sltu(&mut self, instruction: Instruction) {
+		let d = instruction.d();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = self.r(s) < self.r(t);
+
+		self.delayed_load();
+
+		self.set_r(d, v as u32);
+	}
+
+	/// Various branch instructions: BGEZ, BLTZ, BGEZAL, BLTZAL. Bits
+	/// [20:16] are used to figure out which one to use.
+	fn op_bxx(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let s = instruction.s();
+
+		let op = instruction.0;
+
+		let is_bgez = (op >> 16) & 1;
+		// It's not enough to test for bit 20 to see if we're
+		// supposed to link, if any bit in the range [19:17] is set
+		// the link doesn't take place and RA is left untouched.
+		let is_link = (op >> 17) & 0xf == 0x8;
+
+		let v = self.r(s) as i32;
+
+		// Test "less than zero"
+		let test = (v < 0) as u32;
+
+		// If the test is "greater than or equal to zero" we need to
+		// negate the comparison above ("a >= 0" <=> "!(a < 0)"). The
+		// xor takes care of that.
+		let test = test ^ is_bgez;
+
+		self.delayed_load();
+
+		self.delay_slot_next();
+
+		// If linking is requested it occurs unconditionally, even if
+		// the branch is not taken
+		if is_link {
+			let ra = self.delay_slot_pc().wrapping_add(4);
+
+			// Store return address in R31
+			self.set_r(RegisterIndex(31), ra);
+		}
+
+		if test != 0 {
+			self.branch(i);
+		}
+	}
+
+	/// Jump
+	fn op_j(&mut self, instruction: Instruction) {
+		let i = instruction.imm_jump();
+
+		self.next_pc = (self.delay_slot_pc() & 0xf0000000) | (i << 2);
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+	}
+
+	/// Jump And Link
+	fn op_jal(&mut self, instruction: Instruction) {
+		let ra = self.delay_slot_pc().wrapping_add(4);
+
+		self.op_j(instruction);
+
+		// Store return address in R31
+		self.set_r(RegisterIndex(31), ra);
+	}
+
+	/// Branch if Equal
+	fn op_beq(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		if self.r(s) == self.r(t) {
+			self.branch(i);
+		}
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+	}
+
+	/// Branch if Not Equal
+	fn op_bne(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		if self.r(s) != self.r(t) {
+			self.branch(i);
+		}
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+	}
+
+	/// Branch if Less than or Equal to Zero
+	fn op_blez(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let s = instruction.s();
+
+		let v = self.r(s) as i32;
+
+		if v <= 0 {
+			self.branch(i);
+		}
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+	}
+
+	/// Branch if Greater Than Zero
+	fn op_bgtz(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let s = instruction.s();
+
+		let v = self.r(s) as i32;
+
+		if v > 0 {
+			self.branch(i);
+		}
+
+		self.delay_slot_next();
+
+		self.delayed_load();
+	}
+
+	/// Add Immediate and check for signed overflow
+	fn op_addi(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se() as i32;
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let s = self.r(s) as i32;
+
+		self.delayed_load();
+
+		match s.checked_add(i) {
+			Some(v) => self.set_r(t, v as u32),
+			None    => self.exception(Exception::Overflow),
+		}
+	}
+
+	/// Add Immediate Unsigned
+	fn op_addiu(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let v = self.r(s).wrapping_add(i);
+
+		self.delayed_load();
+
+		self.set_r(t, v);
+	}
+
+	/// Set if Less Than Immediate (signed)
+	fn op_slti(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se() as i32;
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = (self.r(s) as i32) < i;
+
+		self.delayed_load();
+
+		self.set_r(t, v as u32);
+	}
+
+	/// Set if Less Than Immediate Unsigned
+	fn op_sltiu(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let s = instruction.s();
+		let t = instruction.t();
+
+		let v = self.r(s) < i;
+
+		self.delayed_load();
+
+		self.set_r(t, v as u32);
+	}
+
+	/// Bitwise And Immediate
+	fn op_andi(&mut self, instruction: Instruction) {
+		let i = instruction.imm();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let v = self.r(s) & i;
+
+		self.delayed_load();
+
+		self.set_r(t, v);
+	}
+
+	/// Bitwise Or Immediate
+	fn op_ori(&mut self, instruction: Instruction) {
+		let i = instruction.imm();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let v = self.r(s) | i;
+
+		self.delayed_load();
+
+		self.set_r(t, v);
+	}
+
+	/// Bitwise eXclusive Or Immediate
+	fn op_xori(&mut self, instruction: Instruction) {
+		let i = instruction.imm();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let v = self.r(s) ^ i;
+
+		self.delayed_load();
+
+		self.set_r(t, v);
+	}
+
+	/// Load Upper Immediate
+	fn op_lui(&mut self, instruction: Instruction) {
+		let i = instruction.imm();
+		let t = instruction.t();
+
+		// Low 16bits are set to 0
+		let v = i << 16;
+
+		self.delayed_load();
+
+		self.set_r(t, v);
+	}
+
+	/// Coprocessor 0 opcode
+	fn op_cop0(&mut self, instruction: Instruction) {
+		match instruction.cop_opcode() {
+			0b00000 => self.op_mfc0(instruction),
+			0b00100 => self.op_mtc0(instruction),
+			0b10000 => self.op_rfe(instruction),
+			_       => self.op_illegal(instruction),
+		}
+	}
+
+	/// Move From Coprocessor 0
+	fn op_mfc0(&mut self, instruction: Instruction) {
+		let cpu_r = instruction.t();
+		let cop_r = instruction.d().0;
+
+		let v = match cop_r {
+			8 => self.cop0.bad_vaddr(),
+			12 => self.cop0.sr(),
+			13 => self.cop0.cause(self.inter.irq_state()),
+			14 => self.cop0.epc(),
+			// Processor ID
+			15 => 0x0000_0002,
+			_ => {
+				warn!("Unhandled read from COP0r{}", cop_r);
+				0
+			}
+		};
+
+		self.delayed_load_chain(cpu_r, v);
+	}
+
+	/// Move To Coprocessor 0
+	fn op_mtc0(&mut self, instruction: Instruction) {
+		let cpu_r = instruction.t();
+		let cop_r = instruction.d().0;
+
+		let v = self.r(cpu_r);
+
+		self.delayed_load();
+
+		match cop_r {
+			// Breakpoint registers, only handle writes of 0
+			3 | 5 | 6 | 7 | 9 | 11 =>
+				if v != 0 {
+					warn!("Unhandled write to COP0r{}: 0x{:08x}", cop_r, v)
+				},
+			12 => self.cop0.set_sr(v),
+			13 => self.cop0.set_cause(v),
+			_  => warn!("Unhandled write to COP0r{}: 0x{:08x}", cop_r, v),
+		}
+	}
+
+	/// Return From Exception
+	fn op_rfe(&mut self, instruction: Instruction) {
+		// There are other instructions with the same encoding but all
+		// are virtual memory related and the PlayStation doesn't
+		// implement them.
+		if instruction.subfunction() != 0b010000 {
+			self.op_illegal(instruction);
+			return;
+		}
+
+		self.delayed_load();
+
+		self.cop0.return_from_exception();
+	}
+
+	/// Coprocessor 1 opcode (does not exist on the PlayStation)
+	fn op_cop1(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		self.coprocessor_error(1);
+	}
+
+	/// Coprocessor 2 opcode (GTE)
+	fn op_cop2(&mut self, instruction: Instruction) {
+		if !self.cop0.cop2_enabled() {
+			self.delayed_load();
+			self.coprocessor_error(2);
+			return;
+		}
+
+		let cop_opcode = instruction.cop_opcode();
+
+		if cop_opcode & 0x10 != 0 {
+			// GTE command. It doesn't see the register write of the
+			// previous instruction yet.
+			self.gte_command(instruction.0);
+			self.delayed_load();
+		} else {
+			match cop_opcode {
+				0b00000 => self.op_mfc2(instruction),
+				0b00010 => self.op_cfc2(instruction),
+				0b00100 => self.op_mtc2(instruction),
+				0b00110 => self.op_ctc2(instruction),
+				_       => self.op_illegal(instruction),
+			}
+		}
+	}
+
+	/// Move From Coprocessor 2 Data register
+	fn op_mfc2(&mut self, instruction: Instruction) {
+		let cpu_r = instruction.t();
+		let cop_r = instruction.d().0;
+
+		let v = self.gte_data(cop_r);
+
+		self.delayed_load_chain(cpu_r, v);
+	}
+
+	/// Move From Coprocessor 2 Control register
+	fn op_cfc2(&mut self, instruction: Instruction) {
+		let cpu_r = instruction.t();
+		let cop_r = instruction.d().0;
+
+		let v = self.gte_control(cop_r);
+
+		self.delayed_load_chain(cpu_r, v);
+	}
+
+	/// Move To Coprocessor 2 Data register
+	fn op_mtc2(&mut self, instruction: Instruction) {
+		let cpu_r = instruction.t();
+		let cop_r = instruction.d().0;
+
+		let v = self.r(cpu_r);
+
+		self.delayed_load();
+
+		self.gte_load = Some((GteRegister::Data(cop_r), v));
+	}
+
+	/// Move To Coprocessor 2 Control register
+	fn op_ctc2(&mut self, instruction: Instruction) {
+		let cpu_r = instruction.t();
+		let cop_r = instruction.d().0;
+
+		let v = self.r(cpu_r);
+
+		self.delayed_load();
+
+		self.gte_load = Some((GteRegister::Control(cop_r), v));
+	}
+
+	/// Coprocessor 3 opcode (does not exist on the PlayStation)
+	fn op_cop3(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		self.coprocessor_error(3);
+	}
+
+	/// Load Byte (signed)
+	fn op_lb(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		// Cast as i8 to force sign extension
+		let v = self.load::<Byte>(addr) as i8;
+
+		self.delayed_load_chain(t, v as u32);
+	}
+
+	/// Load Halfword (signed)
+	fn op_lh(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		// Address must be 16bit aligned
+		if addr.is_multiple_of(2) {
+			// Cast as i16 to force sign extension
+			let v = self.load::<HalfWord>(addr) as i16;
+
+			self.delayed_load_chain(t, v as u32);
+		} else {
+			self.delayed_load();
+			self.address_error(Exception::LoadAddressError, addr);
+		}
+	}
+
+	/// Load Word Left (little-endian only implementation)
+	fn op_lwl(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		// This instruction bypasses the load delay restriction: this
+		// instruction will merge the new contents with the value
+		// currently being loaded if need be.
+		let (pending_reg, pending_val) = self.load;
+
+		let cur_v =
+			if pending_reg == t {
+				pending_val
+			} else {
+				self.r(t)
+			};
+
+		// Next we load the *aligned* word containing the first
+		// addressed byte
+		let aligned_addr = addr & !3;
+		let aligned_word = self.load::<Word>(aligned_addr);
+
+		// Depending on the address alignment we fetch the 1, 2, 3 or
+		// 4 *most* significant bytes and put them in the target
+		// register.
+		let v = match addr & 3 {
+			0 => (cur_v & 0x00ffffff) | (aligned_word << 24),
+			1 => (cur_v & 0x0000ffff) | (aligned_word << 16),
+			2 => (cur_v & 0x000000ff) | (aligned_word << 8),
+			3 => aligned_word,
+			_ => unreachable!(),
+		};
+
+		self.delayed_load_chain(t, v);
+	}
+
+	/// Load Word
+	fn op_lw(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		// Address must be 32bit aligned
+		if addr.is_multiple_of(4) {
+			let v = self.load::<Word>(addr);
+
+			self.delayed_load_chain(t, v);
+		} else {
+			self.delayed_load();
+			self.address_error(Exception::LoadAddressError, addr);
+		}
+	}
+
+	/// Load Byte Unsigned
+	fn op_lbu(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		let v = self.load::<Byte>(addr);
+
+		self.delayed_load_chain(t, v);
+	}
+
+	/// Load Halfword Unsigned
+	fn op_lhu(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		// Address must be 16bit aligned
+		if addr.is_multiple_of(2) {
+			let v = self.load::<HalfWord>(addr);
+
+			self.delayed_load_chain(t, v);
+		} else {
+			self.delayed_load();
+			self.address_error(Exception::LoadAddressError, addr);
+		}
+	}
+
+	/// Load Word Right (little-endian only implementation)
+	fn op_lwr(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		// This instruction bypasses the load delay restriction: this
+		// instruction will merge the new contents with the value
+		// currently being loaded if need be.
+		let (pending_reg, pending_val) = self.load;
+
+		let cur_v =
+			if pending_reg == t {
+				pending_val
+			} else {
+				self.r(t)
+			};
+
+		// Next we load the *aligned* word containing the first
+		// addressed byte
+		let aligned_addr = addr & !3;
+		let aligned_word = self.load::<Word>(aligned_addr);
+
+		// Depending on the address alignment we fetch the 1, 2, 3 or
+		// 4 *least* significant bytes and put them in the target
+		// register.
+		let v = match addr & 3 {
+			0 => aligned_word,
+			1 => (cur_v & 0xff000000) | (aligned_word >> 8),
+			2 => (cur_v & 0xffff0000) | (aligned_word >> 16),
+			3 => (cur_v & 0xffffff00) | (aligned_word >> 24),
+			_ => unreachable!(),
+		};
+
+		self.delayed_load_chain(t, v);
+	}
+
+	/// Store Byte
+	fn op_sb(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+		let v = self.r(t);
+
+		self.delayed_load();
+
+		self.store::<Byte>(addr, v);
+	}
+
+	/// Store Halfword
+	fn op_sh(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+		let v = self.r(t);
+
+		self.delayed_load();
+
+		// Address must be 16bit aligned
+		if addr.is_multiple_of(2) {
+			self.store::<HalfWord>(addr, v);
+		} else {
+			self.address_error(Exception::StoreAddressError, addr);
+		}
+	}
+
+	/// Store Word Left (little-endian only implementation)
+	fn op_swl(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+		let v = self.r(t);
+
+		let aligned_addr = addr & !3;
+		// Load the current value for the aligned word at the target
+		// address
+		let cur_mem = self.load::<Word>(aligned_addr);
+
+		let mem = match addr & 3 {
+			0 => (cur_mem & 0xffffff00) | (v >> 24),
+			1 => (cur_mem & 0xffff0000) | (v >> 16),
+			2 => (cur_mem & 0xff000000) | (v >> 8),
+			3 => v,
+			_ => unreachable!(),
+		};
+
+		self.delayed_load();
+
+		self.store::<Word>(aligned_addr, mem);
+	}
+
+	/// Store Word
+	fn op_sw(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+		let v = self.r(t);
+
+		self.delayed_load();
+
+		// Address must be 32bit aligned
+		if addr.is_multiple_of(4) {
+			self.store::<Word>(addr, v);
+		} else {
+			self.address_error(Exception::StoreAddressError, addr);
+		}
+	}
+
+	/// Store Word Right (little-endian only implementation)
+	fn op_swr(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let t = instruction.t();
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+		let v = self.r(t);
+
+		let aligned_addr = addr & !3;
+		// Load the current value for the aligned word at the target
+		// address
+		let cur_mem = self.load::<Word>(aligned_addr);
+
+		let mem = match addr & 3 {
+			0 => v,
+			1 => (cur_mem & 0x000000ff) | (v << 8),
+			2 => (cur_mem & 0x0000ffff) | (v << 16),
+			3 => (cur_mem & 0x00ffffff) | (v << 24),
+			_ => unreachable!(),
+		};
+
+		self.delayed_load();
+
+		self.store::<Word>(aligned_addr, mem);
+	}
+
+	/// Load Word in Coprocessor 0
+	fn op_lwc0(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		// Not supported by this coprocessor
+		self.coprocessor_error(0);
+	}
+
+	/// Load Word in Coprocessor 1
+	fn op_lwc1(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		// Not supported by this coprocessor
+		self.coprocessor_error(1);
+	}
+
+	/// Load Word in Coprocessor 2
+	fn op_lwc2(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let cop_r = instruction.t().0;
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		self.delayed_load();
+
+		if !self.cop0.cop2_enabled() {
+			self.coprocessor_error(2);
+			return;
+		}
+
+		// Address must be 32bit aligned
+		if addr.is_multiple_of(4) {
+			let v = self.load::<Word>(addr);
+
+			// Send to coprocessor
+			self.gte_load = Some((GteRegister::Data(cop_r), v));
+		} else {
+			self.address_error(Exception::LoadAddressError, addr);
+		}
+	}
+
+	/// Load Word in Coprocessor 3
+	fn op_lwc3(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		// Not supported by this coprocessor
+		self.coprocessor_error(3);
+	}
+
+	/// Store Word in Coprocessor 0
+	fn op_swc0(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		// Not supported by this coprocessor
+		self.coprocessor_error(0);
+	}
+
+	/// Store Word in Coprocessor 1
+	fn op_swc1(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		// Not supported by this coprocessor
+		self.coprocessor_error(1);
+	}
+
+	/// Store Word in Coprocessor 2
+	fn op_swc2(&mut self, instruction: Instruction) {
+		let i = instruction.imm_se();
+		let cop_r = instruction.t().0;
+		let s = instruction.s();
+
+		let addr = self.r(s).wrapping_add(i);
+
+		if !self.cop0.cop2_enabled() {
+			self.delayed_load();
+			self.coprocessor_error(2);
+			return;
+		}
+
+		// Address must be 32bit aligned
+		if addr.is_multiple_of(4) {
+			// Read before the pending GTE write lands
+			let v = self.gte_data(cop_r);
+
+			self.delayed_load();
+
+			self.store::<Word>(addr, v);
+		} else {
+			self.delayed_load();
+			self.address_error(Exception::StoreAddressError, addr);
+		}
+	}
+
+	/// Store Word in Coprocessor 3
+	fn op_swc3(&mut self, _: Instruction) {
+		self.delayed_load();
+
+		// Not supported by this coprocessor
+		self.coprocessor_error(3);
+	}
+
+	/// Advance the cycle counter by `cycles`
+	fn tick(&mut self, cycles: u32) {
+		self.cycle_counter += cycles as u64;
+	}
+
+	/// Stall the CPU until the GTE is done with its current command
+	fn gte_interlock(&mut self) {
+		if self.gte_ready > self.cycle_counter {
+			self.cycle_counter = self.gte_ready;
+		}
+	}
+
+	/// Start a new GTE command. If the previous command is still
+	/// running the CPU stalls until it completes.
+	fn gte_command(&mut self, command: u32) {
+		self.gte_interlock();
+
+		self.gte.command(command);
+
+		self.gte_ready = self.cycle_counter + gte::command_cycles(command) as u64;
+	}
+
+	/// Read a GTE data register, stalling if a command is still
+	/// running
+	fn gte_data(&mut self, reg: u32) -> u32 {
+		self.gte_interlock();
+
+		self.gte.data(reg)
+	}
+
+	/// Read a GTE control register, stalling if a command is still
+	/// running
+	fn gte_control(&mut self, reg: u32) -> u32 {
+		self.gte_interlock();
+
+		self.gte.control(reg)
+	}
+}
+
+/// The interconnect and coprocessors are stored in their own sections
+impl Component for Cpu {
+	const SECTION: &'static str = "cpu";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
+
+/// GTE register targeted by a delayed write
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum GteRegister {
+	Data(u32),
+	Control(u32),
 }
 
-#[derive(Clone, Copy)]
-struct Instruction(u32);
+/// Raw instruction word
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Instruction(pub u32);
 
 impl Instruction {
 	/// Return bits [31:26] of the instruction
@@ -85,9 +1821,9 @@ impl Instruction {
 		let Instruction(op) = self;
 		op & 0x3ffffff
 	}
-    /// Return true if the instruction contains a GTE/COP2 opcode
+    /// Return true if the instruction contains a GTE command
     fn is_gte_op(self) -> bool {
-        self.function() == 0b010001
+        self.function() == 0b010010 && self.cop_opcode() & 0x10 != 0
     }
 }
 
@@ -97,10 +1833,10 @@ impl Display for Instruction {
 	}
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 struct RegisterIndex(u32);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct InstrCacheLines {
 	tag_valid: u32,
 	line: [Instruction; 4]
@@ -146,3 +1882,546 @@ impl Default for InstrCacheLines {
 		InstrCacheLines::new()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::assembler::syntax::*;
+
+	use super::test_rom::{TestRom, BASE};
+	use super::cop0::Exception;
+
+	/// Words loaded by the tests: bytes 0x00 to 0x77 then 0xdeadbeef
+	/// and 0xcafef00d
+	const DATA: [Instruction; 5] = [
+		Global("data"),
+		Word(0x3322_1100),
+		Word(0x7766_5544),
+		Word(0xdead_beef),
+		Word(0xcafe_f00d),
+	];
+
+	/// Run `code` with the address of `DATA` in $s0
+	fn run_with_data(code: &[Instruction]) -> TestRom {
+		let mut program = vec![La(S0, Label::Global("data"))];
+
+		program.extend_from_slice(code);
+		program.push(Break(0));
+		program.extend_from_slice(&DATA);
+
+		TestRom::run(&program)
+	}
+
+	#[test]
+	fn load_delay() {
+		let rom = run_with_data(&[
+			Li(T0, 1),
+			Lw(T0, S0, 8),
+			// Load delay slot: still the old value
+			Move(T1, T0),
+			Move(T2, T0),
+		]);
+
+		assert_eq!(rom.reg(T1), 1);
+		assert_eq!(rom.reg(T2), 0xdead_beef);
+	}
+
+	#[test]
+	fn load_then_write_same_register() {
+		let rom = run_with_data(&[
+			Lw(T0, S0, 8),
+			// The write in the delay slot wins over the load
+			Addiu(T0, R0, 5),
+			Move(T1, T0),
+		]);
+
+		assert_eq!(rom.reg(T0), 5);
+		assert_eq!(rom.reg(T1), 5);
+	}
+
+	#[test]
+	fn back_to_back_loads_same_register() {
+		let rom = run_with_data(&[
+			Li(T0, 1),
+			Lw(T0, S0, 8),
+			Lw(T0, S0, 12),
+			// The first load is cancelled, it never reaches the
+			// register
+			Move(T1, T0),
+			Move(T2, T0),
+		]);
+
+		assert_eq!(rom.reg(T1), 1);
+		assert_eq!(rom.reg(T2), 0xcafe_f00d);
+	}
+
+	#[test]
+	fn back_to_back_loads_different_registers() {
+		let rom = run_with_data(&[
+			Lw(T0, S0, 8),
+			Lw(T1, S0, 12),
+			// The first load is complete, the second is pending
+			Or(T2, T0, T1),
+			Move(T3, T1),
+		]);
+
+		assert_eq!(rom.reg(T2), 0xdead_beef);
+		assert_eq!(rom.reg(T3), 0xcafe_f00d);
+	}
+
+	#[test]
+	fn unaligned_lwl_lwr() {
+		let rom = run_with_data(&[
+			Li(T0, 0xaaaa_aaaa),
+			Lwr(T0, S0, 1),
+			Lwl(T0, S0, 4),
+			Nop,
+		]);
+
+		assert_eq!(rom.reg(T0), 0x4433_2211);
+	}
+
+	#[test]
+	fn lwl_lwr_merge_with_pending_load() {
+		let rom = run_with_data(&[
+			Lw(T0, S0, 8),
+			// Merges with the value being loaded, not the register
+			Lwr(T0, S0, 1),
+			Move(T1, T0),
+			Nop,
+			Move(T2, T0),
+			Lw(T3, S0, 12),
+			Lwl(T3, S0, 5),
+			Nop,
+			Move(T4, T3),
+		]);
+
+		assert_eq!(rom.reg(T1), 0);
+		assert_eq!(rom.reg(T2), 0xde33_2211);
+		assert_eq!(rom.reg(T4), 0x5544_f00d);
+	}
+
+	#[test]
+	fn branch_in_delay_slot() {
+		let rom = TestRom::run(&[
+			B(Label::Global("first")),
+			// Executes in the delay slot: its target is relative to
+			// its own address
+			Beq(R0, R0, Label::Global("second")),
+			Addiu(V0, V0, 0x100),
+			Break(0),
+			Global("first"),
+			// Delay slot of the BEQ
+			Addiu(V0, V0, 1),
+			Addiu(V0, V0, 0x10),
+			Global("second"),
+			B(Label::Global("third")),
+			// The return address is relative to the JAL too
+			Jal(Label::Global("fourth")),
+			Break(0),
+			Break(0),
+			Global("third"),
+			// Delay slot of the JAL
+			Addiu(V0, V0, 2),
+			Addiu(V0, V0, 0x20),
+			Global("fourth"),
+			Break(0),
+		]);
+
+		assert_eq!(rom.reg(V0), 3);
+		assert_eq!(rom.reg(RA), BASE + 36);
+		assert_eq!(rom.traps().len(), 1);
+	}
+
+	#[test]
+	fn not_taken_branch_in_delay_slot() {
+		let rom = TestRom::run(&[
+			B(Label::Global("first")),
+			Bne(R0, R0, Label::Global("first")),
+			Break(0),
+			Global("first"),
+			// Delay slot of the BNE, then execution continues
+			Addiu(V0, V0, 1),
+			Addiu(V0, V0, 2),
+			Break(0),
+		]);
+
+		assert_eq!(rom.reg(V0), 3);
+	}
+
+	#[test]
+	fn exception_in_delay_slot() {
+		for branch in [Beq(R0, R0, Label::Global("target")),
+					   Bne(R0, R0, Label::Global("target")),
+					   J(Label::Global("target")),
+					   Jr(S0)] {
+			let rom = TestRom::run(&[
+				La(S0, Label::Global("target")),
+				Li(T0, 0x7fff_ffff),
+				branch,
+				Add(T1, T0, T0),
+				Global("target"),
+				Break(0),
+			]);
+
+			let trap = rom.traps()[0];
+
+			assert_eq!(trap.code(), Exception::Overflow as u32, "{:?}", branch);
+			assert!(trap.in_delay_slot(), "{:?}", branch);
+			// EPC points to the branch
+			assert_eq!(trap.epc, BASE + 16, "{:?}", branch);
+		}
+	}
+
+	#[test]
+	fn exception_in_delay_slot_of_branch_in_delay_slot() {
+		let rom = TestRom::run(&[
+			Li(T0, 0x7fff_ffff),
+			Beq(R0, R0, Label::Global("first")),
+			B(Label::Global("second")),
+			Break(0),
+			Global("first"),
+			// Delay slot of the branch at BASE + 12
+			Add(T1, T0, T0),
+			Global("second"),
+			Break(0),
+		]);
+
+		let trap = rom.traps()[0];
+
+		assert_eq!(trap.code(), Exception::Overflow as u32);
+		assert!(trap.in_delay_slot());
+		assert_eq!(trap.epc, BASE + 12);
+	}
+
+	#[test]
+	fn exception_after_delay_slot() {
+		let rom = TestRom::run(&[
+			Li(T0, 0x7fff_ffff),
+			B(Label::Global("target")),
+			Nop,
+			Global("target"),
+			Add(T1, T0, T0),
+			Break(0),
+		]);
+
+		let trap = rom.traps()[0];
+
+		assert_eq!(trap.code(), Exception::Overflow as u32);
+		assert!(!trap.in_delay_slot());
+		assert_eq!(trap.epc, BASE + 16);
+	}
+
+	#[test]
+	fn break_in_delay_slot() {
+		let rom = TestRom::run(&[
+			Jal(Label::Global("target")),
+			Break(0),
+			Global("target"),
+			Break(1),
+		]);
+
+		let trap = rom.traps()[0];
+
+		assert_eq!(rom.traps().len(), 1);
+		assert!(trap.in_delay_slot());
+		assert_eq!(trap.epc, BASE);
+		// The link happens before the exception
+		assert_eq!(rom.reg(RA), BASE + 8);
+	}
+
+	#[test]
+	fn misaligned_jump_target() {
+		let rom = TestRom::run(&[
+			Li(S0, BASE + 0x102),
+			Jr(S0),
+			Nop,
+			Break(1),
+		]);
+
+		let trap = rom.traps()[0];
+
+		// The fetch fails at the target, after the delay slot
+		assert_eq!(trap.code(), Exception::LoadAddressError as u32);
+		assert!(!trap.in_delay_slot());
+		assert_eq!(trap.epc, BASE + 0x102);
+		assert_eq!(rom.cpu().cop0().bad_vaddr(), BASE + 0x102);
+	}
+
+	#[test]
+	fn misaligned_data_address() {
+		for (access, addr, cause) in [
+			(Lw(T0, S0, 2), BASE + 2, Exception::LoadAddressError),
+			(Lh(T0, S0, 1), BASE + 1, Exception::LoadAddressError),
+			(Sw(T0, S0, -1), BASE - 1, Exception::StoreAddressError),
+			(Swc2(0, S0, 6), BASE + 6, Exception::StoreAddressError),
+		] {
+			// Enable COP2 for SWC2
+			let rom = TestRom::run(&[
+				Lui(T0, 0x4000),
+				Mtc0(T0, 12),
+				Lui(S0, (BASE >> 16) as u16),
+				access,
+				Break(1),
+			]);
+
+			let trap = rom.traps()[0];
+
+			assert_eq!(trap.code(), cause as u32, "{:?}", access);
+			assert_eq!(trap.epc, BASE + 12, "{:?}", access);
+			assert_eq!(rom.cpu().cop0().bad_vaddr(), addr, "{:?}", access);
+		}
+	}
+
+	/// Enable COP2, run RTPT followed by `delay` NOPs then `read`
+	/// and return the number of cycles taken by `read`
+	fn gte_read_cycles(delay: usize, read: Instruction) -> u64 {
+		let mut program = vec![
+			Lui(T0, 0x4000),
+			Mtc0(T0, 12),
+			Rtpt,
+		];
+
+		program.extend(std::iter::repeat_n(Nop, delay));
+		program.push(read);
+		program.push(Break(0));
+
+		let mut rom = TestRom::load_at(BASE, &program);
+
+		for _ in 0..(3 + delay) {
+			assert!(rom.step().is_none());
+		}
+
+		let start = rom.cpu().cycle_counter();
+
+		assert!(rom.step().is_none());
+
+		rom.cpu().cycle_counter() - start
+	}
+
+	#[test]
+	fn gte_read_after_rtpt_stalls() {
+		let rtpt = super::gte::command_cycles(0x0280030) as u64;
+
+		assert_eq!(rtpt, 23);
+		assert_eq!(gte_read_cycles(0, Mfc2(T1, 0)), rtpt);
+		assert_eq!(gte_read_cycles(0, Cfc2(T1, 0)), rtpt);
+		// Instructions which don't touch the GTE don't wait
+		assert!(gte_read_cycles(0, Nop) < rtpt);
+	}
+
+	#[test]
+	fn gte_read_stalls_for_remaining_cycles() {
+		let nop = gte_read_cycles(0, Nop);
+
+		// Only the cycles not already spent in the NOPs are waited
+		for delay in [1, 2, 3] {
+			let left = 23u64.saturating_sub(nop * delay as u64).max(nop);
+
+			assert_eq!(gte_read_cycles(delay, Mfc2(T1, 0)), left);
+		}
+
+		// Once the command is done the read takes as long as a NOP
+		assert_eq!(gte_read_cycles(23, Mfc2(T1, 0)), nop);
+		assert_eq!(gte_read_cycles(23, Cfc2(T1, 0)), nop);
+	}
+
+	#[test]
+	fn unknown_gte_command() {
+		let rom = TestRom::run(&[
+			Lui(T0, 0x4000),
+			Mtc0(T0, 12),
+			Cop2(0x00),
+			Mfc2(T1, 0),
+			Break(0),
+		]);
+
+		// Ignored instead of bringing down the emulator
+		assert_eq!(rom.traps().len(), 1);
+	}
+
+	/// Set SR.CU2 so that GTE instructions can be used
+	const ENABLE_COP2: [Instruction; 2] = [
+		Lui(T0, 0x4000),
+		Mtc0(T0, 12),
+	];
+
+	#[test]
+	fn mfc2_load_delay() {
+		let mut code = ENABLE_COP2.to_vec();
+
+		code.extend_from_slice(&[
+			Li(T1, 0x5678_1234),
+			Mtc2(T1, 0),
+			Nop,
+			Li(T0, 1),
+			Mfc2(T0, 0),
+			// Load delay slot: still the old value
+			Move(T2, T0),
+			Move(T3, T0),
+			Break(0),
+		]);
+
+		let rom = TestRom::run(&code);
+
+		assert_eq!(rom.reg(T2), 1);
+		assert_eq!(rom.reg(T3), 0x5678_1234);
+	}
+
+	#[test]
+	fn lwc2_swc2_round_trip() {
+		let mut code = ENABLE_COP2.to_vec();
+
+		code.extend_from_slice(&[
+			// VXY0 and VXY1 hold a full word each
+			Lwc2(0, S0, 8),
+			Lwc2(2, S0, 12),
+			Nop,
+			Swc2(2, S0, 0),
+			Swc2(0, S0, 4),
+		]);
+
+		let mut rom = run_with_data(&code);
+
+		let data = rom.cpu().reg(S0.0 as u32);
+
+		assert_eq!(rom.load(data), 0xcafe_f00d);
+		assert_eq!(rom.load(data + 4), 0xdead_beef);
+		assert_eq!(rom.traps().len(), 1);
+	}
+
+	#[test]
+	fn cop2_disabled() {
+		for op in [Mfc2(T0, 0), Mtc2(T0, 0), Lwc2(0, S0, 0), Swc2(0, S0, 0), Rtpt] {
+			let rom = run_with_data(&[op]);
+
+			let trap = rom.traps()[0];
+
+			assert_eq!(rom.traps().len(), 2, "{:?}", op);
+			assert_eq!(trap.code(), Exception::CoprocessorError as u32);
+			assert_eq!(trap.epc, BASE + 8);
+			// CAUSE.CE
+			assert_eq!((trap.cause >> 28) & 3, 2);
+		}
+	}
+
+	#[test]
+	fn missing_coprocessors() {
+		// COP1, COP3 then LWCn and SWCn for coprocessors 0, 1 and 3
+		for (word, cop) in [(0x4400_0000, 1), (0x4c00_0000, 3),
+							(0xc000_0000, 0), (0xc400_0000, 1), (0xcc00_0000, 3),
+							(0xe000_0000, 0), (0xe400_0000, 1), (0xec00_0000, 3)] {
+			let rom = TestRom::run(&[Word(word), Break(0)]);
+
+			let trap = rom.traps()[0];
+
+			assert_eq!(trap.code(), Exception::CoprocessorError as u32);
+			assert_eq!((trap.cause >> 28) & 3, cop, "{:08x}", word);
+			assert_eq!(trap.epc, BASE);
+		}
+	}
+
+	#[test]
+	fn gte_write_delay() {
+		let mut code = ENABLE_COP2.to_vec();
+
+		code.extend_from_slice(&[
+			// VXY0
+			Li(T1, 0x1111_1111),
+			Mtc2(T1, 0),
+			Li(T1, 0x2222_2222),
+			Mtc2(T1, 0),
+			// Delay slot of the write: still the old value
+			Mfc2(T2, 0),
+			Mfc2(T3, 0),
+			// TRX
+			Li(T1, 0x3333_3333),
+			Ctc2(T1, 5),
+			Cfc2(T4, 5),
+			Cfc2(T5, 5),
+			// VXY1
+			Lwc2(2, S0, 8),
+			Mfc2(T6, 2),
+			Mfc2(T7, 2),
+			// IR1, squared to MAC1 by SQR
+			Li(T1, 2),
+			Mtc2(T1, 9),
+			Li(T1, 3),
+			Mtc2(T1, 9),
+			Sqr(false),
+			Nop,
+			Mfc2(S1, 25),
+			Swc2(9, S0, 0),
+			Nop,
+		]);
+
+		let mut rom = run_with_data(&code);
+
+		assert_eq!(rom.reg(T2), 0x1111_1111);
+		assert_eq!(rom.reg(T3), 0x2222_2222);
+		assert_eq!(rom.reg(T4), 0);
+		assert_eq!(rom.reg(T5), 0x3333_3333);
+		assert_eq!(rom.reg(T6), 0);
+		assert_eq!(rom.reg(T7), 0xdead_beef);
+		// SQR ran with the old IR1
+		assert_eq!(rom.reg(S1), 4);
+
+		let data = rom.reg(S0);
+		assert_eq!(rom.load(data), 3);
+	}
+
+	#[test]
+	fn unsupported_cop0_instruction() {
+		let rom = TestRom::run(&[
+			// TLBR, not implemented on the PlayStation
+			Word(0x4200_0001),
+			Break(0),
+		]);
+
+		let trap = rom.traps()[0];
+
+		assert_eq!(trap.code(), Exception::IllegalInstruction as u32);
+		assert_eq!(trap.epc, BASE);
+	}
+
+	#[test]
+	fn intercept_putchar() {
+		let mut code = Vec::new();
+
+		for (vector, function, c) in [(0xa0, 0x3c, b'a'), (0xb0, 0x3d, b'b'),
+									  // Not putchar
+									  (0xa0, 0x3d, b'x'), (0xb0, 0x3c, b'y')] {
+			code.extend([
+				Li(A0, c as u32),
+				Li(T1, function),
+				Li(T2, vector),
+				Jalr(RA, T2),
+				Nop,
+			]);
+		}
+		code.push(Break(0));
+
+		for intercept in [true, false] {
+			let mut rom = TestRom::load_at(BASE, &code);
+
+			let inter = rom.cpu_mut().interconnect_mut();
+
+			// The dummy BIOS has no kernel, return straight away
+			for vector in [0xa0, 0xb0] {
+				// jr $ra; nop
+				inter.store::<super::Word>(vector, 0x03e0_0008);
+				inter.store::<super::Word>(vector + 4, 0);
+			}
+
+			inter.tty_mut().set_intercept_putchar(intercept);
+
+			rom.resume();
+
+			let output = rom.cpu_mut().interconnect_mut().tty_mut().take_output();
+
+			match intercept {
+				true => assert_eq!(output, b"ab"),
+				false => assert!(output.is_empty()),
+			}
+		}
+	}
+}
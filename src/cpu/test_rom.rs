@@ -0,0 +1,315 @@
+//! Test ROM harness
+//!
+//! Assemble a snippet written with `assembler::syntax`, run it on a
+//! fresh `Cpu` with the dummy or the HLE BIOS until it raises a `Break`
+//! exception, or step through it, and give access to the resulting
+//! registers and RAM.
+
+use crate::assembler::syntax::{Break, Instruction, Register};
+use crate::assembler::Assembler;
+use crate::bios::Bios;
+use crate::memory::{self, Interconnect};
+
+use super::cop0::Exception;
+use super::Cpu;
+
+/// Default load address of the test programs
+pub const BASE: u32 = 0x8001_0000;
+
+/// General exception vector in RAM, used while SR.BEV is clear
+const EXCEPTION_VECTOR: u32 = 0x8000_0080;
+
+/// Exception vector in the BIOS, used while SR.BEV is set
+const BOOT_EXCEPTION_VECTOR: u32 = 0xbfc0_0180;
+
+/// Number of instructions after which a program is considered stuck
+const MAX_INSTRUCTIONS: u32 = 1_000_000;
+
+/// Exception raised while running a test program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+	/// Value of CAUSE when the handler was entered
+	pub cause: u32,
+	/// Value of EPC when the handler was entered
+	pub epc: u32,
+}
+
+impl Trap {
+	/// Exception code field of CAUSE
+	pub fn code(&self) -> u32 {
+		(self.cause >> 2) & 0x1f
+	}
+
+	/// True if CAUSE.BD is set: the exception occurred in a branch
+	/// delay slot
+	pub fn in_delay_slot(&self) -> bool {
+		self.cause & (1 << 31) != 0
+	}
+}
+
+/// State of the CPU after running a test program
+pub struct TestRom {
+	cpu: Cpu,
+	traps: Vec<Trap>,
+}
+
+impl TestRom {
+	/// Assemble `code` at `BASE` and run it, see `run_at`
+	pub fn run(code: &[Instruction]) -> TestRom {
+		TestRom::run_at(BASE, code)
+	}
+
+	/// Assemble `code` at `base`, load it in RAM and run it from its
+	/// first instruction until a `Break` exception is raised, see
+	/// `load_at` and `resume`
+	pub fn run_at(base: u32, code: &[Instruction]) -> TestRom {
+		let mut rom = TestRom::load_at(base, code);
+
+		rom.resume();
+
+		rom
+	}
+
+	/// Like `run` but with the HLE BIOS: kernel calls and exceptions
+	/// other than `Break` are handled by `bios::hle`
+	pub fn run_hle(code: &[Instruction]) -> TestRom {
+		let mut rom = TestRom::load_with(Bios::hle(), BASE, code);
+
+		rom.resume();
+
+		rom
+	}
+
+	/// Assemble `code` at `base` and load it in RAM without running
+	/// it. A `Break` is installed at the RAM exception vector
+	/// beforehand so that any other exception ends the program too,
+	/// unless `code` overwrites it. Panics if the code doesn't
+	/// assemble.
+	pub fn load_at(base: u32, code: &[Instruction]) -> TestRom {
+		TestRom::load_with(Bios::dummy(), base, code)
+	}
+
+	fn load_with(bios: Bios, base: u32, code: &[Instruction]) -> TestRom {
+		let mut asm = Assembler::from_base(base);
+
+		if let Err(e) = asm.assemble(code) {
+			panic!("Test ROM doesn't assemble: {}", e);
+		}
+
+		let mut cpu = Cpu::new(Interconnect::new(bios));
+
+		let mut handler = Assembler::from_base(EXCEPTION_VECTOR);
+
+		handler.assemble(&[Break(0)]).unwrap();
+
+		for (addr, code) in [(EXCEPTION_VECTOR, handler.code()), (base, asm.code())] {
+			for (i, &b) in code.iter().enumerate() {
+				let addr = addr.wrapping_add(i as u32);
+
+				cpu.interconnect_mut().store::<memory::Byte>(addr, b as u32);
+			}
+		}
+
+		cpu.set_pc(base);
+
+		TestRom::from_cpu(cpu)
+	}
+
+	/// Wrap a CPU set up by the caller, for instance one that booted
+	/// an executable
+	pub fn from_cpu(cpu: Cpu) -> TestRom {
+		TestRom { cpu, traps: Vec::new() }
+	}
+
+	/// Run from the current PC until a `Break` exception is raised.
+	/// Panics if the code never breaks.
+	pub fn resume(&mut self) {
+		for _ in 0..MAX_INSTRUCTIONS {
+			if let Some(trap) = self.step()
+				&& trap.code() == Exception::Break as u32 {
+				return;
+			}
+		}
+
+		panic!("Test ROM didn't break after {} instructions, PC: 0x{:08x}",
+			   MAX_INSTRUCTIONS, self.cpu.pc());
+	}
+
+	/// Run a single instruction. Return the exception raised by it,
+	/// if any
+	pub fn step(&mut self) -> Option<Trap> {
+		self.cpu.run_next_instruction();
+
+		let pc = self.cpu.pc();
+
+		if pc != EXCEPTION_VECTOR && pc != BOOT_EXCEPTION_VECTOR {
+			return None;
+		}
+
+		let trap = Trap {
+			cause: self.cpu.cop0().cause(self.cpu.interconnect().irq_state()),
+			epc: self.cpu.cop0().epc(),
+		};
+
+		self.traps.push(trap);
+
+		Some(trap)
+	}
+
+	/// Value of general purpose register `r`
+	pub fn reg(&self, r: Register) -> u32 {
+		self.cpu.reg(r.0 as u32)
+	}
+
+	/// Copy of the whole register file
+	pub fn regs(&self) -> [u32; 32] {
+		self.cpu.regs
+	}
+
+	/// Load the word at `addr`
+	pub fn load(&mut self, addr: u32) -> u32 {
+		self.cpu.interconnect_mut().load::<memory::Word>(addr)
+	}
+
+	/// Read `len` bytes starting at `addr`
+	pub fn bytes(&mut self, addr: u32, len: usize) -> Vec<u8> {
+		let inter = self.cpu.interconnect_mut();
+
+		(0..len)
+			.map(|i| inter.load::<memory::Byte>(addr.wrapping_add(i as u32)) as u8)
+			.collect()
+	}
+
+	/// Exceptions raised while running, the last one is the `Break`
+	/// which ended the program
+	pub fn traps(&self) -> &[Trap] {
+		&self.traps
+	}
+
+	pub fn cpu(&self) -> &Cpu {
+		&self.cpu
+	}
+
+	pub fn cpu_mut(&mut self) -> &mut Cpu {
+		&mut self.cpu
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::assembler::syntax::*;
+
+	use super::*;
+
+	#[test]
+	fn arithmetic() {
+		let rom = TestRom::run(&[
+			Li(T0, 0x1234_5678),
+			Li(T1, 0x1111_1111),
+			Addu(T2, T0, T1),
+			Subu(T3, T0, T1),
+			Sll(T4, T1, 4),
+			Sra(T5, T0, 8),
+			Nor(T6, T0, R0),
+			Slt(T7, T1, T0),
+			Break(0),
+		]);
+
+		assert_eq!(rom.reg(T2), 0x2345_6789);
+		assert_eq!(rom.reg(T3), 0x0123_4567);
+		assert_eq!(rom.reg(T4), 0x1111_1110);
+		assert_eq!(rom.reg(T5), 0x0012_3456);
+		assert_eq!(rom.reg(T6), !0x1234_5678);
+		assert_eq!(rom.reg(T7), 1);
+		assert_eq!(rom.regs()[0], 0);
+	}
+
+	#[test]
+	fn mult_div() {
+		let rom = TestRom::run(&[
+			Li(A0, -7i32 as u32),
+			Li(A1, 3),
+			Mult(A0, A1),
+			Mflo(T0),
+			Mfhi(T1),
+			Div(A0, A1),
+			Mflo(T2),
+			Mfhi(T3),
+			Break(0),
+		]);
+
+		assert_eq!(rom.reg(T0), -21i32 as u32);
+		assert_eq!(rom.reg(T1), 0xffff_ffff);
+		assert_eq!(rom.reg(T2), -2i32 as u32);
+		assert_eq!(rom.reg(T3), -1i32 as u32);
+	}
+
+	#[test]
+	fn load_store() {
+		let mut rom = TestRom::run(&[
+			La(S0, Label::Global("data")),
+			Li(T0, 0xdead_beef),
+			Sw(T0, S0, 0),
+			Sh(T0, S0, 4),
+			Sb(T0, S0, 7),
+			Lb(T1, S0, 3),
+			Lbu(T2, S0, 3),
+			Lhu(T3, S0, 4),
+			Nop,
+			Break(0),
+			Align(2),
+			Global("data"),
+			Word(0),
+			Word(0),
+		]);
+
+		let data = rom.cpu().reg(S0.0 as u32);
+
+		assert_eq!(rom.load(data), 0xdead_beef);
+		assert_eq!(rom.bytes(data + 4, 4), [0xef, 0xbe, 0x00, 0xef]);
+		assert_eq!(rom.reg(T1), 0xffff_ffde);
+		assert_eq!(rom.reg(T2), 0xde);
+		assert_eq!(rom.reg(T3), 0xbeef);
+	}
+
+	#[test]
+	fn branches_and_calls() {
+		let rom = TestRom::run(&[
+			Li(T0, 5),
+			Li(V0, 0),
+			Local("1"),
+			Addiu(V0, V0, 3),
+			Addiu(T0, T0, -1),
+			Bnez(T0, Label::Local("1", 'b')),
+			Nop,
+			Jal(Label::Global("double")),
+			Nop,
+			Break(0),
+			Global("double"),
+			Jr(RA),
+			Addu(V0, V0, V0),
+		]);
+
+		assert_eq!(rom.reg(V0), 30);
+		assert_eq!(rom.traps().len(), 1);
+	}
+
+	#[test]
+	fn other_exceptions_break_in_handler() {
+		let rom = TestRom::run(&[
+			Li(T0, 0x7fff_ffff),
+			Add(T1, T0, T0),
+			Break(0),
+		]);
+
+		let traps = rom.traps();
+
+		assert_eq!(traps.len(), 2);
+		assert_eq!(traps[0].code(), Exception::Overflow as u32);
+		assert_eq!(traps[0].epc, BASE + 8);
+		assert!(!traps[0].in_delay_slot());
+		assert_eq!(traps[1].epc, EXCEPTION_VECTOR);
+		// The destination register is not modified
+		assert_eq!(rom.reg(T1), 0);
+	}
+}
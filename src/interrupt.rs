@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+use crate::savestate::Component;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Interrupt {
 	VBlank = 0,
@@ -9,6 +13,7 @@ pub enum Interrupt {
 	PadMemCard = 7,
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct InterruptState {
 	status: u16,
 	mask: u16,
@@ -62,3 +67,14 @@ impl InterruptState {
 	}
 }
 
+impl Default for InterruptState {
+	fn default() -> InterruptState {
+		InterruptState::new()
+	}
+}
+
+impl Component for InterruptState {
+	const SECTION: &'static str = "irq";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
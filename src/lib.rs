@@ -4,9 +4,17 @@ extern crate log;
 pub mod cpu;
 pub mod memory;
 pub mod interrupt;
+pub mod tty;
 pub mod bios;
 pub mod assembler;
+pub mod savestate;
+pub mod rewind;
 
 #[macro_use]
 mod box_array;
 pub mod cdrom;
+
+pub mod version {
+	// VERSION and VERSION_CSTR are generated by build.rs
+	include!(concat!(env!("OUT_DIR"), "/version.rs"));
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+use crate::savestate::Component;
+use crate::tty::Tty;
+
+/// SCN2681 dual UART found on the expansion 2 port of development
+/// boards. Only the transmit side of channel A is really emulated:
+/// that's what the BIOS uses for its debug output. Transmission is
+/// instantaneous so the transmitter is always ready.
+#[derive(Serialize, Deserialize)]
+pub struct Duart {
+	/// Mode registers: MR1 and MR2 for both channels
+	mode: [[u8; 2]; 2],
+	/// Index of the next mode register accessed for each channel
+	mode_pointer: [usize; 2],
+	/// Clock select registers
+	clock_select: [u8; 2],
+	/// Transmitter enabled
+	tx_enabled: [bool; 2],
+	/// Auxiliary control register
+	aux_control: u8,
+	/// Interrupt mask register
+	interrupt_mask: u8,
+	/// Counter/timer preset
+	counter: u16,
+	/// Output port configuration register
+	output_config: u8,
+	/// Output port bits
+	output: u8,
+}
+
+impl Duart {
+	pub fn new() -> Duart {
+		Duart {
+			mode: [[0; 2]; 2],
+			mode_pointer: [0; 2],
+			clock_select: [0; 2],
+			tx_enabled: [false; 2],
+			aux_control: 0,
+			interrupt_mask: 0,
+			counter: 0,
+			output_config: 0,
+			output: 0,
+		}
+	}
+
+	/// Register read, `offset` is relative to the start of the DUART
+	/// registers
+	pub fn load(&mut self, offset: u32) -> u8 {
+		match offset {
+			0x0 | 0x8 => {
+				let channel = (offset >> 3) as usize;
+				let v = self.mode[channel][self.mode_pointer[channel]];
+				self.mode_pointer[channel] = 1;
+				v
+			}
+			// Status register: TxRDY | TxEMT
+			0x1 | 0x9 => 0x0c,
+			// Receive holding register: nothing is ever received
+			0x3 | 0xb => 0,
+			// Input port change register
+			0x4 => 0,
+			// Interrupt status register: TxRDYA | TxRDYB when enabled
+			0x5 => 0x11 & self.interrupt_mask,
+			0x6 => (self.counter >> 8) as u8,
+			0x7 => self.counter as u8,
+			// Input port
+			0xd => 0,
+			_ => {
+				warn!("Unhandled DUART read at offset {:x}", offset);
+				0
+			}
+		}
+	}
+
+	/// Register write
+	pub fn store(&mut self, offset: u32, val: u8, tty: &mut Tty) {
+		match offset {
+			0x0 | 0x8 => {
+				let channel = (offset >> 3) as usize;
+				self.mode[channel][self.mode_pointer[channel]] = val;
+				self.mode_pointer[channel] = 1;
+			}
+			0x1 | 0x9 => self.clock_select[(offset >> 3) as usize] = val,
+			0x2 | 0xa => self.command((offset >> 3) as usize, val),
+			0x3 => {
+				if self.tx_enabled[0] {
+					tty.putchar(val);
+				}
+			}
+			0xb => {
+				if self.tx_enabled[1] {
+					warn!("Unhandled DUART channel B output 0x{:02x}", val);
+				}
+			}
+			0x4 => self.aux_control = val,
+			0x5 => self.interrupt_mask = val,
+			0x6 => self.counter = (self.counter & 0xff) | ((val as u16) << 8),
+			0x7 => self.counter = (self.counter & 0xff00) | val as u16,
+			0xd => self.output_config = val,
+			0xe => self.output |= val,
+			0xf => self.output &= !val,
+			_ => warn!("Unhandled DUART write at offset {:x}: {:02x}", offset, val),
+		}
+	}
+
+	fn command(&mut self, channel: usize, cmd: u8) {
+		// Transmitter enable/disable
+		match (cmd >> 2) & 3 {
+			1 => self.tx_enabled[channel] = true,
+			2 => self.tx_enabled[channel] = false,
+			_ => (),
+		}
+
+		// Miscellaneous commands
+		match (cmd >> 4) & 7 {
+			// Reset mode register pointer
+			1 => self.mode_pointer[channel] = 0,
+			// Reset transmitter
+			3 => self.tx_enabled[channel] = false,
+			_ => (),
+		}
+	}
+}
+
+impl Default for Duart {
+	fn default() -> Duart {
+		Duart::new()
+	}
+}
+
+impl Component for Duart {
+	const SECTION: &'static str = "duart";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::bios::Bios;
+	use crate::memory::{Byte, Interconnect};
+
+	/// Channel A registers in expansion 2
+	const COMMAND_A: u32 = 0x1f80_2022;
+	const TX_A: u32 = 0x1f80_2023;
+
+	#[test]
+	fn transmit() {
+		let mut inter = Interconnect::new(Bios::dummy());
+
+		// Transmitter disabled after reset
+		inter.store::<Byte>(TX_A, b'x' as u32);
+		assert!(inter.tty_mut().take_output().is_empty());
+
+		// Enable TX
+		inter.store::<Byte>(COMMAND_A, 0x04);
+		inter.store::<Byte>(TX_A, b'o' as u32);
+		inter.store::<Byte>(TX_A, b'k' as u32);
+		assert_eq!(inter.tty_mut().take_output(), b"ok");
+
+		// Disable TX
+		inter.store::<Byte>(COMMAND_A, 0x08);
+		inter.store::<Byte>(TX_A, b'x' as u32);
+		assert!(inter.tty_mut().take_output().is_empty());
+
+		// Enable then reset the transmitter
+		inter.store::<Byte>(COMMAND_A, 0x04);
+		inter.store::<Byte>(COMMAND_A, 0x30);
+		inter.store::<Byte>(TX_A, b'x' as u32);
+		assert!(inter.tty_mut().take_output().is_empty());
+
+		// Channel B doesn't go to the TTY
+		inter.store::<Byte>(0x1f80_202a, 0x04);
+		inter.store::<Byte>(0x1f80_202b, b'x' as u32);
+		assert!(inter.tty_mut().take_output().is_empty());
+	}
+}
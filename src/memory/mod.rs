@@ -1,4 +1,17 @@
+mod duart;
+mod ram;
+
+use serde::{Deserialize, Serialize};
+
 use crate::bios::Bios;
+use crate::interrupt::InterruptState;
+use crate::savestate::{self, Component, StateReader, StateWriter};
+use crate::tty::Tty;
+
+use self::duart::Duart;
+use self::ram::Ram;
+
+pub use self::ram::RAM_SIZE;
 
 const RDRAM_START: usize = 0x0000_0000;
 const RDRAM_END: usize = 0x0200_0000;
@@ -9,19 +22,302 @@ const HARDWARE_IO_REGS_END: usize = 0x1100_0000;
 const PRIVILEGED_GS_REGS_START: usize = 0x1200_0000;
 const PRIVILEGED_GS_REGS_END: usize = 0x1300_0000;
 
+const MEM_CONTROL_START: usize = 0x1F80_1000;
+const MEM_CONTROL_END: usize = 0x1F80_1024;
+
+const RAM_SIZE_REG: usize = 0x1F80_1060;
+
+const IRQ_STATUS: usize = 0x1F80_1070;
+const IRQ_MASK: usize = 0x1F80_1074;
+
+const EXPANSION_2_START: usize = 0x1F80_2000;
+const EXPANSION_2_END: usize = 0x1F80_2080;
+
+/// Offset of the DUART registers in expansion 2
+const DUART_START: usize = 0x20;
+const DUART_END: usize = 0x30;
+
 const BIOS_START: usize = 0x1FC0_0000;
 const BIOS_END: usize = 0x2000_0000;
 
+const CACHE_CONTROL: usize = 0xFFFE_0130;
 
+
+#[derive(Serialize, Deserialize)]
 pub struct Interconnect {
 	bios: Bios,
+	ram: Ram,
+	#[serde(skip)]
+	irq_state: InterruptState,
+	#[serde(skip)]
+	duart: Duart,
+	/// Guest text output. Not part of the emulated state.
+	#[serde(skip)]
+	tty: Tty,
 
 	ram_size: u32,
 	mem_control: [u32; 9],
+	cache_control: CacheControl,
 }
 
 impl Interconnect {
+	pub fn new(bios: Bios) -> Interconnect {
+		Interconnect {
+			bios,
+			ram: Ram::new(),
+			irq_state: InterruptState::new(),
+			duart: Duart::new(),
+			tty: Tty::new(),
+			ram_size: 0,
+			mem_control: [0; 9],
+			cache_control: CacheControl(0),
+		}
+	}
+
+	/// Store the interconnect and the devices it owns in `state`
+	pub fn save_state(&self, state: &mut StateWriter) -> Result<(), savestate::Error> {
+		state.add(self)?;
+		state.add(&self.irq_state)?;
+		state.add(&self.duart)
+	}
+
+	/// Rebuild the interconnect from the sections in `state`
+	pub fn load_state(state: &StateReader) -> Result<Interconnect, savestate::Error> {
+		let mut inter: Interconnect = state.get()?;
+
+		inter.irq_state = state.get()?;
+		inter.duart = state.get()?;
+
+		Ok(inter)
+	}
+
+	pub fn bios(&self) -> &Bios {
+		&self.bios
+	}
+
+	/// Save states only contain the BIOS checksum, this method is used
+	/// to put the real ROM image back after loading. Fails and gives
+	/// `bios` back if its checksum doesn't match the saved one.
+	pub fn reattach_bios(&mut self, bios: Bios) -> Result<(), Bios> {
+		if bios.metadata().sha256 != self.bios.metadata().sha256 {
+			return Err(bios);
+		}
+
+		self.bios = bios;
+
+		Ok(())
+	}
+
+	/// Remove the BIOS from the interconnect, leaving a dummy image
+	/// in its place
+	pub fn take_bios(&mut self) -> Bios {
+		std::mem::replace(&mut self.bios, Bios::dummy())
+	}
+
+	pub fn irq_state(&self) -> InterruptState {
+		self.irq_state
+	}
+
+	pub fn irq_state_mut(&mut self) -> &mut InterruptState {
+		&mut self.irq_state
+	}
+
+	pub fn tty(&self) -> &Tty {
+		&self.tty
+	}
+
+	pub fn tty_mut(&mut self) -> &mut Tty {
+		&mut self.tty
+	}
+
+	/// Remove the TTY from the interconnect, leaving an empty one in
+	/// its place
+	pub fn take_tty(&mut self) -> Tty {
+		std::mem::take(&mut self.tty)
+	}
+
+	pub fn set_tty(&mut self, tty: Tty) {
+		self.tty = tty;
+	}
+
+	pub fn cache_control(&self) -> CacheControl {
+		self.cache_control
+	}
+
+	/// Read the little endian value of type `T` at `abs_addr` without
+	/// side effects. Only RAM and the BIOS can be read this way, returns
+	/// `None` for any other address.
+	pub fn peek<T: Addressable>(&self, abs_addr: u32) -> Option<u32> {
+		let addr = mask_region(abs_addr) as usize;
+
+		// The last byte must be in the same region
+		let last = addr + T::size() as usize - 1;
+
+		match (addr, last) {
+			(RDRAM_START..RDRAM_END, RDRAM_START..RDRAM_END) =>
+				Some(self.ram.load::<T>((addr - RDRAM_START) as u32)),
+			(BIOS_START..BIOS_END, BIOS_START..BIOS_END) =>
+				Some(self.bios.load::<T>((addr - BIOS_START) as u32)),
+			_ => None,
+		}
+	}
+
+	/// Store the little endian value `val` of type `T` at `abs_addr`
+	/// without side effects. Only RAM can be written this way, returns
+	/// `None` for any other address.
+	pub fn poke<T: Addressable>(&mut self, abs_addr: u32, val: u32) -> Option<()> {
+		let addr = mask_region(abs_addr) as usize;
+
+		let last = addr + T::size() as usize - 1;
+
+		match (addr, last) {
+			(RDRAM_START..RDRAM_END, RDRAM_START..RDRAM_END) => {
+				self.ram.store::<T>((addr - RDRAM_START) as u32, val);
+				Some(())
+			}
+			_ => None,
+		}
+	}
+
+	/// Load the little endian value of type `T` at `abs_addr`
+	pub fn load<T: Addressable>(&mut self, abs_addr: u32) -> u32 {
+		let addr = mask_region(abs_addr) as usize;
+
+		match addr {
+			RDRAM_START..RDRAM_END =>
+				self.ram.load::<T>((addr - RDRAM_START) as u32),
+			BIOS_START..BIOS_END =>
+				self.bios.load::<T>((addr - BIOS_START) as u32),
+			MEM_CONTROL_START..MEM_CONTROL_END => {
+				if T::size() != 4 {
+					panic!("Unhandled MEM_CONTROL access ({})", T::size());
+				}
+
+				self.mem_control[(addr - MEM_CONTROL_START) >> 2]
+			}
+			RAM_SIZE_REG => self.ram_size,
+			IRQ_STATUS => self.irq_state.status() as u32,
+			IRQ_MASK => self.irq_state.mask() as u32,
+			CACHE_CONTROL => self.cache_control.0,
+			EXPANSION_2_START..EXPANSION_2_END => {
+				let offset = addr - EXPANSION_2_START;
+
+				match offset {
+					DUART_START..DUART_END =>
+						self.duart.load((offset - DUART_START) as u32) as u32,
+					_ => {
+						warn!("Unhandled expansion 2 load at 0x{:08x}", abs_addr);
+						0
+					}
+				}
+			}
+			HARDWARE_IO_REGS_START..HARDWARE_IO_REGS_END => {
+				warn!("Unhandled hardware register load at 0x{:08x}", abs_addr);
+				0
+			}
+			PRIVILEGED_GS_REGS_START..PRIVILEGED_GS_REGS_END => {
+				warn!("Unhandled GS register load at 0x{:08x}", abs_addr);
+				0
+			}
+			_ => panic!("Unhandled load at address 0x{:08x}", abs_addr),
+		}
+	}
+
+	/// Store the little endian value `val` of type `T` at `abs_addr`
+	pub fn store<T: Addressable>(&mut self, abs_addr: u32, val: u32) {
+		let addr = mask_region(abs_addr) as usize;
+
+		match addr {
+			RDRAM_START..RDRAM_END =>
+				self.ram.store::<T>((addr - RDRAM_START) as u32, val),
+			BIOS_START..BIOS_END =>
+				warn!("Write to BIOS ROM at 0x{:08x}: 0x{:08x}", abs_addr, val),
+			MEM_CONTROL_START..MEM_CONTROL_END => {
+				if T::size() != 4 {
+					panic!("Unhandled MEM_CONTROL access ({})", T::size());
+				}
 
+				self.mem_control[(addr - MEM_CONTROL_START) >> 2] = val;
+			}
+			RAM_SIZE_REG => self.ram_size = val,
+			IRQ_STATUS => self.irq_state.ack(val as u16),
+			IRQ_MASK => self.irq_state.set_mask(val as u16),
+			CACHE_CONTROL => self.cache_control = CacheControl(val),
+			EXPANSION_2_START..EXPANSION_2_END => {
+				let offset = addr - EXPANSION_2_START;
+
+				match offset {
+					DUART_START..DUART_END =>
+						self.duart.store((offset - DUART_START) as u32,
+										 val as u8,
+										 &mut self.tty),
+					_ => warn!("Unhandled expansion 2 store at 0x{:08x}: 0x{:08x}",
+							   abs_addr, val),
+				}
+			}
+			HARDWARE_IO_REGS_START..HARDWARE_IO_REGS_END =>
+				warn!("Unhandled hardware register store at 0x{:08x}: 0x{:08x}",
+					  abs_addr, val),
+			PRIVILEGED_GS_REGS_START..PRIVILEGED_GS_REGS_END =>
+				warn!("Unhandled GS register store at 0x{:08x}: 0x{:08x}",
+					  abs_addr, val),
+			_ => panic!("Unhandled store at address 0x{:08x}: 0x{:08x}",
+						abs_addr, val),
+		}
+	}
+}
+
+/// Placeholder used while a save state is being loaded, the BIOS is
+/// re-attached afterwards
+impl Default for Interconnect {
+	fn default() -> Interconnect {
+		Interconnect::new(Bios::dummy())
+	}
+}
+
+impl Component for Interconnect {
+	const SECTION: &'static str = "interconnect";
+	const REVISION: u32 = 1;
+	const SINCE: &'static str = "0.1.0";
+}
+
+/// Mask array used to strip the region bits of the address. The
+/// mask is selected using the 3 MSBs of the address so each entry
+/// effectively matches 512kB of the address space. KSEG2 is not
+/// touched since it doesn't share anything with the other regions.
+const REGION_MASK: [u32; 8] = [
+	// KUSEG: 2048MB
+	0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff,
+	// KSEG0: 512MB
+	0x7fffffff,
+	// KSEG1: 512MB
+	0x1fffffff,
+	// KSEG2: 1024MB
+	0xffffffff, 0xffffffff,
+];
+
+/// Mask a CPU address to remove the region bits.
+pub fn mask_region(addr: u32) -> u32 {
+	let index = (addr >> 29) as usize;
+
+	addr & REGION_MASK[index]
+}
+
+/// Cache control register
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct CacheControl(u32);
+
+impl CacheControl {
+	/// Return true if the instruction cache is enabled
+	pub fn icache_enabled(self) -> bool {
+		self.0 & 0x800 != 0
+	}
+
+	/// When true the cache maintenance writes invalidate whole
+	/// cachelines instead of storing instructions
+	pub fn tag_test_mode(self) -> bool {
+		self.0 & 4 != 0
+	}
 }
 
 pub trait Addressable {
@@ -50,4 +346,4 @@ impl Addressable for Word {
     fn size() -> u8 {
         4
     }
-}
\ No newline at end of file
+}
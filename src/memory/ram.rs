@@ -0,0 +1,110 @@
+use std::fmt;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::box_array;
+
+use super::Addressable;
+
+/// Main system RAM
+pub const RAM_SIZE: usize = super::RDRAM_END - super::RDRAM_START;
+
+pub struct Ram {
+	data: Box<[u8; RAM_SIZE]>,
+}
+
+impl Ram {
+	/// Instantiate main RAM with all bytes set to 0
+	pub fn new() -> Ram {
+		Ram {
+			data: box_array![0; RAM_SIZE],
+		}
+	}
+
+	/// Fetch the little endian value at `offset`
+	pub fn load<T: Addressable>(&self, offset: u32) -> u32 {
+		let offset = offset as usize;
+
+		let mut v = 0;
+
+		for i in 0..T::size() as usize {
+			v |= (self.data[offset + i] as u32) << (i * 8)
+		}
+
+		v
+	}
+
+	/// Store the little endian value `val` at `offset`
+	pub fn store<T: Addressable>(&mut self, offset: u32, val: u32) {
+		let offset = offset as usize;
+
+		for i in 0..T::size() as usize {
+			self.data[offset + i] = (val >> (i * 8)) as u8;
+		}
+	}
+}
+
+impl Default for Ram {
+	fn default() -> Ram {
+		Ram::new()
+	}
+}
+
+impl Serialize for Ram {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+		where
+			S: Serializer
+	{
+		serializer.serialize_bytes(&self.data[..])
+	}
+}
+
+impl<'de> Deserialize<'de> for Ram {
+	fn deserialize<D>(deserializer: D) -> Result<Ram, D::Error>
+		where
+			D: Deserializer<'de>
+	{
+		struct RamVisitor;
+
+		impl<'de> de::Visitor<'de> for RamVisitor {
+			type Value = Ram;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				write!(f, "{} bytes of RAM", RAM_SIZE)
+			}
+
+			fn visit_bytes<E>(self, v: &[u8]) -> Result<Ram, E>
+				where
+					E: de::Error
+			{
+				if v.len() != RAM_SIZE {
+					return Err(E::invalid_length(v.len(), &self));
+				}
+
+				let mut ram = Ram::new();
+
+				ram.data.copy_from_slice(v);
+
+				Ok(ram)
+			}
+
+			fn visit_seq<A>(self, mut seq: A) -> Result<Ram, A::Error>
+				where
+					A: de::SeqAccess<'de>
+			{
+				let mut ram = Ram::new();
+
+				for i in 0..RAM_SIZE {
+					match seq.next_element()? {
+						Some(b) => ram.data[i] = b,
+						None => return Err(de::Error::invalid_length(i, &self)),
+					}
+				}
+
+				Ok(ram)
+			}
+		}
+
+		deserializer.deserialize_bytes(RamVisitor)
+	}
+}
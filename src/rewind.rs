@@ -0,0 +1,376 @@
+//! Rewind support.
+//!
+//! Save states are taken every few frames and kept in a ring buffer.
+//! To keep memory usage reasonable most snapshots are stored as the
+//! XOR of the state with the previous keyframe, which is mostly
+//! zeroes and compresses very well with a simple run-length encoding.
+
+use std::collections::VecDeque;
+
+use crate::cpu::Cpu;
+use crate::savestate;
+
+/// Number of delta snapshots stored after each keyframe
+const DELTAS_PER_KEYFRAME: usize = 15;
+
+/// Minimum number of consecutive zeroes worth interrupting a literal
+/// run in the RLE encoding
+const MIN_ZERO_RUN: usize = 8;
+
+pub struct RewindBuffer {
+	/// Number of frames between two snapshots
+	interval: u32,
+	/// Maximum number of snapshots kept in the buffer
+	capacity: usize,
+	/// Frames elapsed since the last snapshot
+	frames_since_snapshot: u32,
+	/// Snapshots grouped by keyframe, oldest first
+	groups: VecDeque<Group>,
+	/// Uncompressed copy of the most recent keyframe, used to encode
+	/// new deltas
+	keyframe: Vec<u8>,
+	/// Buffer the snapshots are serialized into before being encoded.
+	/// The whole state, RAM included, is written uncompressed on
+	/// every snapshot so the allocation is kept from one to the next.
+	scratch: Vec<u8>,
+}
+
+impl RewindBuffer {
+	/// Create a buffer taking a snapshot every `interval` frames and
+	/// keeping up to `capacity` snapshots
+	pub fn new(interval: u32, capacity: usize) -> RewindBuffer {
+		RewindBuffer {
+			interval: interval.max(1),
+			capacity: capacity.max(1),
+			frames_since_snapshot: 0,
+			groups: VecDeque::new(),
+			keyframe: Vec::new(),
+			scratch: Vec::new(),
+		}
+	}
+
+	/// Number of snapshots currently held
+	pub fn len(&self) -> usize {
+		self.groups.iter().map(|g| g.len()).sum()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.groups.is_empty()
+	}
+
+	/// Approximate amount of memory used by the stored snapshots, in
+	/// bytes
+	pub fn memory_usage(&self) -> usize {
+		let snapshots: usize = self.groups.iter().map(|g| g.memory_usage()).sum();
+
+		snapshots + self.keyframe.len()
+	}
+
+	/// Drop all the snapshots
+	pub fn clear(&mut self) {
+		self.groups.clear();
+		self.keyframe.clear();
+		self.frames_since_snapshot = 0;
+	}
+
+	/// Must be called by the frontend at the end of every frame. A
+	/// snapshot of `cpu` is taken every `interval` frames.
+	pub fn end_of_frame(&mut self, cpu: &Cpu) -> Result<(), savestate::Error> {
+		self.frames_since_snapshot += 1;
+
+		if self.frames_since_snapshot >= self.interval || self.is_empty() {
+			self.snapshot(cpu)?;
+		}
+
+		Ok(())
+	}
+
+	/// Take a snapshot of `cpu` right away
+	pub fn snapshot(&mut self, cpu: &Cpu) -> Result<(), savestate::Error> {
+		let mut state = std::mem::take(&mut self.scratch);
+
+		state.clear();
+
+		savestate::save(&mut state, cpu)?;
+
+		self.frames_since_snapshot = 0;
+
+		let new_keyframe =
+			match self.groups.back() {
+				Some(g) => g.deltas.len() >= DELTAS_PER_KEYFRAME ||
+					state.len() != self.keyframe.len(),
+				None => true,
+			};
+
+		if new_keyframe {
+			self.groups.push_back(Group {
+				keyframe: rle_encode(&state),
+				deltas: Vec::new(),
+			});
+
+			// The previous keyframe becomes the next scratch buffer
+			std::mem::swap(&mut self.keyframe, &mut state);
+		} else {
+			for (s, k) in state.iter_mut().zip(self.keyframe.iter()) {
+				*s ^= *k;
+			}
+
+			if let Some(g) = self.groups.back_mut() {
+				g.deltas.push(rle_encode(&state));
+			}
+		}
+
+		self.scratch = state;
+
+		// Drop the oldest groups. Deltas can't be decoded without
+		// their keyframe so we always remove complete groups.
+		while self.groups.len() > 1 && self.len() > self.capacity {
+			self.groups.pop_front();
+		}
+
+		Ok(())
+	}
+
+	/// Restore `cpu` to the state it was in about `frames` frames ago,
+	/// or the oldest snapshot available if the buffer doesn't go back
+	/// that far. Snapshots more recent than the restored one are
+	/// discarded. Returns the number of frames actually rewound: since
+	/// we can only go back to a snapshot this can exceed `frames` by up
+	/// to `interval - 1`, or fall short of it if the buffer runs out.
+	pub fn rewind(&mut self, cpu: &mut Cpu, frames: u32) -> Result<u32, savestate::Error> {
+		let available = self.len();
+
+		if frames == 0 || available == 0 {
+			return Ok(0);
+		}
+
+		// Number of snapshots to go back from the most recent one
+		let mut back = 0;
+		let mut rewound = self.frames_since_snapshot;
+
+		while rewound < frames && back + 1 < available {
+			back += 1;
+			rewound += self.interval;
+		}
+
+		// Drop the snapshots we're rewinding past
+		for _ in 0..back {
+			self.pop_latest();
+		}
+
+		let state = match self.latest() {
+			Some(s) => s,
+			None => return Ok(0),
+		};
+
+		savestate::restore(&state[..], cpu)?;
+
+		self.frames_since_snapshot = 0;
+
+		Ok(rewound)
+	}
+
+	/// Remove the most recent snapshot
+	fn pop_latest(&mut self) {
+		let group = match self.groups.back_mut() {
+			Some(g) => g,
+			None => return,
+		};
+
+		if group.deltas.pop().is_none() {
+			self.groups.pop_back();
+
+			// The previous keyframe becomes the base for new deltas
+			self.keyframe = match self.groups.back() {
+				Some(g) => rle_decode(&g.keyframe),
+				None => Vec::new(),
+			};
+		}
+	}
+
+	/// Decode the most recent snapshot
+	fn latest(&self) -> Option<Vec<u8>> {
+		let group = self.groups.back()?;
+
+		let state = match group.deltas.last() {
+			Some(delta) => {
+				let mut state = rle_decode(delta);
+
+				for (s, k) in state.iter_mut().zip(self.keyframe.iter()) {
+					*s ^= *k;
+				}
+
+				state
+			}
+			None => self.keyframe.clone(),
+		};
+
+		Some(state)
+	}
+}
+
+/// A keyframe and the deltas encoded against it
+struct Group {
+	/// RLE-encoded full snapshot
+	keyframe: Vec<u8>,
+	/// RLE-encoded XOR of the snapshot with the keyframe
+	deltas: Vec<Vec<u8>>,
+}
+
+impl Group {
+	fn len(&self) -> usize {
+		1 + self.deltas.len()
+	}
+
+	fn memory_usage(&self) -> usize {
+		self.keyframe.len() + self.deltas.iter().map(|d| d.len()).sum::<usize>()
+	}
+}
+
+/// Run-length encode `data`. The output is a sequence of
+/// `(zeroes, literal_len, literal bytes...)` tokens, both lengths
+/// stored as little endian u32.
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut pos = 0;
+
+	while pos < data.len() {
+		let zeroes = data[pos..].iter().take_while(|&&b| b == 0).count();
+		pos += zeroes;
+
+		// Extend the literal run until we reach a run of zeroes long
+		// enough to be worth a new token
+		let start = pos;
+		while pos < data.len() {
+			let run = data[pos..].iter().take(MIN_ZERO_RUN).take_while(|&&b| b == 0).count();
+
+			if run == MIN_ZERO_RUN || pos + run == data.len() {
+				break;
+			}
+
+			pos += run.max(1);
+		}
+
+		out.extend_from_slice(&(zeroes as u32).to_le_bytes());
+		out.extend_from_slice(&((pos - start) as u32).to_le_bytes());
+		out.extend_from_slice(&data[start..pos]);
+	}
+
+	out
+}
+
+/// Decode a buffer created by `rle_encode`
+fn rle_decode(data: &[u8]) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut pos = 0;
+
+	let read_u32 = |pos: usize| {
+		let mut b = [0; 4];
+		b.copy_from_slice(&data[pos..pos + 4]);
+		u32::from_le_bytes(b) as usize
+	};
+
+	while pos < data.len() {
+		let zeroes = read_u32(pos);
+		let literal = read_u32(pos + 4);
+		pos += 8;
+
+		out.resize(out.len() + zeroes, 0);
+		out.extend_from_slice(&data[pos..pos + literal]);
+		pos += literal;
+	}
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+
+	use crate::bios::Bios;
+	use crate::memory::{Interconnect, Word};
+
+	use super::*;
+
+	fn save(cpu: &Cpu) -> Vec<u8> {
+		let mut state = Vec::new();
+
+		savestate::save(&mut state, cpu).unwrap();
+
+		state
+	}
+
+	#[test]
+	fn rewind_frames() {
+		let mut cpu = Cpu::new(Interconnect::new(Bios::dummy()));
+		let mut buffer = RewindBuffer::new(1, 40);
+		let mut states = Vec::new();
+
+		// Enough frames to span two keyframes
+		for frame in 0..18u32 {
+			cpu.interconnect_mut().store::<Word>(0x1000 + (frame % 8) * 4, frame);
+			cpu.set_pc(0x8001_0000 + frame * 4);
+
+			buffer.end_of_frame(&cpu).unwrap();
+			states.push(save(&cpu));
+		}
+
+		assert_eq!(buffer.len(), 18);
+
+		// The last rewind crosses back into the first keyframe
+		for n in [1, 3] {
+			let rewound = buffer.rewind(&mut cpu, n).unwrap();
+
+			assert_eq!(rewound, n);
+
+			states.truncate(states.len() - n as usize);
+
+			assert_eq!(&save(&cpu), states.last().unwrap());
+		}
+
+		assert_eq!(buffer.len(), 14);
+
+		// Rewinding further than the oldest snapshot stops there
+		assert_eq!(buffer.rewind(&mut cpu, 100).unwrap(), 13);
+		assert_eq!(save(&cpu), states[0]);
+	}
+
+	#[test]
+	fn rewind_interval() {
+		let mut cpu = Cpu::new(Interconnect::new(Bios::dummy()));
+		let mut buffer = RewindBuffer::new(4, 40);
+		let mut states = Vec::new();
+
+		// Snapshots are taken at frames 0, 4 and 8
+		for frame in 0..10u32 {
+			cpu.set_pc(0x8001_0000 + frame * 4);
+
+			buffer.end_of_frame(&cpu).unwrap();
+			states.push(save(&cpu));
+		}
+
+		assert_eq!(buffer.len(), 3);
+
+		// Rewinding zero frames leaves the CPU alone
+		assert_eq!(buffer.rewind(&mut cpu, 0).unwrap(), 0);
+		assert_eq!(&save(&cpu), &states[9]);
+
+		// Back to the snapshot of frame 8
+		assert_eq!(buffer.rewind(&mut cpu, 1).unwrap(), 1);
+		assert_eq!(&save(&cpu), &states[8]);
+
+		// There's no snapshot two frames back so we go all the way to
+		// frame 4 and report it
+		assert_eq!(buffer.rewind(&mut cpu, 2).unwrap(), 4);
+		assert_eq!(&save(&cpu), &states[4]);
+		assert_eq!(buffer.len(), 2);
+	}
+
+	proptest! {
+		#[test]
+		fn rle_round_trip(data in proptest::collection::vec(
+			prop_oneof![3 => Just(0u8), 1 => any::<u8>()], 0..2048)) {
+			prop_assert_eq!(rle_decode(&rle_encode(&data)), data);
+		}
+	}
+}
@@ -0,0 +1,525 @@
+//! Versioned machine snapshots.
+//!
+//! A save state starts with a magic and the container format version,
+//! followed by a bincode-encoded header containing the version of the
+//! emulator that created it and a table of sections. Each component
+//! of the machine is stored in its own section alongside the layout
+//! revision it was written with, so that states created by older
+//! builds can be migrated (or rejected with a meaningful error)
+//! instead of failing somewhere in the middle of deserialization.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bios::Bios;
+use crate::cpu::Cpu;
+use crate::version::VERSION;
+
+/// Magic identifying save state files
+const MAGIC: [u8; 8] = *b"HVPS2SST";
+
+/// Version of the container layout (header and section table). The
+/// layout of individual components is versioned separately through
+/// `Component::REVISION`.
+const FORMAT_VERSION: u32 = 1;
+
+/// Serialize the complete state of the machine into `writer`
+pub fn save<W: Write>(mut writer: W, cpu: &Cpu) -> Result<(), Error> {
+	let mut state = StateWriter::new();
+
+	cpu.save_state(&mut state)?;
+
+	writer.write_all(&MAGIC)?;
+	writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+	let header = HeaderRef {
+		version: VERSION,
+		sections: &state.sections,
+	};
+
+	bincode::serialize_into(writer, &header)?;
+
+	Ok(())
+}
+
+/// Rebuild a machine from a state created by `save`. The BIOS ROM is
+/// not part of the save state so `bios` must be the image that was
+/// used when the state was created.
+pub fn load<R: Read>(reader: R, bios: Bios) -> Result<Cpu, Error> {
+	let mut cpu = read_state(reader)?;
+
+	if cpu.interconnect_mut().reattach_bios(bios).is_err() {
+		return Err(Error::BiosMismatch);
+	}
+
+	Ok(cpu)
+}
+
+/// Replace the state of `cpu` with the one read from `reader`,
+/// keeping the BIOS currently attached to `cpu`. On error `cpu` is
+/// left untouched.
+pub fn restore<R: Read>(reader: R, cpu: &mut Cpu) -> Result<(), Error> {
+	let mut restored = read_state(reader)?;
+
+	let sha256 = cpu.interconnect().bios().metadata().sha256;
+
+	if restored.interconnect().bios().metadata().sha256 != sha256 {
+		return Err(Error::BiosMismatch);
+	}
+
+	let bios = cpu.interconnect_mut().take_bios();
+
+	// Can't fail, we've just compared the checksums
+	let _ = restored.interconnect_mut().reattach_bios(bios);
+
+	// The TTY and kernel call tracer belong to the host, keep their
+	// buffers and callbacks
+	let tty = cpu.interconnect_mut().take_tty();
+	restored.interconnect_mut().set_tty(tty);
+
+	let tracer = cpu.set_kernel_tracer(None);
+	restored.set_kernel_tracer(tracer);
+
+	*cpu = restored;
+
+	Ok(())
+}
+
+/// Decode a save state. The resulting CPU only has a placeholder BIOS
+fn read_state<R: Read>(mut reader: R) -> Result<Cpu, Error> {
+	let mut magic = [0; 8];
+	reader.read_exact(&mut magic)?;
+
+	if magic != MAGIC {
+		return Err(Error::BadMagic);
+	}
+
+	let mut format = [0; 4];
+	reader.read_exact(&mut format)?;
+	let format = u32::from_le_bytes(format);
+
+	if format != FORMAT_VERSION {
+		return Err(Error::UnsupportedFormat(format));
+	}
+
+	let header: Header = bincode::deserialize_from(reader)?;
+
+	if header.version != VERSION {
+		info!("Loading save state created by version {} (current {})",
+			  header.version, VERSION);
+	}
+
+	let state = StateReader {
+		version: header.version,
+		sections: header.sections,
+	};
+
+	Cpu::load_state(&state)
+}
+
+/// A piece of the machine stored in its own save state section
+pub trait Component: Serialize + DeserializeOwned {
+	/// Name of the section holding this component
+	const SECTION: &'static str;
+	/// Revision of the serialized layout. Must be incremented every
+	/// time the serialized form of the component changes.
+	const REVISION: u32;
+	/// Emulator version that introduced `REVISION`
+	const SINCE: &'static str;
+
+	/// Called when the section was written by an older revision of
+	/// the component. Implementations that know how to convert
+	/// `revision` should decode `data` themselves, the default
+	/// rejects the state.
+	fn migrate(revision: u32, data: &[u8]) -> Result<Self, Error> {
+		let _ = data;
+
+		Err(Error::Incompatible {
+			section: Self::SECTION,
+			revision,
+			since: Self::SINCE,
+		})
+	}
+}
+
+/// Collects the sections of a save state
+pub struct StateWriter {
+	sections: Vec<Section>,
+}
+
+impl StateWriter {
+	fn new() -> StateWriter {
+		StateWriter {
+			sections: Vec::new(),
+		}
+	}
+
+	/// Serialize `component` in a new section
+	pub fn add<T: Component>(&mut self, component: &T) -> Result<(), Error> {
+		let data = bincode::serialize(component)?;
+
+		self.sections.push(Section {
+			name: T::SECTION.into(),
+			revision: T::REVISION,
+			data,
+		});
+
+		Ok(())
+	}
+}
+
+/// Gives access to the sections of a save state being loaded
+pub struct StateReader {
+	version: String,
+	sections: Vec<Section>,
+}
+
+impl StateReader {
+	/// Version of the emulator that created the state
+	pub fn version(&self) -> &str {
+		&self.version
+	}
+
+	/// Decode the section of component `T`, migrating it if it was
+	/// written by an older revision
+	pub fn get<T: Component>(&self) -> Result<T, Error> {
+		let section = self.sections.iter()
+			.find(|s| s.name == T::SECTION)
+			.ok_or(Error::MissingSection(T::SECTION))?;
+
+		if section.revision == T::REVISION {
+			Ok(bincode::deserialize(&section.data)?)
+		} else if section.revision < T::REVISION {
+			T::migrate(section.revision, &section.data)
+		} else {
+			Err(Error::TooNew {
+				section: T::SECTION,
+				revision: section.revision,
+				version: self.version.clone(),
+			})
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct Section {
+	name: String,
+	revision: u32,
+	#[serde(with = "bytes")]
+	data: Vec<u8>,
+}
+
+/// Serialize section data as a single byte buffer instead of a
+/// sequence of `u8`s. The encoding is the same with bincode but it
+/// avoids going through serde for every byte of RAM.
+mod bytes {
+	use std::fmt;
+
+	use serde::{de, Deserializer, Serializer};
+
+	pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_bytes(data)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+		struct BytesVisitor;
+
+		impl<'de> de::Visitor<'de> for BytesVisitor {
+			type Value = Vec<u8>;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				f.write_str("a byte buffer")
+			}
+
+			fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+				Ok(v.to_vec())
+			}
+
+			fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+				Ok(v)
+			}
+		}
+
+		deserializer.deserialize_byte_buf(BytesVisitor)
+	}
+}
+
+#[derive(Serialize)]
+struct HeaderRef<'a> {
+	version: &'a str,
+	sections: &'a [Section],
+}
+
+#[derive(Deserialize)]
+struct Header {
+	version: String,
+	sections: Vec<Section>,
+}
+
+/// Error returned when saving or loading a state fails
+#[derive(Debug)]
+pub enum Error {
+	/// I/O error while reading or writing the state
+	Io(io::Error),
+	/// Serialization error
+	Encoding(bincode::Error),
+	/// The file is not a save state
+	BadMagic,
+	/// The container uses a format this build doesn't understand
+	UnsupportedFormat(u32),
+	/// A component is missing from the state
+	MissingSection(&'static str),
+	/// A section was written by an older revision of the component
+	/// that can't be migrated
+	Incompatible {
+		section: &'static str,
+		/// Revision found in the state
+		revision: u32,
+		/// Emulator version that broke compatibility
+		since: &'static str,
+	},
+	/// A section was written by a newer revision of the component
+	TooNew {
+		section: &'static str,
+		revision: u32,
+		/// Version of the emulator that created the state
+		version: String,
+	},
+	/// The BIOS passed to `load` is not the one the state was saved
+	/// with
+	BiosMismatch,
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Error {
+		Error::Io(e)
+	}
+}
+
+impl From<bincode::Error> for Error {
+	fn from(e: bincode::Error) -> Error {
+		Error::Encoding(e)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "Save state I/O error: {}", e),
+			Error::Encoding(e) => write!(f, "Save state encoding error: {}", e),
+			Error::BadMagic => write!(f, "Not a save state"),
+			Error::UnsupportedFormat(v) =>
+				write!(f, "Unsupported save state format {}", v),
+			Error::MissingSection(s) =>
+				write!(f, "Save state is missing section '{}'", s),
+			Error::Incompatible { section, revision, since } =>
+				write!(f, "Save state section '{}' (revision {}) is \
+						   incompatible since v{}", section, revision, since),
+			Error::TooNew { section, revision, version } =>
+				write!(f, "Save state section '{}' (revision {}) was created \
+						   by a newer version ({})", section, revision, version),
+			Error::BiosMismatch =>
+				write!(f, "The BIOS doesn't match the one used by the save state"),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+	use crate::bios::Bios;
+	use crate::memory::{Interconnect, Word};
+
+	use super::*;
+
+	fn machine() -> Cpu {
+		let mut cpu = Cpu::new(Interconnect::new(Bios::dummy()));
+
+		cpu.set_pc(0x8001_0000);
+		cpu.set_reg(8, 0x1234_5678);
+		cpu.interconnect_mut().store::<Word>(0x1000, 0xcafe_f00d);
+
+		cpu
+	}
+
+	/// Build a save state containing `sections`
+	fn state_with(sections: &[Section]) -> Vec<u8> {
+		let mut state = MAGIC.to_vec();
+
+		state.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+		let header = HeaderRef {
+			version: "9.9.9",
+			sections,
+		};
+
+		bincode::serialize_into(&mut state, &header).unwrap();
+
+		state
+	}
+
+	/// Sections written by `cpu`
+	fn sections(cpu: &Cpu) -> Vec<Section> {
+		let mut state = StateWriter::new();
+
+		cpu.save_state(&mut state).unwrap();
+
+		state.sections
+	}
+
+	#[derive(Serialize, Deserialize, Debug, PartialEq)]
+	struct Counter(u32);
+
+	impl Component for Counter {
+		const SECTION: &'static str = "counter";
+		const REVISION: u32 = 3;
+		const SINCE: &'static str = "0.2.0";
+
+		fn migrate(revision: u32, data: &[u8]) -> Result<Counter, Error> {
+			match revision {
+				// Revision 2 used a u16
+				2 => {
+					let v: u16 = bincode::deserialize(data)?;
+					Ok(Counter(v as u32))
+				}
+				_ => Err(Error::Incompatible {
+					section: Self::SECTION,
+					revision,
+					since: Self::SINCE,
+				}),
+			}
+		}
+	}
+
+	fn reader(revision: u32, data: Vec<u8>) -> StateReader {
+		StateReader {
+			version: "9.9.9".into(),
+			sections: vec![Section {
+				name: Counter::SECTION.into(),
+				revision,
+				data,
+			}],
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let cpu = machine();
+
+		let mut state = Vec::new();
+		save(&mut state, &cpu).unwrap();
+
+		let mut loaded = load(&state[..], Bios::dummy()).unwrap();
+
+		assert_eq!(loaded.pc(), 0x8001_0000);
+		assert_eq!(loaded.reg(8), 0x1234_5678);
+		assert_eq!(loaded.interconnect_mut().load::<Word>(0x1000), 0xcafe_f00d);
+
+		// Saving again gives the same state
+		let mut again = Vec::new();
+		save(&mut again, &loaded).unwrap();
+		assert!(again == state);
+
+		let mut other = Cpu::new(Interconnect::new(Bios::dummy()));
+		restore(&state[..], &mut other).unwrap();
+		assert_eq!(other.reg(8), 0x1234_5678);
+	}
+
+	#[test]
+	fn bad_magic() {
+		let mut state = Vec::new();
+		save(&mut state, &machine()).unwrap();
+
+		state[0] ^= 0xff;
+
+		assert!(matches!(load(&state[..], Bios::dummy()), Err(Error::BadMagic)));
+		assert!(matches!(load(&b"HVPS"[..], Bios::dummy()), Err(Error::Io(_))));
+	}
+
+	#[test]
+	fn unsupported_format() {
+		let mut state = Vec::new();
+		save(&mut state, &machine()).unwrap();
+
+		state[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+		assert!(matches!(load(&state[..], Bios::dummy()),
+						 Err(Error::UnsupportedFormat(v)) if v == FORMAT_VERSION + 1));
+	}
+
+	#[test]
+	fn missing_section() {
+		let mut sections = sections(&machine());
+
+		sections.retain(|s| s.name != "duart");
+
+		let state = state_with(&sections);
+
+		assert!(matches!(load(&state[..], Bios::dummy()),
+						 Err(Error::MissingSection("duart"))));
+
+		// Restoring leaves the running machine untouched
+		let mut cpu = Cpu::new(Interconnect::new(Bios::dummy()));
+
+		assert!(restore(&state[..], &mut cpu).is_err());
+		assert_eq!(cpu.reg(8), 0);
+
+		assert!(matches!(reader(3, vec![]).get::<Cpu>(),
+						 Err(Error::MissingSection("cpu"))));
+	}
+
+	#[test]
+	fn too_new() {
+		let state = reader(4, bincode::serialize(&7u32).unwrap());
+
+		match state.get::<Counter>() {
+			Err(Error::TooNew { section, revision, version }) => {
+				assert_eq!((section, revision), ("counter", 4));
+				assert_eq!(version, "9.9.9");
+			}
+			r => panic!("Unexpected result {:?}", r),
+		}
+
+		// A newer section in a whole state
+		let mut sections = sections(&machine());
+
+		for s in sections.iter_mut().filter(|s| s.name == "cop0") {
+			s.revision += 1;
+		}
+
+		assert!(matches!(load(&state_with(&sections)[..], Bios::dummy()),
+						 Err(Error::TooNew { section: "cop0", .. })));
+	}
+
+	#[test]
+	fn incompatible() {
+		let state = reader(3, bincode::serialize(&7u32).unwrap());
+		assert_eq!(state.get::<Counter>().unwrap(), Counter(7));
+
+		let state = reader(2, bincode::serialize(&9u16).unwrap());
+		assert_eq!(state.get::<Counter>().unwrap(), Counter(9));
+
+		let state = reader(1, bincode::serialize(&9u16).unwrap());
+
+		match state.get::<Counter>() {
+			Err(Error::Incompatible { section, revision, since }) => {
+				assert_eq!((section, revision, since), ("counter", 1, "0.2.0"));
+			}
+			r => panic!("Unexpected result {:?}", r),
+		}
+
+		// The default implementation rejects every older revision
+		let mut sections = sections(&machine());
+
+		for s in sections.iter_mut().filter(|s| s.name == "hle") {
+			s.revision -= 1;
+		}
+
+		assert!(matches!(load(&state_with(&sections)[..], Bios::dummy()),
+						 Err(Error::Incompatible { section: "hle", revision: 0, .. })));
+	}
+}
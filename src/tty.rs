@@ -0,0 +1,100 @@
+//! Guest text output
+//!
+//! Characters written by the guest through the debug UART or the
+//! BIOS putchar function end up here, where the frontend can read
+//! them back or have them streamed to a callback.
+
+/// Maximum number of unread bytes kept in the buffer. Older output is
+/// discarded past this limit.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+type Listener = Box<dyn FnMut(u8)>;
+
+pub struct Tty {
+	/// Output not yet retrieved with `take_output`
+	buffer: Vec<u8>,
+	/// Current incomplete line, used for logging
+	line: Vec<u8>,
+	/// If true complete lines are logged
+	log_lines: bool,
+	/// Receives every character as it's output
+	listener: Option<Listener>,
+	/// If true the CPU captures the calls to the BIOS putchar
+	/// function
+	intercept_putchar: bool,
+}
+
+impl Tty {
+	pub fn new() -> Tty {
+		Tty {
+			buffer: Vec::new(),
+			line: Vec::new(),
+			log_lines: true,
+			listener: None,
+			intercept_putchar: false,
+		}
+	}
+
+	/// Output a single character
+	pub fn putchar(&mut self, c: u8) {
+		if let Some(ref mut l) = self.listener {
+			l(c);
+		}
+
+		if self.buffer.len() >= BUFFER_SIZE {
+			let excess = self.buffer.len() + 1 - BUFFER_SIZE;
+			self.buffer.drain(..excess);
+		}
+		self.buffer.push(c);
+
+		match c {
+			b'\n' => {
+				if self.log_lines {
+					info!("TTY: {}", String::from_utf8_lossy(&self.line));
+				}
+				self.line.clear();
+			}
+			b'\r' => (),
+			c => self.line.push(c),
+		}
+	}
+
+	/// Output unread so far
+	pub fn output(&self) -> &[u8] {
+		&self.buffer
+	}
+
+	/// Return the unread output and clear the buffer
+	pub fn take_output(&mut self) -> Vec<u8> {
+		std::mem::take(&mut self.buffer)
+	}
+
+	/// Enable or disable logging of the output lines
+	pub fn set_log_lines(&mut self, enabled: bool) {
+		self.log_lines = enabled;
+	}
+
+	/// Capture the characters passed to the BIOS putchar function
+	/// (A0:3C and B0:3D). Useful when the BIOS doesn't have a debug
+	/// UART patch, if it does the output will be duplicated. The HLE
+	/// kernel always writes to the TTY.
+	pub fn set_intercept_putchar(&mut self, enabled: bool) {
+		self.intercept_putchar = enabled;
+	}
+
+	pub fn intercepts_putchar(&self) -> bool {
+		self.intercept_putchar
+	}
+
+	/// Stream every output character to `listener`, or stop streaming
+	/// if `listener` is `None`
+	pub fn set_listener(&mut self, listener: Option<Listener>) {
+		self.listener = listener;
+	}
+}
+
+impl Default for Tty {
+	fn default() -> Tty {
+		Tty::new()
+	}
+}